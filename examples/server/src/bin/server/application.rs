@@ -1,42 +1,19 @@
 // Pyrinas related
 use pyrinas_codec_example::EnvironmentData;
-use pyrinas_server::{settings::PyrinasSettings, Event};
+use pyrinas_server::{application::Application, settings::PyrinasSettings, Event};
 
 // async Related
-use flume::{unbounded, Sender};
+use flume::Sender;
 use std::sync::Arc;
 
-pub async fn run(_settings: Arc<PyrinasSettings>, broker_sender: Sender<Event>) {
-    // Get the sender/reciever associated with this particular task
-    let (sender, reciever) = unbounded::<Event>();
+pub async fn run(settings: Arc<PyrinasSettings>, broker_sender: Sender<Event>) {
+    Application::new()
+        // Data from MQTT clients published to <uid>/app/p/env
+        .handle("env", |ctx, msg: EnvironmentData| async move {
+            log::info!("{}: {:?}", ctx.uid(), msg);
 
-    // Register this task
-    broker_sender
-        .send_async(Event::NewRunner {
-            name: "app".to_string(),
-            sender: sender.clone(),
+            Ok(())
         })
-        .await
-        .unwrap();
-
-    // Wait for event on reciever
-    while let Ok(event) = reciever.recv_async().await {
-        log::info!("{:?}", event);
-
-        // Match the event. The only one we're interested in is the `ApplicationRequest`
-        if let Event::ApplicationRequest(req) = event {
-            if req.target.as_str() == "env" {
-                // Deserialize data from MQTT clients
-                let msg: EnvironmentData = match serde_cbor::from_slice(&req.msg) {
-                    Ok(m) => m,
-                    Err(e) => {
-                        log::warn!("Unable to deserialize from MQTT message. Err: {}", e);
-                        continue;
-                    }
-                };
-
-                log::info!("{:?}", msg);
-            }
-        }
-    }
+        .run(settings, broker_sender)
+        .await;
 }
@@ -3,7 +3,6 @@ mod application;
 mod structures;
 
 // async Related
-use flume::unbounded;
 use std::sync::Arc;
 use tokio::task;
 
@@ -11,7 +10,7 @@ use tokio::task;
 use clap::Parser;
 
 // Local crate related
-use pyrinas_server::{self, settings, Event};
+use pyrinas_server::{self, application::RUNNER_NAME, broker, settings, PyrinasServer};
 
 /// Pyrinas Tracker example
 #[derive(Parser)]
@@ -19,6 +18,9 @@ use pyrinas_server::{self, settings, Event};
 struct Opts {
     /// Path to the required configuration file
     config: String,
+    /// Replay events recorded with `broker.record_path` instead of running normally
+    #[clap(long)]
+    replay: Option<String>,
 }
 
 #[tokio::main()]
@@ -41,21 +43,48 @@ async fn main() {
         }
     };
 
-    // Channels for communication
-    let (broker_sender, broker_reciever) = unbounded::<Event>();
+    // Feed recorded events through the application and OTA
+    if let Some(path) = opts.replay {
+        // Channels for communication
+        let (broker_sender, broker_reciever) = broker::channel(&settings.broker);
 
-    // Start (very) basic application
-    let task_settings = settings.clone();
-    let task_sender = broker_sender.clone();
-    let app_task = task::spawn(application::run(task_settings, task_sender));
+        // Start (very) basic application
+        task::spawn(application::run(settings.clone(), broker_sender.clone()));
 
-    // Start the server and all underlying tasks
-    let pyrinas_task = task::spawn(pyrinas_server::run(
-        settings,
-        broker_sender,
-        broker_reciever,
-    ));
+        if let Err(e) = pyrinas_server::replay::run(
+            settings,
+            path,
+            &[RUNNER_NAME],
+            broker_sender,
+            broker_reciever,
+        )
+        .await
+        {
+            log::error!("Replay error! Err: {}", e);
+        }
+
+        return;
+    }
+
+    // Server with all underlying tasks and the (very) basic application
+    let app_settings = settings.clone();
+    let server = match PyrinasServer::builder(settings)
+        .runner(RUNNER_NAME, move |sender| {
+            application::run(app_settings.clone(), sender)
+        })
+        .build()
+    {
+        Ok(s) => s,
+        Err(e) => {
+            log::error!("Unable to create server! Err: {}", e);
+            return;
+        }
+    };
+
+    // Stop cleanly on Ctrl-C/SIGTERM
+    server.shutdown().trigger_on_signal();
 
-    // Join hands kids
-    let _join = tokio::join!(app_task, pyrinas_task);
+    if let Err(e) = server.run().await {
+        log::error!("Server error! Err: {}", e);
+    }
 }
@@ -1,2 +1 @@
 pub mod data;
-pub mod influx;
@@ -1,8 +1,9 @@
-use crate::structures::influx;
 use chrono::{serde::ts_milliseconds::deserialize as from_ts, DateTime, Utc};
+use pyrinas_server::payload::PyrinasPayload;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PyrinasPayload)]
+#[pyrinas(measurement = "gps")]
 pub struct TrackerGpsReport {
     pub lng: f32,
     pub lat: f32,
@@ -11,26 +12,11 @@ pub struct TrackerGpsReport {
     pub spd: f32,
     pub hdg: f32,
     #[serde(deserialize_with = "from_ts")]
+    #[pyrinas(timestamp)]
     pub ts: DateTime<Utc>,
 }
 
-impl TrackerGpsReport {
-    pub fn to_influx(&self, id: &str) -> influx::TrackerGpsInfluxReport {
-        // Return new data structure that's friendly with Influx
-        influx::TrackerGpsInfluxReport {
-            time: self.ts,
-            id: id.to_string(),
-            lng: self.lng,
-            lat: self.lat,
-            acc: self.acc,
-            alt: self.alt,
-            spd: self.spd,
-            hdg: self.hdg,
-        }
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PyrinasPayload)]
 pub struct TrackerNetworkReport {
     pub rsrp: u16,
     pub area: u32,
@@ -39,79 +25,53 @@ pub struct TrackerNetworkReport {
     pub cell: u32,
     pub ip: String,
     pub band: u16,
+    #[pyrinas(rename = "mode_gps")]
     pub m_gps: u16,
+    #[pyrinas(rename = "mode_lte")]
     pub m_lte: u16,
+    #[pyrinas(rename = "mode_nbiot")]
     pub m_nb: u16,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PyrinasPayload)]
 pub struct TrackerSimReport {
     pub iccid: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PyrinasPayload)]
 pub struct TrackerInfoReport {
     #[serde(alias = "modv")]
+    #[pyrinas(rename = "modem_version")]
     pub mod_version: String,
     #[serde(alias = "brdv")]
+    #[pyrinas(rename = "board")]
     pub brd_version: String,
     #[serde(alias = "appv")]
     pub app_version: String,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PyrinasPayload)]
+#[pyrinas(measurement = "boot")]
 pub struct TrackerDeviceReport {
     pub vbat: u16,
+    #[pyrinas(flatten)]
     pub nw: TrackerNetworkReport,
+    #[pyrinas(flatten)]
     pub sim: TrackerSimReport,
+    #[pyrinas(flatten)]
     pub inf: TrackerInfoReport,
     #[serde(deserialize_with = "from_ts")]
+    #[pyrinas(timestamp)]
     pub ts: DateTime<Utc>,
 }
 
-impl TrackerDeviceReport {
-    pub fn to_influx(&self, id: &str) -> influx::TrackerDeviceInfluxReport {
-        // Return new data structure that's friendly with Influx
-        influx::TrackerDeviceInfluxReport {
-            time: self.ts,
-            id: id.to_string(),
-            rsrp: self.nw.rsrp,
-            area: self.nw.area,
-            mnc: self.nw.mnc,
-            mcc: self.nw.mcc,
-            cell: self.nw.cell,
-            ip: self.nw.ip.clone(),
-            band: self.nw.band,
-            mode_gps: self.nw.m_gps,
-            mode_lte: self.nw.m_lte,
-            mode_nbiot: self.nw.m_nb,
-            iccid: self.sim.iccid.clone(),
-            modem_version: self.inf.mod_version.clone(),
-            board: self.inf.brd_version.clone(),
-            app_version: self.inf.app_version.clone(),
-            vbat: self.vbat,
-        }
-    }
-}
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PyrinasPayload)]
+#[pyrinas(measurement = "accel")]
 pub struct TrackerAccelReport {
     pub x: f32,
     pub y: f32,
     pub z: f32,
     #[serde(deserialize_with = "from_ts")]
+    #[pyrinas(timestamp)]
     pub ts: DateTime<Utc>,
 }
-
-impl TrackerAccelReport {
-    pub fn to_influx(&self, id: &str) -> influx::TrackerAccelInfluxReport {
-        // Return new data structure that's friendly with Influx
-        influx::TrackerAccelInfluxReport {
-            time: self.ts,
-            id: id.to_string(),
-            x: self.x,
-            y: self.y,
-            z: self.z,
-        }
-    }
-}
@@ -1,6 +1,6 @@
 use clap::Parser;
 use pyrinas_cli::{ota, CertCmd, Error};
-use pyrinas_cli::{ConfigCmd, OtaCmd};
+use pyrinas_cli::{BrokerCmd, ConfigCmd, DataCmd, DeviceCmd, OtaCmd};
 
 /// Command line utility to communicate with Pyrinas server over
 /// a websockets connection.
@@ -15,6 +15,9 @@ struct Opts {
 #[clap(version)]
 enum SubCommand {
     Ota(OtaCmd),
+    Broker(BrokerCmd),
+    Device(DeviceCmd),
+    Data(DataCmd),
     Config(ConfigCmd),
     Cert(CertCmd),
 }
@@ -53,6 +56,27 @@ fn main() -> Result<(), Error> {
 
             crate::ota::process(&mut socket, &c.subcmd)?;
         }
+        // Process broker commands (needs to be connected)
+        SubCommand::Broker(c) => {
+            // Get socket
+            let mut socket = pyrinas_cli::get_socket(&config)?;
+
+            pyrinas_cli::broker::process(&mut socket, &c.subcmd)?;
+        }
+        // Process device commands (needs to be connected)
+        SubCommand::Device(c) => {
+            // Get socket
+            let mut socket = pyrinas_cli::get_socket(&config)?;
+
+            pyrinas_cli::device::process(&mut socket, &c.subcmd)?;
+        }
+        // Process data commands (needs to be connected)
+        SubCommand::Data(c) => {
+            // Get socket
+            let mut socket = pyrinas_cli::get_socket(&config)?;
+
+            pyrinas_cli::data::process(&mut socket, &c.subcmd)?;
+        }
         // Depending on the input, create CA, server or client cert
         SubCommand::Cert(c) => pyrinas_cli::certs::process(&config, &c)?,
         // Process config commands
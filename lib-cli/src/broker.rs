@@ -0,0 +1,135 @@
+// Pyrinas
+use chrono::{Local, TimeZone};
+use pyrinas_shared::{
+    BrokerStatsResponse, DeadLetterListResponse, DeadLetterRequest, ManagmentDataType,
+};
+
+// Std lib
+use std::net::TcpStream;
+
+// Websocket
+use tungstenite::{protocol::WebSocket, stream::MaybeTlsStream};
+
+// Error handling
+use thiserror::Error;
+
+use crate::{management, BrokerSubCommand, DeadLetterSubCommand};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Serde CBOR error
+    #[error("serde_cbor error: {source}")]
+    CborError {
+        #[from]
+        source: serde_cbor::Error,
+    },
+
+    /// Request/response error
+    #[error("{source}")]
+    ManagementError {
+        #[from]
+        source: management::Error,
+    },
+}
+
+/// Functon for processing all incoming broker commands.
+pub fn process(
+    socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    cmd: &BrokerSubCommand,
+) -> Result<(), Error> {
+    match cmd {
+        BrokerSubCommand::Stats => {
+            let stats = get_broker_stats(socket)?;
+
+            println!(
+                "{:<12} {:>8} {:>8} {:>8} {:>8}",
+                "runner", "queued", "capacity", "spilled", "dropped"
+            );
+
+            for runner in stats.runners.iter() {
+                // Print out the entry
+                println!(
+                    "{:<12} {:>8} {:>8} {:>8} {:>8}",
+                    runner.name, runner.queued, runner.capacity, runner.spilled, runner.dropped
+                );
+            }
+
+            // Only there if the middleware is enabled
+            if !stats.events.is_empty() {
+                println!("\n{:<36} {:>8}", "event", "count");
+
+                for event in stats.events.iter() {
+                    println!("{:<36} {:>8}", event.name, event.count);
+                }
+            }
+
+            if !stats.rejected.is_empty() {
+                println!("\n{:<36} {:>8}", "rejected by", "count");
+
+                for rejected in stats.rejected.iter() {
+                    println!("{:<36} {:>8}", rejected.name, rejected.count);
+                }
+            }
+        }
+        BrokerSubCommand::DeadLetters(c) => match &c.subcmd {
+            DeadLetterSubCommand::List => {
+                let list = get_dead_letters(socket)?;
+
+                for entry in list.entries.iter() {
+                    // Get the date
+                    let date = match Local.timestamp_millis_opt(entry.timestamp_ms).single() {
+                        Some(d) => d.to_string(),
+                        None => entry.timestamp_ms.to_string(),
+                    };
+
+                    // Print out the entry
+                    println!(
+                        "{} {} {} ({})\n  {}",
+                        entry.id, date, entry.runner, entry.reason, entry.event
+                    );
+                }
+            }
+            DeadLetterSubCommand::Replay(s) => {
+                dead_letter_request(socket, ManagmentDataType::ReplayDeadLetters, s.id)?;
+
+                println!("Dead letters replayed!");
+            }
+            DeadLetterSubCommand::Purge(s) => {
+                dead_letter_request(socket, ManagmentDataType::PurgeDeadLetters, s.id)?;
+
+                println!("Dead letters purged!");
+            }
+        },
+    };
+
+    Ok(())
+}
+
+pub fn get_broker_stats(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+) -> Result<BrokerStatsResponse, Error> {
+    let data = management::request(stream, ManagmentDataType::GetBrokerStats, None, [].to_vec())?;
+
+    Ok(serde_cbor::from_slice(&data)?)
+}
+
+pub fn get_dead_letters(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+) -> Result<DeadLetterListResponse, Error> {
+    let data = management::request(stream, ManagmentDataType::GetDeadLetters, None, [].to_vec())?;
+
+    Ok(serde_cbor::from_slice(&data)?)
+}
+
+/// Replay or purge dead letters. All of them if `id` is `None`.
+pub fn dead_letter_request(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    cmd: ManagmentDataType,
+    id: Option<u64>,
+) -> Result<(), Error> {
+    let msg = serde_cbor::to_vec(&DeadLetterRequest { id })?;
+
+    management::request(stream, cmd, None, msg)?;
+
+    Ok(())
+}
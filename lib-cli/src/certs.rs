@@ -879,6 +879,11 @@ pub fn generate_device_cert(
     // Set the ext key useage
     params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
 
+    // The server matches this against the uid in the device's topics
+    params
+        .distinguished_name
+        .push(DnType::CommonName, name.to_string());
+
     // Set the alt name
     params.subject_alt_names = vec![SanType::Rfc822Name(format!(
         "{}@{}",
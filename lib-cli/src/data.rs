@@ -0,0 +1,148 @@
+// Pyrinas
+use chrono::{Local, TimeZone};
+use pyrinas_shared::{InfluxQueryResponse, ManagmentDataType, SeriesQuery, SeriesQueryResponse};
+
+// Std lib
+use std::net::TcpStream;
+
+// Websocket
+use tungstenite::{protocol::WebSocket, stream::MaybeTlsStream};
+
+// Error handling
+use thiserror::Error;
+
+use crate::{management, DataSubCommand};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Serde CBOR error
+    #[error("serde_cbor error: {source}")]
+    CborError {
+        #[from]
+        source: serde_cbor::Error,
+    },
+
+    /// JSON error
+    #[error("serde_json error: {source}")]
+    JsonError {
+        #[from]
+        source: serde_json::Error,
+    },
+
+    /// Request/response error
+    #[error("{source}")]
+    ManagementError {
+        #[from]
+        source: management::Error,
+    },
+}
+
+/// Split `name=value`
+pub fn parse_tag(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) => Ok((name.to_string(), value.to_string())),
+        None => Err(format!("expected name=value, got {}", s)),
+    }
+}
+
+/// Functon for processing all incoming data commands.
+pub fn process(
+    socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    cmd: &DataSubCommand,
+) -> Result<(), Error> {
+    match cmd {
+        DataSubCommand::Query(q) => {
+            let mut query = SeriesQuery {
+                measurement: q.measurement.clone(),
+                tags: q.tags.iter().cloned().collect(),
+                start_ms: q.start_ms,
+                end_ms: q.end_ms,
+                limit: q.limit,
+            };
+
+            // Tagged by the server
+            if let Some(uid) = &q.device {
+                query.tags.insert("id".to_string(), uid.clone());
+            }
+
+            if let Some(secs) = q.last_secs {
+                query.start_ms = Some(Local::now().timestamp_millis() - secs * 1000);
+            }
+
+            let response = query_series(socket, &query)?;
+
+            for point in response.points {
+                let time = match Local.timestamp_millis_opt(point.time_ms).single() {
+                    Some(d) => d.to_string(),
+                    None => point.time_ms.to_string(),
+                };
+
+                let tags: Vec<String> = point
+                    .tags
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect();
+
+                println!(
+                    "{} {} {}",
+                    time,
+                    tags.join(","),
+                    serde_json::to_string(&point.fields)?
+                );
+            }
+        }
+        DataSubCommand::Influx(q) => {
+            let response = query_influx(socket, &q.query)?;
+
+            for series in response.series {
+                let tags: Vec<String> = series
+                    .tags
+                    .iter()
+                    .map(|(k, v)| format!("{}={}", k, v))
+                    .collect();
+
+                println!("{} {}", series.name, tags.join(","));
+                println!("{}", series.columns.join(" "));
+
+                for row in series.values {
+                    let row: Vec<String> = row
+                        .iter()
+                        .map(serde_json::to_string)
+                        .collect::<Result<_, _>>()?;
+
+                    println!("{}", row.join(" "));
+                }
+            }
+        }
+    };
+
+    Ok(())
+}
+
+pub fn query_series(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    query: &SeriesQuery,
+) -> Result<SeriesQueryResponse, Error> {
+    let data = management::request(
+        stream,
+        ManagmentDataType::QuerySeries,
+        None,
+        serde_cbor::to_vec(query)?,
+    )?;
+
+    Ok(serde_cbor::from_slice(&data)?)
+}
+
+pub fn query_influx(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    query: &str,
+) -> Result<InfluxQueryResponse, Error> {
+    let data = management::request(
+        stream,
+        ManagmentDataType::QueryInflux,
+        None,
+        serde_cbor::to_vec(&query)?,
+    )?;
+
+    Ok(serde_cbor::from_slice(&data)?)
+}
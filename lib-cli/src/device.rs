@@ -1,4 +1,52 @@
+// Pyrinas
+use chrono::{Local, TimeZone};
+use pyrinas_shared::{
+    CommandId, CommandRecord, CommandRequest, DeviceInfo, DeviceListResponse, DeviceUpdate,
+    ManagmentDataType, ShadowDocument, ShadowUpdate,
+};
+
+// Serde
 use serde::{Deserialize, Serialize};
+
+// Std lib
+use std::net::TcpStream;
+use std::{thread, time::Duration};
+
+// Websocket
+use tungstenite::{protocol::WebSocket, stream::MaybeTlsStream};
+
+// Error handling
+use thiserror::Error;
+
+use crate::{management, CommandSubCommand, DeviceSubCommand, ShadowSubCommand};
+
+/// Time between checks on a command that's been sent
+const COMMAND_POLL_MS: u64 = 500;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Serde CBOR error
+    #[error("serde_cbor error: {source}")]
+    CborError {
+        #[from]
+        source: serde_cbor::Error,
+    },
+
+    /// JSON error
+    #[error("serde_json error: {source}")]
+    JsonError {
+        #[from]
+        source: serde_json::Error,
+    },
+
+    /// Request/response error
+    #[error("{source}")]
+    ManagementError {
+        #[from]
+        source: management::Error,
+    },
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DeviceCert {
@@ -7,3 +55,317 @@ pub struct DeviceCert {
     pub private_key: String,
     pub client_cert: String,
 }
+
+/// Functon for processing all incoming device commands.
+pub fn process(
+    socket: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    cmd: &DeviceSubCommand,
+) -> Result<(), Error> {
+    match cmd {
+        DeviceSubCommand::List => {
+            let list = get_devices(socket)?;
+
+            println!(
+                "{:<20} {:<15} {:<8} {:<36}",
+                "uid", "state", "status", "last seen"
+            );
+
+            for device in list.devices.iter() {
+                let status = match device.online {
+                    true => "online",
+                    false => "offline",
+                };
+
+                // Print out the entry
+                println!(
+                    "{:<20} {:<15} {:<8} {:<36}",
+                    device.uid,
+                    device.state.to_string(),
+                    status,
+                    last_seen(device)
+                );
+            }
+        }
+        DeviceSubCommand::Show(s) => {
+            let device = get_device(socket, &s.uid)?;
+            let empty = || "-".to_string();
+
+            println!("uid:              {}", device.uid);
+            println!("state:            {}", device.state);
+            println!(
+                "type:             {}",
+                device
+                    .device_type
+                    .map(|t| t.to_string())
+                    .unwrap_or_else(empty)
+            );
+            println!(
+                "board:            {}",
+                device.board.clone().unwrap_or_else(empty)
+            );
+            println!(
+                "imei:             {}",
+                device.imei.clone().unwrap_or_else(empty)
+            );
+            println!(
+                "iccid:            {}",
+                device.iccid.clone().unwrap_or_else(empty)
+            );
+            println!(
+                "firmware version: {}",
+                device.firmware_version.clone().unwrap_or_else(empty)
+            );
+            println!("tags:             {}", device.tags.join(", "));
+            println!("created:          {}", format_time(device.created_ms));
+            println!(
+                "first seen:       {}",
+                device.first_seen_ms.map(format_time).unwrap_or_else(empty)
+            );
+            println!("last seen:        {}", last_seen(&device));
+            println!("online:           {}", device.online);
+        }
+        DeviceSubCommand::Add(u) => {
+            add_device(socket, u)?;
+            println!("Added {}.", u.uid);
+        }
+        DeviceSubCommand::Update(u) => {
+            update_device(socket, u)?;
+            println!("Updated {}.", u.uid);
+        }
+        DeviceSubCommand::Remove(s) => {
+            remove_device(socket, &s.uid)?;
+            println!("Removed {}.", s.uid);
+        }
+        DeviceSubCommand::Shadow(c) => match &c.subcmd {
+            ShadowSubCommand::Show(s) => {
+                let shadow = get_shadow(socket, &s.uid)?;
+
+                println!("version:  {}", shadow.version);
+                println!(
+                    "desired:  {}",
+                    serde_json::to_string_pretty(&shadow.desired)?
+                );
+                println!(
+                    "reported: {}",
+                    serde_json::to_string_pretty(&shadow.reported)?
+                );
+                println!("delta:    {}", serde_json::to_string_pretty(&shadow.delta)?);
+
+                let reported = match shadow.reported_ms {
+                    0 => "never".to_string(),
+                    t => format_time(t),
+                };
+                println!("last reported: {}", reported);
+            }
+            ShadowSubCommand::Set(s) => {
+                let update = ShadowUpdate {
+                    uid: s.uid.clone(),
+                    desired: serde_json::from_str(&s.desired)?,
+                };
+
+                set_shadow(socket, &update)?;
+                println!("Updated shadow for {}.", s.uid);
+            }
+        },
+        DeviceSubCommand::Cmd(c) => match &c.subcmd {
+            CommandSubCommand::Send(s) => {
+                // JSON to CBOR
+                let msg = match &s.data {
+                    Some(d) => {
+                        let value: serde_cbor::Value = serde_json::from_str(d)?;
+                        serde_cbor::to_vec(&value)?
+                    }
+                    None => Vec::new(),
+                };
+
+                let mut request = CommandRequest::new(&s.uid, &s.name, msg);
+                request.timeout_secs = s.timeout_secs;
+                request.retries = s.retries;
+
+                let id = send_command(socket, &request)?.id;
+                println!("Sent command {}.", id);
+
+                // Wait for it to finish
+                let start = std::time::Instant::now();
+                let record = loop {
+                    let record = get_command(socket, id)?;
+
+                    if record.state.is_finished()
+                        || start.elapsed() > Duration::from_secs(s.wait_secs)
+                    {
+                        break record;
+                    }
+
+                    thread::sleep(Duration::from_millis(COMMAND_POLL_MS));
+                };
+
+                print_command(&record)?;
+            }
+            CommandSubCommand::Status(s) => {
+                let record = get_command(socket, s.id)?;
+                print_command(&record)?;
+            }
+        },
+    };
+
+    Ok(())
+}
+
+fn print_command(record: &CommandRecord) -> Result<(), Error> {
+    println!("id:       {}", record.id);
+    println!("device:   {}", record.uid);
+    println!("name:     {}", record.name);
+    println!("state:    {}", record.state);
+    println!("attempts: {} of {}", record.attempts, record.retries + 1);
+
+    if let Some(ack) = &record.ack {
+        println!("code:     {}", ack.code);
+
+        // Show CBOR results as JSON
+        match serde_cbor::from_slice::<serde_cbor::Value>(&ack.msg) {
+            Ok(v) => println!("result:   {}", serde_json::to_string_pretty(&v)?),
+            Err(_) => println!("result:   {:?}", ack.msg),
+        }
+    }
+
+    Ok(())
+}
+
+/// Local time for a unix timestamp (ms)
+fn format_time(timestamp_ms: i64) -> String {
+    match Local.timestamp_millis_opt(timestamp_ms).single() {
+        Some(d) => d.to_string(),
+        None => timestamp_ms.to_string(),
+    }
+}
+
+fn last_seen(device: &DeviceInfo) -> String {
+    match device.first_seen_ms {
+        Some(_) => format_time(device.last_seen_ms),
+        None => "never".to_string(),
+    }
+}
+
+pub fn get_devices(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+) -> Result<DeviceListResponse, Error> {
+    let data = management::request(stream, ManagmentDataType::GetDeviceList, None, [].to_vec())?;
+
+    Ok(serde_cbor::from_slice(&data)?)
+}
+
+pub fn get_device(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    uid: &str,
+) -> Result<DeviceInfo, Error> {
+    let data = management::request(
+        stream,
+        ManagmentDataType::GetDevice,
+        None,
+        uid.as_bytes().to_vec(),
+    )?;
+
+    Ok(serde_cbor::from_slice(&data)?)
+}
+
+pub fn add_device(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    update: &DeviceUpdate,
+) -> Result<(), Error> {
+    management::request(
+        stream,
+        ManagmentDataType::AddDevice,
+        None,
+        serde_cbor::to_vec(update)?,
+    )?;
+
+    Ok(())
+}
+
+pub fn update_device(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    update: &DeviceUpdate,
+) -> Result<(), Error> {
+    management::request(
+        stream,
+        ManagmentDataType::UpdateDevice,
+        None,
+        serde_cbor::to_vec(update)?,
+    )?;
+
+    Ok(())
+}
+
+/// Removes a device from the registry
+pub fn remove_device(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    uid: &str,
+) -> Result<(), Error> {
+    management::request(
+        stream,
+        ManagmentDataType::RemoveDevice,
+        None,
+        uid.as_bytes().to_vec(),
+    )?;
+
+    Ok(())
+}
+
+pub fn get_shadow(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    uid: &str,
+) -> Result<ShadowDocument, Error> {
+    let data = management::request(
+        stream,
+        ManagmentDataType::GetShadow,
+        None,
+        uid.as_bytes().to_vec(),
+    )?;
+
+    Ok(serde_cbor::from_slice(&data)?)
+}
+
+/// Merges `update` into the device's desired state
+pub fn set_shadow(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    update: &ShadowUpdate,
+) -> Result<(), Error> {
+    management::request(
+        stream,
+        ManagmentDataType::UpdateShadow,
+        None,
+        serde_cbor::to_vec(update)?,
+    )?;
+
+    Ok(())
+}
+
+/// Queues a command on the server. Returns it with the id it was given. Use `get_command` to
+/// check on it.
+pub fn send_command(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    request: &CommandRequest,
+) -> Result<CommandRecord, Error> {
+    let data = management::request(
+        stream,
+        ManagmentDataType::SendCommand,
+        None,
+        serde_cbor::to_vec(request)?,
+    )?;
+
+    Ok(serde_cbor::from_slice(&data)?)
+}
+
+pub fn get_command(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    id: CommandId,
+) -> Result<CommandRecord, Error> {
+    let data = management::request(
+        stream,
+        ManagmentDataType::GetCommand,
+        None,
+        serde_cbor::to_vec(&id)?,
+    )?;
+
+    Ok(serde_cbor::from_slice(&data)?)
+}
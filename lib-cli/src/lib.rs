@@ -1,11 +1,14 @@
+pub mod broker;
 pub mod certs;
 pub mod config;
+pub mod data;
 pub mod device;
 pub mod git;
+pub mod management;
 pub mod ota;
 
 use clap::Parser;
-use pyrinas_shared::{ota::OTAPackageVersion, OtaLink};
+use pyrinas_shared::{ota::OTAPackageVersion, DeviceUpdate, OtaLink};
 use serde::{Deserialize, Serialize};
 use std::{net::TcpStream, num};
 
@@ -56,6 +59,24 @@ pub enum Error {
         source: ota::Error,
     },
 
+    #[error("broker error: {source}")]
+    BrokerError {
+        #[from]
+        source: broker::Error,
+    },
+
+    #[error("device error: {source}")]
+    DeviceError {
+        #[from]
+        source: device::Error,
+    },
+
+    #[error("data error: {source}")]
+    DataError {
+        #[from]
+        source: data::Error,
+    },
+
     #[error("{source}")]
     CertsError {
         #[from]
@@ -71,6 +92,209 @@ pub struct OtaCmd {
     pub subcmd: OtaSubCommand,
 }
 
+/// Commands related to the server's broker
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct BrokerCmd {
+    #[clap(subcommand)]
+    pub subcmd: BrokerSubCommand,
+}
+
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub enum BrokerSubCommand {
+    /// Show queue statistics for each runner
+    Stats,
+    /// Events the broker was unable to deliver
+    DeadLetters(DeadLetterCmd),
+}
+
+/// Inspect, replay or purge undeliverable events
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct DeadLetterCmd {
+    #[clap(subcommand)]
+    pub subcmd: DeadLetterSubCommand,
+}
+
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub enum DeadLetterSubCommand {
+    /// List dead letters
+    List,
+    /// Try delivering dead letters again
+    Replay(DeadLetterSelect),
+    /// Remove dead letters
+    Purge(DeadLetterSelect),
+}
+
+/// Select dead letters
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct DeadLetterSelect {
+    /// Id of the dead letter. All of them if not provided.
+    pub id: Option<u64>,
+}
+
+/// Commands related to devices
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct DeviceCmd {
+    #[clap(subcommand)]
+    pub subcmd: DeviceSubCommand,
+}
+
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub enum DeviceSubCommand {
+    /// List devices with their state and presence
+    List,
+    /// Show everything known about a device
+    Show(DeviceSelect),
+    /// Add a device to the registry
+    Add(DeviceUpdate),
+    /// Change a device's metadata or state (i.e. `--state suspended`)
+    Update(DeviceUpdate),
+    /// Remove a device from the registry
+    Remove(DeviceSelect),
+    /// Desired and reported state
+    Shadow(ShadowCmd),
+    /// Run commands on a device
+    Cmd(CommandCmd),
+}
+
+/// Send commands to devices and check on them
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct CommandCmd {
+    #[clap(subcommand)]
+    pub subcmd: CommandSubCommand,
+}
+
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub enum CommandSubCommand {
+    /// Send a command and wait for the result
+    Send(CommandSend),
+    /// Show the state of a command
+    Status(CommandStatus),
+}
+
+/// Send a command to a device
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct CommandSend {
+    /// Device Id
+    pub uid: String,
+    /// Command name. Published to `<uid>/cmd/s/<name>`.
+    pub name: String,
+    /// JSON payload, i.e. '{"mode": "periodic"}'. Sent to the device as CBOR.
+    #[clap(long, short)]
+    pub data: Option<String>,
+    /// Seconds to wait for each acknowledgement. Uses the server's default if not set.
+    #[clap(long)]
+    pub timeout_secs: Option<u64>,
+    /// Times to resend if it isn't acknowledged. Uses the server's default if not set.
+    #[clap(long)]
+    pub retries: Option<u32>,
+    /// Seconds to wait for the result before giving up. Commands to offline devices stay queued.
+    #[clap(long, default_value = "60")]
+    pub wait_secs: u64,
+}
+
+/// Select a command
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct CommandStatus {
+    /// Command Id
+    pub id: u64,
+}
+
+/// Read or change a device's shadow
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct ShadowCmd {
+    #[clap(subcommand)]
+    pub subcmd: ShadowSubCommand,
+}
+
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub enum ShadowSubCommand {
+    /// Show desired, reported and delta
+    Show(DeviceSelect),
+    /// Merge a change into the desired state
+    Set(ShadowSet),
+}
+
+/// Change the desired state
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct ShadowSet {
+    /// Device Id
+    pub uid: String,
+    /// JSON object to merge, i.e. '{"interval": 3600}'. Set a key to null to remove it.
+    pub desired: String,
+}
+
+/// Select a device
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct DeviceSelect {
+    /// Device Id
+    pub uid: String,
+}
+
+/// Data saved by the server's local time-series store or Influx
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct DataCmd {
+    #[clap(subcommand)]
+    pub subcmd: DataSubCommand,
+}
+
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub enum DataSubCommand {
+    /// Get points from a measurement
+    Query(DataQuery),
+    /// Run an InfluxQL query against the server's Influx
+    Influx(DataInflux),
+}
+
+/// Run an InfluxQL query, i.e. `SELECT last(*) FROM telemetry GROUP BY id`
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct DataInflux {
+    pub query: String,
+}
+
+/// Get points from a measurement, oldest first
+#[derive(Parser, Debug)]
+#[clap(version)]
+pub struct DataQuery {
+    /// Measurement, i.e. `telemetry`
+    pub measurement: String,
+    /// Only points from this device
+    #[clap(long, short)]
+    pub device: Option<String>,
+    /// Only points with this tag, i.e. `--tag name=value`. Can be repeated.
+    #[clap(long = "tag", parse(try_from_str = data::parse_tag))]
+    pub tags: Vec<(String, String)>,
+    /// Only points from the last this many seconds
+    #[clap(long, conflicts_with = "start-ms")]
+    pub last_secs: Option<i64>,
+    /// Unix timestamp (ms) of the earliest point
+    #[clap(long)]
+    pub start_ms: Option<i64>,
+    /// Unix timestamp (ms) of the latest point
+    #[clap(long)]
+    pub end_ms: Option<i64>,
+    /// Only the most recent points, up to this many
+    #[clap(long, short)]
+    pub limit: Option<usize>,
+}
+
 /// Commands related to certs
 #[derive(Parser, Debug)]
 #[clap(version)]
@@ -0,0 +1,126 @@
+use chrono::{Duration, Utc};
+
+// Pyrinas
+use pyrinas_shared::{CorrelationId, ManagementData, ManagementResponse, ManagmentDataType};
+
+// Std lib
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+// Websocket
+use tungstenite::{protocol::WebSocket, stream::MaybeTlsStream, Message};
+
+// Error handling
+use thiserror::Error;
+
+/// How long to wait for the server to respond to a request
+const RESPONSE_TIMEOUT_SECS: i64 = 10;
+
+/// Id for the next request sent from this process
+static NEXT_ID: AtomicU32 = AtomicU32::new(1);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    /// Serde CBOR error
+    #[error("serde_cbor error: {source}")]
+    CborError {
+        #[from]
+        source: serde_cbor::Error,
+    },
+
+    /// Websocket error
+    #[error("websocket error: {source}")]
+    WebsocketError {
+        #[from]
+        source: tungstenite::Error,
+    },
+
+    /// Server didn't respond in time
+    #[error("no response from server")]
+    TimeoutError,
+
+    /// Server responded with an error
+    #[error("server error: {0}")]
+    ServerError(String),
+}
+
+/// Send a management request and wait for the matching response.
+///
+/// Returns the response data on success.
+pub fn request(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    cmd: ManagmentDataType,
+    target: Option<String>,
+    msg: Vec<u8>,
+) -> Result<Vec<u8>, Error> {
+    let id = send(stream, cmd, target, msg)?;
+
+    wait(stream, id)
+}
+
+/// Send a management request without waiting for the response.
+///
+/// Returns the id the response will carry.
+pub fn send(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    cmd: ManagmentDataType,
+    target: Option<String>,
+    msg: Vec<u8>,
+) -> Result<CorrelationId, Error> {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+
+    // Then configure the outer data
+    let msg = ManagementData {
+        cmd,
+        target,
+        msg,
+        id: Some(id),
+    };
+
+    // If second encode looks good send it off
+    let data = serde_cbor::to_vec(&msg)?;
+
+    // Send over socket
+    stream.write_message(Message::binary(data))?;
+
+    Ok(id)
+}
+
+/// Wait for the response to request `id`. Responses to other requests are ignored.
+pub fn wait(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+    id: CorrelationId,
+) -> Result<Vec<u8>, Error> {
+    let start = Utc::now();
+
+    loop {
+        if Utc::now() > start + Duration::seconds(RESPONSE_TIMEOUT_SECS) {
+            return Err(Error::TimeoutError);
+        }
+
+        let data = match stream.read_message() {
+            Ok(Message::Binary(b)) => b,
+            Ok(_) => {
+                log::warn!("Unexpected WS message!");
+                continue;
+            }
+            Err(_) => continue,
+        };
+
+        let res: ManagementResponse = match serde_cbor::from_slice(&data) {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!("Unable to decode response! Error: {}", e);
+                continue;
+            }
+        };
+
+        // Not for us
+        if res.id != Some(id) {
+            log::debug!("Skipping response {:?} to {:?}", res.id, res.cmd);
+            continue;
+        }
+
+        return res.result.map_err(Error::ServerError);
+    }
+}
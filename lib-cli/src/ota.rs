@@ -1,10 +1,8 @@
-use chrono::{Duration, Local, Utc};
+use chrono::{Local, Utc};
 
 // Pyrinas
 use pyrinas_shared::ota::v2::{OTAImageData, OTAImageType, OTAPackage, OTAUpdate};
-use pyrinas_shared::{
-    ManagementData, ManagmentDataType, OtaGroupListResponse, OtaImageListResponse,
-};
+use pyrinas_shared::{ManagmentDataType, OtaGroupListResponse, OtaImageListResponse};
 
 // Cbor
 use serde_cbor;
@@ -15,12 +13,12 @@ use std::io::{self, prelude::*};
 use std::net::TcpStream;
 
 // Websocket
-use tungstenite::{protocol::WebSocket, stream::MaybeTlsStream, Message};
+use tungstenite::{protocol::WebSocket, stream::MaybeTlsStream};
 
 // Error handling
 use thiserror::Error;
 
-use crate::{git, OtaLink, OtaSubCommand};
+use crate::{git, management, OtaLink, OtaSubCommand};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -44,6 +42,13 @@ pub enum Error {
         source: tungstenite::Error,
     },
 
+    /// Request/response error
+    #[error("{source}")]
+    ManagementError {
+        #[from]
+        source: management::Error,
+    },
+
     /// Error to indicate repo is dirty
     #[error("repository is dirty. Run --force to override")]
     DirtyError,
@@ -103,88 +108,22 @@ pub fn process(
             println!("OTA Linked! {:?}", &a);
         }
         OtaSubCommand::ListGroups => {
-            crate::ota::get_ota_group_list(socket)?;
+            let list = crate::ota::get_ota_group_list(socket)?;
 
-            let start = Utc::now();
-
-            // Get message
-            loop {
-                if Utc::now() > start + Duration::seconds(10) {
-                    eprintln!("No response from server!");
-                    break;
-                }
-
-                match socket.read_message() {
-                    Ok(msg) => {
-                        let data = match msg {
-                            tungstenite::Message::Binary(b) => b,
-                            _ => {
-                                eprintln!("Unexpected WS message!");
-                                break;
-                            }
-                        };
-
-                        let list: OtaGroupListResponse = match serde_cbor::from_slice(&data) {
-                            Ok(m) => m,
-                            Err(e) => {
-                                eprintln!("Unable to get image list! Error: {}", e);
-                                break;
-                            }
-                        };
-
-                        for name in list.groups.iter() {
-                            // Print out the entry
-                            println!("{}", name);
-                        }
-
-                        break;
-                    }
-                    Err(_) => continue,
-                };
+            for name in list.groups.iter() {
+                // Print out the entry
+                println!("{}", name);
             }
         }
         OtaSubCommand::ListImages => {
-            crate::ota::get_ota_image_list(socket)?;
-
-            let start = Utc::now();
+            let list = crate::ota::get_ota_image_list(socket)?;
 
-            // Get message
-            loop {
-                if Utc::now() > start + Duration::seconds(10) {
-                    eprintln!("No response from server!");
-                    break;
-                }
+            for (name, package) in list.images.iter() {
+                // Get the date
+                let date = package.date_added.with_timezone(&Local).to_string();
 
-                match socket.read_message() {
-                    Ok(msg) => {
-                        let data = match msg {
-                            tungstenite::Message::Binary(b) => b,
-                            _ => {
-                                eprintln!("Unexpected WS message!");
-                                break;
-                            }
-                        };
-
-                        let list: OtaImageListResponse = match serde_cbor::from_slice(&data) {
-                            Ok(m) => m,
-                            Err(e) => {
-                                eprintln!("Unable to get image list! Error: {}", e);
-                                break;
-                            }
-                        };
-
-                        for (name, package) in list.images.iter() {
-                            // Get the date
-                            let date = package.date_added.with_timezone(&Local).to_string();
-
-                            // Print out the entry
-                            println!("{} {}", name, date);
-                        }
-
-                        break;
-                    }
-                    Err(_) => continue,
-                };
+                // Print out the entry
+                println!("{} {}", name, date);
             }
         }
     };
@@ -257,18 +196,8 @@ pub fn add_ota(
     // Serialize to cbor
     let data = serde_cbor::to_vec(&new)?;
 
-    // Then configure the outer data
-    let msg = ManagementData {
-        cmd: ManagmentDataType::AddOta,
-        target: None,
-        msg: data,
-    };
-
-    // If second encode looks good send it off
-    let data = serde_cbor::to_vec(&msg)?;
-
-    // Send over socket
-    stream.write_message(Message::binary(data))?;
+    // Wait for the server to save it
+    management::request(stream, ManagmentDataType::AddOta, None, data)?;
 
     Ok(package_version.to_string())
 }
@@ -277,18 +206,12 @@ pub fn unlink(
     stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
     link: &OtaLink,
 ) -> Result<(), Error> {
-    // Then configure the outer data
-    let msg = ManagementData {
-        cmd: ManagmentDataType::UnlinkOta,
-        target: None,
-        msg: serde_cbor::to_vec(link)?,
-    };
-
-    // If second encode looks good send it off
-    let data = serde_cbor::to_vec(&msg)?;
-
-    // Send over socket
-    stream.write_message(Message::binary(data))?;
+    management::request(
+        stream,
+        ManagmentDataType::UnlinkOta,
+        None,
+        serde_cbor::to_vec(link)?,
+    )?;
 
     Ok(())
 }
@@ -297,73 +220,43 @@ pub fn link(
     stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
     link: &OtaLink,
 ) -> Result<(), Error> {
-    // Then configure the outer data
-    let msg = ManagementData {
-        cmd: ManagmentDataType::LinkOta,
-        target: None,
-        msg: serde_cbor::to_vec(link)?,
-    };
-
-    // If second encode looks good send it off
-    let data = serde_cbor::to_vec(&msg)?;
-
-    // Send over socket
-    stream.write_message(Message::binary(data))?;
+    management::request(
+        stream,
+        ManagmentDataType::LinkOta,
+        None,
+        serde_cbor::to_vec(link)?,
+    )?;
 
     Ok(())
 }
 
-/// Adds and OTA image from an included manifest file to the server
+/// Removes an OTA image from the server
 pub fn remove_ota(
     stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
     image_id: &str,
 ) -> Result<(), Error> {
-    // Then configure the outer data
-    let msg = ManagementData {
-        cmd: ManagmentDataType::RemoveOta,
-        target: None,
-        msg: image_id.as_bytes().to_vec(),
-    };
-
-    // If second encode looks good send it off
-    let data = serde_cbor::to_vec(&msg)?;
-
-    // Send over socket
-    stream.write_message(Message::binary(data))?;
+    management::request(
+        stream,
+        ManagmentDataType::RemoveOta,
+        None,
+        image_id.as_bytes().to_vec(),
+    )?;
 
     Ok(())
 }
 
-pub fn get_ota_group_list(stream: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Result<(), Error> {
-    // Then configure the outer data
-    let msg = ManagementData {
-        cmd: ManagmentDataType::GetGroupList,
-        target: None,
-        msg: [].to_vec(),
-    };
-
-    // If second encode looks good send it off
-    let data = serde_cbor::to_vec(&msg)?;
-
-    // Send over socket
-    stream.write_message(Message::binary(data))?;
+pub fn get_ota_group_list(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+) -> Result<OtaGroupListResponse, Error> {
+    let data = management::request(stream, ManagmentDataType::GetGroupList, None, [].to_vec())?;
 
-    Ok(())
+    Ok(serde_cbor::from_slice(&data)?)
 }
 
-pub fn get_ota_image_list(stream: &mut WebSocket<MaybeTlsStream<TcpStream>>) -> Result<(), Error> {
-    // Then configure the outer data
-    let msg = ManagementData {
-        cmd: ManagmentDataType::GetImageList,
-        target: None,
-        msg: [].to_vec(),
-    };
-
-    // If second encode looks good send it off
-    let data = serde_cbor::to_vec(&msg)?;
-
-    // Send over socket
-    stream.write_message(Message::binary(data))?;
+pub fn get_ota_image_list(
+    stream: &mut WebSocket<MaybeTlsStream<TcpStream>>,
+) -> Result<OtaImageListResponse, Error> {
+    let data = management::request(stream, ManagmentDataType::GetImageList, None, [].to_vec())?;
 
-    Ok(())
+    Ok(serde_cbor::from_slice(&data)?)
 }
@@ -0,0 +1,173 @@
+//! `#[derive(PyrinasPayload)]` for application payloads. See `pyrinas_server::payload`.
+
+// Macro related
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr};
+
+/// How a field ends up in the point
+enum Kind {
+    Field,
+    Tag,
+    Timestamp,
+    Flatten,
+    Skip,
+}
+
+/// `GpsReport` becomes `gps_report`
+fn snake_case(name: &str) -> String {
+    let mut out = String::new();
+
+    for (i, c) in name.chars().enumerate() {
+        if c.is_uppercase() {
+            if i > 0 {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+
+    out
+}
+
+/// Measurement from `#[pyrinas(measurement = "...")]`
+fn measurement(input: &DeriveInput) -> syn::Result<String> {
+    let mut measurement = snake_case(&input.ident.to_string());
+
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("pyrinas")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("measurement") {
+                measurement = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else {
+                Err(meta.error("expected `measurement`"))
+            }
+        })?;
+    }
+
+    Ok(measurement)
+}
+
+/// Kind and name from `#[pyrinas(tag|timestamp|flatten|skip, rename = "...")]`
+fn field(field: &syn::Field) -> syn::Result<(Kind, String)> {
+    let mut kind = Kind::Field;
+    let mut name = field.ident.as_ref().unwrap().to_string();
+
+    for attr in field.attrs.iter().filter(|a| a.path().is_ident("pyrinas")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("tag") {
+                kind = Kind::Tag;
+            } else if meta.path.is_ident("timestamp") {
+                kind = Kind::Timestamp;
+            } else if meta.path.is_ident("flatten") {
+                kind = Kind::Flatten;
+            } else if meta.path.is_ident("skip") {
+                kind = Kind::Skip;
+            } else if meta.path.is_ident("rename") {
+                name = meta.value()?.parse::<LitStr>()?.value();
+            } else {
+                return Err(meta.error(
+                    "expected `tag`, `timestamp`, `flatten`, `skip` or `rename = \"...\"`",
+                ));
+            }
+
+            Ok(())
+        })?;
+    }
+
+    Ok((kind, name))
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let measurement = measurement(&input)?;
+
+    let fields = match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(f) => &f.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    &input.ident,
+                    "PyrinasPayload needs named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "PyrinasPayload can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut adds = Vec::new();
+    let mut timestamp = None;
+
+    for f in fields {
+        let ident = f.ident.as_ref().unwrap();
+        let (kind, name) = field(f)?;
+
+        match kind {
+            Kind::Field => adds.push(quote! {
+                ::pyrinas_server::payload::add_field(point, #name, &self.#ident)?;
+            }),
+            Kind::Tag => adds.push(quote! {
+                point.tags.insert(#name.to_string(), ::std::string::ToString::to_string(&self.#ident));
+            }),
+            Kind::Flatten => adds.push(quote! {
+                ::pyrinas_server::payload::PyrinasPayload::add_to(&self.#ident, point)?;
+            }),
+            Kind::Timestamp => {
+                if timestamp.is_some() {
+                    return Err(syn::Error::new_spanned(f, "only one field can be the timestamp"));
+                }
+
+                timestamp = Some(quote! {
+                    ::std::option::Option::Some(::pyrinas_server::payload::PayloadTime::time_ms(&self.#ident))
+                });
+            }
+            Kind::Skip => (),
+        }
+    }
+
+    let timestamp = timestamp.unwrap_or_else(|| quote! { ::std::option::Option::None });
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::pyrinas_server::payload::PyrinasPayload for #ident #ty_generics #where_clause {
+            const MEASUREMENT: &'static str = #measurement;
+
+            fn add_to(&self, point: &mut ::pyrinas_server::SeriesPoint) -> ::std::result::Result<(), ::pyrinas_server::Error> {
+                #(#adds)*
+                ::std::result::Result::Ok(())
+            }
+
+            fn time_ms(&self) -> ::std::option::Option<i64> {
+                #timestamp
+            }
+        }
+    })
+}
+
+/// Implements `pyrinas_server::payload::PyrinasPayload`.
+///
+/// On the struct, `#[pyrinas(measurement = "gps")]` sets the measurement. It's the struct name
+/// in snake case otherwise. On fields:
+///
+/// * `#[pyrinas(tag)]` - stored as a tag instead of a field
+/// * `#[pyrinas(timestamp)]` - time of the point. Now if there isn't one
+/// * `#[pyrinas(flatten)]` - adds the tags and fields of a nested `PyrinasPayload`
+/// * `#[pyrinas(skip)]` - left out
+/// * `#[pyrinas(rename = "name")]` - stored under another name
+#[proc_macro_derive(PyrinasPayload, attributes(pyrinas))]
+pub fn derive_payload(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
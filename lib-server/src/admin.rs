@@ -1,7 +1,7 @@
 use std::sync::Arc;
 
 // async Related
-use flume::{unbounded, Sender};
+use flume::{bounded, Sender};
 use futures::{FutureExt, StreamExt};
 use tokio::net::TcpStream;
 use tokio::{net::TcpListener, sync::Mutex};
@@ -12,17 +12,83 @@ use tokio_tungstenite::tungstenite::{
 
 // Local lib related
 use crate::settings;
-use crate::Event;
-use pyrinas_shared::ManagmentDataType;
+use crate::{acknowledge, broker, influx, metrics, Event};
+use pyrinas_shared::{
+    CommandId, CommandRequest, CorrelationId, DeadLetterRequest, DeviceUpdate, ManagementResponse,
+    ManagmentDataType, SeriesQuery, ShadowUpdate,
+};
+
+// Influx
+use influxdb::ReadQuery;
 
 // Cbor
+use serde::{de::DeserializeOwned, Serialize};
 use serde_cbor;
 
 // Error
 use crate::Error;
 
+/// Messages buffered for the websocket before sending blocks
+const WS_QUEUE_SIZE: usize = 32;
+
 pub type AdminClient = Arc<Mutex<Option<Sender<Result<tungstenite::Message, tungstenite::Error>>>>>;
 
+/// Wrap response data for the admin client
+fn to_response<T: Serialize>(
+    cmd: ManagmentDataType,
+    id: Option<CorrelationId>,
+    data: &T,
+) -> ManagementResponse {
+    ManagementResponse {
+        cmd,
+        id,
+        target: None,
+        result: serde_cbor::to_vec(data).map_err(|e| e.to_string()),
+    }
+}
+
+/// Decode the message of a request. Failures are acknowledged so a client waiting on the id
+/// hears about it.
+async fn decode<T: DeserializeOwned>(
+    broker_sender: &Sender<Event>,
+    req: &pyrinas_shared::ManagementData,
+    what: &str,
+) -> Option<T> {
+    let res = serde_cbor::from_slice(&req.msg).map_err(Error::from);
+    decoded(broker_sender, req, what, res).await
+}
+
+/// Same as `decode` for messages that are a plain string
+async fn decode_str(
+    broker_sender: &Sender<Event>,
+    req: &pyrinas_shared::ManagementData,
+    what: &str,
+) -> Option<String> {
+    let res = String::from_utf8(req.msg.clone()).map_err(|e| Error::CustomError(e.to_string()));
+    decoded(broker_sender, req, what, res).await
+}
+
+async fn decoded<T>(
+    broker_sender: &Sender<Event>,
+    req: &pyrinas_shared::ManagementData,
+    what: &str,
+    res: Result<T, Error>,
+) -> Option<T> {
+    match res {
+        Ok(v) => Some(v),
+        Err(e) => {
+            log::warn!("Unable to deserialize {}! Err: {}", what, e);
+
+            let result = Err(Error::CustomError(format!(
+                "Unable to deserialize {}: {}",
+                what, e
+            )));
+            acknowledge(broker_sender, &req.id, req.cmd, result).await;
+            None
+        }
+    }
+}
+
 // Handle the incoming connection
 async fn handle_connection(
     stream: TcpStream,
@@ -77,9 +143,10 @@ async fn handle_connection(
     // Make a connection
     let (ws_tx, mut ws_rx) = websocket.split();
 
-    // Use an unbounded channel to handle buffering and flushing of messages
-    // to the websocket...
-    let (tx, rx) = unbounded();
+    // Use a bounded channel to handle buffering and flushing of messages
+    // to the websocket. A slow client backs up into the "sock" runner queue
+    // where the broker's overflow policy applies.
+    let (tx, rx) = bounded(WS_QUEUE_SIZE);
     tokio::task::spawn(rx.into_stream().forward(ws_tx).map(|result| {
         if let Err(e) = result {
             eprintln!("websocket send error: {}", e);
@@ -91,35 +158,54 @@ async fn handle_connection(
         *client.lock().await = Some(tx);
     }
 
+    metrics::add(metrics::ADMIN_CONNECTIONS, &[], 1.0);
+
     while let Some(Ok(msg)) = ws_rx.next().await {
         let data = msg.into_data();
         log::debug!("msg size: {}", data.len());
 
-        // First deocde into ManagementRequest struct
-        let req: pyrinas_shared::ManagementData =
-            serde_cbor::from_slice(&data).expect("Unable to deserialize ManagementData");
+        // First deocde into ManagementRequest struct. Without it there's no id to reply to.
+        let req: pyrinas_shared::ManagementData = match serde_cbor::from_slice(&data) {
+            Ok(r) => r,
+            Err(e) => {
+                log::warn!("Unable to deserialize ManagementData! Err: {}", e);
+                continue;
+            }
+        };
+
+        metrics::inc(
+            metrics::ADMIN_REQUESTS,
+            &[("cmd", &format!("{:?}", req.cmd))],
+        );
 
         // Next step in the managment request process
         match req.cmd {
             ManagmentDataType::AddOta => {
                 // Dedcode ota update
                 let ota_update: pyrinas_shared::ota::v2::OTAUpdate =
-                    serde_cbor::from_slice(&req.msg).expect("Unable to deserialize OtaUpdate");
+                    match decode(&broker_sender, &req, "OtaUpdate").await {
+                        Some(u) => u,
+                        None => continue,
+                    };
 
                 // Send if decode was successful
                 broker_sender
-                    .send_async(Event::OtaNewPackage(ota_update))
+                    .send_async(Event::OtaNewPackage(req.id, ota_update))
                     .await
                     .expect("Unable to send OtaNewPackage to broker.");
             }
             ManagmentDataType::LinkOta => {
                 // Dedcode ota update
-                let a: pyrinas_shared::OtaLink =
-                    serde_cbor::from_slice(&req.msg).expect("Unable to deserialize OtaLink");
+                let link = decode(&broker_sender, &req, "OtaLink").await;
+                let a: pyrinas_shared::OtaLink = match link {
+                    Some(a) => a,
+                    None => continue,
+                };
 
                 // Send if decode was successful
                 broker_sender
                     .send_async(Event::OtaLink {
+                        id: req.id,
                         device_id: a.device_id,
                         group_id: a.group_id,
                         image_id: a.image_id,
@@ -129,17 +215,14 @@ async fn handle_connection(
             }
             ManagmentDataType::RemoveOta => {
                 // Dedcode ota update
-                let image_id = match String::from_utf8(req.msg) {
-                    Ok(id) => id,
-                    Err(_) => {
-                        log::warn!("Unable to get image_id!");
-                        continue;
-                    }
+                let image_id = match decode_str(&broker_sender, &req, "image_id").await {
+                    Some(id) => id,
+                    None => continue,
                 };
 
                 // Send if decode was successful
                 broker_sender
-                    .send_async(Event::OtaDeletePackage(image_id))
+                    .send_async(Event::OtaDeletePackage(req.id, image_id))
                     .await
                     .expect("Unable to send OtaNewPackage to broker.");
             }
@@ -152,12 +235,16 @@ async fn handle_connection(
             }
             ManagmentDataType::UnlinkOta => {
                 // Dedcode ota update
-                let a: pyrinas_shared::OtaLink =
-                    serde_cbor::from_slice(&req.msg).expect("Unable to deserialize OtaLink");
+                let link = decode(&broker_sender, &req, "OtaLink").await;
+                let a: pyrinas_shared::OtaLink = match link {
+                    Some(a) => a,
+                    None => continue,
+                };
 
                 // Send if decode was successful
                 broker_sender
                     .send_async(Event::OtaUnlink {
+                        id: req.id,
                         device_id: a.device_id,
                         group_id: a.group_id,
                     })
@@ -166,16 +253,187 @@ async fn handle_connection(
             }
             ManagmentDataType::GetGroupList => {
                 broker_sender
-                    .send_async(Event::OtaUpdateGroupListRequest())
+                    .send_async(Event::OtaUpdateGroupListRequest(req.id))
                     .await
                     .expect("Unable to send ApplicationManagementRequest to broker.");
             }
             ManagmentDataType::GetImageList => {
                 broker_sender
-                    .send_async(Event::OtaUpdateImageListRequest())
+                    .send_async(Event::OtaUpdateImageListRequest(req.id))
                     .await
                     .expect("Unable to send ApplicationManagementRequest to broker.");
             }
+            ManagmentDataType::GetBrokerStats => {
+                broker_sender
+                    .send_async(Event::BrokerStatsRequest(req.id))
+                    .await
+                    .expect("Unable to send BrokerStatsRequest to broker.");
+            }
+            ManagmentDataType::GetDeadLetters => {
+                broker_sender
+                    .send_async(Event::DeadLetterListRequest(req.id))
+                    .await
+                    .expect("Unable to send DeadLetterListRequest to broker.");
+            }
+            ManagmentDataType::GetDeviceList => {
+                broker_sender
+                    .send_async(Event::DeviceListRequest(req.id))
+                    .await
+                    .expect("Unable to send DeviceListRequest to broker.");
+            }
+            ManagmentDataType::GetDevice | ManagmentDataType::RemoveDevice => {
+                let uid = match decode_str(&broker_sender, &req, "uid").await {
+                    Some(uid) => uid,
+                    None => continue,
+                };
+
+                let event = match req.cmd {
+                    ManagmentDataType::GetDevice => Event::DeviceGetRequest(req.id, uid),
+                    _ => Event::DeviceRemove(req.id, uid),
+                };
+
+                broker_sender
+                    .send_async(event)
+                    .await
+                    .expect("Unable to send device request to broker.");
+            }
+            ManagmentDataType::GetShadow => {
+                let uid = match decode_str(&broker_sender, &req, "uid").await {
+                    Some(uid) => uid,
+                    None => continue,
+                };
+
+                broker_sender
+                    .send_async(Event::ShadowGetRequest(req.id, uid))
+                    .await
+                    .expect("Unable to send ShadowGetRequest to broker.");
+            }
+            ManagmentDataType::UpdateShadow => {
+                let update: ShadowUpdate = match decode(&broker_sender, &req, "ShadowUpdate").await
+                {
+                    Some(u) => u,
+                    None => continue,
+                };
+
+                broker_sender
+                    .send_async(Event::ShadowDesired(req.id, Box::new(update)))
+                    .await
+                    .expect("Unable to send ShadowDesired to broker.");
+            }
+            ManagmentDataType::SendCommand => {
+                let request: CommandRequest =
+                    match decode(&broker_sender, &req, "CommandRequest").await {
+                        Some(r) => r,
+                        None => continue,
+                    };
+
+                broker_sender
+                    .send_async(Event::CommandSend {
+                        id: req.id,
+                        reply_to: "sock".to_string(),
+                        request: Box::new(request),
+                    })
+                    .await
+                    .expect("Unable to send CommandSend to broker.");
+            }
+            ManagmentDataType::GetCommand => {
+                let command: CommandId = match decode(&broker_sender, &req, "CommandId").await {
+                    Some(c) => c,
+                    None => continue,
+                };
+
+                broker_sender
+                    .send_async(Event::CommandGetRequest(req.id, command))
+                    .await
+                    .expect("Unable to send CommandGetRequest to broker.");
+            }
+            ManagmentDataType::QuerySeries => {
+                let query: SeriesQuery = match decode(&broker_sender, &req, "SeriesQuery").await {
+                    Some(q) => q,
+                    None => continue,
+                };
+
+                broker_sender
+                    .send_async(Event::SeriesQueryRequest(req.id, Box::new(query)))
+                    .await
+                    .expect("Unable to send SeriesQueryRequest to broker.");
+            }
+            ManagmentDataType::QueryInflux => {
+                let query: String = match decode(&broker_sender, &req, "query").await {
+                    Some(q) => q,
+                    None => continue,
+                };
+
+                // Nothing that changes the database
+                if !influx::is_read_only(&query) {
+                    log::warn!("Refused Influx query: {}", query);
+
+                    let result = Err(Error::CustomError(
+                        "Only SELECT and SHOW queries without regexes or division are allowed."
+                            .to_string(),
+                    ));
+                    acknowledge(
+                        &broker_sender,
+                        &req.id,
+                        ManagmentDataType::QueryInflux,
+                        result,
+                    )
+                    .await;
+                    continue;
+                }
+
+                broker_sender
+                    .send_async(Event::InfluxDataRequest {
+                        id: req.id,
+                        reply_to: "sock".to_string(),
+                        query: ReadQuery::new(query),
+                    })
+                    .await
+                    .expect("Unable to send InfluxDataRequest to broker.");
+            }
+            ManagmentDataType::AddDevice | ManagmentDataType::UpdateDevice => {
+                let update: DeviceUpdate = match decode(&broker_sender, &req, "DeviceUpdate").await
+                {
+                    Some(u) => u,
+                    None => continue,
+                };
+
+                let event = match req.cmd {
+                    ManagmentDataType::AddDevice => Event::DeviceAdd(req.id, Box::new(update)),
+                    _ => Event::DeviceUpdate(req.id, Box::new(update)),
+                };
+
+                broker_sender
+                    .send_async(event)
+                    .await
+                    .expect("Unable to send device request to broker.");
+            }
+            ManagmentDataType::ReplayDeadLetters | ManagmentDataType::PurgeDeadLetters => {
+                // Empty means all of them
+                let selection: DeadLetterRequest = match req.msg.is_empty() {
+                    true => Default::default(),
+                    false => match decode(&broker_sender, &req, "DeadLetterRequest").await {
+                        Some(s) => s,
+                        None => continue,
+                    },
+                };
+
+                let event = match req.cmd {
+                    ManagmentDataType::ReplayDeadLetters => Event::DeadLetterReplay {
+                        id: req.id,
+                        entry: selection.id,
+                    },
+                    _ => Event::DeadLetterPurge {
+                        id: req.id,
+                        entry: selection.id,
+                    },
+                };
+
+                broker_sender
+                    .send_async(event)
+                    .await
+                    .expect("Unable to send dead letter request to broker.");
+            }
         }
     }
 
@@ -183,49 +441,94 @@ async fn handle_connection(
     {
         *client.lock().await = None;
     }
+
+    metrics::add(metrics::ADMIN_CONNECTIONS, &[], -1.0);
 }
 
 // Only requires a sender. No response necessary here... yet.
 pub async fn run(settings: &settings::Admin, broker_sender: Sender<Event>) -> Result<(), Error> {
-    // Get the sender/reciever associated with this particular task
-    let (sender, receiver) = unbounded::<Event>();
+    // Set up server
+    let listener = TcpListener::bind(format!("127.0.0.1:{}", settings.port)).await?;
+
+    serve(settings, &listener, broker_sender).await
+}
+
+/// Same as `run` but accepts admin clients on a listener that's already bound
+pub async fn serve(
+    settings: &settings::Admin,
+    listener: &TcpListener,
+    broker_sender: Sender<Event>,
+) -> Result<(), Error> {
+    // Register this task
+    let receiver = broker::register("sock", &broker_sender).await?;
 
     // Client mutex
     let client: AdminClient = Default::default();
     let from_broker_client = client.clone();
 
-    // Set up server
-    let listener = TcpListener::bind(format!("127.0.0.1:{}", settings.port))
-        .await
-        .unwrap();
-
-    tokio::task::spawn(async move {
+    let from_broker = tokio::task::spawn(async move {
         let c = from_broker_client;
 
         while let Ok(event) = receiver.recv_async().await {
             log::info!("{:?}", event);
 
-            let data = match event {
-                Event::OtaUpdateImageListRequestResponse(r) => match serde_cbor::to_vec(&r) {
-                    Ok(v) => v,
-                    Err(_) => {
-                        log::warn!("Unable to serialize image list!");
-                        continue;
-                    }
+            let response = match event {
+                Event::OtaUpdateImageListRequestResponse(id, r) => {
+                    to_response(ManagmentDataType::GetImageList, id, &r)
+                }
+                Event::OtaUpdateGroupListRequestResponse(id, r) => {
+                    to_response(ManagmentDataType::GetGroupList, id, &r)
+                }
+                Event::BrokerStatsResponse(id, r) => {
+                    to_response(ManagmentDataType::GetBrokerStats, id, &r)
+                }
+                Event::DeadLetterListResponse(id, r) => {
+                    to_response(ManagmentDataType::GetDeadLetters, id, &r)
+                }
+                Event::DeviceListResponse(id, r) => {
+                    to_response(ManagmentDataType::GetDeviceList, id, &r)
+                }
+                Event::DeviceGetResponse(id, r) => {
+                    to_response(ManagmentDataType::GetDevice, id, &r)
+                }
+                Event::ShadowGetResponse(id, r) => {
+                    to_response(ManagmentDataType::GetShadow, id, &r)
+                }
+                Event::CommandGetResponse(id, r) => {
+                    to_response(ManagmentDataType::GetCommand, id, &r)
+                }
+                Event::SeriesQueryResponse(id, r) => {
+                    to_response(ManagmentDataType::QuerySeries, id, &r)
+                }
+                Event::InfluxDataResponse { id, result, .. } => match result {
+                    Ok(r) => to_response(ManagmentDataType::QueryInflux, id, &r),
+                    Err(e) => ManagementResponse {
+                        cmd: ManagmentDataType::QueryInflux,
+                        id,
+                        target: None,
+                        result: Err(e),
+                    },
+                },
+                Event::CommandSendResponse { id, result, .. } => match result {
+                    Ok(r) => to_response(ManagmentDataType::SendCommand, id, &r),
+                    Err(e) => ManagementResponse {
+                        cmd: ManagmentDataType::SendCommand,
+                        id,
+                        target: None,
+                        result: Err(e),
+                    },
                 },
-                Event::OtaUpdateGroupListRequestResponse(r) => match serde_cbor::to_vec(&r) {
-                    Ok(v) => v,
-                    Err(_) => {
-                        log::warn!("Unable to serialize group list!");
-                        continue;
-                    }
+                Event::ApplicationManagementResponse(r) => ManagementResponse {
+                    cmd: r.cmd,
+                    id: r.id,
+                    target: r.target,
+                    result: Ok(r.msg),
                 },
-                Event::ApplicationManagementResponse(r) => match serde_cbor::to_vec(&r) {
-                    Ok(v) => v,
-                    Err(_) => {
-                        log::warn!("Unable to application management response!");
-                        continue;
-                    }
+                Event::ManagementAck { id, cmd, result } => ManagementResponse {
+                    cmd,
+                    id: Some(id),
+                    target: None,
+                    result: result.map(|_| Vec::new()),
                 },
                 _ => {
                     log::warn!("Unhandled command sent to admin!");
@@ -233,6 +536,14 @@ pub async fn run(settings: &settings::Admin, broker_sender: Sender<Event>) -> Re
                 }
             };
 
+            let data = match serde_cbor::to_vec(&response) {
+                Ok(v) => v,
+                Err(_) => {
+                    log::warn!("Unable to serialize {:?} response!", response.cmd);
+                    continue;
+                }
+            };
+
             if let Some(c) = c.lock().await.as_ref() {
                 if let Err(e) = c.send_async(Ok(tungstenite::Message::binary(data))).await {
                     log::error!("Unabe to send message to admin! Err: {}", e);
@@ -241,24 +552,24 @@ pub async fn run(settings: &settings::Admin, broker_sender: Sender<Event>) -> Re
         }
     });
 
-    // Register this task
-    broker_sender
-        .send_async(Event::NewRunner {
-            name: "sock".to_string(),
-            sender: sender.clone(),
-        })
-        .await?;
-
-    loop {
-        let (stream, _) = listener.accept().await?;
-        tokio::task::spawn(handle_connection(
-            stream,
-            broker_sender.clone(),
-            settings.clone(),
-            client.clone(),
-        ))
-        .await?;
+    let res: Result<(), Error> = async {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            tokio::task::spawn(handle_connection(
+                stream,
+                broker_sender.clone(),
+                settings.clone(),
+                client.clone(),
+            ))
+            .await?;
+        }
     }
+    .await;
+
+    // Releases the "sock" registration so a restarted admin task can register again
+    from_broker.abort();
+
+    res
 }
 
 // TODO: (test) try to send an "other" managment_request (gets forwarded to the application.)
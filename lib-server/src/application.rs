@@ -0,0 +1,419 @@
+// System related
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+// Async Related
+use flume::Sender;
+use futures::future::BoxFuture;
+
+// Influx
+use influxdb::{ReadQuery, WriteQuery};
+
+// Serializing payloads
+use serde::{de::DeserializeOwned, Serialize};
+
+// Local lib related
+use crate::payload::PyrinasPayload;
+use crate::shadow;
+use crate::{
+    broker, ApplicationData, CommandRecord, CommandRequest, CorrelationId, Error, Event,
+    InfluxQueryResponse, SeriesPoint, ShadowDocument, ShadowUpdate,
+};
+
+/// Name the broker routes application events to
+pub const RUNNER_NAME: &str = "app";
+
+/// Decodes a payload and calls the handler with it
+type Handler<S> = Box<
+    dyn Fn(Context<S>, &[u8]) -> Result<BoxFuture<'static, Result<(), Error>>, Error> + Send + Sync,
+>;
+
+/// Called with every finished command
+type ResultHandler<S> =
+    Box<dyn Fn(Context<S>, CommandRecord) -> BoxFuture<'static, Result<(), Error>> + Send + Sync>;
+
+/// Called with the id from `Context::command` and the saved command or the reason it wasn't
+type SentHandler<S> = Box<
+    dyn Fn(
+            Context<S>,
+            CorrelationId,
+            Result<CommandRecord, String>,
+        ) -> BoxFuture<'static, Result<(), Error>>
+        + Send
+        + Sync,
+>;
+
+/// Called with the id from `Context::query` and the series returned or the reason it failed
+type QueryHandler<S> = Box<
+    dyn Fn(
+            Context<S>,
+            CorrelationId,
+            Result<InfluxQueryResponse, String>,
+        ) -> BoxFuture<'static, Result<(), Error>>
+        + Send
+        + Sync,
+>;
+
+/// Last id handed out by `Context::query`
+static LAST_QUERY_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Last id handed out by `Context::command`
+static LAST_COMMAND_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Handed to every handler along with the decoded payload
+pub struct Context<S> {
+    uid: String,
+    settings: Arc<S>,
+    broker_sender: Sender<Event>,
+    shadows: Option<shadow::Store>,
+}
+
+impl<S> Context<S> {
+    /// Id of the device that sent the request
+    pub fn uid(&self) -> &str {
+        &self.uid
+    }
+
+    /// Settings passed to `Application::run`
+    pub fn settings(&self) -> &S {
+        &self.settings
+    }
+
+    /// For sending anything else to the broker
+    pub fn broker_sender(&self) -> &Sender<Event> {
+        &self.broker_sender
+    }
+
+    /// Send a CBOR encoded response to the device. Published to `<uid>/app/s/<target>`.
+    pub async fn reply<T: Serialize>(&self, target: &str, msg: &T) -> Result<(), Error> {
+        self.publish(&self.uid, target, msg).await
+    }
+
+    /// Send a CBOR encoded message to any device. Published to `<uid>/app/s/<target>`.
+    pub async fn publish<T: Serialize>(
+        &self,
+        uid: &str,
+        target: &str,
+        msg: &T,
+    ) -> Result<(), Error> {
+        self.broker_sender
+            .send_async(Event::ApplicationResponse(ApplicationData {
+                uid: uid.to_string(),
+                target: target.to_string(),
+                msg: serde_cbor::to_vec(msg)?,
+            }))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Save a data point to every sink that takes its measurement
+    pub async fn save(&self, point: SeriesPoint) -> Result<(), Error> {
+        self.broker_sender
+            .send_async(Event::DataSave(Box::new(point)))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Save a data point to Influx
+    pub async fn write(&self, query: WriteQuery) -> Result<(), Error> {
+        self.broker_sender
+            .send_async(Event::InfluxDataSave(query))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Read from Influx. Returns the id the query handler (`Application::on_query_result`)
+    /// will see along with the result.
+    pub async fn query(&self, query: ReadQuery) -> Result<CorrelationId, Error> {
+        let id = LAST_QUERY_ID.fetch_add(1, Ordering::Relaxed) + 1;
+
+        self.broker_sender
+            .send_async(Event::InfluxDataRequest {
+                id: Some(id),
+                reply_to: RUNNER_NAME.to_string(),
+                query,
+            })
+            .await?;
+
+        Ok(id)
+    }
+
+    /// A device's shadow document. Needs a store set with `Application::shadows`.
+    pub fn shadow(&self, uid: &str) -> Result<ShadowDocument, Error> {
+        match &self.shadows {
+            Some(s) => s.get(uid),
+            None => Err(Error::CustomError("Shadow store not set!".to_string())),
+        }
+    }
+
+    /// Merge a change into a device's desired state. `desired` has to encode to a map.
+    /// The delta is published to the device once it's saved.
+    pub async fn set_desired<T: Serialize>(&self, uid: &str, desired: &T) -> Result<(), Error> {
+        let update = ShadowUpdate {
+            uid: uid.to_string(),
+            desired: serde_cbor::value::to_value(desired)?,
+        };
+
+        self.broker_sender
+            .send_async(Event::ShadowDesired(None, Box::new(update)))
+            .await?;
+
+        Ok(())
+    }
+
+    /// Run a command on a device using the server's timeout and retries. `msg` is CBOR encoded.
+    ///
+    /// The command is queued while the device is offline. Returns the id the sent handler
+    /// (`Application::on_command_sent`) will see along with the command's id. The result
+    /// handler (`Application::on_command_result`) is called with the command once it finishes.
+    pub async fn command<T: Serialize>(
+        &self,
+        uid: &str,
+        name: &str,
+        msg: &T,
+    ) -> Result<CorrelationId, Error> {
+        self.send_command(CommandRequest::new(uid, name, serde_cbor::to_vec(msg)?))
+            .await
+    }
+
+    /// Same as `command` but with your own timeout and retries
+    pub async fn send_command(&self, request: CommandRequest) -> Result<CorrelationId, Error> {
+        let id = LAST_COMMAND_ID.fetch_add(1, Ordering::Relaxed) + 1;
+
+        self.broker_sender
+            .send_async(Event::CommandSend {
+                id: Some(id),
+                reply_to: RUNNER_NAME.to_string(),
+                request: Box::new(request),
+            })
+            .await?;
+
+        Ok(id)
+    }
+}
+
+/// Routes application requests from devices to a handler by target.
+///
+/// ```ignore
+/// Application::new()
+///     .handle("env", |ctx, msg: EnvironmentData| async move {
+///         log::info!("{}: {:?}", ctx.uid(), msg);
+///         Ok(())
+///     })
+///     .run(settings, broker_sender)
+///     .await;
+/// ```
+pub struct Application<S> {
+    handlers: HashMap<String, Handler<S>>,
+    results: Option<ResultHandler<S>>,
+    sent: Option<SentHandler<S>>,
+    queries: Option<QueryHandler<S>>,
+    shadows: Option<shadow::Store>,
+}
+
+impl<S: Send + Sync + 'static> Default for Application<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<S: Send + Sync + 'static> Application<S> {
+    pub fn new() -> Self {
+        Self {
+            handlers: HashMap::new(),
+            results: None,
+            sent: None,
+            queries: None,
+            shadows: None,
+        }
+    }
+
+    /// Lets handlers read shadows with `Context::shadow`. Open it on the database passed to
+    /// `Builder::devices_db`.
+    pub fn shadows(mut self, store: shadow::Store) -> Self {
+        self.shadows = Some(store);
+        self
+    }
+
+    /// Handle requests sent to `<uid>/app/p/<target>`. The CBOR payload is decoded to `T`.
+    pub fn handle<T, F, Fut>(mut self, target: &str, handler: F) -> Self
+    where
+        T: DeserializeOwned + Send + 'static,
+        F: Fn(Context<S>, T) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        let handler: Handler<S> = Box::new(move |ctx, msg| {
+            let payload: T = serde_cbor::from_slice(msg)?;
+            Ok(Box::pin(handler(ctx, payload)))
+        });
+
+        if self.handlers.insert(target.to_string(), handler).is_some() {
+            log::warn!("Handler for {} replaced.", target);
+        }
+
+        self
+    }
+
+    /// Save requests sent to `<uid>/app/p/<target>` as data points without a handler.
+    /// See `payload::PyrinasPayload`.
+    pub fn store<T>(self, target: &str) -> Self
+    where
+        T: PyrinasPayload + DeserializeOwned + Send + 'static,
+    {
+        self.handle(target, |ctx, payload: T| async move {
+            ctx.save(payload.to_point(ctx.uid())?).await
+        })
+    }
+
+    /// Called once the server has saved a command sent with `Context::command`. The record has
+    /// the id the command was given. The context's uid is empty.
+    pub fn on_command_sent<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Context<S>, CorrelationId, Result<CommandRecord, String>) -> Fut
+            + Send
+            + Sync
+            + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.sent = Some(Box::new(move |ctx, id, result| {
+            Box::pin(handler(ctx, id, result))
+        }));
+        self
+    }
+
+    /// Called once a command sent with `Context::command` succeeds, fails or times out.
+    /// The context's uid is the device the command was sent to.
+    pub fn on_command_result<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Context<S>, CommandRecord) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.results = Some(Box::new(move |ctx, record| Box::pin(handler(ctx, record))));
+        self
+    }
+
+    /// Called with the result of every query sent with `Context::query`. The context's uid is
+    /// empty.
+    pub fn on_query_result<F, Fut>(mut self, handler: F) -> Self
+    where
+        F: Fn(Context<S>, CorrelationId, Result<InfluxQueryResponse, String>) -> Fut
+            + Send
+            + Sync
+            + 'static,
+        Fut: Future<Output = Result<(), Error>> + Send + 'static,
+    {
+        self.queries = Some(Box::new(move |ctx, id, result| {
+            Box::pin(handler(ctx, id, result))
+        }));
+        self
+    }
+
+    fn context(&self, uid: &str, settings: &Arc<S>, broker_sender: &Sender<Event>) -> Context<S> {
+        Context {
+            uid: uid.to_string(),
+            settings: settings.clone(),
+            broker_sender: broker_sender.clone(),
+            shadows: self.shadows.clone(),
+        }
+    }
+
+    /// Register with the broker and handle requests until the broker goes away.
+    ///
+    /// Requests are handled one at a time in the order they're recieved.
+    pub async fn run(self, settings: Arc<S>, broker_sender: Sender<Event>) {
+        // Register this task
+        let reciever = match broker::register(RUNNER_NAME, &broker_sender).await {
+            Ok(r) => r,
+            Err(e) => {
+                log::error!("Unable to register application. Err: {}", e);
+                return;
+            }
+        };
+
+        // Wait for event on reciever
+        while let Ok(event) = reciever.recv_async().await {
+            let req = match event {
+                Event::ApplicationRequest(req) => req,
+                Event::CommandResult(record) => {
+                    if let Some(handler) = &self.results {
+                        let ctx = self.context(&record.uid, &settings, &broker_sender);
+                        let id = record.id;
+
+                        if let Err(e) = handler(ctx, *record).await {
+                            log::error!("Command result handler error for {}. Err: {}", id, e);
+                        }
+                    }
+
+                    continue;
+                }
+                Event::CommandSendResponse {
+                    id: Some(id),
+                    result,
+                    ..
+                } => {
+                    if let Some(handler) = &self.sent {
+                        let ctx = self.context("", &settings, &broker_sender);
+
+                        if let Err(e) = handler(ctx, id, result.map(|r| *r)).await {
+                            log::error!("Command sent handler error for {}. Err: {}", id, e);
+                        }
+                    }
+
+                    continue;
+                }
+                Event::InfluxDataResponse {
+                    id: Some(id),
+                    result,
+                    ..
+                } => {
+                    if let Some(handler) = &self.queries {
+                        let ctx = self.context("", &settings, &broker_sender);
+
+                        if let Err(e) = handler(ctx, id, result.map(|r| *r)).await {
+                            log::error!("Query result handler error for {}. Err: {}", id, e);
+                        }
+                    }
+
+                    continue;
+                }
+                _ => {
+                    log::debug!("Unhandled application event: {:?}", event);
+                    continue;
+                }
+            };
+
+            let handler = match self.handlers.get(&req.target) {
+                Some(h) => h,
+                None => {
+                    log::debug!("No handler for {} from {}", req.target, req.uid);
+                    continue;
+                }
+            };
+
+            let ctx = self.context(&req.uid, &settings, &broker_sender);
+
+            // Decode then run
+            let res = match handler(ctx, &req.msg) {
+                Ok(f) => f.await,
+                Err(e) => {
+                    log::warn!(
+                        "Unable to decode {} payload from {}. Err: {}",
+                        req.target,
+                        req.uid,
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            if let Err(e) = res {
+                log::error!("{} handler error for {}. Err: {}", req.target, req.uid, e);
+            }
+        }
+    }
+}
@@ -1,101 +1,849 @@
 // System related
 use log::debug;
 use std::collections::hash_map::{Entry, HashMap};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
 
 // Channels
-use flume::{Receiver, Sender};
+use flume::{bounded, Receiver, Sender, TrySendError};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 
 // Local lib related
+use crate::command;
+use crate::dead_letter::DeadLetters;
+use crate::device;
+use crate::metrics;
+use crate::middleware::{Chain, Middleware};
+use crate::recorder::Recorder;
+use crate::retention;
+use crate::settings::{self, OverflowPolicy};
+use crate::shadow;
+use crate::sink;
 use crate::Event;
+use pyrinas_shared::{
+    BrokerRunnerStats, BrokerStatsResponse, CorrelationId, DeadLetterListResponse,
+    ManagmentDataType,
+};
 
 // Error
 use crate::Error;
 
-pub async fn run(broker_reciever: Receiver<Event>) {
-    let mut runners: HashMap<String, Sender<Event>> = HashMap::new();
+/// Events written to disk while a runner using `OverflowPolicy::Spill` catches up
+struct Spill {
+    db: sled::Db,
+    tree: sled::Tree,
+    notify: Arc<Notify>,
+    drain: JoinHandle<()>,
+}
 
-    // Handle broker events
-    while let Ok(event) = broker_reciever.recv_async().await {
-        match event.clone() {
-            // Upon creating a new server thread, the thread has to register with the broker.
-            Event::NewRunner { name, sender } => {
-                // Check to see if the runner is already in the HashMap
-                match runners.entry(name.clone()) {
-                    Entry::Occupied(..) => (),
-                    Entry::Vacant(entry) => {
-                        // Inserts the Sender<event> into the HashMap
-                        debug!("Adding {} to broker.", name);
-                        entry.insert(sender);
-                    }
+impl Drop for Spill {
+    /// Whatever is left stays on disk for next time
+    fn drop(&mut self) {
+        self.drain.abort();
+    }
+}
+
+impl Spill {
+    /// Append event to the end of the spill
+    fn push(&self, event: &Event) -> Result<(), Error> {
+        let id = self.db.generate_id()?;
+        self.tree
+            .insert(id.to_be_bytes(), serde_cbor::to_vec(event)?)?;
+
+        // Wake up the drain task
+        self.notify.notify_one();
+
+        Ok(())
+    }
+}
+
+/// Events waiting for room in the queue of a runner using `OverflowPolicy::Block`. A task of its
+/// own waits for the runner so a full queue doesn't hold up every other runner.
+struct Backlog {
+    sender: Sender<Event>,
+    /// Events handed to the task that it hasn't delivered or given up on yet
+    pending: Arc<AtomicUsize>,
+}
+
+impl Backlog {
+    fn new(
+        name: &str,
+        capacity: usize,
+        runner: Sender<Event>,
+        timeout: Duration,
+        dropped: Arc<AtomicU64>,
+    ) -> Backlog {
+        let (sender, reciever) = bounded(capacity);
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        tokio::task::spawn(forward(
+            name.to_string(),
+            reciever,
+            runner,
+            timeout,
+            pending.clone(),
+            dropped,
+        ));
+
+        Backlog { sender, pending }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pending.load(Ordering::Acquire) == 0
+    }
+
+    /// Add an event to the end of the backlog. Returns it if the backlog is full as well.
+    fn push(&self, event: Event) -> Result<(), Event> {
+        self.pending.fetch_add(1, Ordering::AcqRel);
+
+        self.sender.try_send(event).map_err(|e| {
+            self.pending.fetch_sub(1, Ordering::AcqRel);
+            e.into_inner()
+        })
+    }
+}
+
+/// Hands backlogged events to the runner, waiting up to `timeout` for room for each. Finishes
+/// once the backlog is dropped and empty, or the runner has gone away.
+async fn forward(
+    name: String,
+    reciever: Receiver<Event>,
+    runner: Sender<Event>,
+    timeout: Duration,
+    pending: Arc<AtomicUsize>,
+    dropped: Arc<AtomicU64>,
+) {
+    while let Ok(event) = reciever.recv_async().await {
+        match tokio::time::timeout(timeout, runner.send_async(event)).await {
+            Ok(Ok(())) => (),
+            Ok(Err(_)) => return,
+            Err(_) => drop_event(&name, &dropped),
+        }
+
+        pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Count (and occasionally log) an event dropped for a runner
+fn drop_event(name: &str, dropped: &AtomicU64) {
+    let dropped = dropped.fetch_add(1, Ordering::Relaxed) + 1;
+
+    // Don't flood the log if a runner is way behind
+    if dropped % 100 == 1 {
+        log::warn!("{} is falling behind! {} events dropped.", name, dropped);
+    }
+}
+
+/// Broker side of a registered runner
+struct Runner {
+    name: String,
+    sender: Sender<Event>,
+    /// Kept so the oldest event can be discarded with `OverflowPolicy::DropOldest`
+    reciever: Receiver<Event>,
+    settings: settings::Runner,
+    spill: Option<Spill>,
+    backlog: Option<Backlog>,
+    dropped: Arc<AtomicU64>,
+    /// Dead once the runner's `Registration` is dropped
+    alive: Weak<()>,
+    /// Number of liveness checks in a row the queue has been full
+    full_checks: u32,
+}
+
+impl Runner {
+    fn new(
+        name: &str,
+        settings: &settings::Broker,
+        spill_db: Option<&sled::Db>,
+        alive: Weak<()>,
+    ) -> Runner {
+        let runner_settings = settings.runner(name).clone();
+        let (sender, reciever) = bounded(runner_settings.capacity);
+
+        // Only set up spilling if it's used by this runner
+        let spill = match (runner_settings.overflow, spill_db) {
+            (OverflowPolicy::Spill, Some(db)) => match db.open_tree(format!("spill/{}", name)) {
+                Ok(tree) => {
+                    let notify = Arc::new(Notify::new());
+                    let drain =
+                        tokio::task::spawn(drain(tree.clone(), notify.clone(), sender.clone()));
+
+                    Some(Spill {
+                        db: db.clone(),
+                        tree,
+                        notify,
+                        drain,
+                    })
+                }
+                Err(e) => {
+                    log::error!("Unable to open spill for {}. Err: {}", name, e);
+                    None
                 }
+            },
+            (OverflowPolicy::Spill, None) => {
+                log::warn!("No spill_path set. {} will block instead.", name);
+                None
             }
-            Event::InfluxDataSave(_query) => {
-                debug!("broker_run: InfluxDataSave");
+            _ => None,
+        };
 
-                // Send to influx
-                if let Err(e) = send("influx", &event, &mut runners).await {
-                    log::error!("{}", e);
-                }
+        // Block, also used for spill if there's nowhere to spill to
+        let dropped = Arc::new(AtomicU64::new(0));
+        let backlog = match (runner_settings.overflow, &spill) {
+            (OverflowPolicy::Block, _) | (OverflowPolicy::Spill, None) => Some(Backlog::new(
+                name,
+                runner_settings.capacity,
+                sender.clone(),
+                Duration::from_millis(settings.block_timeout_ms),
+                dropped.clone(),
+            )),
+            _ => None,
+        };
+
+        Runner {
+            name: name.to_string(),
+            sender,
+            reciever,
+            settings: runner_settings,
+            spill,
+            backlog,
+            dropped,
+            alive,
+            full_checks: 0,
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        self.alive.strong_count() > 0
+    }
+
+    fn disconnected(&self) -> Error {
+        Error::CustomError(format!("{} broker task disconnected!", self.name))
+    }
+
+    fn drop_event(&self) {
+        drop_event(&self.name, &self.dropped);
+    }
+
+    /// Leave the event to the backlog. Dropped if the backlog is full too.
+    fn block(&self, event: Event) -> Result<(), Error> {
+        match &self.backlog {
+            Some(backlog) if backlog.push(event).is_ok() => (),
+            _ => self.drop_event(),
+        }
+
+        Ok(())
+    }
+
+    /// Queue an event for this runner according to its overflow policy
+    fn send(&mut self, event: Event) -> Result<(), Error> {
+        // Keep events in order while there's a backlog on disk
+        if let Some(spill) = &self.spill {
+            if !spill.tree.is_empty() {
+                return spill.push(&event);
             }
-            Event::ApplicationRequest(_) | Event::ApplicationManagementRequest(_) => {
-                debug!("broker_run: ApplicationManagementRequest");
+        }
 
-                // Send to app handler
-                if let Err(e) = send("app", &event, &mut runners).await {
-                    log::error!("{}", e);
-                }
+        // Same for events waiting for room
+        if let Some(backlog) = &self.backlog {
+            if !backlog.is_empty() {
+                return self.block(event);
+            }
+        }
+
+        let event = match self.sender.try_send(event) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(event)) => event,
+            Err(TrySendError::Disconnected(_)) => return Err(self.disconnected()),
+        };
+
+        match (self.settings.overflow, &self.spill) {
+            (OverflowPolicy::Spill, Some(spill)) => spill.push(&event),
+            (OverflowPolicy::DropNewest, _) => {
+                self.drop_event();
+                Ok(())
             }
-            Event::ApplicationManagementResponse(_data) => {
-                debug!("broker_run: ApplicationManagementResponse");
+            (OverflowPolicy::DropOldest, _) => {
+                let mut event = event;
 
-                // Send to app handler
-                if let Err(e) = send("sock", &event, &mut runners).await {
-                    log::error!("{}", e);
+                loop {
+                    if self.reciever.try_recv().is_ok() {
+                        self.drop_event();
+                    }
+
+                    event = match self.sender.try_send(event) {
+                        Ok(()) => return Ok(()),
+                        Err(TrySendError::Full(event)) => event,
+                        Err(TrySendError::Disconnected(_)) => return Err(self.disconnected()),
+                    };
                 }
             }
-            Event::ApplicationResponse(_)
-            | Event::OtaResponse(_)
-            | Event::OtaDownloadResponse(_) => {
-                debug!("broker_run: ApplicationResponse");
-                // Send to mqtt handler
-                if let Err(e) = send("mqtt", &event, &mut runners).await {
-                    log::error!("{}", e);
+            // Block, also used for spill if there's nowhere to spill to
+            _ => self.block(event),
+        }
+    }
+
+    fn stats(&self) -> BrokerRunnerStats {
+        BrokerRunnerStats {
+            name: self.name.clone(),
+            queued: self.sender.len(),
+            capacity: self.settings.capacity,
+            spilled: self.spill.as_ref().map(|s| s.tree.len()).unwrap_or(0),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Feeds spilled events back to the runner, oldest first
+async fn drain(tree: sled::Tree, notify: Arc<Notify>, sender: Sender<Event>) {
+    loop {
+        while let Ok(Some((key, value))) = tree.first() {
+            match serde_cbor::from_slice::<Event>(&value) {
+                Ok(event) => {
+                    // Runner has gone away
+                    if sender.send_async(event).await.is_err() {
+                        return;
+                    }
                 }
+                Err(e) => log::error!("Unable to decode spilled event. Err: {}", e),
+            }
+
+            // Only removed once delivered so the broker keeps spilling until caught up
+            if let Err(e) = tree.remove(key) {
+                log::error!("Unable to remove spilled event. Err: {}", e);
+                return;
+            }
+        }
+
+        notify.notified().await;
+    }
+}
+
+/// Create the channel used to send events to the broker
+pub fn channel(settings: &settings::Broker) -> (Sender<Event>, Receiver<Event>) {
+    bounded(settings.capacity)
+}
+
+/// A runner's registration with the broker.
+///
+/// Derefs to the reciever the broker delivers events on. The runner is deregistered once this is dropped.
+pub struct Registration {
+    reciever: Receiver<Event>,
+    _alive: Arc<()>,
+}
+
+impl Deref for Registration {
+    type Target = Receiver<Event>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.reciever
+    }
+}
+
+/// Register a runner with the broker.
+pub async fn register(name: &str, broker_sender: &Sender<Event>) -> Result<Registration, Error> {
+    let (reply, reciever) = bounded(1);
+    let alive = Arc::new(());
+
+    broker_sender
+        .send_async(Event::NewRunner {
+            name: name.to_string(),
+            alive: Arc::downgrade(&alive),
+            reply,
+        })
+        .await?;
+
+    Ok(Registration {
+        reciever: reciever.recv_async().await?,
+        _alive: alive,
+    })
+}
+
+/// Remove a runner along with anything still queued for it
+fn deregister(name: &str, runners: &mut HashMap<String, Runner>) {
+    if let Some(runner) = runners.remove(name) {
+        log::warn!(
+            "{} is no longer running. Removed from broker. {} queued events discarded.",
+            name,
+            runner.sender.len()
+        );
+
+        metrics::set(metrics::BROKER_QUEUED, &[("runner", name)], 0.0);
+    }
+}
+
+/// Queue depths and drops for every runner
+fn report(runners: &HashMap<String, Runner>) {
+    for runner in runners.values() {
+        let stats = runner.stats();
+        let labels = [("runner", stats.name.as_str())];
+
+        metrics::set(metrics::BROKER_QUEUED, &labels, stats.queued as f64);
+        metrics::set(metrics::BROKER_CAPACITY, &labels, stats.capacity as f64);
+        metrics::set(metrics::BROKER_SPILLED, &labels, stats.spilled as f64);
+        metrics::set(metrics::BROKER_DROPPED, &labels, stats.dropped as f64);
+    }
+}
+
+/// Remove runners that have gone away
+fn remove_dead(runners: &mut HashMap<String, Runner>) {
+    let dead: Vec<String> = runners
+        .values()
+        .filter(|r| !r.is_alive())
+        .map(|r| r.name.clone())
+        .collect();
+
+    for name in dead {
+        deregister(&name, runners);
+    }
+}
+
+/// Remove runners that have gone away and warn about those that have stopped taking events
+fn check_liveness(runners: &mut HashMap<String, Runner>) {
+    remove_dead(runners);
+
+    for runner in runners.values_mut() {
+        if !runner.sender.is_full() {
+            runner.full_checks = 0;
+            continue;
+        }
+
+        runner.full_checks += 1;
+
+        // Only warn once per stall
+        if runner.full_checks == 2 {
+            log::warn!("{} queue is still full. Is it stuck?", runner.name);
+        }
+    }
+}
+
+pub async fn run(settings: settings::Broker, broker_reciever: Receiver<Event>) {
+    run_with_middleware(settings, broker_reciever, Vec::new()).await
+}
+
+/// Same as `run` with middleware of your own. See `middleware::Chain::new` for the order it runs in.
+pub async fn run_with_middleware(
+    settings: settings::Broker,
+    broker_reciever: Receiver<Event>,
+    middleware: Vec<Box<dyn Middleware>>,
+) {
+    let mut runners: HashMap<String, Runner> = HashMap::new();
+
+    // Every event goes through this before it's routed
+    let mut chain = Chain::new(&settings, middleware);
+
+    // Only needed if a runner spills to disk
+    let spill_db = match &settings.spill_path {
+        Some(path) => match sled::open(path) {
+            Ok(db) => Some(db),
+            Err(e) => {
+                log::error!("Unable to open spill db. Err: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    // Only used when debugging
+    let mut recorder = match &settings.record_path {
+        Some(path) => match Recorder::open(path) {
+            Ok(r) => Some(r),
+            Err(e) => {
+                log::error!("Unable to open event recording. Err: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    // Keeps events that can't be delivered
+    let dead_letters = match &settings.dead_letter {
+        Some(d) => match DeadLetters::open(d) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                log::error!("Unable to open dead letter db. Err: {}", e);
+                None
             }
-            Event::OtaUnlink { .. }
-            | Event::OtaLink { .. }
-            | Event::OtaUpdateImageListRequest()
-            | Event::OtaUpdateGroupListRequest()
-            | Event::OtaDeletePackage(_)
-            | Event::OtaNewPackage(_)
-            | Event::OtaRequest { .. } => {
-                // Send to ota task
-                if let Err(e) = send("ota", &event, &mut runners).await {
-                    log::error!("{}", e);
+        },
+        None => None,
+    };
+
+    // Counted for every event. Looked up once per kind.
+    let mut event_counts: HashMap<&'static str, metrics::Handle> = HashMap::new();
+
+    // Periodically clean up after runners that have gone away
+    let mut liveness = tokio::time::interval(Duration::from_millis(settings.liveness_interval_ms));
+
+    // Handle broker events
+    'broker: loop {
+        let event = tokio::select! {
+            event = broker_reciever.recv_async() => match event {
+                Ok(event) => event,
+                Err(_) => break,
+            },
+            _ = liveness.tick() => {
+                check_liveness(&mut runners);
+                report(&runners);
+
+                if let Some(r) = recorder.as_mut() {
+                    if let Err(e) = r.flush() {
+                        log::error!("Unable to flush event recording. Err: {}", e);
+                    }
                 }
+
+                continue;
+            }
+        };
+
+        if let Some(r) = recorder.as_mut() {
+            if let Err(e) = r.record(&event) {
+                log::error!("Unable to record event. Recording stopped. Err: {}", e);
+                recorder = None;
             }
-            Event::OtaUpdateImageListRequestResponse(_)
-            | Event::OtaUpdateGroupListRequestResponse(_) => {
-                // Send to app handler
-                if let Err(e) = send("sock", &event, &mut runners).await {
-                    log::error!("{}", e);
+        }
+
+        // Registration and shutdown skip the middleware
+        let events = match event {
+            Event::NewRunner { .. } | Event::Shutdown => vec![event],
+            _ => chain.process(event),
+        };
+
+        for event in events {
+            event_counts
+                .entry(event.kind())
+                .or_insert_with_key(|kind| {
+                    metrics::handle(metrics::BROKER_EVENTS, &[("kind", kind)])
+                })
+                .inc();
+
+            match event.clone() {
+                // Everything sent before this has been handed to the runners
+                Event::Shutdown => {
+                    log::info!("Broker shutting down.");
+                    break 'broker;
+                }
+                // Upon creating a new server thread, the thread has to register with the broker.
+                Event::NewRunner { name, alive, reply } => {
+                    // A previous instance that has since gone away can be replaced
+                    if runners.get(&name).map(|r| !r.is_alive()).unwrap_or(false) {
+                        deregister(&name, &mut runners);
+                    }
+
+                    // Check to see if the runner is already in the HashMap
+                    match runners.entry(name.clone()) {
+                        Entry::Occupied(..) => {
+                            log::warn!("{} is already registered with the broker.", name);
+                        }
+                        Entry::Vacant(entry) => {
+                            // Inserts the runner into the HashMap
+                            debug!("Adding {} to broker.", name);
+                            let runner = entry.insert(Runner::new(
+                                &name,
+                                &settings,
+                                spill_db.as_ref(),
+                                alive,
+                            ));
+
+                            // Hand the reciever back to the runner
+                            if reply.send_async(runner.reciever.clone()).await.is_err() {
+                                log::warn!("{} went away before registering.", name);
+                            }
+                        }
+                    }
                 }
+                Event::BrokerStatsRequest(id) => {
+                    remove_dead(&mut runners);
+                    report(&runners);
+
+                    let mut stats: Vec<BrokerRunnerStats> =
+                        runners.values().map(|r| r.stats()).collect();
+                    stats.sort_by(|a, b| a.name.cmp(&b.name));
+
+                    let event = Event::BrokerStatsResponse(
+                        id,
+                        BrokerStatsResponse {
+                            runners: stats,
+                            events: chain.events(),
+                            rejected: chain.rejected(),
+                        },
+                    );
+
+                    // Send to admin
+                    deliver("sock", &event, &mut runners, dead_letters.as_ref());
+                }
+                Event::DeadLetterListRequest(id) => {
+                    match get_dead_letters(&dead_letters).and_then(|d| d.list()) {
+                        Ok(entries) => {
+                            let event = Event::DeadLetterListResponse(
+                                id,
+                                DeadLetterListResponse { entries },
+                            );
+
+                            // Send to admin
+                            deliver("sock", &event, &mut runners, None);
+                        }
+                        Err(e) => {
+                            acknowledge(
+                                id,
+                                ManagmentDataType::GetDeadLetters,
+                                Err(e),
+                                &mut runners,
+                            );
+                        }
+                    }
+                }
+                Event::DeadLetterReplay { id, entry } => {
+                    let result = match get_dead_letters(&dead_letters).and_then(|d| d.take(entry)) {
+                        Ok(events) => {
+                            log::info!("Replaying {} dead letters.", events.len());
+
+                            // Ends up back in the dead letters if it still can't be delivered
+                            for (runner, event) in events {
+                                deliver(&runner, &event, &mut runners, dead_letters.as_ref());
+                            }
+
+                            Ok(())
+                        }
+                        Err(e) => Err(e),
+                    };
+
+                    acknowledge(
+                        id,
+                        ManagmentDataType::ReplayDeadLetters,
+                        result,
+                        &mut runners,
+                    );
+                }
+                Event::DeadLetterPurge { id, entry } => {
+                    let result = get_dead_letters(&dead_letters)
+                        .and_then(|d| d.purge(entry))
+                        .map(|count| log::info!("Purged {} dead letters.", count));
+
+                    acknowledge(
+                        id,
+                        ManagmentDataType::PurgeDeadLetters,
+                        result,
+                        &mut runners,
+                    );
+                }
+                Event::DataSave(_) | Event::DataExpire { .. } => {
+                    // Rollups are worked out from a copy of every point
+                    if matches!(event, Event::DataSave(_))
+                        && runners.contains_key(retention::RUNNER_NAME)
+                    {
+                        deliver(
+                            retention::RUNNER_NAME,
+                            &event,
+                            &mut runners,
+                            dead_letters.as_ref(),
+                        );
+                    }
+
+                    // Influx stands in when there aren't any sinks
+                    let name = match runners.contains_key(sink::RUNNER_NAME) {
+                        true => sink::RUNNER_NAME,
+                        false => "influx",
+                    };
+
+                    deliver(name, &event, &mut runners, dead_letters.as_ref());
+                }
+                Event::InfluxDataSave(_)
+                | Event::InfluxDataExpire { .. }
+                | Event::InfluxDataRequest { .. }
+                | Event::SeriesQueryRequest(..) => {
+                    debug!("broker_run: InfluxDataSave");
+
+                    // Send to influx, or the local store standing in for it
+                    deliver("influx", &event, &mut runners, dead_letters.as_ref());
+                }
+                // Back to whoever asked
+                Event::InfluxDataResponse { reply_to, .. }
+                | Event::CommandSendResponse { reply_to, .. } => {
+                    let name = reply_to.clone();
+                    deliver(&name, &event, &mut runners, dead_letters.as_ref());
+                }
+                Event::ApplicationRequest(_) | Event::ApplicationManagementRequest(_) => {
+                    debug!("broker_run: ApplicationManagementRequest");
+
+                    // Send to app handler
+                    deliver("app", &event, &mut runners, dead_letters.as_ref());
+                }
+                Event::ApplicationManagementResponse(_data) => {
+                    debug!("broker_run: ApplicationManagementResponse");
+
+                    // Send to app handler
+                    deliver("sock", &event, &mut runners, dead_letters.as_ref());
+                }
+                Event::ApplicationResponse(_)
+                | Event::OtaResponse(_)
+                | Event::OtaDownloadResponse(_)
+                | Event::ShadowDelta { .. }
+                | Event::CommandPublish { .. } => {
+                    debug!("broker_run: ApplicationResponse");
+                    // Send to mqtt handler
+                    deliver("mqtt", &event, &mut runners, dead_letters.as_ref());
+                }
+                Event::OtaUnlink { .. }
+                | Event::OtaLink { .. }
+                | Event::OtaUpdateImageListRequest(_)
+                | Event::OtaUpdateGroupListRequest(_)
+                | Event::OtaDeletePackage(..)
+                | Event::OtaNewPackage(..)
+                | Event::OtaRequest { .. } => {
+                    // Send to ota task
+                    deliver("ota", &event, &mut runners, dead_letters.as_ref());
+                }
+                Event::DeviceSeen(_)
+                | Event::DeviceConnection { .. }
+                | Event::DeviceListRequest(_)
+                | Event::DeviceGetRequest(..)
+                | Event::DeviceAdd(..)
+                | Event::DeviceUpdate(..)
+                | Event::DeviceRemove(..) => {
+                    // Send to device registry
+                    deliver(
+                        device::RUNNER_NAME,
+                        &event,
+                        &mut runners,
+                        dead_letters.as_ref(),
+                    );
+                }
+                Event::CommandSend { .. }
+                | Event::CommandGetRequest(..)
+                | Event::CommandAck { .. } => {
+                    // Send to command service
+                    deliver(
+                        command::RUNNER_NAME,
+                        &event,
+                        &mut runners,
+                        dead_letters.as_ref(),
+                    );
+                }
+                Event::ShadowReported { .. }
+                | Event::ShadowDesired(..)
+                | Event::ShadowGetRequest(..) => {
+                    // Send to shadow service
+                    deliver(
+                        shadow::RUNNER_NAME,
+                        &event,
+                        &mut runners,
+                        dead_letters.as_ref(),
+                    );
+                }
+                // Only to whoever is listening. Queued commands are sent once a device is online.
+                Event::DeviceOnline(_) | Event::DeviceOffline(_) => {
+                    for name in ["app", command::RUNNER_NAME] {
+                        if runners.contains_key(name) {
+                            deliver(name, &event, &mut runners, dead_letters.as_ref());
+                        }
+                    }
+                }
+                // Only if the application is listening
+                Event::CommandResult(_) if runners.contains_key("app") => {
+                    deliver("app", &event, &mut runners, dead_letters.as_ref());
+                }
+                Event::OtaUpdateImageListRequestResponse(..)
+                | Event::OtaUpdateGroupListRequestResponse(..)
+                | Event::DeviceListResponse(..)
+                | Event::DeviceGetResponse(..)
+                | Event::ShadowGetResponse(..)
+                | Event::CommandGetResponse(..)
+                | Event::SeriesQueryResponse(..)
+                | Event::ManagementAck { .. } => {
+                    // Send to app handler
+                    deliver("sock", &event, &mut runners, dead_letters.as_ref());
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // Runners finish what's queued once their queues are dropped
+    drop(runners);
+
+    if let Some(r) = recorder.as_mut() {
+        if let Err(e) = r.flush() {
+            log::error!("Unable to flush event recording. Err: {}", e);
+        }
+    }
+
+    if let Some(db) = spill_db {
+        if let Err(e) = db.flush_async().await {
+            log::error!("Unable to flush spill db. Err: {}", e);
+        }
+    }
+
+    if let Some(d) = dead_letters {
+        if let Err(e) = d.flush().await {
+            log::error!("Unable to flush dead letter db. Err: {}", e);
+        }
+    }
+}
+
+/// Send an event to a runner. Kept as a dead letter if that fails.
+fn deliver(
+    task_name: &str,
+    event: &Event,
+    runners: &mut HashMap<String, Runner>,
+    dead_letters: Option<&DeadLetters>,
+) {
+    if let Err(e) = send(task_name, event, runners) {
+        log::error!("{}", e);
+
+        if let Some(d) = dead_letters {
+            if let Err(e) = d.push(task_name, &e.to_string(), event) {
+                log::error!("Unable to save dead letter. Err: {}", e);
             }
-            _ => (),
         }
     }
 }
 
-/// Local only function to search for and find the corresponding sender
-async fn send(
+fn get_dead_letters(dead_letters: &Option<DeadLetters>) -> Result<&DeadLetters, Error> {
+    dead_letters
+        .as_ref()
+        .ok_or_else(|| Error::CustomError("Dead letters are not enabled.".to_string()))
+}
+
+/// Let the admin client know how a request went. Only sent if the request has an id.
+fn acknowledge(
+    id: Option<CorrelationId>,
+    cmd: ManagmentDataType,
+    result: Result<(), Error>,
+    runners: &mut HashMap<String, Runner>,
+) {
+    if let Err(e) = &result {
+        log::error!("{:?} failed. Err: {}", cmd, e);
+    }
+
+    if let Some(id) = id {
+        let event = Event::ManagementAck {
+            id,
+            cmd,
+            result: result.map_err(|e| e.to_string()),
+        };
+
+        deliver("sock", &event, runners, None);
+    }
+}
+
+/// Local only function to search for and find the corresponding runner
+fn send(
     task_name: &str,
     event: &Event,
-    runners: &mut HashMap<String, Sender<Event>>,
+    runners: &mut HashMap<String, Runner>,
 ) -> Result<(), Error> {
     match runners.get_mut(task_name) {
-        Some(sender) => {
-            sender.send_async(event.clone()).await?;
-            Ok(())
+        Some(runner) if runner.is_alive() => runner.send(event.clone()),
+        Some(_) => {
+            deregister(task_name, runners);
+
+            Err(Error::CustomError(format!(
+                "{} broker task not running!",
+                task_name
+            )))
         }
         None => Err(Error::CustomError(format!(
             "{} broker task not registered!",
@@ -0,0 +1,391 @@
+// System related
+use std::time::Duration;
+
+// Async Related
+use flume::Sender;
+
+// Local lib related
+use crate::device::Registry;
+use crate::mqtt::SECURITY_LOG;
+use crate::{acknowledge, broker, settings, Error, Event};
+use pyrinas_shared::{
+    CommandAck, CommandId, CommandRecord, CommandRequest, CommandState, DeviceCommand,
+    ManagmentDataType,
+};
+
+/// Name the broker routes command events to
+pub const RUNNER_NAME: &str = "command";
+
+/// Time between checks for commands that haven't been acknowledged
+const CHECK_INTERVAL_MS: u64 = 1000;
+
+/// Time between removing commands that finished more than `keep_secs` ago
+const PRUNE_INTERVAL_MS: u64 = 3600 * 1000;
+
+/// Persistent record of every command until it's been finished for `commands.keep_secs`
+#[derive(Debug, Clone)]
+pub struct Store {
+    db: sled::Db,
+    tree: sled::Tree,
+    /// Ids of the commands that haven't finished, so checking them doesn't go through every
+    /// finished one as well
+    pending: sled::Tree,
+}
+
+impl Store {
+    pub fn open(db: &sled::Db) -> Result<Store, Error> {
+        let tree = db.open_tree("commands")?;
+        let pending = db.open_tree("commands/pending")?;
+
+        // Commands saved before there was an index
+        if pending.is_empty() {
+            for value in tree.iter().values() {
+                let record: CommandRecord = serde_cbor::from_slice(&value?)?;
+
+                if !record.state.is_finished() {
+                    pending.insert(record.id.to_be_bytes(), &[])?;
+                }
+            }
+        }
+
+        Ok(Store {
+            db: db.clone(),
+            tree,
+            pending,
+        })
+    }
+
+    pub fn get(&self, id: CommandId) -> Result<Option<CommandRecord>, Error> {
+        match self.tree.get(id.to_be_bytes())? {
+            Some(v) => Ok(Some(serde_cbor::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Commands stay in the pending index until they're saved as finished. They're indexed before
+    /// they're saved and removed after, so an unfinished command can't be left out.
+    pub fn save(&self, record: &CommandRecord) -> Result<(), Error> {
+        let key = record.id.to_be_bytes();
+
+        if !record.state.is_finished() {
+            self.pending.insert(key, &[])?;
+        }
+
+        self.tree.insert(key, serde_cbor::to_vec(record)?)?;
+
+        if record.state.is_finished() {
+            self.pending.remove(key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Queue a new command with a new id. Unset timeout and retries come from `settings`.
+    pub fn add(
+        &self,
+        request: CommandRequest,
+        settings: &settings::Commands,
+        now_ms: i64,
+    ) -> Result<CommandRecord, Error> {
+        let record = CommandRecord {
+            id: self.db.generate_id()?,
+            uid: request.uid,
+            name: request.name,
+            msg: request.msg,
+            state: CommandState::Queued,
+            timeout_secs: request.timeout_secs.unwrap_or(settings.timeout_secs),
+            retries: request.retries.unwrap_or(settings.retries),
+            attempts: 0,
+            created_ms: now_ms,
+            sent_ms: None,
+            finished_ms: None,
+            ack: None,
+        };
+
+        self.save(&record)?;
+
+        Ok(record)
+    }
+
+    /// Commands that haven't finished, oldest first
+    pub fn pending(&self) -> Result<Vec<CommandRecord>, Error> {
+        let mut pending = Vec::new();
+
+        for key in self.pending.iter().keys() {
+            let key = key?;
+
+            match self.tree.get(&key)? {
+                Some(value) => {
+                    let record: CommandRecord = serde_cbor::from_slice(&value)?;
+
+                    match record.state.is_finished() {
+                        true => {
+                            self.pending.remove(key)?;
+                        }
+                        false => pending.push(record),
+                    }
+                }
+                // Left behind if the server stopped part way through a save
+                None => {
+                    self.pending.remove(key)?;
+                }
+            }
+        }
+
+        Ok(pending)
+    }
+
+    /// Remove commands that finished before `before_ms`. Returns how many were removed. Goes
+    /// through every command, so it's only run every `PRUNE_INTERVAL_MS`.
+    pub fn prune(&self, before_ms: i64) -> Result<usize, Error> {
+        let mut removed = 0;
+
+        for value in self.tree.iter().values() {
+            let record: CommandRecord = serde_cbor::from_slice(&value?)?;
+
+            if matches!(record.finished_ms, Some(f) if f < before_ms) {
+                self.tree.remove(record.id.to_be_bytes())?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+/// Publish the command to the device and wait for it to be acknowledged. Returns the saved
+/// record.
+async fn send(
+    broker_sender: &Sender<Event>,
+    store: &Store,
+    mut record: CommandRecord,
+    now_ms: i64,
+) -> Result<CommandRecord, Error> {
+    record.state = CommandState::Sent;
+    record.attempts += 1;
+    record.sent_ms = Some(now_ms);
+    store.save(&record)?;
+
+    log::debug!(
+        "Sending command {} to {} (attempt {})",
+        record.id,
+        record.uid,
+        record.attempts
+    );
+
+    broker_sender
+        .send_async(Event::CommandPublish {
+            uid: record.uid.clone(),
+            name: record.name.clone(),
+            command: DeviceCommand {
+                id: record.id,
+                msg: record.msg.clone(),
+            },
+        })
+        .await?;
+
+    Ok(record)
+}
+
+/// Save the outcome and let the application know
+async fn finish(
+    broker_sender: &Sender<Event>,
+    store: &Store,
+    mut record: CommandRecord,
+    state: CommandState,
+    ack: Option<CommandAck>,
+    now_ms: i64,
+) -> Result<(), Error> {
+    record.state = state;
+    record.ack = ack;
+    record.finished_ms = Some(now_ms);
+    store.save(&record)?;
+
+    log::info!("Command {} to {} {}.", record.id, record.uid, state);
+
+    broker_sender
+        .send_async(Event::CommandResult(Box::new(record)))
+        .await?;
+
+    Ok(())
+}
+
+async fn process_event(
+    broker_sender: &Sender<Event>,
+    store: &Store,
+    registry: &Registry,
+    settings: &settings::Commands,
+    event: Event,
+) -> Result<(), Error> {
+    let now_ms = chrono::Utc::now().timestamp_millis();
+
+    match event {
+        Event::CommandSend {
+            id,
+            reply_to,
+            request,
+        } => {
+            let result = match store.add(*request, settings, now_ms) {
+                Ok(record) if registry.is_online(&record.uid) => {
+                    send(broker_sender, store, record, now_ms).await
+                }
+                Ok(record) => {
+                    log::info!("{} is offline. Queued command {}.", record.uid, record.id);
+                    Ok(record)
+                }
+                Err(e) => Err(e),
+            };
+
+            if let Err(e) = &result {
+                log::warn!("Unable to send command. Err: {}", e);
+            }
+
+            // Back to whoever asked with the id it was given
+            let event = Event::CommandSendResponse {
+                id,
+                reply_to,
+                result: result.map(Box::new).map_err(|e| e.to_string()),
+            };
+
+            broker_sender.send_async(event).await?;
+        }
+        Event::CommandGetRequest(id, command) => match store.get(command)? {
+            Some(record) => {
+                broker_sender
+                    .send_async(Event::CommandGetResponse(id, Box::new(record)))
+                    .await?;
+            }
+            None => {
+                let result = Err(Error::CustomError(format!(
+                    "Command {} not found!",
+                    command
+                )));
+                acknowledge(broker_sender, &id, ManagmentDataType::GetCommand, result).await;
+            }
+        },
+        Event::CommandAck { uid, ack } => match store.get(ack.id)? {
+            Some(record) if record.uid != uid => {
+                log::warn!(
+                    target: SECURITY_LOG,
+                    "{} acknowledged command {} meant for {}",
+                    uid,
+                    ack.id,
+                    record.uid
+                );
+            }
+            Some(record) if record.state.is_finished() => {
+                log::debug!("Command {} already {}.", record.id, record.state);
+            }
+            Some(record) => {
+                let state = match ack.code {
+                    0 => CommandState::Succeeded,
+                    _ => CommandState::Failed,
+                };
+
+                finish(broker_sender, store, record, state, Some(ack), now_ms).await?;
+            }
+            None => log::warn!("Ack for unknown command {} from {}", ack.id, uid),
+        },
+        Event::DeviceOnline(uid) => {
+            // Deliver everything that was waiting for it
+            for record in store.pending()? {
+                if record.uid == uid && record.state == CommandState::Queued {
+                    send(broker_sender, store, record, now_ms).await?;
+                }
+            }
+        }
+        _ => (),
+    };
+
+    Ok(())
+}
+
+/// Retry or time out commands that haven't been acknowledged
+async fn check(
+    broker_sender: &Sender<Event>,
+    store: &Store,
+    registry: &Registry,
+) -> Result<(), Error> {
+    let now_ms = chrono::Utc::now().timestamp_millis();
+
+    for mut record in store.pending()? {
+        let sent_ms = match (record.state, record.sent_ms) {
+            (CommandState::Sent, Some(s)) => s,
+            _ => continue,
+        };
+
+        if now_ms < sent_ms + record.timeout_secs as i64 * 1000 {
+            continue;
+        }
+
+        if record.attempts > record.retries {
+            finish(
+                broker_sender,
+                store,
+                record,
+                CommandState::TimedOut,
+                None,
+                now_ms,
+            )
+            .await?;
+        } else if registry.is_online(&record.uid) {
+            send(broker_sender, store, record, now_ms).await?;
+        } else {
+            // Sent again once the device is back
+            record.state = CommandState::Queued;
+            store.save(&record)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Remove commands that finished more than `keep_secs` ago
+fn prune(store: &Store, settings: &settings::Commands) -> Result<(), Error> {
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let removed = store.prune(now_ms - settings.keep_secs as i64 * 1000)?;
+
+    if removed > 0 {
+        log::debug!("Removed {} finished commands.", removed);
+    }
+
+    Ok(())
+}
+
+/// Handle commands using a database that's already open, usually the device registry's.
+/// `registry` is used to tell if a device is online, so it should be the one the device runner
+/// keeps up to date.
+pub async fn run_with_db(
+    sled_db: sled::Db,
+    registry: Registry,
+    settings: &settings::Commands,
+    broker_sender: Sender<Event>,
+) {
+    // Register this task
+    let reciever = broker::register(RUNNER_NAME, &broker_sender).await.unwrap();
+
+    let store = Store::open(&sled_db).expect("Unable to create command db tree.");
+
+    let mut interval = tokio::time::interval(Duration::from_millis(CHECK_INTERVAL_MS));
+    let mut prune_interval = tokio::time::interval(Duration::from_millis(PRUNE_INTERVAL_MS));
+
+    loop {
+        let res = tokio::select! {
+            event = reciever.recv_async() => match event {
+                Ok(event) => process_event(&broker_sender, &store, &registry, settings, event).await,
+                Err(_) => break,
+            },
+            _ = interval.tick() => check(&broker_sender, &store, &registry).await,
+            _ = prune_interval.tick() => prune(&store, settings),
+        };
+
+        if let Err(e) = res {
+            log::error!("Command error. Err: {}", e);
+        }
+    }
+
+    // Broker has stopped
+    if let Err(e) = sled_db.flush_async().await {
+        log::error!("Unable to flush command db. Err: {}", e);
+    }
+}
@@ -0,0 +1,146 @@
+// Serializing events
+use serde::{Deserialize, Serialize};
+
+// Local lib related
+use crate::{settings, DeadLetterEntry, Error, Event};
+
+/// Longest event description included in a `DeadLetterEntry`
+const DESCRIPTION_LEN: usize = 128;
+
+/// What's stored for each undeliverable event
+#[derive(Debug, Serialize, Deserialize)]
+struct Letter {
+    timestamp_ms: i64,
+    runner: String,
+    reason: String,
+    event: Event,
+}
+
+/// Bounded, on disk store for events the broker couldn't deliver
+pub struct DeadLetters {
+    db: sled::Db,
+    tree: sled::Tree,
+    capacity: usize,
+}
+
+impl DeadLetters {
+    pub fn open(settings: &settings::DeadLetter) -> Result<DeadLetters, Error> {
+        let db = sled::open(&settings.path)?;
+        let tree = db.open_tree("dead_letter")?;
+
+        Ok(DeadLetters {
+            db,
+            tree,
+            capacity: settings.capacity,
+        })
+    }
+
+    /// Write anything buffered to disk
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.db.flush_async().await?;
+
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Add an event. Removes the oldest entries if full.
+    pub fn push(&self, runner: &str, reason: &str, event: &Event) -> Result<(), Error> {
+        let letter = Letter {
+            timestamp_ms: chrono::Utc::now().timestamp_millis(),
+            runner: runner.to_string(),
+            reason: reason.to_string(),
+            event: event.clone(),
+        };
+
+        let id = self.db.generate_id()?;
+        self.tree
+            .insert(id.to_be_bytes(), serde_cbor::to_vec(&letter)?)?;
+
+        while self.tree.len() > self.capacity {
+            if self.tree.pop_min()?.is_none() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Summary of every entry, oldest first
+    pub fn list(&self) -> Result<Vec<DeadLetterEntry>, Error> {
+        let mut entries = Vec::new();
+
+        for item in self.tree.iter() {
+            let (key, value) = item?;
+            let letter: Letter = serde_cbor::from_slice(&value)?;
+
+            let mut event = format!("{:?}", letter.event);
+            if event.len() > DESCRIPTION_LEN {
+                let mut end = DESCRIPTION_LEN;
+                while !event.is_char_boundary(end) {
+                    end -= 1;
+                }
+
+                event.truncate(end);
+                event.push_str("...");
+            }
+
+            entries.push(DeadLetterEntry {
+                id: get_id(&key),
+                timestamp_ms: letter.timestamp_ms,
+                runner: letter.runner,
+                reason: letter.reason,
+                event,
+            });
+        }
+
+        Ok(entries)
+    }
+
+    /// Remove and return entries (all of them if `id` is `None`) as (runner, event), oldest first
+    pub fn take(&self, id: Option<u64>) -> Result<Vec<(String, Event)>, Error> {
+        let mut events = Vec::new();
+
+        for key in self.keys(id)? {
+            if let Some(value) = self.tree.remove(key)? {
+                let letter: Letter = serde_cbor::from_slice(&value)?;
+                events.push((letter.runner, letter.event));
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Remove entries (all of them if `id` is `None`). Returns the number removed.
+    pub fn purge(&self, id: Option<u64>) -> Result<usize, Error> {
+        let mut count = 0;
+
+        for key in self.keys(id)? {
+            if self.tree.remove(key)?.is_some() {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    fn keys(&self, id: Option<u64>) -> Result<Vec<sled::IVec>, Error> {
+        match id {
+            Some(id) => Ok(vec![id.to_be_bytes().as_ref().into()]),
+            None => Ok(self.tree.iter().keys().collect::<Result<Vec<_>, _>>()?),
+        }
+    }
+}
+
+fn get_id(key: &[u8]) -> u64 {
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&key[..8]);
+
+    u64::from_be_bytes(id)
+}
@@ -0,0 +1,480 @@
+// System related
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Async Related
+use flume::Sender;
+
+// Local lib related
+use crate::{acknowledge, broker, metrics, settings, Error, Event};
+use pyrinas_shared::{
+    DeviceInfo, DeviceListResponse, DeviceState, DeviceUpdate, ManagmentDataType,
+};
+
+/// Name the broker routes device events to
+pub const RUNNER_NAME: &str = "device";
+
+/// Shortest time between checks for devices that have gone quiet
+const MIN_CHECK_INTERVAL_MS: u64 = 1000;
+
+/// Time between saving when online devices were last seen
+const SAVE_INTERVAL_MS: u64 = 60 * 1000;
+
+/// When an online device was last seen
+#[derive(Debug, Clone, Copy)]
+struct Seen {
+    last_seen_ms: i64,
+    /// `last_seen_ms` has been written to the registry
+    saved: bool,
+}
+
+/// Persistent record of every device the server has heard from or an admin has added.
+///
+/// Which devices are online is only kept in memory and shared by every clone. Presence from
+/// before a restart can't be trusted, so every device starts out offline.
+#[derive(Debug, Clone)]
+pub struct Registry {
+    tree: sled::Tree,
+    online: Arc<Mutex<HashMap<String, Seen>>>,
+}
+
+impl Registry {
+    pub fn open(db: &sled::Db) -> Result<Registry, Error> {
+        Ok(Registry {
+            tree: db.open_tree("devices")?,
+            online: Default::default(),
+        })
+    }
+
+    /// Device as it was last saved
+    fn load(&self, uid: &str) -> Result<Option<DeviceInfo>, Error> {
+        match self.tree.get(uid)? {
+            Some(v) => Ok(Some(serde_cbor::from_slice(&v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Fill in presence, which isn't saved
+    fn with_presence(&self, mut info: DeviceInfo) -> DeviceInfo {
+        let online = self.online.lock().unwrap();
+
+        info.online = match online.get(&info.uid) {
+            Some(seen) => {
+                info.last_seen_ms = seen.last_seen_ms;
+                true
+            }
+            None => false,
+        };
+
+        info
+    }
+
+    pub fn get(&self, uid: &str) -> Result<Option<DeviceInfo>, Error> {
+        Ok(self.load(uid)?.map(|info| self.with_presence(info)))
+    }
+
+    /// Whether the device is online. Doesn't touch the database.
+    pub fn is_online(&self, uid: &str) -> bool {
+        self.online.lock().unwrap().contains_key(uid)
+    }
+
+    fn save(&self, info: &DeviceInfo) -> Result<(), Error> {
+        self.tree
+            .insert(info.uid.as_bytes(), serde_cbor::to_vec(info)?)?;
+
+        Ok(())
+    }
+
+    /// Every device, sorted by uid
+    pub fn list(&self) -> Result<Vec<DeviceInfo>, Error> {
+        self.tree
+            .iter()
+            .values()
+            .map(|v| Ok(self.with_presence(serde_cbor::from_slice(&v?)?)))
+            .collect()
+    }
+
+    /// Add a device that isn't in the registry yet. Unset fields are left empty.
+    pub fn add(&self, update: &DeviceUpdate, now_ms: i64) -> Result<DeviceInfo, Error> {
+        if self.get(&update.uid)?.is_some() {
+            return Err(Error::CustomError(format!(
+                "{} already exists!",
+                update.uid
+            )));
+        }
+
+        let mut info = DeviceInfo {
+            uid: update.uid.clone(),
+            created_ms: now_ms,
+            changed_ms: now_ms,
+            ..Default::default()
+        };
+
+        apply(&mut info, update);
+        self.save(&info)?;
+
+        Ok(info)
+    }
+
+    /// Change the fields that are set in `update`
+    pub fn update(&self, update: &DeviceUpdate) -> Result<DeviceInfo, Error> {
+        let mut info = self
+            .load(&update.uid)?
+            .ok_or_else(|| Error::CustomError(format!("{} not found!", update.uid)))?;
+
+        apply(&mut info, update);
+        self.save(&info)?;
+
+        Ok(self.with_presence(info))
+    }
+
+    pub fn remove(&self, uid: &str) -> Result<(), Error> {
+        if self.tree.remove(uid)?.is_none() {
+            return Err(Error::CustomError(format!("{} not found!", uid)));
+        }
+
+        self.online.lock().unwrap().remove(uid);
+
+        Ok(())
+    }
+
+    /// Record a message from the device. Returns true if it was offline.
+    ///
+    /// Only the device coming online is saved straight away. After that `last_seen_ms` is kept in
+    /// memory until `save_seen`. Devices that were only provisioned become active.
+    pub fn seen(&self, uid: &str, now_ms: i64) -> Result<bool, Error> {
+        if let Some(seen) = self.online.lock().unwrap().get_mut(uid) {
+            seen.last_seen_ms = now_ms;
+            seen.saved = false;
+            return Ok(false);
+        }
+
+        let mut info = self.load(uid)?.unwrap_or_else(|| DeviceInfo {
+            uid: uid.to_string(),
+            created_ms: now_ms,
+            ..Default::default()
+        });
+
+        info.last_seen_ms = now_ms;
+        info.changed_ms = now_ms;
+        info.first_seen_ms.get_or_insert(now_ms);
+
+        if info.state == DeviceState::Provisioned {
+            info.state = DeviceState::Active;
+        }
+
+        self.save(&info)?;
+
+        let seen = Seen {
+            last_seen_ms: now_ms,
+            saved: true,
+        };
+        self.online.lock().unwrap().insert(uid.to_string(), seen);
+
+        Ok(true)
+    }
+
+    /// Set whether the device is connected. Returns true if that changed.
+    pub fn set_online(&self, uid: &str, online: bool, now_ms: i64) -> Result<bool, Error> {
+        if online {
+            return self.seen(uid, now_ms);
+        }
+
+        let seen = match self.online.lock().unwrap().remove(uid) {
+            Some(s) => s,
+            None => return Ok(false),
+        };
+
+        if let Some(mut info) = self.load(uid)? {
+            info.last_seen_ms = seen.last_seen_ms;
+            info.changed_ms = now_ms;
+            self.save(&info)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Number of devices that are online
+    pub fn online(&self) -> usize {
+        self.online.lock().unwrap().len()
+    }
+
+    /// Mark devices that haven't been seen since `before_ms` offline. Returns their uids. Only
+    /// online devices are checked.
+    pub fn expire(&self, before_ms: i64, now_ms: i64) -> Result<Vec<String>, Error> {
+        let quiet: Vec<String> = self
+            .online
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, seen)| seen.last_seen_ms < before_ms)
+            .map(|(uid, _)| uid.clone())
+            .collect();
+
+        let mut expired = Vec::new();
+
+        for uid in quiet {
+            if self.set_online(&uid, false, now_ms)? {
+                expired.push(uid);
+            }
+        }
+
+        Ok(expired)
+    }
+
+    /// Save when online devices were last seen. Returns how many were saved.
+    pub fn save_seen(&self) -> Result<usize, Error> {
+        let unsaved: Vec<(String, i64)> = self
+            .online
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, seen)| !seen.saved)
+            .map(|(uid, seen)| (uid.clone(), seen.last_seen_ms))
+            .collect();
+
+        for (uid, last_seen_ms) in &unsaved {
+            if let Some(mut info) = self.load(uid)? {
+                info.last_seen_ms = *last_seen_ms;
+                self.save(&info)?;
+            }
+
+            // Unless it's been seen again since
+            if let Some(seen) = self.online.lock().unwrap().get_mut(uid) {
+                if seen.last_seen_ms == *last_seen_ms {
+                    seen.saved = true;
+                }
+            }
+        }
+
+        Ok(unsaved.len())
+    }
+
+    /// Save when devices were last seen and flush the database
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.save_seen()?;
+        self.tree.flush_async().await?;
+
+        Ok(())
+    }
+}
+
+/// Copy the fields that are set
+fn apply(info: &mut DeviceInfo, update: &DeviceUpdate) {
+    if let Some(device_type) = update.device_type {
+        info.device_type = Some(device_type);
+    }
+
+    if let Some(board) = &update.board {
+        info.board = Some(board.clone());
+    }
+
+    if let Some(imei) = &update.imei {
+        info.imei = Some(imei.clone());
+    }
+
+    if let Some(iccid) = &update.iccid {
+        info.iccid = Some(iccid.clone());
+    }
+
+    if let Some(version) = &update.firmware_version {
+        info.firmware_version = Some(version.clone());
+    }
+
+    if !update.tags.is_empty() {
+        info.tags = update.tags.clone();
+    }
+
+    if let Some(state) = update.state {
+        info.state = state;
+    }
+}
+
+/// Devices online for `/metrics`
+fn report(registry: &Registry) {
+    metrics::set(metrics::DEVICES_ONLINE, &[], registry.online() as f64);
+}
+
+/// Let the rest of the server know a device came online or went offline
+async fn notify(broker_sender: &Sender<Event>, registry: &Registry, uid: &str, online: bool) {
+    report(registry);
+
+    let event = match online {
+        true => Event::DeviceOnline(uid.to_string()),
+        false => Event::DeviceOffline(uid.to_string()),
+    };
+
+    log::info!("{} is {}.", uid, if online { "online" } else { "offline" });
+
+    if let Err(e) = broker_sender.send_async(event).await {
+        log::error!("Unable to send presence. Err: {}", e);
+    }
+}
+
+async fn process_event(broker_sender: &Sender<Event>, registry: &Registry, event: Event) {
+    let now_ms = chrono::Utc::now().timestamp_millis();
+
+    match event {
+        Event::DeviceSeen(uid) => match registry.seen(&uid, now_ms) {
+            Ok(true) => notify(broker_sender, registry, &uid, true).await,
+            Ok(false) => (),
+            Err(e) => log::error!("Unable to update {}. Err: {}", uid, e),
+        },
+        Event::DeviceConnection { uid, online } => {
+            match registry.set_online(&uid, online, now_ms) {
+                Ok(true) => notify(broker_sender, registry, &uid, online).await,
+                Ok(false) => (),
+                Err(e) => log::error!("Unable to update {}. Err: {}", uid, e),
+            }
+        }
+        Event::DeviceListRequest(id) => {
+            let devices = match registry.list() {
+                Ok(d) => d,
+                Err(e) => {
+                    log::error!("Unable to get devices. Err: {}", e);
+                    return;
+                }
+            };
+
+            let event = Event::DeviceListResponse(id, DeviceListResponse { devices });
+
+            if let Err(e) = broker_sender.send_async(event).await {
+                log::error!("Unable to send device list. Err: {}", e);
+            }
+        }
+        Event::DeviceGetRequest(id, uid) => {
+            let info = match registry.get(&uid) {
+                Ok(Some(i)) => i,
+                Ok(None) => {
+                    let result = Err(Error::CustomError(format!("{} not found!", uid)));
+                    acknowledge(broker_sender, &id, ManagmentDataType::GetDevice, result).await;
+                    return;
+                }
+                Err(e) => {
+                    log::error!("Unable to get {}. Err: {}", uid, e);
+                    acknowledge(broker_sender, &id, ManagmentDataType::GetDevice, Err(e)).await;
+                    return;
+                }
+            };
+
+            if let Err(e) = broker_sender
+                .send_async(Event::DeviceGetResponse(id, Box::new(info)))
+                .await
+            {
+                log::error!("Unable to send device. Err: {}", e);
+            }
+        }
+        Event::DeviceAdd(id, update) => {
+            let result = registry.add(&update, now_ms).map(|info| {
+                log::info!("Added {} ({}).", info.uid, info.state);
+            });
+
+            if let Err(e) = &result {
+                log::warn!("Unable to add {}. Err: {}", update.uid, e);
+            }
+
+            acknowledge(broker_sender, &id, ManagmentDataType::AddDevice, result).await;
+        }
+        Event::DeviceUpdate(id, update) => {
+            let result = registry.update(&update).map(|info| {
+                log::info!("Updated {} ({}).", info.uid, info.state);
+            });
+
+            if let Err(e) = &result {
+                log::warn!("Unable to update {}. Err: {}", update.uid, e);
+            }
+
+            acknowledge(broker_sender, &id, ManagmentDataType::UpdateDevice, result).await;
+        }
+        Event::DeviceRemove(id, uid) => {
+            let result = registry.remove(&uid);
+
+            match &result {
+                Ok(_) => {
+                    log::info!("Removed {}.", uid);
+                    report(registry);
+                }
+                Err(e) => log::warn!("Unable to remove {}. Err: {}", uid, e),
+            }
+
+            acknowledge(broker_sender, &id, ManagmentDataType::RemoveDevice, result).await;
+        }
+        _ => (),
+    }
+}
+
+pub async fn run(settings: &settings::Devices, broker_sender: Sender<Event>) {
+    // Open the DB
+    let sled_db = sled::open(&settings.db_path).expect("Error opening sled db.");
+
+    run_with_db(sled_db, settings, broker_sender).await;
+}
+
+/// Same as `run` but uses a database that's already open.
+pub async fn run_with_db(
+    sled_db: sled::Db,
+    settings: &settings::Devices,
+    broker_sender: Sender<Event>,
+) {
+    let registry = Registry::open(&sled_db).expect("Unable to create device db tree.");
+
+    run_with_registry(registry, settings, broker_sender).await;
+}
+
+/// Same as `run` but shares a registry that's already open. Clones of it see the same devices
+/// online.
+///
+/// Presence isn't saved, so every device starts out offline. Connection changes may have been
+/// missed while the server was down, so devices come back online with their next message.
+pub async fn run_with_registry(
+    registry: Registry,
+    settings: &settings::Devices,
+    broker_sender: Sender<Event>,
+) {
+    report(&registry);
+
+    // Register this task
+    let reciever = broker::register(RUNNER_NAME, &broker_sender).await.unwrap();
+
+    // Only check for quiet devices if there's a timeout
+    let timeout_ms = settings.offline_timeout_secs.map(|t| t as i64 * 1000);
+    let interval_ms = std::cmp::max(
+        settings.offline_timeout_secs.unwrap_or(1) * 1000 / 4,
+        MIN_CHECK_INTERVAL_MS,
+    );
+    let mut interval = tokio::time::interval(Duration::from_millis(interval_ms));
+    let mut save_interval = tokio::time::interval(Duration::from_millis(SAVE_INTERVAL_MS));
+
+    loop {
+        tokio::select! {
+            event = reciever.recv_async() => match event {
+                Ok(event) => process_event(&broker_sender, &registry, event).await,
+                Err(_) => break,
+            },
+            _ = interval.tick(), if timeout_ms.is_some() => {
+                let now_ms = chrono::Utc::now().timestamp_millis();
+                let before_ms = now_ms - timeout_ms.unwrap_or_default();
+
+                match registry.expire(before_ms, now_ms) {
+                    Ok(expired) => {
+                        for uid in expired {
+                            notify(&broker_sender, &registry, &uid, false).await;
+                        }
+                    }
+                    Err(e) => log::error!("Unable to check for offline devices. Err: {}", e),
+                }
+            }
+            _ = save_interval.tick() => {
+                if let Err(e) = registry.save_seen() {
+                    log::error!("Unable to save when devices were last seen. Err: {}", e);
+                }
+            }
+        };
+    }
+
+    // Broker has stopped
+    if let Err(e) = registry.flush().await {
+        log::error!("Unable to flush device db. Err: {}", e);
+    }
+}
@@ -0,0 +1,73 @@
+// Serde
+use serde::{de::DeserializeOwned, Serialize};
+
+// Local lib related
+use crate::Error;
+
+/// How a device encodes its payloads. CBOR unless the topic ends with `/json`
+/// (i.e. `<uid>/tel/p/json`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Encoding {
+    #[default]
+    Cbor,
+    Json,
+}
+
+impl Encoding {
+    /// Trailing topic segment for this encoding
+    pub fn suffix(self) -> Option<&'static str> {
+        match self {
+            Encoding::Cbor => None,
+            Encoding::Json => Some("json"),
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(self, payload: &[u8]) -> Result<T, Error> {
+        match self {
+            Encoding::Cbor => Ok(serde_cbor::from_slice(payload)?),
+            Encoding::Json => Ok(serde_json::from_slice(payload)?),
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            Encoding::Cbor => Ok(serde_cbor::to_vec(value)?),
+            Encoding::Json => Ok(serde_json::to_vec(value)?),
+        }
+    }
+}
+
+/// Re-encode a payload without knowing its type. Used for application payloads, which are
+/// always CBOR inside the server.
+pub fn transcode(payload: &[u8], from: Encoding, to: Encoding) -> Result<Vec<u8>, Error> {
+    if from == to {
+        return Ok(payload.to_vec());
+    }
+
+    let value: serde_cbor::Value = from.decode(payload)?;
+    to.encode(&value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transcode_success() {
+        let json = br#"{"ok":true,"temp":21.5}"#;
+
+        let cbor = transcode(json, Encoding::Json, Encoding::Cbor).unwrap();
+        let value: serde_cbor::Value = serde_cbor::from_slice(&cbor).unwrap();
+        assert!(matches!(value, serde_cbor::Value::Map(_)));
+
+        assert_eq!(
+            transcode(&cbor, Encoding::Cbor, Encoding::Json).unwrap(),
+            json.to_vec()
+        );
+    }
+
+    #[test]
+    fn transcode_failure() {
+        assert!(transcode(b"{not json", Encoding::Json, Encoding::Cbor).is_err());
+    }
+}
@@ -1,31 +1,1050 @@
+// System related
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+
 // Config related
-use crate::{settings, Event};
+use crate::{broker, metrics, settings, Error, Event};
+use pyrinas_shared::{
+    CorrelationId, InfluxQueryResponse, InfluxSeries, ManagmentDataType, SeriesPoint,
+};
 
 // async Related
-use flume::{unbounded, Sender};
+use flume::Sender;
 
 // Influx Related
-use influxdb::Client;
+use influxdb::{Client, Query, ReadQuery, Timestamp, WriteQuery};
 
-pub async fn run(settings: &settings::Influx, broker_sender: Sender<Event>) {
-    // Get the sender/reciever associated with this particular task
-    let (sender, reciever) = unbounded::<Event>();
+// Serde Related
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
 
-    // Register this task
-    broker_sender
-        .send_async(Event::NewRunner {
-            name: "influx".to_string(),
-            sender: sender.clone(),
-        })
-        .await
-        .unwrap();
+// Http Related
+use reqwest::{header, StatusCode};
+
+// Time Related
+use chrono::{SecondsFormat, TimeZone, Utc};
+
+/// Name the broker routes Influx events to
+pub const RUNNER_NAME: &str = "influx";
+
+/// Most spooled batches written per flush. The rest wait for the next one so events aren't held
+/// up while a backlog is written.
+const DRAIN_BATCHES: usize = 10;
+
+/// Convert a backend neutral point. Values without an Influx type are written as JSON text.
+pub fn to_query(point: &SeriesPoint) -> WriteQuery {
+    let time = Timestamp::Milliseconds(point.time_ms.max(0) as u128);
+    let mut query = WriteQuery::new(time, point.measurement.as_str());
+
+    for (name, value) in &point.tags {
+        query = query.add_tag(name, value.as_str());
+    }
+
+    for (name, value) in &point.fields {
+        query = match value {
+            Value::Bool(b) => query.add_field(name, *b),
+            Value::Integer(i) => match i64::try_from(*i) {
+                Ok(i) => query.add_field(name, i),
+                Err(_) => query.add_field(name, *i as f64),
+            },
+            Value::Float(f) => query.add_field(name, *f),
+            Value::Text(t) => query.add_field(name, t.as_str()),
+            v => query.add_field(name, serde_json::to_string(v).unwrap_or_default()),
+        };
+    }
+
+    query
+}
 
+/// Create a client from the `[influx]` settings
+pub fn client(settings: &settings::Influx) -> Client {
     // Set up the URL
-    let url = format!("http://{}:{}", settings.host, settings.port);
+    let url = format!("{}://{}:{}", scheme(settings), settings.host, settings.port);
 
     // Create the client
-    let client = Client::new(url, settings.database.clone())
-        .with_auth(settings.user.clone(), settings.password.clone());
+    Client::new(url, settings.database.clone())
+        .with_auth(settings.user.clone(), settings.password.clone())
+}
+
+fn scheme(settings: &settings::Influx) -> &'static str {
+    match settings.tls {
+        true => "https",
+        false => "http",
+    }
+}
+
+/// Double quoted with `"` and `\` escaped
+fn quote(measurement: &str) -> String {
+    format!(
+        "\"{}\"",
+        measurement.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
+
+/// InfluxQL that removes points from `measurement` older than `before_ms`
+fn delete_query(measurement: &str, before_ms: i64) -> ReadQuery {
+    ReadQuery::new(format!(
+        "DELETE FROM {} WHERE time < {}ms",
+        quote(measurement),
+        before_ms
+    ))
+}
+
+/// Whether every statement in an InfluxQL query only reads. That's `SHOW` and `SELECT` without
+/// `INTO`. Quoted strings, identifiers and comments are skipped.
+///
+/// Queries with a `/` outside of those aren't read only either. A `/regex/` can't be told apart
+/// from division without parsing the query, and quotes or `;` inside one would be misread.
+pub fn is_read_only(query: &str) -> bool {
+    let mut statements: Vec<Vec<String>> = vec![Vec::new()];
+    let mut word = String::new();
+    let mut chars = query.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c.is_alphanumeric() || c == '_' {
+            word.push(c);
+            continue;
+        }
+
+        if !word.is_empty() {
+            let statement = statements.last_mut().unwrap();
+            statement.push(std::mem::take(&mut word).to_lowercase());
+        }
+
+        match c {
+            '\'' | '"' => {
+                let mut escaped = false;
+
+                for q in chars.by_ref() {
+                    match q {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        _ if q == c => break,
+                        _ => (),
+                    }
+                }
+            }
+            '-' if chars.peek() == Some(&'-') => {
+                for q in chars.by_ref() {
+                    if q == '\n' {
+                        break;
+                    }
+                }
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+
+                for q in chars.by_ref() {
+                    if last == '*' && q == '/' {
+                        break;
+                    }
+                    last = q;
+                }
+            }
+            '/' => return false,
+            ';' => statements.push(Vec::new()),
+            _ => (),
+        }
+    }
+
+    if !word.is_empty() {
+        statements.last_mut().unwrap().push(word.to_lowercase());
+    }
+
+    let statements: Vec<Vec<String>> = statements.into_iter().filter(|s| !s.is_empty()).collect();
+
+    !statements.is_empty()
+        && statements.iter().all(|words| {
+            matches!(words[0].as_str(), "select" | "show") && !words.iter().any(|w| w == "into")
+        })
+}
+
+/// Why a batch wasn't written
+#[derive(Debug)]
+pub enum WriteError {
+    /// Influx couldn't be reached or is busy. Worth trying again.
+    Retry(String),
+    /// Influx refused the points. Sending them again won't help.
+    Rejected(String),
+}
+
+impl std::fmt::Display for WriteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WriteError::Retry(e) => write!(f, "{}", e),
+            WriteError::Rejected(e) => write!(f, "rejected: {}", e),
+        }
+    }
+}
+
+/// Where and how points are written
+#[derive(Debug, Clone)]
+enum Target {
+    /// InfluxDB 1.x `/write` with an optional user and password
+    V1 {
+        database: String,
+        credentials: Option<(String, String)>,
+    },
+    /// InfluxDB 2.x `/api/v2/write` with an API token
+    V2 {
+        org: String,
+        bucket: String,
+        token: String,
+    },
+}
+
+/// Writes batches of line protocol to InfluxDB 1.x or 2.x and runs InfluxQL queries
+#[derive(Debug, Clone)]
+pub struct Writer {
+    http: reqwest::Client,
+    url: String,
+    query_url: String,
+    delete_url: String,
+    target: Target,
+}
+
+impl Writer {
+    pub fn new(settings: &settings::Influx) -> Result<Writer, Error> {
+        let base = format!("{}://{}:{}", scheme(settings), settings.host, settings.port);
+
+        let (url, target) = match &settings.token {
+            Some(token) => {
+                let (org, bucket) = match (&settings.org, &settings.bucket) {
+                    (Some(org), Some(bucket)) => (org.clone(), bucket.clone()),
+                    _ => {
+                        return Err(Error::CustomError(
+                            "Influx 2.x needs an org and bucket along with the token!".to_string(),
+                        ))
+                    }
+                };
+
+                (
+                    format!("{}/api/v2/write", base),
+                    Target::V2 {
+                        org,
+                        bucket,
+                        token: token.clone(),
+                    },
+                )
+            }
+            None => {
+                let credentials = match settings.user.is_empty() {
+                    true => None,
+                    false => Some((settings.user.clone(), settings.password.clone())),
+                };
+
+                (
+                    format!("{}/write", base),
+                    Target::V1 {
+                        database: settings.database.clone(),
+                        credentials,
+                    },
+                )
+            }
+        };
+
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_millis(settings.batch.timeout_ms))
+            .build()
+            .map_err(|e| Error::CustomError(format!("Unable to create Influx client: {}", e)))?;
+
+        // 2.x takes InfluxQL through its 1.x compatible API
+        let query_url = format!("{}/query", base);
+
+        // Only used by 2.x. It doesn't take InfluxQL `DELETE`.
+        let delete_url = format!("{}/api/v2/delete", base);
+
+        Ok(Writer {
+            http,
+            url,
+            query_url,
+            delete_url,
+            target,
+        })
+    }
+
+    /// Write `body`, lines of line protocol that all use `precision`
+    pub async fn write(&self, precision: &str, body: String) -> Result<(), WriteError> {
+        let request = match &self.target {
+            Target::V1 {
+                database,
+                credentials,
+            } => {
+                let request = self
+                    .http
+                    .post(&self.url)
+                    .query(&[("db", database.as_str()), ("precision", precision)]);
+
+                match credentials {
+                    Some((user, password)) => request.basic_auth(user, Some(password)),
+                    None => request,
+                }
+            }
+            Target::V2 { org, bucket, token } => {
+                // 2.x only takes these
+                let precision = match precision {
+                    "ns" | "ms" | "s" => precision,
+                    "u" => "us",
+                    p => {
+                        return Err(WriteError::Rejected(format!(
+                            "Precision {} isn't supported by Influx 2.x",
+                            p
+                        )))
+                    }
+                };
+
+                self.http
+                    .post(&self.url)
+                    .query(&[
+                        ("org", org.as_str()),
+                        ("bucket", bucket.as_str()),
+                        ("precision", precision),
+                    ])
+                    .header(header::AUTHORIZATION, format!("Token {}", token))
+            }
+        };
+
+        let response = request
+            .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| WriteError::Retry(e.to_string()))?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+
+        let reason = format!(
+            "{} {}",
+            status,
+            response.text().await.unwrap_or_default().trim()
+        );
+
+        match status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            true => Err(WriteError::Retry(reason)),
+            false => Err(WriteError::Rejected(reason)),
+        }
+    }
+
+    /// Run an InfluxQL query. With 2.x, `bucket` is used as the database.
+    pub async fn query(&self, query: &ReadQuery) -> Result<InfluxQueryResponse, Error> {
+        let q = query
+            .build()
+            .map_err(|e| Error::CustomError(e.to_string()))?
+            .get();
+
+        let request = match &self.target {
+            Target::V1 {
+                database,
+                credentials,
+            } => {
+                let request = self
+                    .http
+                    .post(&self.query_url)
+                    .query(&[("db", database.as_str()), ("q", q.as_str())]);
+
+                match credentials {
+                    Some((user, password)) => request.basic_auth(user, Some(password)),
+                    None => request,
+                }
+            }
+            Target::V2 { bucket, token, .. } => self
+                .http
+                .post(&self.query_url)
+                .query(&[("db", bucket.as_str()), ("q", q.as_str())])
+                .header(header::AUTHORIZATION, format!("Token {}", token)),
+        };
+
+        let response = request
+            .send()
+            .await
+            .map_err(|e| Error::CustomError(format!("Unable to query Influx: {}", e)))?;
+
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| Error::CustomError(format!("Unable to read Influx response: {}", e)))?;
+
+        if !status.is_success() {
+            return Err(Error::CustomError(format!(
+                "Influx query failed: {} {}",
+                status,
+                body.trim()
+            )));
+        }
+
+        parse_response(&body)
+    }
+
+    /// Remove points from `measurement` older than `before_ms`
+    pub async fn delete(&self, measurement: &str, before_ms: i64) -> Result<(), Error> {
+        let (org, bucket, token) = match &self.target {
+            Target::V1 { .. } => {
+                return self
+                    .query(&delete_query(measurement, before_ms))
+                    .await
+                    .map(|_| ())
+            }
+            Target::V2 { org, bucket, token } => (org, bucket, token),
+        };
+
+        let stop = Utc
+            .timestamp_millis_opt(before_ms)
+            .single()
+            .ok_or_else(|| Error::CustomError(format!("Invalid time: {}", before_ms)))?;
+
+        let body = serde_json::json!({
+            "start": "1970-01-01T00:00:00Z",
+            "stop": stop.to_rfc3339_opts(SecondsFormat::Millis, true),
+            "predicate": format!("_measurement={}", quote(measurement)),
+        });
+
+        let response = self
+            .http
+            .post(&self.delete_url)
+            .query(&[("org", org.as_str()), ("bucket", bucket.as_str())])
+            .header(header::AUTHORIZATION, format!("Token {}", token))
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| Error::CustomError(format!("Unable to reach Influx: {}", e)))?;
+
+        let status = response.status();
+
+        match status.is_success() {
+            true => Ok(()),
+            false => Err(Error::CustomError(format!(
+                "Influx delete failed: {} {}",
+                status,
+                response.text().await.unwrap_or_default().trim()
+            ))),
+        }
+    }
+}
+
+/// Body of an InfluxQL query response
+#[derive(Deserialize)]
+struct QueryResults {
+    #[serde(default)]
+    results: Vec<StatementResult>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct StatementResult {
+    #[serde(default)]
+    series: Vec<JsonSeries>,
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JsonSeries {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+    #[serde(default)]
+    columns: Vec<String>,
+    #[serde(default)]
+    values: Vec<Vec<serde_json::Value>>,
+}
+
+/// Every series from every statement in a query response
+pub(crate) fn parse_response(body: &str) -> Result<InfluxQueryResponse, Error> {
+    let results: QueryResults = serde_json::from_str(body)?;
+
+    if let Some(e) = results.error {
+        return Err(Error::CustomError(e));
+    }
+
+    let mut series = Vec::new();
+
+    for result in results.results {
+        if let Some(e) = result.error {
+            return Err(Error::CustomError(e));
+        }
+
+        for s in result.series {
+            let mut values = Vec::with_capacity(s.values.len());
+            for row in s.values {
+                values.push(
+                    row.into_iter()
+                        .map(serde_cbor::value::to_value)
+                        .collect::<Result<Vec<Value>, _>>()?,
+                );
+            }
+
+            series.push(InfluxSeries {
+                name: s.name,
+                tags: s.tags,
+                columns: s.columns,
+                values,
+            });
+        }
+    }
+
+    Ok(InfluxQueryResponse { series })
+}
+
+/// Send the result of a query to the runner that asked for it
+pub(crate) async fn respond(
+    broker_sender: &Sender<Event>,
+    id: Option<CorrelationId>,
+    reply_to: String,
+    result: Result<InfluxQueryResponse, Error>,
+) {
+    if let Err(e) = &result {
+        log::warn!("Influx query for {} failed. Err: {}", reply_to, e);
+    }
+
+    let event = Event::InfluxDataResponse {
+        id,
+        reply_to,
+        result: result.map(Box::new).map_err(|e| e.to_string()),
+    };
+
+    if let Err(e) = broker_sender.send_async(event).await {
+        log::error!("Unable to send query response. Err: {}", e);
+    }
+}
+
+/// Points waiting to be written. Grouped by precision since each write only takes one.
+#[derive(Debug, Default)]
+pub struct Batch {
+    lines: BTreeMap<String, Vec<String>>,
+    len: usize,
+}
+
+impl Batch {
+    pub fn push(&mut self, query: &WriteQuery) -> Result<(), Error> {
+        let line = query
+            .build()
+            .map_err(|e| Error::CustomError(e.to_string()))?
+            .get();
+
+        self.push_line(&query.get_precision(), line);
+
+        Ok(())
+    }
+
+    pub fn push_line(&mut self, precision: &str, line: String) {
+        self.lines
+            .entry(precision.to_string())
+            .or_default()
+            .push(line);
+        self.len += 1;
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Remove points from `measurement` older than `before_ms`. Returns how many.
+    pub fn expire(&mut self, measurement: &str, before_ms: i64) -> usize {
+        let before = self.len;
+
+        for (precision, lines) in self.lines.iter_mut() {
+            lines.retain(|line| !is_expired(precision, line, measurement, before_ms));
+        }
+
+        self.lines.retain(|_, lines| !lines.is_empty());
+        self.len = self.lines.values().map(Vec::len).sum();
+
+        before - self.len
+    }
+
+    /// Empty the batch. Returns each precision with its lines joined into a request body and how
+    /// many points it holds.
+    pub fn take(&mut self) -> Vec<(String, String, usize)> {
+        self.len = 0;
+
+        std::mem::take(&mut self.lines)
+            .into_iter()
+            .map(|(precision, lines)| {
+                let count = lines.len();
+                (precision, lines.join("\n"), count)
+            })
+            .collect()
+    }
+}
+
+/// Whether a line of line protocol is from `measurement` and older than `before_ms`. Lines
+/// without a timestamp are stamped by Influx when they're written, so they're never expired.
+fn is_expired(precision: &str, line: &str, measurement: &str, before_ms: i64) -> bool {
+    let line = match parse_line(line) {
+        Ok(l) => l,
+        Err(_) => return false,
+    };
+
+    let time = match line.timestamp {
+        Some(t) if line.measurement == measurement => t,
+        _ => return false,
+    };
+
+    let time_ms = match precision {
+        "ns" => time / 1_000_000,
+        "u" => time / 1000,
+        "ms" => time,
+        "s" => time * 1000,
+        "m" => time * 60 * 1000,
+        "h" => time * 60 * 60 * 1000,
+        _ => return false,
+    };
+
+    time_ms < before_ms.max(0) as u128
+}
+
+/// Delay before retry number `attempt` (starting at 0)
+pub fn backoff(settings: &settings::InfluxBatch, attempt: u32) -> Duration {
+    let delay = settings
+        .initial_backoff_ms
+        .saturating_mul(1u64.checked_shl(attempt).unwrap_or(u64::MAX));
+
+    Duration::from_millis(std::cmp::min(delay, settings.max_backoff_ms))
+}
+
+/// A batch of line protocol kept in the spool
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SpoolEntry {
+    pub precision: String,
+    pub body: String,
+    /// Number of points in `body`
+    pub points: usize,
+}
+
+#[derive(Debug, Default)]
+struct SpoolCounts {
+    points: AtomicUsize,
+    batches: AtomicUsize,
+    dropped: AtomicU64,
+}
+
+/// Depth of the spool. Cheap to clone and read from other tasks.
+#[derive(Debug, Clone, Default)]
+pub struct SpoolMetrics {
+    counts: Arc<SpoolCounts>,
+}
+
+impl SpoolMetrics {
+    /// Points waiting to be written
+    pub fn points(&self) -> usize {
+        self.counts.points.load(Ordering::Relaxed)
+    }
+
+    /// Batches waiting to be written
+    pub fn batches(&self) -> usize {
+        self.counts.batches.load(Ordering::Relaxed)
+    }
+
+    /// Points dropped to stay under `influx.spool.max_points` since the server started
+    pub fn dropped(&self) -> u64 {
+        self.counts.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/// On-disk queue of batches waiting to be written to Influx, oldest first
+#[derive(Debug, Clone)]
+pub struct Spool {
+    db: sled::Db,
+    tree: sled::Tree,
+    max_points: usize,
+    metrics: SpoolMetrics,
+}
+
+impl Spool {
+    /// Open the spool. Anything left from the last run is written first.
+    pub fn open(db: &sled::Db, max_points: usize) -> Result<Spool, Error> {
+        let tree = db.open_tree("influx-spool")?;
+        let metrics = SpoolMetrics::default();
+
+        for value in tree.iter().values() {
+            let entry: SpoolEntry = serde_cbor::from_slice(&value?)?;
+            metrics
+                .counts
+                .points
+                .fetch_add(entry.points, Ordering::Relaxed);
+            metrics.counts.batches.fetch_add(1, Ordering::Relaxed);
+        }
+
+        Ok(Spool {
+            db: db.clone(),
+            tree,
+            max_points,
+            metrics,
+        })
+    }
+
+    pub fn metrics(&self) -> SpoolMetrics {
+        self.metrics.clone()
+    }
+
+    /// Append a batch to the end. The oldest batches are dropped if that goes over `max_points`.
+    pub fn push(&self, entry: &SpoolEntry) -> Result<(), Error> {
+        let id = self.db.generate_id()?;
+        self.tree
+            .insert(id.to_be_bytes(), serde_cbor::to_vec(entry)?)?;
+
+        let counts = &self.metrics.counts;
+        counts.points.fetch_add(entry.points, Ordering::Relaxed);
+        counts.batches.fetch_add(1, Ordering::Relaxed);
+
+        while self.metrics.points() > self.max_points {
+            match self.pop()? {
+                Some(entry) => {
+                    counts
+                        .dropped
+                        .fetch_add(entry.points as u64, Ordering::Relaxed);
+                    log::warn!("Influx spool is full. Dropped {} points.", entry.points);
+                }
+                None => break,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The oldest batch
+    pub fn first(&self) -> Result<Option<SpoolEntry>, Error> {
+        match self.tree.first()? {
+            Some((_, value)) => Ok(Some(serde_cbor::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the oldest batch once it's been written
+    pub fn pop(&self) -> Result<Option<SpoolEntry>, Error> {
+        let entry = match self.tree.pop_min()? {
+            Some((_, value)) => serde_cbor::from_slice::<SpoolEntry>(&value)?,
+            None => return Ok(None),
+        };
+
+        let counts = &self.metrics.counts;
+        counts.points.fetch_sub(entry.points, Ordering::Relaxed);
+        counts.batches.fetch_sub(1, Ordering::Relaxed);
+
+        Ok(Some(entry))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    /// Remove points from `measurement` older than `before_ms`. Returns how many.
+    pub fn expire(&self, measurement: &str, before_ms: i64) -> Result<usize, Error> {
+        let counts = &self.metrics.counts;
+        let mut removed = 0;
+
+        for item in self.tree.iter() {
+            let (key, value) = item?;
+            let mut entry: SpoolEntry = serde_cbor::from_slice(&value)?;
+
+            let lines: Vec<&str> = split_unescaped(&entry.body, '\n', true)
+                .into_iter()
+                .filter(|line| !is_expired(&entry.precision, line, measurement, before_ms))
+                .collect();
+
+            let expired = entry.points.saturating_sub(lines.len());
+            if expired == 0 {
+                continue;
+            }
+
+            if lines.is_empty() {
+                self.tree.remove(key)?;
+                counts.batches.fetch_sub(1, Ordering::Relaxed);
+            } else {
+                entry.points = lines.len();
+                entry.body = lines.join("\n");
+                self.tree.insert(key, serde_cbor::to_vec(&entry)?)?;
+            }
+
+            counts.points.fetch_sub(expired, Ordering::Relaxed);
+            removed += expired;
+        }
+
+        Ok(removed)
+    }
+
+    pub async fn flush(&self) -> Result<(), Error> {
+        self.db.flush_async().await?;
+        Ok(())
+    }
+}
+
+/// Moves batches from the spool to Influx. Backs off while Influx can't be reached.
+struct Flusher {
+    writer: Writer,
+    spool: Spool,
+    settings: settings::InfluxBatch,
+    /// Failed attempts in a row
+    attempt: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Flusher {
+    /// Add a point to `batch`. Written once the batch is full.
+    async fn save(&mut self, batch: &mut Batch, query: &WriteQuery) {
+        if let Err(e) = batch.push(query) {
+            log::error!("Unable to build query. Err: {}", e);
+        }
+
+        if batch.len() >= self.settings.max_points {
+            self.flush(batch).await;
+        }
+    }
+
+    /// Write everything in `batch`. Batches are only spooled if Influx can't take them now, or
+    /// older ones are still spooled.
+    async fn flush(&mut self, batch: &mut Batch) {
+        if !self.backing_off() {
+            if let Err(e) = self.drain().await {
+                log::error!("Unable to read Influx spool. Err: {}", e);
+            }
+        }
+
+        for (precision, body, points) in batch.take() {
+            let entry = SpoolEntry {
+                precision,
+                body,
+                points,
+            };
+
+            // Keep them in order
+            if self.backing_off() || !self.spool.is_empty() || !self.write(&entry).await {
+                if let Err(e) = self.spool.push(&entry) {
+                    log::error!("Unable to spool {} points. Err: {}", points, e);
+                }
+            }
+        }
+
+        self.report();
+    }
+
+    /// Waiting to try Influx again
+    fn backing_off(&self) -> bool {
+        matches!(self.retry_at, Some(at) if Instant::now() < at)
+    }
+
+    /// Write a batch. Returns false if it should be tried again later, after backing off.
+    async fn write(&mut self, entry: &SpoolEntry) -> bool {
+        let started = Instant::now();
+        let result = self
+            .writer
+            .write(&entry.precision, entry.body.clone())
+            .await;
+        metrics::observe(
+            metrics::INFLUX_WRITE_SECONDS,
+            &[],
+            started.elapsed().as_secs_f64(),
+        );
+
+        match result {
+            Ok(()) => {
+                log::debug!("Wrote {} points to Influx.", entry.points);
+                metrics::add(metrics::INFLUX_POINTS, &[], entry.points as f64);
+            }
+            // Sending it again won't help
+            Err(WriteError::Rejected(e)) => {
+                log::error!("Dropped {} points. Err: {}", entry.points, e);
+                metrics::inc(metrics::INFLUX_ERRORS, &[("reason", "rejected")]);
+            }
+            Err(WriteError::Retry(e)) => {
+                metrics::inc(metrics::INFLUX_ERRORS, &[("reason", "retry")]);
+
+                let delay = backoff(&self.settings, self.attempt);
+                self.attempt = self.attempt.saturating_add(1);
+                self.retry_at = Some(Instant::now() + delay);
+
+                log::warn!(
+                    "Unable to write {} points to Influx. Retrying in {}ms. Err: {}",
+                    entry.points,
+                    delay.as_millis(),
+                    e
+                );
+
+                return false;
+            }
+        }
+
+        self.attempt = 0;
+        self.retry_at = None;
+
+        true
+    }
+
+    /// Remove points from `measurement` older than `before_ms` that haven't been written yet
+    fn expire(&mut self, batch: &mut Batch, measurement: &str, before_ms: i64) {
+        let mut removed = batch.expire(measurement, before_ms);
+
+        match self.spool.expire(measurement, before_ms) {
+            Ok(spooled) => removed += spooled,
+            Err(e) => log::error!(
+                "Unable to expire spooled {} points. Err: {}",
+                measurement,
+                e
+            ),
+        }
+
+        if removed > 0 {
+            log::info!("Removed {} unwritten {} points.", removed, measurement);
+            self.report();
+        }
+    }
+
+    /// Spool depth for `/metrics`
+    fn report(&self) {
+        let spool = self.spool.metrics();
+
+        metrics::set(metrics::INFLUX_SPOOL_POINTS, &[], spool.points() as f64);
+        metrics::set(metrics::INFLUX_SPOOL_BATCHES, &[], spool.batches() as f64);
+        metrics::set(metrics::INFLUX_SPOOL_DROPPED, &[], spool.dropped() as f64);
+    }
+
+    /// Write up to `DRAIN_BATCHES` spooled batches in order. Stops early once the spool is empty
+    /// or a write fails.
+    async fn drain(&mut self) -> Result<(), Error> {
+        for _ in 0..DRAIN_BATCHES {
+            let entry = match self.spool.first()? {
+                Some(e) => e,
+                None => break,
+            };
+
+            if !self.write(&entry).await {
+                break;
+            }
+
+            self.spool.pop()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Queries go to Influx directly when it's configured
+async fn refuse_query(broker_sender: &Sender<Event>, id: CorrelationId) {
+    let result: Result<(), Error> = Err(Error::CustomError(
+        "Queries need the local time-series store. Influx is configured.".to_string(),
+    ));
+
+    let ack = Event::ManagementAck {
+        id,
+        cmd: ManagmentDataType::QuerySeries,
+        result: result.map_err(|e| e.to_string()),
+    };
+
+    if let Err(e) = broker_sender.send_async(ack).await {
+        log::error!("Unable to send ack. Err: {}", e);
+    }
+}
+
+/// Write to the Influx configured in `[influx]`. Points are batched according to `influx.batch` and
+/// spooled to `influx.spool.path` until they're written.
+pub async fn run(settings: &settings::Influx, broker_sender: Sender<Event>) {
+    let writer = Writer::new(settings).expect("Unable to create Influx writer.");
+    let db = sled::open(&settings.spool.path).expect("Unable to open Influx spool.");
+    let spool = Spool::open(&db, settings.spool.max_points).expect("Unable to open Influx spool.");
+
+    run_with_writer(writer, spool, &settings.batch, broker_sender).await;
+}
+
+/// Same as `run` but uses an existing writer and spool
+pub async fn run_with_writer(
+    writer: Writer,
+    spool: Spool,
+    settings: &settings::InfluxBatch,
+    broker_sender: Sender<Event>,
+) {
+    // Register this task
+    let reciever = broker::register(RUNNER_NAME, &broker_sender).await.unwrap();
+
+    if !spool.is_empty() {
+        log::info!(
+            "{} spooled points waiting for Influx.",
+            spool.metrics().points()
+        );
+    }
+
+    let mut batch = Batch::default();
+    let mut flusher = Flusher {
+        writer,
+        spool,
+        settings: settings.clone(),
+        attempt: 0,
+        retry_at: None,
+    };
+    let mut interval = tokio::time::interval(Duration::from_millis(settings.flush_interval_ms));
+
+    loop {
+        tokio::select! {
+            event = reciever.recv_async() => match event {
+                Ok(Event::InfluxDataSave(query)) => flusher.save(&mut batch, &query).await,
+                Ok(Event::DataSave(point)) => flusher.save(&mut batch, &to_query(&point)).await,
+                Ok(Event::InfluxDataRequest { id, reply_to, query }) => {
+                    // So recent points show up
+                    flusher.flush(&mut batch).await;
+
+                    let result = flusher.writer.query(&query).await;
+                    respond(&broker_sender, id, reply_to, result).await;
+                }
+                Ok(Event::DataExpire { measurement, before_ms } | Event::InfluxDataExpire { measurement, before_ms }) => {
+                    // Points that haven't been written yet aren't sent after the delete
+                    flusher.expire(&mut batch, &measurement, before_ms);
+
+                    match flusher.writer.delete(&measurement, before_ms).await {
+                        Ok(()) => log::info!("Removed {} points from Influx.", measurement),
+                        Err(e) => log::error!("Unable to remove {} points. Err: {}", measurement, e),
+                    }
+                }
+                Ok(Event::SeriesQueryRequest(Some(id), _)) => refuse_query(&broker_sender, id).await,
+                Ok(_) => (),
+                Err(_) => break,
+            },
+            _ = interval.tick() => flusher.flush(&mut batch).await,
+        }
+    }
+
+    // Broker has stopped. Whatever can't be written now stays spooled for next time.
+    flusher.flush(&mut batch).await;
+
+    if let Err(e) = flusher.spool.flush().await {
+        log::error!("Unable to flush Influx spool. Err: {}", e);
+    }
+}
+
+/// Write a single point. Shows an error if it fails.
+async fn write_with_client(client: &Client, query: &WriteQuery) {
+    let started = Instant::now();
+    let result = client.query(query).await;
+    metrics::observe(
+        metrics::INFLUX_WRITE_SECONDS,
+        &[],
+        started.elapsed().as_secs_f64(),
+    );
+
+    match result {
+        Ok(_) => metrics::inc(metrics::INFLUX_POINTS, &[]),
+        Err(e) => {
+            log::error!("Unable to write query. Error: {}", e);
+            metrics::inc(metrics::INFLUX_ERRORS, &[("reason", "client")]);
+        }
+    }
+}
+
+/// Write using an existing client. Each point is written as it arrives without batching.
+pub async fn run_with_client(client: Client, broker_sender: Sender<Event>) {
+    // Register this task
+    let reciever = broker::register(RUNNER_NAME, &broker_sender).await.unwrap();
 
     // Process putting new data away
     while let Ok(event) = reciever.recv_async().await {
@@ -33,15 +1052,456 @@ pub async fn run(settings: &settings::Influx, broker_sender: Sender<Event>) {
         match event {
             Event::InfluxDataSave(query) => {
                 log::debug!("influx_run: InfluxDataSave");
-                // Create the query. Shows error if it fails
-                if let Err(e) = client.query(&query).await {
-                    log::error!("Unable to write query. Error: {}", e);
-                }
+                write_with_client(&client, &query).await;
+            }
+            Event::DataSave(point) => write_with_client(&client, &to_query(&point)).await,
+            Event::InfluxDataRequest {
+                id,
+                reply_to,
+                query,
+            } => {
+                let result = match client.query(&query).await {
+                    Ok(body) => parse_response(&body),
+                    Err(e) => Err(Error::CustomError(e.to_string())),
+                };
+
+                respond(&broker_sender, id, reply_to, result).await;
+            }
+            Event::DataExpire {
+                measurement,
+                before_ms,
             }
-            Event::InfluxDataRequest(_query) => {
-                log::debug!("influx_run: InfluxDataRequest");
+            | Event::InfluxDataExpire {
+                measurement,
+                before_ms,
+            } => {
+                if let Err(e) = client.query(&delete_query(&measurement, before_ms)).await {
+                    log::error!("Unable to remove {} points. Err: {}", measurement, e);
+                }
             }
+            Event::SeriesQueryRequest(Some(id), _) => refuse_query(&broker_sender, id).await,
             _ => (),
         };
     }
 }
+
+/// Split line protocol on `sep` while skipping escaped characters
+/// (and quoted strings if `quotes` is set)
+fn split_unescaped(s: &str, sep: char, quotes: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if quotes && c == '"' {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Remove line protocol escaping
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                if let Some(c) = chars.next() {
+                    out.push(c);
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+
+    out
+}
+
+/// Parse a single field value
+fn parse_field(value: &str) -> Result<influxdb::Type, String> {
+    use influxdb::Type;
+
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        return Ok(Type::Text(unescape(&value[1..value.len() - 1])));
+    }
+
+    if let Some(v) = value.strip_suffix('i') {
+        if let Ok(v) = v.parse::<i64>() {
+            return Ok(Type::SignedInteger(v));
+        }
+    }
+
+    if let Some(v) = value.strip_suffix('u') {
+        if let Ok(v) = v.parse::<u64>() {
+            return Ok(Type::UnsignedInteger(v));
+        }
+    }
+
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => Ok(Type::Boolean(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => Ok(Type::Boolean(false)),
+        _ => value
+            .parse::<f64>()
+            .map(Type::Float)
+            .map_err(|_| format!("Invalid field value: {}", value)),
+    }
+}
+
+/// Line protocol broken up into its parts
+pub(crate) struct Line {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, influxdb::Type)>,
+    pub timestamp: Option<u128>,
+}
+
+/// Parse a line of line protocol
+pub(crate) fn parse_line(line: &str) -> Result<Line, String> {
+    let sections = split_unescaped(line.trim(), ' ', true);
+
+    let (series, fields, timestamp) = match sections.as_slice() {
+        [series, fields] => (series, fields, None),
+        [series, fields, timestamp] => (series, fields, Some(timestamp)),
+        _ => return Err(format!("Invalid line: {}", line)),
+    };
+
+    // Measurement followed by tags
+    let mut series = split_unescaped(series, ',', false).into_iter();
+    let measurement = unescape(series.next().unwrap_or_default());
+
+    let mut tags = Vec::new();
+    for tag in series {
+        match split_unescaped(tag, '=', false).as_slice() {
+            [key, value] => tags.push((unescape(key), unescape(value))),
+            _ => return Err(format!("Invalid tag: {}", tag)),
+        }
+    }
+
+    let mut parsed_fields = Vec::new();
+    for field in split_unescaped(fields, ',', true) {
+        match split_unescaped(field, '=', true).as_slice() {
+            [key, value] => parsed_fields.push((unescape(key), parse_field(value)?)),
+            _ => return Err(format!("Invalid field: {}", field)),
+        }
+    }
+
+    let timestamp = match timestamp {
+        Some(t) => Some(
+            t.parse::<u128>()
+                .map_err(|_| format!("Invalid timestamp: {}", t))?,
+        ),
+        None => None,
+    };
+
+    Ok(Line {
+        measurement,
+        tags,
+        fields: parsed_fields,
+        timestamp,
+    })
+}
+
+/// (De)serialize a `WriteQuery` as line protocol.
+///
+/// Used with `#[serde(with = "...")]` so queued `InfluxDataSave`
+/// events can be written to disk.
+pub(crate) mod write_query {
+    use influxdb::{Query, Timestamp, WriteQuery};
+    use serde::{de, ser, Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct Encoded {
+        precision: String,
+        line: String,
+    }
+
+    pub fn serialize<S: Serializer>(query: &WriteQuery, serializer: S) -> Result<S::Ok, S::Error> {
+        let line = query.build().map_err(ser::Error::custom)?.get();
+
+        Encoded {
+            precision: query.get_precision(),
+            line,
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<WriteQuery, D::Error> {
+        let encoded = Encoded::deserialize(deserializer)?;
+        let line = super::parse_line(&encoded.line).map_err(de::Error::custom)?;
+
+        let time = line.timestamp.unwrap_or_default();
+        let timestamp = match encoded.precision.as_str() {
+            "ns" => Timestamp::Nanoseconds(time),
+            "u" => Timestamp::Microseconds(time),
+            "ms" => Timestamp::Milliseconds(time),
+            "s" => Timestamp::Seconds(time),
+            "m" => Timestamp::Minutes(time),
+            "h" => Timestamp::Hours(time),
+            p => return Err(de::Error::custom(format!("Unknown precision: {}", p))),
+        };
+
+        let mut query = WriteQuery::new(timestamp, line.measurement);
+
+        for (key, value) in line.tags {
+            query = query.add_tag(key, value);
+        }
+
+        for (key, value) in line.fields {
+            query = query.add_field(key, value);
+        }
+
+        Ok(query)
+    }
+}
+
+/// (De)serialize a `ReadQuery` as its query string
+pub(crate) mod read_query {
+    use influxdb::{Query, ReadQuery};
+    use serde::{ser, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(query: &ReadQuery, serializer: S) -> Result<S::Ok, S::Error> {
+        query
+            .build()
+            .map_err(ser::Error::custom)?
+            .get()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ReadQuery, D::Error> {
+        Ok(ReadQuery::new(String::deserialize(deserializer)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use influxdb::Type;
+
+    #[test]
+    fn parse_line_success() {
+        let line = parse_line(
+            r#"gps\ data,id=1234,name=a\,b lat=1.5,count=3i,ok=true,note="say \"hi\"" 1620000000000"#,
+        )
+        .unwrap();
+
+        assert_eq!(line.measurement, "gps data");
+        assert_eq!(
+            line.tags,
+            vec![
+                ("id".to_string(), "1234".to_string()),
+                ("name".to_string(), "a,b".to_string())
+            ]
+        );
+        assert_eq!(line.timestamp, Some(1620000000000));
+        assert_eq!(line.fields.len(), 4);
+        assert!(matches!(line.fields[0].1, Type::Float(v) if v == 1.5));
+        assert!(matches!(line.fields[1].1, Type::SignedInteger(3)));
+        assert!(matches!(line.fields[2].1, Type::Boolean(true)));
+        assert!(matches!(&line.fields[3].1, Type::Text(t) if t == r#"say "hi""#));
+    }
+
+    fn influx_settings() -> settings::Influx {
+        settings::Influx {
+            host: "localhost".to_string(),
+            port: 8086,
+            tls: false,
+            database: String::new(),
+            user: String::new(),
+            password: String::new(),
+            org: None,
+            bucket: None,
+            token: None,
+            batch: settings::InfluxBatch::default(),
+            spool: settings::InfluxSpool::default(),
+        }
+    }
+
+    #[test]
+    fn batch_success() {
+        let mut batch = Batch::default();
+        batch.push_line("ms", "telemetry rsrp=1i 1".to_string());
+        batch.push_line("ns", "gps lat=1.5 2".to_string());
+        batch.push_line("ms", "telemetry rsrp=2i 3".to_string());
+
+        assert_eq!(batch.len(), 3);
+
+        let taken = batch.take();
+        assert!(batch.is_empty());
+        assert_eq!(
+            taken,
+            vec![
+                (
+                    "ms".to_string(),
+                    "telemetry rsrp=1i 1\ntelemetry rsrp=2i 3".to_string(),
+                    2
+                ),
+                ("ns".to_string(), "gps lat=1.5 2".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn batch_expire_success() {
+        let mut batch = Batch::default();
+        batch.push_line("ms", "gps lat=1.5 1000".to_string());
+        batch.push_line("s", "gps lat=2.5 1".to_string());
+        batch.push_line("ns", "gps lat=3.5 3000000000".to_string());
+        batch.push_line("ms", "telemetry rsrp=1i 1000".to_string());
+        batch.push_line("ms", "gps lat=4.5".to_string());
+
+        // Other measurements, newer points and points without a time stay
+        assert_eq!(batch.expire("gps", 2000), 2);
+        assert_eq!(batch.len(), 3);
+        assert_eq!(
+            batch.take(),
+            vec![
+                (
+                    "ms".to_string(),
+                    "telemetry rsrp=1i 1000\ngps lat=4.5".to_string(),
+                    2
+                ),
+                ("ns".to_string(), "gps lat=3.5 3000000000".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn backoff_success() {
+        let settings = settings::InfluxBatch {
+            initial_backoff_ms: 500,
+            max_backoff_ms: 3000,
+            ..Default::default()
+        };
+
+        assert_eq!(backoff(&settings, 0), Duration::from_millis(500));
+        assert_eq!(backoff(&settings, 2), Duration::from_millis(2000));
+        assert_eq!(backoff(&settings, 3), Duration::from_millis(3000));
+        assert_eq!(backoff(&settings, 100), Duration::from_millis(3000));
+    }
+
+    #[test]
+    fn writer_success() {
+        let writer = Writer::new(&influx_settings()).unwrap();
+        assert_eq!(writer.url, "http://localhost:8086/write");
+        assert_eq!(writer.query_url, "http://localhost:8086/query");
+
+        let mut settings = influx_settings();
+        settings.tls = true;
+        settings.org = Some("pyrinas".to_string());
+        settings.bucket = Some("telemetry".to_string());
+        settings.token = Some("secret".to_string());
+
+        let writer = Writer::new(&settings).unwrap();
+        assert_eq!(writer.url, "https://localhost:8086/api/v2/write");
+        assert!(matches!(writer.target, Target::V2 { .. }));
+    }
+
+    #[test]
+    fn delete_query_success() {
+        let query = delete_query(r#"gps "raw""#, 1620000000000)
+            .build()
+            .unwrap()
+            .get();
+
+        assert_eq!(
+            query,
+            r#"DELETE FROM "gps \"raw\"" WHERE time < 1620000000000ms"#
+        );
+    }
+
+    #[test]
+    fn read_only_success() {
+        assert!(is_read_only(
+            "SELECT mean(lat) FROM gps WHERE time > now() - 1h"
+        ));
+        assert!(is_read_only("show measurements; select * from \"into\""));
+        assert!(is_read_only("SELECT * FROM gps WHERE fix = 'into; DROP'"));
+    }
+
+    #[test]
+    fn read_only_failure() {
+        assert!(!is_read_only(""));
+        assert!(!is_read_only("DROP MEASUREMENT gps"));
+        assert!(!is_read_only("SELECT * FROM gps; DROP DATABASE pyrinas"));
+        assert!(!is_read_only("SELECT * INTO copy FROM gps"));
+        assert!(!is_read_only("/* SELECT */ DELETE FROM gps"));
+        assert!(!is_read_only(
+            r#"SELECT * FROM /"/ ; DROP DATABASE x ; /"/"#
+        ));
+        assert!(!is_read_only("SELECT * FROM gps WHERE id =~ /^12/"));
+        assert!(!is_read_only(
+            "-- SELECT\nCREATE USER admin WITH PASSWORD 'a'"
+        ));
+    }
+
+    #[test]
+    fn writer_failure() {
+        // Token without a bucket
+        let mut settings = influx_settings();
+        settings.org = Some("pyrinas".to_string());
+        settings.token = Some("secret".to_string());
+
+        assert!(Writer::new(&settings).is_err());
+    }
+
+    #[test]
+    fn parse_response_success() {
+        #[derive(serde::Deserialize, Debug, PartialEq)]
+        struct Fix {
+            id: String,
+            time: i64,
+            lat: f64,
+        }
+
+        let response = parse_response(
+            r#"{"results":[{"statement_id":0,"series":[{"name":"gps","tags":{"id":"1234"},"columns":["time","lat"],"values":[[1620000000000,1.5]]}]}]}"#,
+        )
+        .unwrap();
+
+        assert_eq!(response.series.len(), 1);
+        assert_eq!(response.series[0].name, "gps");
+        assert_eq!(
+            response.series[0].rows::<Fix>().unwrap(),
+            vec![Fix {
+                id: "1234".to_string(),
+                time: 1620000000000,
+                lat: 1.5
+            }]
+        );
+
+        // Nothing matched
+        let response = parse_response(r#"{"results":[{"statement_id":0}]}"#).unwrap();
+        assert!(response.series.is_empty());
+    }
+
+    #[test]
+    fn parse_response_failure() {
+        assert!(parse_response(
+            r#"{"results":[{"statement_id":0,"error":"database not found: x"}]}"#
+        )
+        .is_err());
+        assert!(
+            parse_response(r#"{"error":"unable to parse authentication credentials"}"#).is_err()
+        );
+        assert!(parse_response("").is_err());
+    }
+
+    #[test]
+    fn parse_line_failure() {
+        assert!(parse_line("telemetry").is_err());
+        assert!(parse_line("telemetry rsrp=abc").is_err());
+        assert!(parse_line("telemetry rsrp=1i notatime").is_err());
+    }
+}
@@ -1,13 +1,32 @@
 // Lib related
 pub mod admin;
+pub mod application;
 pub mod broker;
+pub mod command;
+pub mod dead_letter;
+pub mod device;
+pub mod encoding;
 pub mod influx;
+pub mod metrics;
+pub mod middleware;
 pub mod mqtt;
 pub mod ota;
+pub mod payload;
+pub mod recorder;
+pub mod replay;
+pub mod retention;
+pub mod server;
 pub mod settings;
+pub mod shadow;
+pub mod shutdown;
+pub mod sink;
+pub mod supervisor;
 pub mod telemetry;
+pub mod timeseries;
+pub mod topic;
 
 pub use pyrinas_shared::*;
+pub use server::PyrinasServer;
 
 // Influx/Event
 use influxdb::{ReadQuery, WriteQuery};
@@ -15,13 +34,16 @@ use influxdb::{ReadQuery, WriteQuery};
 // Async Related
 use flume::{Receiver, Sender};
 use pyrinas_shared::ota::v2::{OTADownload, OTAUpdate};
-use std::{io, sync::Arc};
+use std::{
+    io,
+    sync::{Arc, Weak},
+};
 
-// Runtime
-use tokio::task;
+// Serializing events
+use serde::{Deserialize, Serialize};
 
-// MQTT related
-use librumqttd::async_locallink::construct_broker;
+// Runtime
+use shutdown::Shutdown;
 
 // Error
 use thiserror::Error;
@@ -51,12 +73,24 @@ pub enum Error {
         source: librumqttd::async_locallink::LinkError,
     },
 
+    #[error("{source}")]
+    RecvError {
+        #[from]
+        source: flume::RecvError,
+    },
+
     #[error("{source}")]
     CborError {
         #[from]
         source: serde_cbor::Error,
     },
 
+    #[error("{source}")]
+    JsonError {
+        #[from]
+        source: serde_json::Error,
+    },
+
     #[error("{source}")]
     SledError {
         #[from]
@@ -69,6 +103,12 @@ pub enum Error {
         source: std::string::FromUtf8Error,
     },
 
+    #[error("{source}")]
+    PostgresError {
+        #[from]
+        source: tokio_postgres::Error,
+    },
+
     #[error("{source}")]
     IoError {
         #[from]
@@ -81,104 +121,69 @@ pub enum Error {
         source: tokio::task::JoinError,
     },
 
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    #[error("Invalid topic {topic}: {reason}")]
+    InvalidTopic { topic: String, reason: String },
+
     #[error("err: {0}")]
     CustomError(String),
 }
 
 // TODO: conditional use of tokio OR async_std
+/// Run the server with every built-in task that's configured until `shutdown` is triggered.
+///
+/// Use `PyrinasServer::builder` for more control over what's started.
 pub async fn run(
     settings: Arc<settings::PyrinasSettings>,
     broker_sender: Sender<Event>,
     broker_reciever: Receiver<Event>,
+    shutdown: Shutdown,
 ) -> Result<(), Error> {
-    // Clone these appropriately
-    let task_sender = broker_sender.clone();
-    let task_settings = settings.clone();
-
-    // Init influx connection
-    if settings.clone().influx.is_some() {
-        task::spawn(async move {
-            influx::run(&task_settings.influx.to_owned().unwrap(), task_sender).await;
-        });
-    }
-
-    // Ota task
-    let task_sender = broker_sender.clone();
-    let task_settings = settings.clone();
-    task::spawn(async move {
-        ota::run(&task_settings.ota, task_sender).await;
-    });
-
-    // Clone these appropriately
-    let task_sender = broker_sender.clone();
-    let task_settings = settings.clone();
-
-    // Start unix socket task
-    if task_settings.admin.is_some() {
-        task::spawn(async move {
-            if let Err(e) = admin::run(&task_settings.admin.to_owned().unwrap(), task_sender).await
-            {
-                log::error!("Admin runtime error! Err: {}", e);
-            };
-        });
-    }
-
-    // Set up broker
-    let (mut router, _, rumqtt_server, builder) = construct_broker(settings.mqtt.rumqtt.clone());
-
-    // Running switch
-    task::spawn(async {
-        rumqtt_server.await;
-    });
-
-    // Spawn router task (needs to be done before anything else or else builder.connect blocks)
-    task::spawn_blocking(move || {
-        if let Err(e) = router.start() {
-            log::error!("mqtt router error. err: {}", e);
-        }
-    });
-
-    // Get the rx/tx channels
-    let (mut tx, mut rx) = builder.connect("localclient", 200).await?;
-
-    // Subscribe
-    tx.subscribe(settings.mqtt.topics.clone()).await?;
-
-    // Spawn a new task(s) for the MQTT stuff
-    let task_sender = broker_sender.clone();
-
-    // Start server task
-    task::spawn(async move {
-        mqtt::mqtt_run(&mut rx, task_sender).await;
-    });
-
-    // Start mqtt broker task
-    let task_sender = broker_sender.clone();
-    task::spawn(async move {
-        mqtt::run(&mut tx, task_sender).await;
-    });
-
-    // Spawn the broker task that handles it all!
-    // This blocks this async function from returning.
-    // If this returns, the server it shot anyway..
-    task::spawn(broker::run(broker_reciever)).await?;
-
-    Ok(())
+    PyrinasServer::builder(settings)
+        .channel(broker_sender, broker_reciever)
+        .shutdown(shutdown)
+        .build()?
+        .run()
+        .await
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Event {
+    #[serde(skip)]
     NewRunner {
         name: String,
-        sender: Sender<Event>,
-    },
-    OtaDeletePackage(String),
-    OtaNewPackage(OTAUpdate),
+        alive: Weak<()>,
+        reply: Sender<Receiver<Event>>,
+    }, // Registers a runner. The broker replies with the reciever for its events
+    Shutdown, // Stops the broker once every event before it has been handed to a runner
+    BrokerStatsRequest(Option<CorrelationId>), // Request queue statistics for every runner
+    BrokerStatsResponse(Option<CorrelationId>, BrokerStatsResponse), // Response to BrokerStatsRequest
+    DeadLetterListRequest(Option<CorrelationId>), // Request a summary of every undeliverable event
+    DeadLetterListResponse(Option<CorrelationId>, DeadLetterListResponse), // Response to DeadLetterListRequest
+    DeadLetterReplay {
+        id: Option<CorrelationId>,
+        entry: Option<u64>,
+    }, // Try delivering dead letters again. All of them if `entry` is `None`
+    DeadLetterPurge {
+        id: Option<CorrelationId>,
+        entry: Option<u64>,
+    }, // Remove dead letters. All of them if `entry` is `None`
+    ManagementAck {
+        id: CorrelationId,
+        cmd: ManagmentDataType,
+        result: Result<(), String>,
+    }, // Success/failure of an admin request that has no other response
+    OtaDeletePackage(Option<CorrelationId>, String),
+    OtaNewPackage(Option<CorrelationId>, OTAUpdate),
     OtaUnlink {
+        id: Option<CorrelationId>,
         device_id: Option<String>,
         group_id: Option<String>,
     },
     OtaLink {
+        id: Option<CorrelationId>,
         device_id: Option<String>,
         group_id: Option<String>,
         image_id: Option<String>,
@@ -189,15 +194,165 @@ pub enum Event {
     },
     OtaResponse(OTAUpdate),
     OtaDownloadResponse(OTADownload),
-    OtaUpdateImageListRequest(), // Simple request to get all the firmware image information (id, name, desc, etc)
-    OtaUpdateImageListRequestResponse(OtaImageListResponse), // Message sent to show all the avilable OTA updates
-    OtaUpdateGroupListRequest(), // Simple request to get a list of all the groups with their memebers
-    OtaUpdateGroupListRequestResponse(OtaGroupListResponse), // Message sent to show all the avilable group info
+    OtaUpdateImageListRequest(Option<CorrelationId>), // Simple request to get all the firmware image information (id, name, desc, etc)
+    OtaUpdateImageListRequestResponse(Option<CorrelationId>, OtaImageListResponse), // Message sent to show all the avilable OTA updates
+    OtaUpdateGroupListRequest(Option<CorrelationId>), // Simple request to get a list of all the groups with their memebers
+    OtaUpdateGroupListRequestResponse(Option<CorrelationId>, OtaGroupListResponse), // Message sent to show all the avilable group info
     ApplicationManagementRequest(ManagementData), // Message sent for configuration of application
     ApplicationManagementResponse(ManagementData), // Reponse from application management portion of the app
     ApplicationRequest(ApplicationData),           // Request/event from a device
     ApplicationResponse(ApplicationData),          // Reponse from other parts of the server
-    InfluxDataSave(WriteQuery),                    // Takes a pre-prepared query and executes it
-    InfluxDataRequest(ReadQuery), // Takes a pre-prepared query to *read* the database
-    InfluxDataResponse,           // Is the response to InfluxDataRequest
+    DataSave(Box<SeriesPoint>), // Written to every sink that takes its measurement. Influx if there aren't any
+    InfluxDataSave(#[serde(with = "influx::write_query")] WriteQuery), // Takes a pre-prepared query and executes it
+    DataExpire {
+        measurement: String,
+        before_ms: i64,
+    }, // Removes older points from every sink that takes the measurement. Influx if there aren't any
+    InfluxDataExpire {
+        measurement: String,
+        before_ms: i64,
+    }, // Removes older points from Influx
+    InfluxDataRequest {
+        id: Option<CorrelationId>,
+        reply_to: String, // Runner the response is sent to
+        #[serde(with = "influx::read_query")]
+        query: ReadQuery,
+    }, // Takes a pre-prepared query to *read* the database
+    InfluxDataResponse {
+        id: Option<CorrelationId>,
+        reply_to: String,
+        result: Result<Box<InfluxQueryResponse>, String>,
+    }, // Is the response to InfluxDataRequest
+    SeriesQueryRequest(Option<CorrelationId>, Box<SeriesQuery>), // Points from the local time-series store
+    SeriesQueryResponse(Option<CorrelationId>, Box<SeriesQueryResponse>), // Response to SeriesQueryRequest
+    DeviceSeen(String), // A message was recieved from the device
+    DeviceConnection {
+        uid: String,
+        online: bool,
+    }, // Device reported it connected or its last will reported it disconnected
+    DeviceOnline(String), // Device came online. Sent to the application
+    DeviceOffline(String), // Device went offline. Sent to the application
+    DeviceListRequest(Option<CorrelationId>), // Request presence of every known device
+    DeviceListResponse(Option<CorrelationId>, DeviceListResponse), // Response to DeviceListRequest
+    DeviceGetRequest(Option<CorrelationId>, String), // Request everything known about a device
+    DeviceGetResponse(Option<CorrelationId>, Box<DeviceInfo>), // Response to DeviceGetRequest
+    DeviceAdd(Option<CorrelationId>, Box<DeviceUpdate>), // Add a device to the registry
+    DeviceUpdate(Option<CorrelationId>, Box<DeviceUpdate>), // Change the fields that are set on an existing device
+    DeviceRemove(Option<CorrelationId>, String),            // Remove a device from the registry
+    ShadowReported {
+        uid: String,
+        state: Box<serde_cbor::Value>,
+    }, // State reported by the device. Merged into the reported document
+    ShadowDesired(Option<CorrelationId>, Box<ShadowUpdate>), // Merge a change into the desired document
+    ShadowGetRequest(Option<CorrelationId>, String),         // Request a device's shadow document
+    ShadowGetResponse(Option<CorrelationId>, Box<ShadowDocument>), // Response to ShadowGetRequest
+    ShadowDelta {
+        uid: String,
+        delta: Box<serde_cbor::Value>,
+    }, // Desired state the device hasn't reported yet. Published to the device
+    CommandSend {
+        id: Option<CorrelationId>,
+        reply_to: String, // Runner the response is sent to
+        request: Box<CommandRequest>,
+    }, // Run a command on a device. Queued while it's offline
+    CommandSendResponse {
+        id: Option<CorrelationId>,
+        reply_to: String,
+        result: Result<Box<CommandRecord>, String>,
+    }, // Is the response to CommandSend. Has the id the command was given
+    CommandGetRequest(Option<CorrelationId>, CommandId),     // Request the state of a command
+    CommandGetResponse(Option<CorrelationId>, Box<CommandRecord>), // Response to CommandGetRequest
+    CommandPublish {
+        uid: String,
+        name: String,
+        command: DeviceCommand,
+    }, // Publishes a command to the device
+    CommandAck {
+        uid: String,
+        ack: CommandAck,
+    }, // Device acknowledged a command
+    CommandResult(Box<CommandRecord>), // Command succeeded, failed or timed out. Sent to the application
+}
+
+impl Event {
+    /// Name of the variant. Used for logging and metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::NewRunner { .. } => "NewRunner",
+            Event::Shutdown => "Shutdown",
+            Event::BrokerStatsRequest(_) => "BrokerStatsRequest",
+            Event::BrokerStatsResponse(..) => "BrokerStatsResponse",
+            Event::DeadLetterListRequest(_) => "DeadLetterListRequest",
+            Event::DeadLetterListResponse(..) => "DeadLetterListResponse",
+            Event::DeadLetterReplay { .. } => "DeadLetterReplay",
+            Event::DeadLetterPurge { .. } => "DeadLetterPurge",
+            Event::ManagementAck { .. } => "ManagementAck",
+            Event::OtaDeletePackage(..) => "OtaDeletePackage",
+            Event::OtaNewPackage(..) => "OtaNewPackage",
+            Event::OtaUnlink { .. } => "OtaUnlink",
+            Event::OtaLink { .. } => "OtaLink",
+            Event::OtaRequest { .. } => "OtaRequest",
+            Event::OtaResponse(_) => "OtaResponse",
+            Event::OtaDownloadResponse(_) => "OtaDownloadResponse",
+            Event::OtaUpdateImageListRequest(_) => "OtaUpdateImageListRequest",
+            Event::OtaUpdateImageListRequestResponse(..) => "OtaUpdateImageListRequestResponse",
+            Event::OtaUpdateGroupListRequest(_) => "OtaUpdateGroupListRequest",
+            Event::OtaUpdateGroupListRequestResponse(..) => "OtaUpdateGroupListRequestResponse",
+            Event::ApplicationManagementRequest(_) => "ApplicationManagementRequest",
+            Event::ApplicationManagementResponse(_) => "ApplicationManagementResponse",
+            Event::ApplicationRequest(_) => "ApplicationRequest",
+            Event::ApplicationResponse(_) => "ApplicationResponse",
+            Event::DataSave(_) => "DataSave",
+            Event::InfluxDataSave(_) => "InfluxDataSave",
+            Event::DataExpire { .. } => "DataExpire",
+            Event::InfluxDataExpire { .. } => "InfluxDataExpire",
+            Event::InfluxDataRequest { .. } => "InfluxDataRequest",
+            Event::InfluxDataResponse { .. } => "InfluxDataResponse",
+            Event::DeviceSeen(_) => "DeviceSeen",
+            Event::DeviceConnection { .. } => "DeviceConnection",
+            Event::DeviceOnline(_) => "DeviceOnline",
+            Event::DeviceOffline(_) => "DeviceOffline",
+            Event::DeviceListRequest(_) => "DeviceListRequest",
+            Event::DeviceListResponse(..) => "DeviceListResponse",
+            Event::DeviceGetRequest(..) => "DeviceGetRequest",
+            Event::DeviceGetResponse(..) => "DeviceGetResponse",
+            Event::DeviceAdd(..) => "DeviceAdd",
+            Event::DeviceUpdate(..) => "DeviceUpdate",
+            Event::DeviceRemove(..) => "DeviceRemove",
+            Event::ShadowReported { .. } => "ShadowReported",
+            Event::ShadowDesired(..) => "ShadowDesired",
+            Event::ShadowGetRequest(..) => "ShadowGetRequest",
+            Event::ShadowGetResponse(..) => "ShadowGetResponse",
+            Event::ShadowDelta { .. } => "ShadowDelta",
+            Event::CommandSend { .. } => "CommandSend",
+            Event::CommandSendResponse { .. } => "CommandSendResponse",
+            Event::CommandGetRequest(..) => "CommandGetRequest",
+            Event::CommandGetResponse(..) => "CommandGetResponse",
+            Event::CommandPublish { .. } => "CommandPublish",
+            Event::CommandAck { .. } => "CommandAck",
+            Event::CommandResult(_) => "CommandResult",
+            Event::SeriesQueryRequest(..) => "SeriesQueryRequest",
+            Event::SeriesQueryResponse(..) => "SeriesQueryResponse",
+        }
+    }
+}
+
+/// Let the admin client know how a request went. Only sent if the request has an id.
+pub(crate) async fn acknowledge(
+    broker_sender: &Sender<Event>,
+    id: &Option<CorrelationId>,
+    cmd: ManagmentDataType,
+    result: Result<(), Error>,
+) {
+    if let Some(id) = id {
+        let ack = Event::ManagementAck {
+            id: *id,
+            cmd,
+            result: result.map_err(|e| e.to_string()),
+        };
+
+        if let Err(e) = broker_sender.send_async(ack).await {
+            log::error!("Unable to send ack. Err: {}", e);
+        }
+    }
 }
@@ -0,0 +1,511 @@
+// System related
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// Async Related
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+// Local lib related
+use crate::{settings, Error};
+
+/// 1 once the server has started, until it shuts down
+pub const READY: &str = "pyrinas_ready";
+/// Running instances of each supervised task
+pub const TASK_UP: &str = "pyrinas_task_up";
+pub const TASK_RESTARTS: &str = "pyrinas_task_restarts_total";
+/// By topic `kind` and `direction`
+pub const MQTT_MESSAGES: &str = "pyrinas_mqtt_messages_total";
+/// By `reason`
+pub const MQTT_REJECTED: &str = "pyrinas_mqtt_rejected_total";
+/// By topic `kind`
+pub const DECODE_ERRORS: &str = "pyrinas_decode_errors_total";
+pub const DEVICES_ONLINE: &str = "pyrinas_devices_online";
+/// By event `kind`
+pub const BROKER_EVENTS: &str = "pyrinas_broker_events_total";
+/// By `runner`
+pub const BROKER_QUEUED: &str = "pyrinas_broker_queue_depth";
+pub const BROKER_CAPACITY: &str = "pyrinas_broker_queue_capacity";
+pub const BROKER_SPILLED: &str = "pyrinas_broker_spilled";
+pub const BROKER_DROPPED: &str = "pyrinas_broker_dropped_total";
+/// By `cmd`
+pub const OTA_REQUESTS: &str = "pyrinas_ota_requests_total";
+pub const OTA_BYTES: &str = "pyrinas_ota_bytes_served_total";
+pub const INFLUX_WRITE_SECONDS: &str = "pyrinas_influx_write_duration_seconds";
+pub const INFLUX_POINTS: &str = "pyrinas_influx_points_written_total";
+/// By `reason`
+pub const INFLUX_ERRORS: &str = "pyrinas_influx_write_errors_total";
+pub const INFLUX_SPOOL_POINTS: &str = "pyrinas_influx_spool_points";
+pub const INFLUX_SPOOL_BATCHES: &str = "pyrinas_influx_spool_batches";
+pub const INFLUX_SPOOL_DROPPED: &str = "pyrinas_influx_spool_dropped_total";
+pub const ADMIN_CONNECTIONS: &str = "pyrinas_admin_connections";
+/// By `cmd`
+pub const ADMIN_REQUESTS: &str = "pyrinas_admin_requests_total";
+/// By rollup `measurement`
+pub const ROLLUP_POINTS: &str = "pyrinas_rollup_points_total";
+
+/// Upper bounds (s) of the histogram buckets
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Largest request read before giving up on it
+const MAX_REQUEST_BYTES: usize = 8192;
+
+/// How long a client gets to send its request
+const REQUEST_TIMEOUT_MS: u64 = 5000;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    Histogram,
+}
+
+impl Kind {
+    fn as_str(self) -> &'static str {
+        match self {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+            Kind::Histogram => "histogram",
+        }
+    }
+}
+
+/// Everything that's reported, in the order it's rendered
+const FAMILIES: &[(&str, Kind, &str)] = &[
+    (
+        READY,
+        Kind::Gauge,
+        "1 once the server has started, until it shuts down.",
+    ),
+    (
+        TASK_UP,
+        Kind::Gauge,
+        "Running instances of each supervised task.",
+    ),
+    (
+        TASK_RESTARTS,
+        Kind::Counter,
+        "Times each supervised task has been restarted.",
+    ),
+    (
+        MQTT_MESSAGES,
+        Kind::Counter,
+        "MQTT messages by topic kind and direction.",
+    ),
+    (
+        MQTT_REJECTED,
+        Kind::Counter,
+        "Incoming MQTT messages rejected by reason.",
+    ),
+    (
+        DECODE_ERRORS,
+        Kind::Counter,
+        "Payloads that couldn't be decoded by topic kind.",
+    ),
+    (DEVICES_ONLINE, Kind::Gauge, "Devices currently online."),
+    (
+        BROKER_EVENTS,
+        Kind::Counter,
+        "Events routed by the broker by kind.",
+    ),
+    (
+        BROKER_QUEUED,
+        Kind::Gauge,
+        "Events waiting in each runner's queue.",
+    ),
+    (BROKER_CAPACITY, Kind::Gauge, "Size of each runner's queue."),
+    (
+        BROKER_SPILLED,
+        Kind::Gauge,
+        "Events spilled to disk for each runner.",
+    ),
+    (
+        BROKER_DROPPED,
+        Kind::Counter,
+        "Events dropped for each runner.",
+    ),
+    (
+        OTA_REQUESTS,
+        Kind::Counter,
+        "OTA requests from devices by command.",
+    ),
+    (OTA_BYTES, Kind::Counter, "OTA image bytes sent to devices."),
+    (
+        INFLUX_WRITE_SECONDS,
+        Kind::Histogram,
+        "Time taken by each write to Influx.",
+    ),
+    (INFLUX_POINTS, Kind::Counter, "Points written to Influx."),
+    (
+        INFLUX_ERRORS,
+        Kind::Counter,
+        "Failed writes to Influx by reason.",
+    ),
+    (
+        INFLUX_SPOOL_POINTS,
+        Kind::Gauge,
+        "Points spooled until Influx takes them.",
+    ),
+    (
+        INFLUX_SPOOL_BATCHES,
+        Kind::Gauge,
+        "Batches spooled until Influx takes them.",
+    ),
+    (
+        INFLUX_SPOOL_DROPPED,
+        Kind::Counter,
+        "Points dropped from the full Influx spool.",
+    ),
+    (ADMIN_CONNECTIONS, Kind::Gauge, "Connected admin clients."),
+    (ADMIN_REQUESTS, Kind::Counter, "Admin requests by command."),
+    (
+        ROLLUP_POINTS,
+        Kind::Counter,
+        "Rollup points saved by measurement.",
+    ),
+];
+
+/// A counter or gauge that's already been looked up. Updating it doesn't lock or allocate, so
+/// keep one for anything that's counted on every message.
+#[derive(Debug, Clone, Default)]
+pub struct Handle(Arc<AtomicU64>);
+
+impl Handle {
+    /// Add one to a counter
+    pub fn inc(&self) {
+        self.add(1.0);
+    }
+
+    /// Add to a counter or gauge
+    pub fn add(&self, value: f64) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    /// Set a gauge, or a counter that's kept somewhere else
+    pub fn set(&self, value: f64) {
+        self.0.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(Ordering::Relaxed))
+    }
+}
+
+enum Sample {
+    Value(Handle),
+    Histogram {
+        /// Per bucket, not cumulative
+        counts: [u64; BUCKETS.len()],
+        sum: f64,
+        count: u64,
+    },
+}
+
+/// Samples by metric name then rendered labels
+static SAMPLES: Mutex<BTreeMap<&'static str, BTreeMap<String, Sample>>> =
+    Mutex::new(BTreeMap::new());
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// i.e. `kind="ota",direction="in"`
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    labels
+        .iter()
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn update<F: FnOnce(&mut Sample)>(name: &'static str, labels: &[(&str, &str)], new: Sample, f: F) {
+    let mut samples = SAMPLES.lock().unwrap_or_else(|e| e.into_inner());
+
+    let sample = samples
+        .entry(name)
+        .or_default()
+        .entry(render_labels(labels))
+        .or_insert(new);
+
+    f(sample);
+}
+
+/// Look up a counter or gauge. It's added with a value of 0 the first time.
+pub fn handle(name: &'static str, labels: &[(&str, &str)]) -> Handle {
+    let mut samples = SAMPLES.lock().unwrap_or_else(|e| e.into_inner());
+
+    let sample = samples
+        .entry(name)
+        .or_default()
+        .entry(render_labels(labels))
+        .or_insert_with(|| Sample::Value(Handle::default()));
+
+    match sample {
+        Sample::Value(h) => h.clone(),
+        // Histograms only take `observe`
+        Sample::Histogram { .. } => Handle::default(),
+    }
+}
+
+/// Add one to a counter
+pub fn inc(name: &'static str, labels: &[(&str, &str)]) {
+    add(name, labels, 1.0);
+}
+
+/// Add to a counter or gauge
+pub fn add(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    handle(name, labels).add(value);
+}
+
+/// Set a gauge, or a counter that's kept somewhere else
+pub fn set(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    handle(name, labels).set(value);
+}
+
+/// Add a sample to a histogram
+pub fn observe(name: &'static str, labels: &[(&str, &str)], value: f64) {
+    let new = Sample::Histogram {
+        counts: [0; BUCKETS.len()],
+        sum: 0.0,
+        count: 0,
+    };
+
+    update(name, labels, new, |s| {
+        if let Sample::Histogram { counts, sum, count } = s {
+            if let Some(i) = BUCKETS.iter().position(|b| value <= *b) {
+                counts[i] += 1;
+            }
+
+            *sum += value;
+            *count += 1;
+        }
+    });
+}
+
+/// Current value of a counter or gauge
+pub fn get(name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+    let samples = SAMPLES.lock().unwrap_or_else(|e| e.into_inner());
+
+    match samples.get(name)?.get(&render_labels(labels))? {
+        Sample::Value(v) => Some(v.get()),
+        Sample::Histogram { .. } => None,
+    }
+}
+
+/// Everything in the Prometheus text format
+pub fn render() -> String {
+    let samples = SAMPLES.lock().unwrap_or_else(|e| e.into_inner());
+    let mut out = String::new();
+
+    for (name, kind, help) in FAMILIES {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind.as_str());
+
+        for (labels, sample) in samples.get(name).into_iter().flatten() {
+            match sample {
+                Sample::Value(v) if labels.is_empty() => {
+                    let _ = writeln!(out, "{} {}", name, v.get());
+                }
+                Sample::Value(v) => {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, v.get());
+                }
+                Sample::Histogram { counts, sum, count } => {
+                    let prefix = match labels.is_empty() {
+                        true => String::new(),
+                        false => format!("{},", labels),
+                    };
+
+                    let mut total = 0;
+                    for (bound, c) in BUCKETS.iter().zip(counts.iter()) {
+                        total += c;
+                        let _ = writeln!(
+                            out,
+                            "{}_bucket{{{}le=\"{}\"}} {}",
+                            name, prefix, bound, total
+                        );
+                    }
+
+                    let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, prefix, count);
+
+                    let labels = match labels.is_empty() {
+                        true => String::new(),
+                        false => format!("{{{}}}", labels),
+                    };
+                    let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+                    let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+                }
+            }
+        }
+    }
+
+    out
+}
+
+/// Whether orchestration should send traffic our way. Tasks that are down are returned otherwise.
+pub fn ready() -> Result<(), Vec<String>> {
+    let samples = SAMPLES.lock().unwrap_or_else(|e| e.into_inner());
+
+    let started = matches!(
+        samples.get(READY).and_then(|s| s.get("")),
+        Some(Sample::Value(v)) if v.get() >= 1.0
+    );
+
+    if !started {
+        return Err(vec!["server".to_string()]);
+    }
+
+    let down: Vec<String> = samples
+        .get(TASK_UP)
+        .into_iter()
+        .flatten()
+        .filter(|(_, s)| matches!(s, Sample::Value(v) if v.get() < 1.0))
+        .map(|(labels, _)| labels.clone())
+        .collect();
+
+    match down.is_empty() {
+        true => Ok(()),
+        false => Err(down),
+    }
+}
+
+/// Status line, content type and body for a path
+fn respond(path: &str) -> (&'static str, &'static str, String) {
+    const TEXT: &str = "text/plain; charset=utf-8";
+
+    match path {
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", render()),
+        "/health" => ("200 OK", TEXT, "ok\n".to_string()),
+        "/ready" => match ready() {
+            Ok(()) => ("200 OK", TEXT, "ready\n".to_string()),
+            Err(down) => (
+                "503 Service Unavailable",
+                TEXT,
+                format!("not ready: {}\n", down.join(" ")),
+            ),
+        },
+        _ => ("404 Not Found", TEXT, "not found\n".to_string()),
+    }
+}
+
+/// Answer a single request and close the connection
+async fn handle_connection(mut stream: TcpStream) -> Result<(), Error> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+
+    // Only the request line matters. Read until the end of the headers.
+    let read = async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+            let n = stream.read(&mut buf).await?;
+
+            if n == 0 || request.len() + n > MAX_REQUEST_BYTES {
+                break;
+            }
+
+            request.extend_from_slice(&buf[..n]);
+        }
+
+        Ok::<(), std::io::Error>(())
+    };
+
+    if tokio::time::timeout(Duration::from_millis(REQUEST_TIMEOUT_MS), read)
+        .await
+        .is_err()
+    {
+        return Err(Error::CustomError("Metrics request timed out.".to_string()));
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.lines().next().unwrap_or_default().split(' ');
+
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => respond(path.split('?').next().unwrap_or_default()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain; charset=utf-8",
+            "only GET is supported\n".to_string(),
+        ),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+/// Serve `/metrics`, `/health` and `/ready` on the port from `[metrics]`
+pub async fn run(settings: &settings::Metrics) -> Result<(), Error> {
+    let listener = TcpListener::bind(format!("{}:{}", settings.address, settings.port)).await?;
+
+    serve(&listener).await
+}
+
+/// Same as `run` but uses a listener that's already bound
+pub async fn serve(listener: &TcpListener) -> Result<(), Error> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        tokio::task::spawn(async move {
+            if let Err(e) = handle_connection(stream).await {
+                log::debug!("Metrics request failed. Err: {}", e);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_success() {
+        inc(OTA_REQUESTS, &[("cmd", "check \"x\"")]);
+        observe(INFLUX_WRITE_SECONDS, &[("test", "render")], 0.02);
+        observe(INFLUX_WRITE_SECONDS, &[("test", "render")], 20.0);
+
+        let out = render();
+
+        assert!(out.contains("# TYPE pyrinas_ota_requests_total counter"));
+        assert!(out.contains("pyrinas_ota_requests_total{cmd=\"check \\\"x\\\"\"} 1"));
+
+        let bucket = "pyrinas_influx_write_duration_seconds_bucket{test=\"render\",";
+        assert!(out.contains(&format!("{}le=\"0.01\"}} 0", bucket)));
+        assert!(out.contains(&format!("{}le=\"0.025\"}} 1", bucket)));
+        assert!(out.contains(&format!("{}le=\"10\"}} 1", bucket)));
+        assert!(out.contains(&format!("{}le=\"+Inf\"}} 2", bucket)));
+        assert!(out.contains("pyrinas_influx_write_duration_seconds_count{test=\"render\"} 2"));
+    }
+
+    #[test]
+    fn handle_success() {
+        let labels = [("test", "handle")];
+        let handle = handle(ADMIN_REQUESTS, &labels);
+
+        // Shares the value with the name and labels it was looked up with
+        handle.inc();
+        add(ADMIN_REQUESTS, &labels, 2.0);
+        assert_eq!(handle.get(), 3.0);
+
+        handle.set(1.5);
+        assert_eq!(get(ADMIN_REQUESTS, &labels), Some(1.5));
+    }
+}
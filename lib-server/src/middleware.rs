@@ -0,0 +1,202 @@
+// System related
+use std::collections::HashMap;
+
+// Local lib related
+use crate::{settings, Event};
+use pyrinas_shared::BrokerEventCount;
+
+/// What a middleware decided to do with an event
+#[derive(Debug)]
+pub enum Action {
+    /// Pass the (possibly modified) event to the next middleware
+    Continue(Event),
+    /// Drop the event
+    Reject(String),
+    /// Replace the event with any number of events. Each goes through the rest of the chain.
+    FanOut(Vec<Event>),
+}
+
+/// Inspects every event before the broker routes it.
+///
+/// Registration and shutdown events skip the chain.
+pub trait Middleware: Send {
+    /// Used to place it with `broker.middleware` and in logs
+    fn name(&self) -> &str;
+
+    fn handle(&mut self, event: Event) -> Action;
+
+    /// Counts included in `BrokerStatsResponse.events`
+    fn stats(&self) -> Vec<BrokerEventCount> {
+        Vec::new()
+    }
+}
+
+/// Logs every event at debug level
+pub struct Logging;
+
+impl Middleware for Logging {
+    fn name(&self) -> &str {
+        "logging"
+    }
+
+    fn handle(&mut self, event: Event) -> Action {
+        log::debug!("{:?}", event);
+        Action::Continue(event)
+    }
+}
+
+/// Counts events by kind
+#[derive(Default)]
+pub struct Metrics {
+    counts: HashMap<&'static str, u64>,
+}
+
+impl Middleware for Metrics {
+    fn name(&self) -> &str {
+        "metrics"
+    }
+
+    fn handle(&mut self, event: Event) -> Action {
+        *self.counts.entry(event.kind()).or_default() += 1;
+        Action::Continue(event)
+    }
+
+    fn stats(&self) -> Vec<BrokerEventCount> {
+        get_counts(self.counts.iter().map(|(k, v)| (k.to_string(), *v)))
+    }
+}
+
+/// Rejects events larger than `max_bytes` once CBOR encoded
+pub struct SizeLimit {
+    max_bytes: usize,
+}
+
+impl SizeLimit {
+    pub fn new(max_bytes: usize) -> Self {
+        Self { max_bytes }
+    }
+}
+
+impl Middleware for SizeLimit {
+    fn name(&self) -> &str {
+        "size_limit"
+    }
+
+    fn handle(&mut self, event: Event) -> Action {
+        match serde_cbor::to_vec(&event) {
+            Ok(v) if v.len() > self.max_bytes => {
+                Action::Reject(format!("{} bytes is over {}", v.len(), self.max_bytes))
+            }
+            Ok(_) => Action::Continue(event),
+            Err(e) => Action::Reject(format!("Unable to encode. Err: {}", e)),
+        }
+    }
+}
+
+/// Middleware in the order events go through them
+#[derive(Default)]
+pub struct Chain {
+    middleware: Vec<Box<dyn Middleware>>,
+    rejected: HashMap<String, u64>,
+}
+
+impl Chain {
+    /// Built-ins and `custom` middleware in the order set by `broker.middleware`.
+    ///
+    /// Custom middleware that isn't listed there goes last.
+    pub fn new(settings: &settings::Broker, custom: Vec<Box<dyn Middleware>>) -> Chain {
+        let mut custom: Vec<Option<Box<dyn Middleware>>> = custom.into_iter().map(Some).collect();
+        let mut chain = Chain::default();
+
+        for name in settings.middleware.iter() {
+            let middleware: Box<dyn Middleware> = match name.as_str() {
+                "logging" => Box::new(Logging),
+                "metrics" => Box::new(Metrics::default()),
+                "size_limit" => Box::new(SizeLimit::new(settings.max_event_bytes)),
+                _ => match custom
+                    .iter_mut()
+                    .find(|m| m.as_ref().map(|m| m.name() == name).unwrap_or(false))
+                    .and_then(|m| m.take())
+                {
+                    Some(m) => m,
+                    None => {
+                        log::warn!("Unknown middleware {}. Skipping.", name);
+                        continue;
+                    }
+                },
+            };
+
+            chain.push(middleware);
+        }
+
+        for middleware in custom.into_iter().flatten() {
+            chain.push(middleware);
+        }
+
+        chain
+    }
+
+    pub fn push(&mut self, middleware: Box<dyn Middleware>) {
+        self.middleware.push(middleware);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.middleware.is_empty()
+    }
+
+    /// Run an event through every middleware. Returns what's left to route.
+    pub fn process(&mut self, event: Event) -> Vec<Event> {
+        let mut events = Vec::new();
+        self.process_from(0, event, &mut events);
+
+        events
+    }
+
+    fn process_from(&mut self, start: usize, event: Event, events: &mut Vec<Event>) {
+        let mut event = event;
+
+        for index in start..self.middleware.len() {
+            let middleware = &mut self.middleware[index];
+
+            event = match middleware.handle(event) {
+                Action::Continue(e) => e,
+                Action::Reject(reason) => {
+                    log::warn!("Event rejected by {}. {}", middleware.name(), reason);
+                    *self
+                        .rejected
+                        .entry(middleware.name().to_string())
+                        .or_default() += 1;
+                    return;
+                }
+                Action::FanOut(fanned) => {
+                    for e in fanned {
+                        self.process_from(index + 1, e, events);
+                    }
+                    return;
+                }
+            };
+        }
+
+        events.push(event);
+    }
+
+    /// Counts reported by each middleware
+    pub fn events(&self) -> Vec<BrokerEventCount> {
+        self.middleware.iter().flat_map(|m| m.stats()).collect()
+    }
+
+    /// Events rejected by each middleware
+    pub fn rejected(&self) -> Vec<BrokerEventCount> {
+        get_counts(self.rejected.iter().map(|(k, v)| (k.clone(), *v)))
+    }
+}
+
+/// Sorted by name
+fn get_counts<I: Iterator<Item = (String, u64)>>(counts: I) -> Vec<BrokerEventCount> {
+    let mut counts: Vec<BrokerEventCount> = counts
+        .map(|(name, count)| BrokerEventCount { name, count })
+        .collect();
+    counts.sort_by(|a, b| a.name.cmp(&b.name));
+
+    counts
+}
@@ -1,68 +1,242 @@
 // Sytem related
 use log;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
 
 // async related
-use flume::{unbounded, Sender};
+use flume::Sender;
+
+// Serde
+use serde::{Deserialize, Serialize};
 
 // Shared
+use crate::device::Registry;
+use crate::encoding::{self, Encoding};
 use crate::telemetry;
-use crate::Event;
+use crate::topic::{Direction, Topic, TopicKind, TopicScheme};
+use crate::{broker, metrics, settings, CommandAck, CommandId, DeviceCommand, Error, Event};
 
 // Mqttd
 use librumqttd::async_locallink::{AsyncLinkRx, AsyncLinkTx};
 
-pub async fn mqtt_run(rx: &mut AsyncLinkRx, broker_sender: Sender<Event>) {
-    // Loop for recieving messages
-    loop {
-        let msg = match rx.recv().await {
-            Ok(m) => m,
-            Err(e) => {
-                log::warn!("mqtt error: {}", e);
-                continue;
-            }
+/// Log target for rejected messages
+pub const SECURITY_LOG: &str = "pyrinas_server::security";
+
+/// Check that whoever published a message is the device named in its topic.
+///
+/// `publisher` is the client id or certificate CN reported by the MQTT broker. Messages without
+/// one are only rejected if `require` is set.
+pub fn verify_identity(publisher: Option<&str>, topic: &Topic, require: bool) -> Result<(), Error> {
+    match publisher {
+        Some(p) if p == topic.uid => Ok(()),
+        Some(p) => Err(Error::Unauthorized(format!(
+            "{} published as {}",
+            p, topic.uid
+        ))),
+        None if require => Err(Error::Unauthorized("publisher unknown".to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Encoding each device last published with, by kind. Replies and messages pushed to the device
+/// later use the same one. Devices that use CBOR aren't kept.
+#[derive(Debug, Clone, Default)]
+pub struct Encodings {
+    devices: Arc<RwLock<HashMap<(String, TopicKind), Encoding>>>,
+}
+
+impl Encodings {
+    pub fn get(&self, uid: &str, kind: TopicKind) -> Encoding {
+        let devices = self.devices.read().unwrap();
+
+        devices
+            .get(&(uid.to_string(), kind))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, uid: &str, kind: TopicKind, encoding: Encoding) {
+        let mut devices = self.devices.write().unwrap();
+
+        match encoding {
+            Encoding::Cbor => devices.remove(&(uid.to_string(), kind)),
+            _ => devices.insert((uid.to_string(), kind), encoding),
         };
+    }
+}
 
-        // Get the uid and topic
-        let mut topic = msg.topic.split('/');
+/// Commands and acks as JSON devices see them. `msg` is a value rather than CBOR bytes.
+#[derive(Serialize, Deserialize)]
+struct JsonCommand {
+    id: CommandId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<i32>,
+    #[serde(default)]
+    msg: Option<serde_cbor::Value>,
+}
 
-        let device_id = match topic.next() {
-            Some(t) => t,
-            None => {
-                log::warn!("Unable get uid.");
-                continue;
-            }
+fn decode_ack(encoding: Encoding, payload: &[u8]) -> Result<CommandAck, Error> {
+    match encoding {
+        Encoding::Cbor => encoding.decode(payload),
+        Encoding::Json => {
+            let ack: JsonCommand = encoding.decode(payload)?;
+
+            Ok(CommandAck {
+                id: ack.id,
+                code: ack.code.unwrap_or_default(),
+                msg: match ack.msg {
+                    Some(m) => serde_cbor::to_vec(&m)?,
+                    None => Vec::new(),
+                },
+            })
+        }
+    }
+}
+
+fn encode_command(encoding: Encoding, command: &DeviceCommand) -> Result<Vec<u8>, Error> {
+    match encoding {
+        Encoding::Cbor => encoding.encode(command),
+        Encoding::Json => {
+            let msg = match command.msg.is_empty() {
+                true => None,
+                false => Some(serde_cbor::from_slice(&command.msg)?),
+            };
+
+            encoding.encode(&JsonCommand {
+                id: command.id,
+                code: None,
+                msg,
+            })
+        }
+    }
+}
+
+/// Checks and routes messages published by devices
+#[derive(Debug, Clone)]
+pub struct Inbound {
+    pub topics: TopicScheme,
+    /// Reject messages when the broker doesn't report who published them
+    pub require_identity: bool,
+    /// Used to refuse suspended and decommissioned devices. Not checked if `None`.
+    pub devices: Option<Registry>,
+    /// Encoding each device used. Share with `run` so replies match.
+    pub encodings: Encodings,
+    /// Schemas are looked up by the device type in `devices`
+    pub telemetry: settings::Telemetry,
+}
+
+impl Inbound {
+    pub fn new(settings: &settings::Mqtt) -> Inbound {
+        Inbound {
+            topics: TopicScheme::new(settings.prefix.clone()),
+            require_identity: settings.require_identity,
+            devices: None,
+            encodings: Default::default(),
+            telemetry: Default::default(),
+        }
+    }
+
+    /// Where telemetry is stored and the schemas it's checked against
+    pub fn with_telemetry(mut self, telemetry: settings::Telemetry) -> Inbound {
+        self.telemetry = telemetry;
+        self
+    }
+
+    /// Telemetry schema for the device's type, if it has one
+    fn schema(&self, uid: &str) -> Option<&HashMap<String, settings::FieldType>> {
+        let info = self.devices.as_ref()?.get(uid).ok()??;
+
+        self.telemetry.schemas.get(&info.device_type?.to_string())
+    }
+
+    /// Refuse messages from devices whose state doesn't allow them
+    pub fn with_registry(mut self, devices: Registry) -> Inbound {
+        self.devices = Some(devices);
+        self
+    }
+
+    /// Check the device's state in the registry. Devices that aren't registered are allowed.
+    fn verify_state(&self, uid: &str) -> Result<(), Error> {
+        let devices = match &self.devices {
+            Some(d) => d,
+            None => return Ok(()),
         };
 
-        let event_type = match topic.next() {
-            Some(t) => t,
-            None => {
-                log::warn!("Unable event type.");
-                continue;
+        match devices.get(uid)? {
+            Some(info) if !info.state.is_allowed() => {
+                Err(Error::Unauthorized(format!("{} is {}", uid, info.state)))
             }
-        };
+            _ => Ok(()),
+        }
+    }
 
-        let pub_sub = match topic.next() {
-            Some(t) => t,
-            None => {
-                log::warn!("Unable to get pub/sub.");
-                continue;
+    /// Route a message to the broker. See `verify_identity` for `publisher`.
+    pub async fn handle<P: AsRef<[u8]>>(
+        &self,
+        publisher: Option<&str>,
+        topic: &str,
+        payloads: &[P],
+        broker_sender: &Sender<Event>,
+    ) {
+        // Get the uid, kind and target
+        let topic = match self.topics.parse(topic) {
+            Ok(t) => t,
+            Err(e) => {
+                log::warn!("{}", e);
+                metrics::inc(metrics::MQTT_REJECTED, &[("reason", "topic")]);
+                return;
             }
         };
 
-        // Get the topic string after pub or sub
-        let targets: Vec<&str> = msg.topic.trim().split(pub_sub).collect();
-        let mut target: &str = "";
-        if let Some(t) = targets.last() {
-            target = t.trim_end_matches('/').trim_start_matches('/');
+        // Only handle messages from devices
+        if topic.direction != Direction::Publish {
+            log::debug!("Ignoring {}", topic);
+            return;
+        }
+
+        if let Err(e) = verify_identity(publisher, &topic, self.require_identity) {
+            log::warn!(target: SECURITY_LOG, "Rejected message on {}. {}", topic, e);
+            metrics::inc(metrics::MQTT_REJECTED, &[("reason", "identity")]);
+            return;
         }
 
+        if let Err(e) = self.verify_state(&topic.uid) {
+            log::warn!(target: SECURITY_LOG, "Refused message on {}. {}", topic, e);
+            metrics::inc(metrics::MQTT_REJECTED, &[("reason", "state")]);
+            return;
+        }
+
+        let device_id = topic.uid.as_str();
+        let target = topic.target.as_deref().unwrap_or_default();
+        let encoding = topic.encoding;
+
+        // Status payloads are plain text
+        if topic.kind != TopicKind::Status {
+            self.encodings.set(device_id, topic.kind, encoding);
+        }
+
+        // Status messages (including last wills) set presence themselves
+        if topic.kind != TopicKind::Status {
+            if let Err(e) = broker_sender
+                .send_async(Event::DeviceSeen(device_id.to_string()))
+                .await
+            {
+                log::error!("Unable to send DeviceSeen. Err: {}", e);
+            }
+        }
+
+        let kind = topic.kind.as_str();
+
         // Go over each payload
-        for payload in msg.payload {
-            match event_type {
-                "ota" => {
-                    // Get the telemetry data
-                    let res: Result<pyrinas_shared::OtaRequest, serde_cbor::error::Error> =
-                        serde_cbor::from_slice(&payload);
+        for payload in payloads {
+            count_message(topic.kind, "in");
+
+            match topic.kind {
+                TopicKind::Ota => {
+                    // Get the request
+                    let res: Result<pyrinas_shared::OtaRequest, Error> =
+                        encoding.decode(payload.as_ref());
 
                     // Match function to handle error
                     match res {
@@ -78,64 +252,193 @@ pub async fn mqtt_run(rx: &mut AsyncLinkRx, broker_sender: Sender<Event>) {
                                 .await
                                 .unwrap();
                         }
-                        Err(e) => log::error!("OTA decode error: {}", e),
+                        Err(e) => {
+                            log::error!("OTA decode error: {}", e);
+                            metrics::inc(metrics::DECODE_ERRORS, &[("kind", kind)]);
+                        }
                     }
                 }
-                "tel" => {
+                TopicKind::Telemetry => {
                     // Get the telemetry data
-                    let res: Result<telemetry::TelemetryData, serde_cbor::error::Error> =
-                        serde_cbor::from_slice(&payload);
+                    let res = encoding
+                        .decode(payload.as_ref())
+                        .and_then(telemetry::TelemetryData::from_value);
+
+                    let mut data = match res {
+                        Ok(d) => d,
+                        Err(e) => {
+                            log::error!("Telemetry decode error: {}", e);
+                            metrics::inc(metrics::DECODE_ERRORS, &[("kind", kind)]);
+                            continue;
+                        }
+                    };
 
-                    // Match function to handle error
-                    match res {
-                        Ok(n) => {
-                            log::debug!("{:?}", n);
+                    if let Some(schema) = self.schema(device_id) {
+                        for field in data.apply_schema(schema) {
+                            log::warn!("Dropped {} from {}. Wrong type.", field, device_id);
+                        }
+                    }
 
-                            // Create query
-                            let query = n
-                                .to_influx_data(device_id.to_string())
-                                .to_influx_query("telemetry".to_string());
+                    log::debug!("{:?}", data);
 
-                            // Send data to broker
-                            broker_sender
-                                .send_async(Event::InfluxDataSave(query))
-                                .await
-                                .unwrap();
+                    if data.fields.is_empty() {
+                        log::warn!("No telemetry fields from {}", device_id);
+                        continue;
+                    }
+
+                    let point = data.to_point(device_id, &self.telemetry.measurement);
+
+                    // Send data to broker
+                    broker_sender
+                        .send_async(Event::DataSave(Box::new(point)))
+                        .await
+                        .unwrap();
+                }
+                TopicKind::Status => {
+                    let online = match payload.as_ref() {
+                        b"online" => true,
+                        b"offline" => false,
+                        _ => {
+                            log::warn!("Unknown status from {}", device_id);
+                            continue;
                         }
-                        Err(e) => log::error!("Telemetry decode error: {}", e),
+                    };
+
+                    if let Err(e) = broker_sender
+                        .send_async(Event::DeviceConnection {
+                            uid: device_id.to_string(),
+                            online,
+                        })
+                        .await
+                    {
+                        log::error!("Unable to send DeviceConnection. Err: {}", e);
                     }
                 }
-                "app" => {
+                TopicKind::Shadow => {
+                    let state: serde_cbor::Value = match encoding.decode(payload.as_ref()) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            log::error!("Shadow decode error: {}", e);
+                            metrics::inc(metrics::DECODE_ERRORS, &[("kind", kind)]);
+                            continue;
+                        }
+                    };
+
+                    if let Err(e) = broker_sender
+                        .send_async(Event::ShadowReported {
+                            uid: device_id.to_string(),
+                            state: Box::new(state),
+                        })
+                        .await
+                    {
+                        log::error!("Unable to send ShadowReported. Err: {}", e);
+                    }
+                }
+                TopicKind::Command => {
+                    let ack = match decode_ack(encoding, payload.as_ref()) {
+                        Ok(a) => a,
+                        Err(e) => {
+                            log::error!("Command ack decode error: {}", e);
+                            metrics::inc(metrics::DECODE_ERRORS, &[("kind", kind)]);
+                            continue;
+                        }
+                    };
+
+                    if let Err(e) = broker_sender
+                        .send_async(Event::CommandAck {
+                            uid: device_id.to_string(),
+                            ack,
+                        })
+                        .await
+                    {
+                        log::error!("Unable to send CommandAck. Err: {}", e);
+                    }
+                }
+                TopicKind::Application => {
                     log::debug!("app: from:{:?}", device_id.to_string());
 
+                    // Applications always get CBOR
+                    let msg = match encoding::transcode(payload.as_ref(), encoding, Encoding::Cbor)
+                    {
+                        Ok(m) => m,
+                        Err(e) => {
+                            log::error!("Application decode error: {}", e);
+                            metrics::inc(metrics::DECODE_ERRORS, &[("kind", kind)]);
+                            continue;
+                        }
+                    };
+
                     // Send data to broker
                     broker_sender
                         .send_async(Event::ApplicationRequest(pyrinas_shared::ApplicationData {
                             uid: device_id.to_string(),
                             target: target.to_string(),
-                            msg: payload.to_vec(),
+                            msg,
                         }))
                         .await
                         .unwrap();
                 }
-                _ => {}
             }
         }
     }
 }
 
-pub async fn run(tx: &mut AsyncLinkTx, broker_sender: Sender<Event>) {
-    // Get the sender/reciever associated with this particular task
-    let (sender, reciever) = unbounded::<Event>();
+thread_local! {
+    /// `MQTT_MESSAGES` by kind and direction. Looked up once per thread so counting a message
+    /// doesn't lock or allocate.
+    static MESSAGES: RefCell<HashMap<(TopicKind, &'static str), metrics::Handle>> =
+        RefCell::new(HashMap::new());
+}
+
+/// Count a message from (`in`) or to (`out`) a device
+fn count_message(kind: TopicKind, direction: &'static str) {
+    MESSAGES.with(|messages| {
+        messages
+            .borrow_mut()
+            .entry((kind, direction))
+            .or_insert_with(|| {
+                let labels = [("kind", kind.as_str()), ("direction", direction)];
+                metrics::handle(metrics::MQTT_MESSAGES, &labels)
+            })
+            .inc();
+    });
+}
+
+/// Log and count a message sent to a device
+fn published(kind: TopicKind, sub_topic: &str) {
+    log::debug!("Published to {}", sub_topic);
+    count_message(kind, "out");
+}
 
+pub async fn mqtt_run(rx: &mut AsyncLinkRx, inbound: &Inbound, broker_sender: Sender<Event>) {
+    // Loop for recieving messages
+    loop {
+        let msg = match rx.recv().await {
+            Ok(m) => m,
+            Err(e) => {
+                log::warn!("mqtt error: {}", e);
+                continue;
+            }
+        };
+
+        // The local link doesn't say which client published the message. The server doesn't
+        // start like this with `require_identity` set.
+        inbound
+            .handle(None, &msg.topic, &msg.payload, &broker_sender)
+            .await;
+    }
+}
+
+/// Publish messages for devices. Each is encoded the way the device last published on that kind
+/// of topic, using the `Encodings` shared with `Inbound`.
+pub async fn run(
+    tx: &mut AsyncLinkTx,
+    topics: &TopicScheme,
+    encodings: &Encodings,
+    broker_sender: Sender<Event>,
+) {
     // Register this task
-    broker_sender
-        .send_async(Event::NewRunner {
-            name: "mqtt".to_string(),
-            sender: sender.clone(),
-        })
-        .await
-        .unwrap();
+    let reciever = broker::register("mqtt", &broker_sender).await.unwrap();
 
     while let Ok(event) = reciever.recv_async().await {
         // Only process OtaNewPackage eventss
@@ -143,14 +446,62 @@ pub async fn run(tx: &mut AsyncLinkTx, broker_sender: Sender<Event>) {
             Event::ApplicationResponse(data) => {
                 log::debug!("Event::ApplicationResponse");
 
+                let encoding = encodings.get(&data.uid, TopicKind::Application);
+
                 // Generate topic
-                let sub_topic = format!("{}/app/s/{}", data.uid, data.target);
+                let sub_topic = topics
+                    .format(&Topic::app_response(&data.uid, &data.target).with_encoding(encoding));
+
+                let res = match encoding::transcode(&data.msg, Encoding::Cbor, encoding) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        log::error!("Unable to encode response for {}. Error: {}", data.uid, e);
+                        continue;
+                    }
+                };
 
                 // Publish to the UID in question
-                if let Err(e) = tx.publish(&sub_topic, false, data.msg).await {
+                if let Err(e) = tx.publish(&sub_topic, false, res).await {
+                    log::error!("Unable to publish to {}. Error: {}", sub_topic, e);
+                } else {
+                    published(TopicKind::Application, &sub_topic);
+                }
+            }
+            Event::CommandPublish { uid, name, command } => {
+                let encoding = encodings.get(&uid, TopicKind::Command);
+                let sub_topic = topics.format(&Topic::command(&uid, &name).with_encoding(encoding));
+
+                let res = match encode_command(encoding, &command) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        log::error!("Unable to encode command for {}. Error: {}", uid, e);
+                        continue;
+                    }
+                };
+
+                if let Err(e) = tx.publish(&sub_topic, false, res).await {
                     log::error!("Unable to publish to {}. Error: {}", sub_topic, e);
                 } else {
-                    log::debug!("Published to {}", sub_topic);
+                    published(TopicKind::Command, &sub_topic);
+                }
+            }
+            Event::ShadowDelta { uid, delta } => {
+                let encoding = encodings.get(&uid, TopicKind::Shadow);
+                let sub_topic = topics.format(&Topic::shadow_delta(&uid).with_encoding(encoding));
+
+                let res = match encoding.encode(&delta) {
+                    Ok(r) => r,
+                    Err(e) => {
+                        log::error!("Unable to encode delta for {}. Error: {}", uid, e);
+                        continue;
+                    }
+                };
+
+                // Retained so devices get the latest delta when they wake up and subscribe
+                if let Err(e) = tx.publish(&sub_topic, true, res).await {
+                    log::error!("Unable to publish to {}. Error: {}", sub_topic, e);
+                } else {
+                    published(TopicKind::Shadow, &sub_topic);
                 }
             }
             Event::OtaDownloadResponse(mut download) => {
@@ -167,11 +518,23 @@ pub async fn run(tx: &mut AsyncLinkTx, broker_sender: Sender<Event>) {
                 // Setting to none no matter what
                 download.device_uid = None;
 
+                let encoding = encodings.get(&device_uid, TopicKind::Ota);
+
                 // Generate topic
-                let sub_topic = format!("{}/ota/s/d", device_uid);
+                let sub_topic =
+                    topics.format(&Topic::ota_download(&device_uid).with_encoding(encoding));
 
                 // Encode
-                let res = minicbor::to_vec(&download).unwrap();
+                let res = match encoding {
+                    Encoding::Cbor => minicbor::to_vec(&download).unwrap(),
+                    Encoding::Json => match encoding.encode(&download) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            log::error!("Unable to encode download. Error: {}", e);
+                            continue;
+                        }
+                    },
+                };
 
                 log::debug!("Publishing message to {}", &sub_topic);
 
@@ -179,37 +542,45 @@ pub async fn run(tx: &mut AsyncLinkTx, broker_sender: Sender<Event>) {
                 if let Err(e) = tx.publish(&sub_topic, false, res).await {
                     log::error!("Unable to publish to {}. Error: {}", sub_topic, e);
                 } else {
-                    log::debug!("Published to {}", sub_topic);
+                    published(TopicKind::Ota, &sub_topic);
                 }
             }
             Event::OtaResponse(update) => {
                 log::debug!("mqtt_run: Event::OtaResponse");
 
-                // Depending on version, convert appropriately!
-                let (res, device_uid) = {
-                    // Get the package. Subtitute with empty one if not valid.
-                    let res = match update.package {
-                        Some(mut p) => {
-                            log::debug!("{:?}", p);
-                            p.file = None;
-                            serde_cbor::ser::to_vec_packed(&p).unwrap()
-                        }
-                        None => Vec::new(),
-                    };
+                let device_uid = match update.device_uid {
+                    Some(uid) => uid,
+                    None => {
+                        log::warn!("Device ID unknown");
+                        continue;
+                    }
+                };
 
-                    let device_uid = match update.device_uid {
-                        Some(uid) => uid,
-                        None => {
-                            log::warn!("Device ID unknown");
+                let encoding = encodings.get(&device_uid, TopicKind::Ota);
+
+                // Get the package. Subtitute with empty one if not valid.
+                let package = update.package.map(|mut p| {
+                    log::debug!("{:?}", p);
+                    p.file = None;
+                    p
+                });
+
+                // Depending on encoding, convert appropriately!
+                let res = match (encoding, package) {
+                    (Encoding::Cbor, Some(p)) => serde_cbor::ser::to_vec_packed(&p).unwrap(),
+                    (Encoding::Cbor, None) => Vec::new(),
+                    (Encoding::Json, p) => match encoding.encode(&p) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            log::error!("Unable to encode package. Error: {}", e);
                             continue;
                         }
-                    };
-
-                    (res, device_uid)
+                    },
                 };
 
                 // Generate topic
-                let sub_topic = format!("{}/ota/s", device_uid);
+                let sub_topic =
+                    topics.format(&Topic::ota_response(&device_uid).with_encoding(encoding));
 
                 log::debug!("Publishing message to {}", &sub_topic);
 
@@ -217,7 +588,7 @@ pub async fn run(tx: &mut AsyncLinkTx, broker_sender: Sender<Event>) {
                 if let Err(e) = tx.publish(&sub_topic, false, res).await {
                     log::error!("Unable to publish to {}. Error: {}", sub_topic, e);
                 } else {
-                    log::debug!("Published to {}", sub_topic);
+                    published(TopicKind::Ota, &sub_topic);
                 }
             }
             _ => (),
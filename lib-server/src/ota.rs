@@ -1,10 +1,12 @@
 // async Related
-use flume::{unbounded, Sender};
+use flume::Sender;
 
 // Local lib related
-use crate::{settings, Event};
+use crate::{acknowledge, broker, metrics, settings, Event};
 use pyrinas_shared::ota::v2::{OTADownload, OTAUpdate};
-use pyrinas_shared::{OtaGroupListResponse, OtaImageListResponse, OtaRequestCmd};
+use pyrinas_shared::{
+    ManagmentDataType, OtaGroupListResponse, OtaImageListResponse, OtaRequestCmd,
+};
 
 // Error
 use crate::Error;
@@ -86,6 +88,13 @@ pub async fn process_event(broker_sender: &Sender<Event>, db: &OTADatabase, even
         Event::OtaRequest { device_uid, msg } => {
             log::debug!("sled_run: Event::OtaRequest");
 
+            let cmd = match msg.cmd {
+                OtaRequestCmd::Done => "done",
+                OtaRequestCmd::Check => "check",
+                OtaRequestCmd::DownloadBytes => "download_bytes",
+            };
+            metrics::inc(metrics::OTA_REQUESTS, &[("cmd", cmd)]);
+
             // Do something different depending on the situation
             match msg.cmd {
                 // Deletes the firmware association if all is well.
@@ -119,10 +128,9 @@ pub async fn process_event(broker_sender: &Sender<Event>, db: &OTADatabase, even
                     };
 
                     // Send it
-                    broker_sender
-                        .send_async(Event::OtaResponse(update))
-                        .await
-                        .unwrap();
+                    if let Err(e) = broker_sender.send_async(Event::OtaResponse(update)).await {
+                        log::error!("Unable to send OtaResponse. Err: {}", e);
+                    }
                 }
                 OtaRequestCmd::DownloadBytes => {
                     let update_id = match &msg.id {
@@ -192,107 +200,49 @@ pub async fn process_event(broker_sender: &Sender<Event>, db: &OTADatabase, even
                     data.len = data.data.len();
 
                     log::info!("Data: {} {} {}", data.start_pos, data.end_pos, data.len);
+                    metrics::add(metrics::OTA_BYTES, &[], data.len as f64);
 
                     // Send it
-                    broker_sender
+                    if let Err(e) = broker_sender
                         .send_async(Event::OtaDownloadResponse(data))
                         .await
-                        .unwrap();
+                    {
+                        log::error!("Unable to send OtaDownloadResponse. Err: {}", e);
+                    }
                 }
             }
         }
 
         Event::OtaUnlink {
+            id,
             device_id,
             group_id,
         } => {
-            // Match the different possiblities
-            match (&device_id, &group_id) {
-                (None, Some(g)) => {
-                    if dissociate_group(db, g).await.is_err() {
-                        log::warn!("Unable to disassociate group: {}", g);
-                    }
-                }
-                (Some(d), None) => {
-                    if dissociate_device(db, d).await.is_err() {
-                        log::warn!("Unable to disassociate device: {}", d);
-                    }
-                }
-                (Some(d), Some(g)) => {
-                    if dissociate_group(db, g).await.is_err() {
-                        log::warn!("Unable to disassociate group: {}", g);
-                    }
+            let result = unlink(db, device_id, group_id).await;
 
-                    if dissociate_device(db, d).await.is_err() {
-                        log::warn!("Unable to disassociate device: {}", d);
-                    }
-                }
-                _ => {}
-            };
+            if let Err(e) = &result {
+                log::warn!("{}", e);
+            }
+
+            acknowledge(broker_sender, id, ManagmentDataType::UnlinkOta, result).await;
         }
         Event::OtaLink {
+            id,
             device_id,
             group_id,
             image_id,
         } => {
-            // Match the different possiblities
-            match (&device_id, &group_id, &image_id) {
-                (None, Some(group), Some(update)) => {
-                    // Connect group -> image
-                    if let Err(err) = associate_group_with_update(db, group, update).await {
-                        log::error!(
-                            "Unable to associate {} with {}. Err: {}",
-                            group,
-                            update,
-                            err
-                        );
-                        return;
-                    }
-                }
-                (Some(device), Some(group), None) => {
-                    // connect device -> group
-                    if let Err(err) = associate_device_with_group(db, device, group).await {
-                        log::error!(
-                            "Unable to associate {} with {}. Err: {}",
-                            device,
-                            group,
-                            err
-                        );
-                        return;
-                    }
-                }
-                (Some(device), Some(group), Some(update)) => {
-                    // connect device -> group
-                    if let Err(err) = associate_device_with_group(db, device, group).await {
-                        log::error!(
-                            "Unable to associate {} with {}. Err: {}",
-                            device,
-                            group,
-                            err
-                        );
-                        return;
-                    }
+            let result = link(db, device_id, group_id, image_id).await;
+            let linked = result.is_ok();
 
-                    // connect group -> image
-                    if let Err(err) = associate_group_with_update(db, group, update).await {
-                        log::error!(
-                            "Unable to associate {} with {}. Err: {}",
-                            group,
-                            update,
-                            err
-                        );
-                        return;
-                    }
-                }
-                _ => {
-                    log::warn!(
-                        "Unsupported associate command: {:?} {:?} {:?}",
-                        device_id,
-                        group_id,
-                        image_id
-                    );
-                    return;
-                }
+            if let Err(e) = &result {
+                log::error!("{}", e);
+            }
+
+            acknowledge(broker_sender, id, ManagmentDataType::LinkOta, result).await;
+
+            if !linked {
+                return;
             }
 
             // If a device has been pushed, send that device the update
@@ -319,41 +269,41 @@ pub async fn process_event(broker_sender: &Sender<Event>, db: &OTADatabase, even
                 };
 
                 // Notify mqtt to send update!
-                broker_sender
-                    .send_async(Event::OtaResponse(update))
-                    .await
-                    .unwrap();
+                if let Err(e) = broker_sender.send_async(Event::OtaResponse(update)).await {
+                    log::error!("Unable to send OtaResponse. Err: {}", e);
+                }
             }
         }
         // Process OtaNewPackage events
-        Event::OtaNewPackage(update) => {
+        Event::OtaNewPackage(id, update) => {
             log::debug!("sled_run: Event::OtaNewPackage");
 
             log::debug!("{:?}", update);
 
             // Save the OTA package to database
-            if let Err(e) = save_ota_update(db, update).await {
+            let result = save_ota_update(db, update).await;
+
+            if let Err(e) = &result {
                 log::error!("Unable to save OTA package. Error: {}", e);
             }
+
+            acknowledge(broker_sender, id, ManagmentDataType::AddOta, result).await;
         }
-        Event::OtaDeletePackage(update_id) => {
-            match update_id.as_str() {
+        Event::OtaDeletePackage(id, update_id) => {
+            let result = match update_id.as_str() {
                 // Delete all option
-                "*" => {
-                    if let Err(e) = delete_all_ota_data(db).await {
-                        log::warn!("Unable to delete all ota data. Err: {}", e);
-                    }
-                }
+                "*" => delete_all_ota_data(db).await,
                 // Delete a signle update
-                _ => {
-                    // Delete by ID
-                    if let Err(e) = delete_ota_package(db, update_id).await {
-                        log::warn!("Unable to remove ota package for {}. Err: {}", update_id, e);
-                    };
-                }
+                _ => delete_ota_package(db, update_id).await,
             };
+
+            if let Err(e) = &result {
+                log::warn!("Unable to remove ota package for {}. Err: {}", update_id, e);
+            }
+
+            acknowledge(broker_sender, id, ManagmentDataType::RemoveOta, result).await;
         }
-        Event::OtaUpdateImageListRequest() => {
+        Event::OtaUpdateImageListRequest(id) => {
             let mut response = OtaImageListResponse { images: Vec::new() };
 
             for image in db.images.into_iter().flatten() {
@@ -381,12 +331,17 @@ pub async fn process_event(broker_sender: &Sender<Event>, db: &OTADatabase, even
             }
 
             // Notify mqtt to send update!
-            broker_sender
-                .send_async(Event::OtaUpdateImageListRequestResponse(response))
+            if let Err(e) = broker_sender
+                .send_async(Event::OtaUpdateImageListRequestResponse(*id, response))
                 .await
-                .unwrap();
+            {
+                log::error!(
+                    "Unable to send OtaUpdateImageListRequestResponse. Err: {}",
+                    e
+                );
+            }
         }
-        Event::OtaUpdateGroupListRequest() => {
+        Event::OtaUpdateGroupListRequest(id) => {
             let mut response = OtaGroupListResponse { groups: Vec::new() };
 
             for image in db.groups.into_iter().flatten() {
@@ -403,10 +358,15 @@ pub async fn process_event(broker_sender: &Sender<Event>, db: &OTADatabase, even
             }
 
             // Notify mqtt to send update!
-            broker_sender
-                .send_async(Event::OtaUpdateGroupListRequestResponse(response))
+            if let Err(e) = broker_sender
+                .send_async(Event::OtaUpdateGroupListRequestResponse(*id, response))
                 .await
-                .unwrap();
+            {
+                log::error!(
+                    "Unable to send OtaUpdateGroupListRequestResponse. Err: {}",
+                    e
+                );
+            }
         }
         _ => (),
     }
@@ -414,26 +374,98 @@ pub async fn process_event(broker_sender: &Sender<Event>, db: &OTADatabase, even
 
 // Only requires a sender. No response necessary here... yet.
 pub async fn run(settings: &settings::Ota, broker_sender: Sender<Event>) {
-    // Get the sender/reciever associated with this particular task
-    let (sender, reciever) = unbounded::<Event>();
+    // Open the DB
+    let sled_db = sled::open(&settings.db_path).expect("Error opening sled db.");
+
+    run_with_db(sled_db, broker_sender).await;
+}
 
+/// Same as `run` but uses a database that's already open
+pub async fn run_with_db(sled_db: sled::Db, broker_sender: Sender<Event>) {
     // Register this task
-    broker_sender
-        .send_async(Event::NewRunner {
-            name: "ota".to_string(),
-            sender: sender.clone(),
-        })
-        .await
-        .unwrap();
+    let reciever = broker::register("ota", &broker_sender).await.unwrap();
 
-    // Open the DB
-    let db = sled::open(&settings.db_path).expect("Error opening sled db.");
-    let db = init_trees(&db).expect("Unable to create OTA db trees.");
+    let db = init_trees(&sled_db).expect("Unable to create OTA db trees.");
 
     // Wait for event on reciever
     while let Ok(event) = reciever.recv_async().await {
         process_event(&broker_sender, &db, &event).await;
     }
+
+    // Broker has stopped
+    if let Err(e) = sled_db.flush_async().await {
+        log::error!("Unable to flush OTA db. Err: {}", e);
+    }
+}
+
+/// Link device -> group and/or group -> image
+async fn link(
+    db: &OTADatabase,
+    device_id: &Option<String>,
+    group_id: &Option<String>,
+    image_id: &Option<String>,
+) -> Result<(), Error> {
+    let associate_err = |a: &str, b: &str, e: Error| {
+        Error::CustomError(format!("Unable to associate {} with {}. Err: {}", a, b, e))
+    };
+
+    // Match the different possiblities
+    match (device_id, group_id, image_id) {
+        (None, Some(group), Some(update)) => {
+            // Connect group -> image
+            associate_group_with_update(db, group, update)
+                .await
+                .map_err(|e| associate_err(group, update, e))
+        }
+        (Some(device), Some(group), None) => {
+            // connect device -> group
+            associate_device_with_group(db, device, group)
+                .await
+                .map_err(|e| associate_err(device, group, e))
+        }
+        (Some(device), Some(group), Some(update)) => {
+            // connect device -> group
+            associate_device_with_group(db, device, group)
+                .await
+                .map_err(|e| associate_err(device, group, e))?;
+
+            // connect group -> image
+            associate_group_with_update(db, group, update)
+                .await
+                .map_err(|e| associate_err(group, update, e))
+        }
+        _ => Err(Error::CustomError(format!(
+            "Unsupported associate command: {:?} {:?} {:?}",
+            device_id, group_id, image_id
+        ))),
+    }
+}
+
+/// Unlink device and/or group
+async fn unlink(
+    db: &OTADatabase,
+    device_id: &Option<String>,
+    group_id: &Option<String>,
+) -> Result<(), Error> {
+    let group_err = |g: &str, e: Error| {
+        Error::CustomError(format!("Unable to disassociate group: {}. Err: {}", g, e))
+    };
+    let device_err = |d: &str, e: Error| {
+        Error::CustomError(format!("Unable to disassociate device: {}. Err: {}", d, e))
+    };
+
+    // Match the different possiblities
+    match (device_id, group_id) {
+        (None, Some(g)) => dissociate_group(db, g).await.map_err(|e| group_err(g, e)),
+        (Some(d), None) => dissociate_device(db, d).await.map_err(|e| device_err(d, e)),
+        (Some(d), Some(g)) => {
+            dissociate_group(db, g).await.map_err(|e| group_err(g, e))?;
+            dissociate_device(db, d).await.map_err(|e| device_err(d, e))
+        }
+        _ => Err(Error::CustomError(
+            "Device or group required to unlink.".to_string(),
+        )),
+    }
 }
 
 async fn dissociate_device(db: &OTADatabase, device_id: &str) -> Result<(), Error> {
@@ -0,0 +1,102 @@
+// Time related
+use chrono::{DateTime, TimeZone, Utc};
+
+// Serde
+use serde::{de::DeserializeOwned, Serialize};
+use serde_cbor::Value;
+
+// Local lib related
+use crate::Error;
+use pyrinas_shared::SeriesPoint;
+
+/// `#[derive(PyrinasPayload)]`
+pub use pyrinas_derive::PyrinasPayload;
+
+/// An application payload that's stored as a data point. Usually derived:
+///
+/// ```ignore
+/// #[derive(Deserialize, PyrinasPayload)]
+/// #[pyrinas(measurement = "gps")]
+/// struct GpsReport {
+///     lat: f32,
+///     lng: f32,
+///     #[pyrinas(timestamp)]
+///     #[serde(deserialize_with = "from_ts")]
+///     ts: DateTime<Utc>,
+/// }
+/// ```
+pub trait PyrinasPayload {
+    /// Measurement the points are saved to
+    const MEASUREMENT: &'static str;
+
+    /// Add the payload's tags and fields to `point`
+    fn add_to(&self, point: &mut SeriesPoint) -> Result<(), Error>;
+
+    /// Unix timestamp (ms) of the payload, if it has one
+    fn time_ms(&self) -> Option<i64>;
+
+    /// Point tagged with the device `id`. Timestamped now if the payload isn't.
+    fn to_point(&self, uid: &str) -> Result<SeriesPoint, Error> {
+        let time_ms = self
+            .time_ms()
+            .unwrap_or_else(|| Utc::now().timestamp_millis());
+
+        let mut point = SeriesPoint::new(Self::MEASUREMENT, time_ms).tag("id", uid);
+        self.add_to(&mut point)?;
+
+        Ok(point)
+    }
+
+    /// Decode a CBOR payload and convert it in one go
+    fn decode(payload: &[u8], uid: &str) -> Result<(Self, SeriesPoint), Error>
+    where
+        Self: Sized + DeserializeOwned,
+    {
+        let payload: Self = serde_cbor::from_slice(payload)?;
+        let point = payload.to_point(uid)?;
+
+        Ok((payload, point))
+    }
+}
+
+/// Types that can be the `#[pyrinas(timestamp)]` of a payload
+pub trait PayloadTime {
+    /// Unix timestamp (ms)
+    fn time_ms(&self) -> i64;
+}
+
+impl<Tz: TimeZone> PayloadTime for DateTime<Tz> {
+    fn time_ms(&self) -> i64 {
+        self.timestamp_millis()
+    }
+}
+
+/// Already in ms
+impl PayloadTime for i64 {
+    fn time_ms(&self) -> i64 {
+        *self
+    }
+}
+
+/// Already in ms
+impl PayloadTime for u64 {
+    fn time_ms(&self) -> i64 {
+        *self as i64
+    }
+}
+
+/// Add a field to `point`. `None` values are left out.
+pub fn add_field<T: Serialize>(
+    point: &mut SeriesPoint,
+    name: &str,
+    value: &T,
+) -> Result<(), Error> {
+    match serde_cbor::value::to_value(value)? {
+        Value::Null => (),
+        value => {
+            point.fields.insert(name.to_string(), value);
+        }
+    }
+
+    Ok(())
+}
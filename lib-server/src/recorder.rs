@@ -0,0 +1,96 @@
+// System related
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+// Serializing events
+use serde::{Deserialize, Serialize};
+
+// Local lib related
+use crate::{Error, Event};
+
+/// A recorded broker event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    /// Unix timestamp (ms) of when the broker recieved the event
+    pub timestamp_ms: i64,
+    pub event: Event,
+}
+
+/// Appends broker events to a log file.
+///
+/// Each record is a big endian `u32` length followed by the CBOR encoded `Record`.
+pub struct Recorder {
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    /// Open (or create) a log file. New records are added to the end.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Recorder, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Recorder {
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Record an event using the current time
+    pub fn record(&mut self, event: &Event) -> Result<(), Error> {
+        // Registrations are specific to a run so they're not recorded
+        if let Event::NewRunner { .. } = event {
+            return Ok(());
+        }
+
+        let record = Record {
+            timestamp_ms: chrono::Utc::now().timestamp_millis(),
+            event: event.clone(),
+        };
+
+        let data = serde_cbor::to_vec(&record)?;
+
+        self.writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&data)?;
+
+        Ok(())
+    }
+
+    /// Write anything buffered to disk
+    pub fn flush(&mut self) -> Result<(), Error> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// Reads records back from a log file, oldest first
+pub struct Reader {
+    reader: BufReader<File>,
+}
+
+impl Reader {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Reader, Error> {
+        Ok(Reader {
+            reader: BufReader::new(File::open(path)?),
+        })
+    }
+}
+
+impl Iterator for Reader {
+    type Item = Result<Record, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut len = [0u8; 4];
+
+        // Clean end of file
+        match self.reader.read_exact(&mut len) {
+            Ok(()) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return None,
+            Err(e) => return Some(Err(e.into())),
+        };
+
+        let mut data = vec![0u8; u32::from_be_bytes(len) as usize];
+        if let Err(e) = self.reader.read_exact(&mut data) {
+            return Some(Err(e.into()));
+        }
+
+        Some(serde_cbor::from_slice(&data).map_err(|e| e.into()))
+    }
+}
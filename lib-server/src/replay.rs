@@ -0,0 +1,215 @@
+// System related
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+// Async Related
+use flume::{Receiver, Sender};
+use tokio::task;
+
+// Local lib related
+use crate::broker::{self, Registration};
+use crate::recorder::Reader;
+use crate::{command, device, ota, settings, shadow, BrokerStatsResponse, Error, Event};
+
+/// How long to wait for runners to register before replaying
+const REGISTER_TIMEOUT_MS: u64 = 10000;
+
+/// Runners that are replaced with a stub while replaying
+const STUBS: [&str; 2] = ["mqtt", "influx"];
+
+/// Events that come from outside the server (devices and admin clients).
+///
+/// Everything else is generated by runners and will be generated again while replaying.
+pub fn is_input(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::OtaRequest { .. }
+            | Event::ApplicationRequest(_)
+            | Event::ApplicationManagementRequest(_)
+            | Event::OtaNewPackage(..)
+            | Event::OtaDeletePackage(..)
+            | Event::OtaLink { .. }
+            | Event::OtaUnlink { .. }
+            | Event::OtaUpdateImageListRequest(_)
+            | Event::OtaUpdateGroupListRequest(_)
+            | Event::DeviceSeen(_)
+            | Event::DeviceConnection { .. }
+            | Event::DeviceListRequest(_)
+            | Event::DeviceGetRequest(..)
+            | Event::DeviceAdd(..)
+            | Event::DeviceUpdate(..)
+            | Event::DeviceRemove(..)
+            | Event::ShadowReported { .. }
+            | Event::ShadowDesired(..)
+            | Event::ShadowGetRequest(..)
+            | Event::CommandSend { .. }
+            | Event::CommandGetRequest(..)
+            | Event::CommandAck { .. }
+            | Event::SeriesQueryRequest(..)
+            | Event::InfluxDataRequest { .. }
+    )
+}
+
+/// Send the input events from a recording to the broker, in order.
+///
+/// Returns the number of events sent.
+pub async fn feed<P: AsRef<Path>>(path: P, broker_sender: &Sender<Event>) -> Result<usize, Error> {
+    let mut count = 0;
+
+    for record in Reader::open(path)? {
+        let record = record?;
+
+        if !is_input(&record.event) {
+            continue;
+        }
+
+        log::debug!("Replaying {} {:?}", record.timestamp_ms, record.event);
+
+        broker_sender.send_async(record.event).await?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Stand in for a runner that talks to the outside world. Logs everything sent to it.
+async fn stub(name: &'static str, broker_sender: Sender<Event>) {
+    let reciever = match broker::register(name, &broker_sender).await {
+        Ok(r) => r,
+        Err(e) => {
+            log::error!("Unable to register {} stub. Err: {}", name, e);
+            return;
+        }
+    };
+
+    while let Ok(event) = reciever.recv_async().await {
+        log::info!("{}: {:?}", name, event);
+    }
+}
+
+/// Get broker stats. Logs anything else that was sent to the admin runner.
+async fn get_stats(
+    broker_sender: &Sender<Event>,
+    sock: &Registration,
+) -> Result<BrokerStatsResponse, Error> {
+    broker_sender
+        .send_async(Event::BrokerStatsRequest(None))
+        .await?;
+
+    loop {
+        match sock.recv_async().await? {
+            Event::BrokerStatsResponse(_, stats) => return Ok(stats),
+            event => log::info!("sock: {:?}", event),
+        }
+    }
+}
+
+/// Replay a recording made with `record_path` through the broker.
+///
+/// OTA runs as usual (point `ota.db_path` somewhere disposable) while the device registry, shadows
+/// and commands start out empty. MQTT and Influx are replaced by stubs that log what would have been sent. `runners` are
+/// the application's runners (i.e. `app`), which need to be registered before anything is replayed.
+///
+/// Returns once every queue is empty.
+pub async fn run(
+    settings: Arc<settings::PyrinasSettings>,
+    path: String,
+    runners: &[&str],
+    broker_sender: Sender<Event>,
+    broker_reciever: Receiver<Event>,
+) -> Result<(), Error> {
+    // Don't record the replay or share databases with a running server
+    let mut broker_settings = settings.broker.clone();
+    broker_settings.record_path = None;
+    broker_settings.spill_path = None;
+    broker_settings.dead_letter = None;
+
+    task::spawn(broker::run(broker_settings, broker_reciever));
+
+    // Stand in for admin
+    let sock = broker::register("sock", &broker_sender).await?;
+
+    // Ota task
+    let task_sender = broker_sender.clone();
+    let task_settings = settings.clone();
+    task::spawn(async move {
+        ota::run(&task_settings.ota, task_sender).await;
+    });
+
+    // Device, shadow and command tasks. Nothing from before the recording is known.
+    let devices_db = sled::Config::new().temporary(true).open()?;
+    let registry = device::Registry::open(&devices_db)?;
+
+    let task_sender = broker_sender.clone();
+    let task_settings = settings.clone();
+    let task_registry = registry.clone();
+    task::spawn(async move {
+        device::run_with_registry(task_registry, &task_settings.devices, task_sender).await;
+    });
+
+    task::spawn(shadow::run_with_db(
+        devices_db.clone(),
+        broker_sender.clone(),
+    ));
+
+    let task_sender = broker_sender.clone();
+    let task_settings = settings.clone();
+    task::spawn(async move {
+        command::run_with_db(devices_db, registry, &task_settings.commands, task_sender).await;
+    });
+
+    for name in STUBS {
+        task::spawn(stub(name, broker_sender.clone()));
+    }
+
+    // Wait for everyone to register
+    let start = tokio::time::Instant::now();
+    loop {
+        let stats = get_stats(&broker_sender, &sock).await?;
+
+        let registered = [
+            "ota",
+            device::RUNNER_NAME,
+            shadow::RUNNER_NAME,
+            command::RUNNER_NAME,
+        ]
+        .iter()
+        .chain(STUBS.iter())
+        .chain(runners.iter())
+        .all(|name| stats.runners.iter().any(|r| &r.name == name));
+
+        if registered {
+            break;
+        }
+
+        if start.elapsed() > Duration::from_millis(REGISTER_TIMEOUT_MS) {
+            return Err(Error::CustomError(
+                "Runners didn't register in time for replay!".to_string(),
+            ));
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let count = feed(&path, &broker_sender).await?;
+
+    log::info!("Replayed {} events from {}", count, path);
+
+    // Wait for the runners to catch up
+    loop {
+        let stats = get_stats(&broker_sender, &sock).await?;
+
+        if stats
+            .runners
+            .iter()
+            .all(|r| r.queued == 0 && r.spilled == 0)
+        {
+            break;
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    Ok(())
+}
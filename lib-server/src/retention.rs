@@ -0,0 +1,375 @@
+// System related
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Async Related
+use flume::Sender;
+use tokio::sync::Mutex;
+
+// Cbor
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+
+// Local lib related
+use crate::settings::{self, Aggregate};
+use crate::{broker, metrics, Error, Event};
+use pyrinas_shared::SeriesPoint;
+
+/// Name the broker sends a copy of every `DataSave` point to
+pub const RUNNER_NAME: &str = "retention";
+
+/// Time between checks for intervals that have ended
+const ROLLUP_INTERVAL_MS: u64 = 10 * 1000;
+
+/// Time between removing points past their `keep_days`
+const EXPIRE_INTERVAL_MS: u64 = 3600 * 1000;
+
+const DAY_MS: i64 = 24 * 3600 * 1000;
+
+/// `60` is `1m`, `3600` is `1h`
+fn interval_name(secs: u64) -> String {
+    match secs {
+        s if s % 86400 == 0 => format!("{}d", s / 86400),
+        s if s % 3600 == 0 => format!("{}h", s / 3600),
+        s if s % 60 == 0 => format!("{}m", s / 60),
+        s => format!("{}s", s),
+    }
+}
+
+/// Measurement a rollup of `measurement` is saved to
+pub fn rollup_measurement(measurement: &str, rollup: &settings::Rollup) -> String {
+    match &rollup.measurement {
+        Some(m) => m.clone(),
+        None => format!("{}_{}", measurement, interval_name(rollup.interval_secs)),
+    }
+}
+
+/// Running totals of a field over an interval
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Totals {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl Totals {
+    fn new(value: f64) -> Totals {
+        Totals {
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    fn get(&self, aggregate: Aggregate) -> Value {
+        match aggregate {
+            Aggregate::Mean => Value::Float(self.sum / self.count as f64),
+            Aggregate::Min => Value::Float(self.min),
+            Aggregate::Max => Value::Float(self.max),
+            Aggregate::Sum => Value::Float(self.sum),
+            Aggregate::Count => Value::Integer(self.count as i128),
+        }
+    }
+}
+
+/// Start of an interval and the tags of the points in it
+type Window = (i64, BTreeMap<String, String>);
+
+/// Key of an open interval in the database
+type WindowKey = (String, i64, BTreeMap<String, String>);
+
+/// A rollup and the intervals that haven't been saved yet
+struct Rollup {
+    source: String,
+    measurement: String,
+    interval_ms: i64,
+    late_ms: i64,
+    aggregates: Vec<Aggregate>,
+    open: BTreeMap<Window, BTreeMap<String, Totals>>,
+    /// Intervals that changed since the last checkpoint
+    changed: BTreeSet<Window>,
+}
+
+impl Rollup {
+    fn key(&self, window: &Window) -> Result<Vec<u8>, Error> {
+        let (start, tags) = window;
+        Ok(serde_cbor::to_vec(&(&self.measurement, start, tags))?)
+    }
+
+    /// Whether points in the interval starting at `start` are no longer taken
+    fn ended(&self, start: i64, now_ms: i64) -> bool {
+        start + self.interval_ms + self.late_ms <= now_ms
+    }
+}
+
+/// The `[[retention]]` policies. Keeps the intervals of every rollup in memory until they end.
+/// Open intervals are written to the database by `checkpoint`, so they survive the server
+/// restarting.
+pub struct Policies {
+    rollups: Vec<Rollup>,
+    /// Measurements with a `keep_days`
+    expiry: Vec<(String, u64)>,
+    tree: sled::Tree,
+}
+
+impl Policies {
+    pub fn open(db: &sled::Db, settings: &[settings::Retention]) -> Result<Policies, Error> {
+        let mut rollups = Vec::new();
+        let mut expiry = Vec::new();
+
+        for policy in settings {
+            if let Some(days) = policy.keep_days {
+                expiry.push((policy.measurement.clone(), days));
+            }
+
+            for rollup in &policy.rollups {
+                if rollup.interval_secs == 0 {
+                    return Err(Error::CustomError(format!(
+                        "Rollup of {} needs an interval!",
+                        policy.measurement
+                    )));
+                }
+
+                let measurement = rollup_measurement(&policy.measurement, rollup);
+
+                if let Some(days) = rollup.keep_days {
+                    expiry.push((measurement.clone(), days));
+                }
+
+                rollups.push(Rollup {
+                    source: policy.measurement.clone(),
+                    measurement,
+                    interval_ms: rollup.interval_secs as i64 * 1000,
+                    late_ms: rollup.late_secs as i64 * 1000,
+                    aggregates: rollup.aggregates.clone(),
+                    open: BTreeMap::new(),
+                    changed: BTreeSet::new(),
+                });
+            }
+        }
+
+        // Pick up where the last run left off
+        let tree = db.open_tree("rollups")?;
+
+        for entry in tree.iter() {
+            let (key, value) = entry?;
+            let (measurement, start, tags): WindowKey = serde_cbor::from_slice(&key)?;
+
+            match rollups.iter_mut().find(|r| r.measurement == measurement) {
+                Some(rollup) => {
+                    rollup
+                        .open
+                        .insert((start, tags), serde_cbor::from_slice(&value)?);
+                }
+                None => {
+                    log::info!("Removing interval of {}. No longer a rollup.", measurement);
+                    tree.remove(key)?;
+                }
+            }
+        }
+
+        Ok(Policies {
+            rollups,
+            expiry,
+            tree,
+        })
+    }
+
+    /// Add a point to the rollups of its measurement. Only integer and float fields are
+    /// summarised. Points that arrive more than `late_secs` after their interval ended, going by
+    /// `now_ms`, are left out.
+    pub fn add(&mut self, point: &SeriesPoint, now_ms: i64) {
+        for rollup in self
+            .rollups
+            .iter_mut()
+            .filter(|r| r.source == point.measurement)
+        {
+            let start = point.time_ms - point.time_ms.rem_euclid(rollup.interval_ms);
+
+            if rollup.ended(start, now_ms) {
+                log::debug!("Point too late for {}.", rollup.measurement);
+                continue;
+            }
+
+            let window = (start, point.tags.clone());
+            let fields = rollup.open.entry(window.clone()).or_default();
+
+            for (name, value) in &point.fields {
+                let value = match value {
+                    Value::Integer(i) => *i as f64,
+                    Value::Float(f) => *f,
+                    _ => continue,
+                };
+
+                fields
+                    .entry(name.clone())
+                    .and_modify(|t| t.add(value))
+                    .or_insert_with(|| Totals::new(value));
+            }
+
+            rollup.changed.insert(window);
+        }
+    }
+
+    /// Write intervals that changed since the last checkpoint to the database. Returns how many.
+    pub fn checkpoint(&mut self) -> Result<usize, Error> {
+        let mut written = 0;
+
+        for rollup in self.rollups.iter_mut() {
+            for window in std::mem::take(&mut rollup.changed) {
+                if let Some(fields) = rollup.open.get(&window) {
+                    self.tree
+                        .insert(rollup.key(&window)?, serde_cbor::to_vec(fields)?)?;
+                    written += 1;
+                }
+            }
+        }
+
+        Ok(written)
+    }
+
+    /// Checkpoint and flush the database
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        self.checkpoint()?;
+        self.tree.flush_async().await?;
+
+        Ok(())
+    }
+
+    /// Rollup points for intervals that ended at least `late_secs` before `now_ms`. Fields are
+    /// named `<field>_<aggregate>` (i.e. `lat_mean`) and the point is timestamped with the start
+    /// of the interval.
+    pub fn take_ended(&mut self, now_ms: i64) -> Vec<SeriesPoint> {
+        let mut points = Vec::new();
+
+        for rollup in self.rollups.iter_mut() {
+            let ended: Vec<Window> = rollup
+                .open
+                .keys()
+                .filter(|(start, _)| rollup.ended(*start, now_ms))
+                .cloned()
+                .collect();
+
+            for window in ended {
+                if let Err(e) = rollup.key(&window).and_then(|k| Ok(self.tree.remove(k)?)) {
+                    log::error!(
+                        "Unable to remove {} interval. Err: {}",
+                        rollup.measurement,
+                        e
+                    );
+                }
+
+                rollup.changed.remove(&window);
+                let fields = rollup.open.remove(&window).unwrap();
+
+                // Only had fields that can't be summarised
+                if fields.is_empty() {
+                    continue;
+                }
+
+                let (start, tags) = window;
+                let mut point = SeriesPoint::new(&rollup.measurement, start);
+                point.tags = tags;
+
+                for (name, totals) in fields {
+                    for aggregate in &rollup.aggregates {
+                        point.fields.insert(
+                            format!("{}_{}", name, aggregate.as_str()),
+                            totals.get(*aggregate),
+                        );
+                    }
+                }
+
+                points.push(point);
+            }
+        }
+
+        points
+    }
+
+    /// Measurements with a `keep_days` and when their points expire
+    pub fn expiries(&self, now_ms: i64) -> Vec<(String, i64)> {
+        self.expiry
+            .iter()
+            .map(|(measurement, days)| (measurement.clone(), now_ms - *days as i64 * DAY_MS))
+            .collect()
+    }
+}
+
+async fn send(broker_sender: &Sender<Event>, event: Event) {
+    if let Err(e) = broker_sender.send_async(event).await {
+        log::error!("Unable to send to broker. Err: {}", e);
+    }
+}
+
+/// Save rollups as `DataSave` points once their intervals end and send `DataExpire` for every
+/// measurement with a `keep_days` each hour. Both go to whichever sinks take the measurement.
+///
+/// The policies are shared so open intervals survive the task being restarted. They're
+/// checkpointed to the database every `ROLLUP_INTERVAL_MS` and when the broker stops, so
+/// intervals still open when the server stops are saved once it's back.
+pub async fn run(policies: Arc<Mutex<Policies>>, broker_sender: Sender<Event>) {
+    // Register this task
+    let reciever = broker::register(RUNNER_NAME, &broker_sender).await.unwrap();
+
+    let mut policies = policies.lock().await;
+    let mut rollup_interval = tokio::time::interval(Duration::from_millis(ROLLUP_INTERVAL_MS));
+    let mut expire_interval = tokio::time::interval(Duration::from_millis(EXPIRE_INTERVAL_MS));
+
+    loop {
+        tokio::select! {
+            event = reciever.recv_async() => match event {
+                Ok(Event::DataSave(point)) => {
+                    policies.add(&point, chrono::Utc::now().timestamp_millis());
+                }
+                Ok(_) => (),
+                Err(_) => break,
+            },
+            _ = rollup_interval.tick() => {
+                for point in policies.take_ended(chrono::Utc::now().timestamp_millis()) {
+                    metrics::inc(metrics::ROLLUP_POINTS, &[("measurement", &point.measurement)]);
+                    send(&broker_sender, Event::DataSave(Box::new(point))).await;
+                }
+
+                if let Err(e) = policies.checkpoint() {
+                    log::error!("Unable to save open rollup intervals. Err: {}", e);
+                }
+            }
+            _ = expire_interval.tick() => {
+                for (measurement, before_ms) in policies.expiries(chrono::Utc::now().timestamp_millis()) {
+                    log::debug!("Expiring {} points before {}.", measurement, before_ms);
+                    send(&broker_sender, Event::DataExpire { measurement, before_ms }).await;
+                }
+            }
+        }
+    }
+
+    // Broker has stopped
+    if let Err(e) = policies.flush().await {
+        log::error!("Unable to save open rollup intervals. Err: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interval_name_success() {
+        assert_eq!(interval_name(30), "30s");
+        assert_eq!(interval_name(60), "1m");
+        assert_eq!(interval_name(900), "15m");
+        assert_eq!(interval_name(3600), "1h");
+        assert_eq!(interval_name(86400), "1d");
+    }
+}
@@ -0,0 +1,730 @@
+// System related
+use std::future::Future;
+use std::sync::Arc;
+
+// Async Related
+use flume::{Receiver, Sender};
+use futures::future::BoxFuture;
+use tokio::net::TcpListener;
+use tokio::{
+    sync::Mutex,
+    task::{self, JoinHandle},
+};
+
+// MQTT related
+use librumqttd::async_locallink::construct_broker;
+
+// Local lib related
+use crate::middleware::Middleware;
+use crate::mqtt::Inbound;
+use crate::shutdown::Shutdown;
+use crate::{
+    admin, broker, command, device, influx, metrics, mqtt, ota, retention, settings, shadow, sink,
+    supervisor, timeseries, Error, Event,
+};
+
+/// Creates each instance of a runner added with `Builder::runner`
+type Runner = Arc<dyn Fn(Sender<Event>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// Configures which tasks `PyrinasServer` starts. Created with `PyrinasServer::builder`.
+pub struct Builder {
+    settings: Arc<settings::PyrinasSettings>,
+    channel: Option<(Sender<Event>, Receiver<Event>)>,
+    shutdown: Shutdown,
+    mqtt: bool,
+    ota: bool,
+    devices: bool,
+    influx: bool,
+    admin: bool,
+    metrics: bool,
+    ota_db: Option<sled::Db>,
+    devices_db: Option<sled::Db>,
+    timeseries_db: Option<sled::Db>,
+    rollup_db: Option<sled::Db>,
+    influx_spool: Option<influx::Spool>,
+    influx_client: Option<influxdb::Client>,
+    admin_listener: Option<TcpListener>,
+    metrics_listener: Option<TcpListener>,
+    runners: Vec<(String, Runner)>,
+    middleware: Vec<Box<dyn Middleware>>,
+    sinks: Vec<sink::Route>,
+}
+
+impl Builder {
+    /// Use an existing broker channel instead of creating one with `broker::channel`
+    pub fn channel(
+        mut self,
+        broker_sender: Sender<Event>,
+        broker_reciever: Receiver<Event>,
+    ) -> Self {
+        self.channel = Some((broker_sender, broker_reciever));
+        self
+    }
+
+    /// Use an existing shutdown handle
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Start the MQTT broker. Enabled by default.
+    pub fn mqtt(mut self, enabled: bool) -> Self {
+        self.mqtt = enabled;
+        self
+    }
+
+    /// Start the OTA task. Enabled by default.
+    pub fn ota(mut self, enabled: bool) -> Self {
+        self.ota = enabled;
+        self
+    }
+
+    /// Start the device registry, shadows and commands. Enabled by default.
+    pub fn devices(mut self, enabled: bool) -> Self {
+        self.devices = enabled;
+        self
+    }
+
+    /// Start the Influx task. Enabled by default. If `[influx]` isn't configured and no client is
+    /// set with `influx_client`, points are saved to the local time-series store instead.
+    pub fn influx(mut self, enabled: bool) -> Self {
+        self.influx = enabled;
+        self
+    }
+
+    /// Start the admin interface. Enabled by default but only runs if `[admin]` is configured.
+    pub fn admin(mut self, enabled: bool) -> Self {
+        self.admin = enabled;
+        self
+    }
+
+    /// Serve metrics and health checks. Enabled by default but only runs if `[metrics]` is
+    /// configured or a listener is set with `metrics_listener`.
+    pub fn metrics(mut self, enabled: bool) -> Self {
+        self.metrics = enabled;
+        self
+    }
+
+    /// Store OTA data in this database instead of opening `ota.db_path`
+    pub fn ota_db(mut self, db: sled::Db) -> Self {
+        self.ota_db = Some(db);
+        self
+    }
+
+    /// Store the device registry, shadows and commands in this database instead of opening
+    /// `devices.db_path`
+    pub fn devices_db(mut self, db: sled::Db) -> Self {
+        self.devices_db = Some(db);
+        self
+    }
+
+    /// Use this database for the local time-series store instead of opening `timeseries.path`
+    pub fn timeseries_db(mut self, db: sled::Db) -> Self {
+        self.timeseries_db = Some(db);
+        self
+    }
+
+    /// Keep open rollup intervals in this database instead of opening `rollup_state.path`
+    pub fn rollup_db(mut self, db: sled::Db) -> Self {
+        self.rollup_db = Some(db);
+        self
+    }
+
+    /// Spool Influx writes here instead of opening `influx.spool.path`. Keep `spool.metrics()` to
+    /// watch how many points are waiting.
+    pub fn influx_spool(mut self, spool: influx::Spool) -> Self {
+        self.influx_spool = Some(spool);
+        self
+    }
+
+    /// Write to Influx using this client instead of one created from `[influx]`
+    pub fn influx_client(mut self, client: influxdb::Client) -> Self {
+        self.influx_client = Some(client);
+        self
+    }
+
+    /// Accept admin clients on this listener instead of binding `admin.port`.
+    /// The `[admin]` settings are still needed for the api key.
+    pub fn admin_listener(mut self, listener: TcpListener) -> Self {
+        self.admin_listener = Some(listener);
+        self
+    }
+
+    /// Serve metrics and health checks on this listener instead of binding `metrics.port`
+    pub fn metrics_listener(mut self, listener: TcpListener) -> Self {
+        self.metrics_listener = Some(listener);
+        self
+    }
+
+    /// Start a runner of your own next to the built-in ones.
+    ///
+    /// `task` is given a broker sender and should register as `name`. It's restarted using
+    /// `supervisor::spawn` and on shutdown the server waits for it to finish its queue.
+    pub fn runner<F, Fut>(mut self, name: &str, task: F) -> Self
+    where
+        F: Fn(Sender<Event>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let runner: Runner = Arc::new(move |sender| Box::pin(task(sender)));
+        self.runners.push((name.to_string(), runner));
+        self
+    }
+
+    /// Add middleware to the broker. It's placed according to `broker.middleware`,
+    /// or after the built-ins if it isn't listed there.
+    pub fn middleware<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Add a data sink along with the ones in `[[sinks]]`
+    pub fn sink(mut self, route: sink::Route) -> Self {
+        self.sinks.push(route);
+        self
+    }
+
+    pub fn build(self) -> Result<PyrinasServer, Error> {
+        if self.admin && self.admin_listener.is_some() && self.settings.admin.is_none() {
+            return Err(Error::CustomError(
+                "admin_listener requires [admin] settings!".to_string(),
+            ));
+        }
+
+        // The bundled broker can't say who published a message, so devices could publish as
+        // each other. Publishers can only be checked when messages come from somewhere else.
+        if self.mqtt {
+            if self.settings.mqtt.require_identity {
+                return Err(Error::CustomError(
+                    "The bundled MQTT broker doesn't report publishers so mqtt.require_identity can't be enforced! Set it to false to run without publisher checks.".to_string(),
+                ));
+            }
+
+            log::warn!(
+                target: mqtt::SECURITY_LOG,
+                "The bundled MQTT broker doesn't report publishers. Devices can publish as other devices."
+            );
+        }
+
+        let (broker_sender, broker_reciever) = match self.channel {
+            Some(c) => c,
+            None => broker::channel(&self.settings.broker),
+        };
+
+        Ok(PyrinasServer {
+            settings: self.settings,
+            broker_sender,
+            broker_reciever,
+            shutdown: self.shutdown,
+            mqtt: self.mqtt,
+            ota: self.ota,
+            devices: self.devices,
+            influx: self.influx,
+            admin: self.admin,
+            metrics: self.metrics,
+            ota_db: self.ota_db,
+            devices_db: self.devices_db,
+            timeseries_db: self.timeseries_db,
+            rollup_db: self.rollup_db,
+            influx_spool: self.influx_spool,
+            influx_client: self.influx_client,
+            admin_listener: self.admin_listener,
+            metrics_listener: self.metrics_listener,
+            runners: self.runners,
+            middleware: self.middleware,
+            sinks: self.sinks,
+        })
+    }
+}
+
+/// The server with every task it's configured to start.
+///
+/// ```ignore
+/// let server = PyrinasServer::builder(settings.clone())
+///     .admin(false)
+///     .runner("app", move |sender| application::run(settings.clone(), sender))
+///     .build()?;
+///
+/// let handle = server.start();
+/// handle.sender().send_async(event).await?;
+/// handle.stop().await?;
+/// ```
+pub struct PyrinasServer {
+    settings: Arc<settings::PyrinasSettings>,
+    broker_sender: Sender<Event>,
+    broker_reciever: Receiver<Event>,
+    shutdown: Shutdown,
+    mqtt: bool,
+    ota: bool,
+    devices: bool,
+    influx: bool,
+    admin: bool,
+    metrics: bool,
+    ota_db: Option<sled::Db>,
+    devices_db: Option<sled::Db>,
+    timeseries_db: Option<sled::Db>,
+    rollup_db: Option<sled::Db>,
+    influx_spool: Option<influx::Spool>,
+    influx_client: Option<influxdb::Client>,
+    admin_listener: Option<TcpListener>,
+    metrics_listener: Option<TcpListener>,
+    runners: Vec<(String, Runner)>,
+    middleware: Vec<Box<dyn Middleware>>,
+    sinks: Vec<sink::Route>,
+}
+
+impl PyrinasServer {
+    pub fn builder(settings: Arc<settings::PyrinasSettings>) -> Builder {
+        Builder {
+            settings,
+            channel: None,
+            shutdown: Shutdown::new(),
+            mqtt: true,
+            ota: true,
+            devices: true,
+            influx: true,
+            admin: true,
+            metrics: true,
+            ota_db: None,
+            devices_db: None,
+            timeseries_db: None,
+            rollup_db: None,
+            influx_spool: None,
+            influx_client: None,
+            admin_listener: None,
+            metrics_listener: None,
+            runners: Vec::new(),
+            middleware: Vec::new(),
+            sinks: Vec::new(),
+        }
+    }
+
+    /// For sending events to the broker
+    pub fn sender(&self) -> &Sender<Event> {
+        &self.broker_sender
+    }
+
+    /// Trigger this to stop the server
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// Run in the background
+    pub fn start(self) -> ServerHandle {
+        let broker_sender = self.broker_sender.clone();
+        let shutdown = self.shutdown.clone();
+
+        ServerHandle {
+            broker_sender,
+            shutdown,
+            task: task::spawn(self.run()),
+        }
+    }
+
+    /// Run until shutdown is triggered.
+    ///
+    /// On shutdown MQTT and admin connections are closed first. Everything already sent to the
+    /// broker is then handed to the runners, which finish their queues before this returns.
+    pub async fn run(self) -> Result<(), Error> {
+        let settings = self.settings;
+        let shutdown = self.shutdown;
+        let broker_sender = self.broker_sender;
+
+        // Runners that need to finish up on shutdown
+        let mut runners = Vec::new();
+
+        // Tasks that bring data in. Stopped first on shutdown.
+        let mut producers = Vec::new();
+
+        // Init influx connection
+        match (self.influx, self.influx_client, settings.influx.clone()) {
+            (true, Some(client), _) => {
+                let task_sender = broker_sender.clone();
+                runners.push(supervisor::spawn(
+                    influx::RUNNER_NAME,
+                    &settings.supervisor,
+                    &shutdown,
+                    move || influx::run_with_client(client.clone(), task_sender.clone()),
+                ));
+            }
+            (true, None, Some(influx_settings)) => {
+                let task_sender = broker_sender.clone();
+                let writer = influx::Writer::new(&influx_settings)?;
+                let spool = match self.influx_spool {
+                    Some(spool) => spool,
+                    None => influx::Spool::open(
+                        &sled::open(&influx_settings.spool.path)?,
+                        influx_settings.spool.max_points,
+                    )?,
+                };
+                runners.push(supervisor::spawn(
+                    influx::RUNNER_NAME,
+                    &settings.supervisor,
+                    &shutdown,
+                    move || {
+                        let writer = writer.clone();
+                        let spool = spool.clone();
+                        let batch_settings = influx_settings.batch.clone();
+                        let task_sender = task_sender.clone();
+                        async move {
+                            influx::run_with_writer(writer, spool, &batch_settings, task_sender)
+                                .await;
+                        }
+                    },
+                ));
+            }
+            // Local store takes its place
+            (true, None, None) => {
+                let task_sender = broker_sender.clone();
+                let db = match self.timeseries_db {
+                    Some(db) => db,
+                    None => sled::open(&settings.timeseries.path)?,
+                };
+                let timeseries_settings = settings.timeseries.clone();
+                runners.push(supervisor::spawn(
+                    timeseries::RUNNER_NAME,
+                    &settings.supervisor,
+                    &shutdown,
+                    move || {
+                        let db = db.clone();
+                        let timeseries_settings = timeseries_settings.clone();
+                        let task_sender = task_sender.clone();
+                        async move {
+                            timeseries::run_with_db(db, &timeseries_settings, task_sender).await;
+                        }
+                    },
+                ));
+            }
+            (false, ..) => (),
+        }
+
+        // Data sinks. Points go straight to Influx without them.
+        let mut sinks = sink::from_settings(&settings.sinks, &broker_sender);
+        sinks.extend(self.sinks);
+
+        if !sinks.is_empty() {
+            let task_sender = broker_sender.clone();
+            let sinks = Arc::new(Mutex::new(sinks));
+            runners.push(supervisor::spawn(
+                sink::RUNNER_NAME,
+                &settings.supervisor,
+                &shutdown,
+                move || sink::run(sinks.clone(), task_sender.clone()),
+            ));
+        }
+
+        // Rollups and expiry
+        if !settings.retention.is_empty() {
+            let task_sender = broker_sender.clone();
+            let rollup_db = match self.rollup_db {
+                Some(db) => db,
+                None => sled::open(&settings.rollup_state.path)?,
+            };
+            let policies = retention::Policies::open(&rollup_db, &settings.retention)?;
+            let policies = Arc::new(Mutex::new(policies));
+            runners.push(supervisor::spawn(
+                retention::RUNNER_NAME,
+                &settings.supervisor,
+                &shutdown,
+                move || retention::run(policies.clone(), task_sender.clone()),
+            ));
+        }
+
+        // Ota task
+        if self.ota {
+            let task_sender = broker_sender.clone();
+            let ota_db = self.ota_db;
+            let ota_settings = settings.ota.clone();
+            runners.push(supervisor::spawn(
+                "ota",
+                &settings.supervisor,
+                &shutdown,
+                move || {
+                    let ota_db = ota_db.clone();
+                    let ota_settings = ota_settings.clone();
+                    let task_sender = task_sender.clone();
+                    async move {
+                        match ota_db {
+                            Some(db) => ota::run_with_db(db, task_sender).await,
+                            None => ota::run(&ota_settings, task_sender).await,
+                        }
+                    }
+                },
+            ));
+        }
+
+        // Device registry task. Opened here since MQTT checks it as well.
+        let mut registry = None;
+
+        if self.devices {
+            let devices_db = match self.devices_db {
+                Some(db) => db,
+                None => sled::open(&settings.devices.db_path)?,
+            };
+
+            let devices = device::Registry::open(&devices_db)?;
+            registry = Some(devices.clone());
+
+            // Shadows are kept next to the registry
+            let task_sender = broker_sender.clone();
+            let shadow_db = devices_db.clone();
+            runners.push(supervisor::spawn(
+                shadow::RUNNER_NAME,
+                &settings.supervisor,
+                &shutdown,
+                move || shadow::run_with_db(shadow_db.clone(), task_sender.clone()),
+            ));
+
+            // As are commands, which need to know if a device is online
+            let task_sender = broker_sender.clone();
+            let command_db = devices_db.clone();
+            let command_registry = devices.clone();
+            let command_settings = settings.commands.clone();
+            runners.push(supervisor::spawn(
+                command::RUNNER_NAME,
+                &settings.supervisor,
+                &shutdown,
+                move || {
+                    let command_db = command_db.clone();
+                    let command_registry = command_registry.clone();
+                    let command_settings = command_settings.clone();
+                    let task_sender = task_sender.clone();
+                    async move {
+                        command::run_with_db(
+                            command_db,
+                            command_registry,
+                            &command_settings,
+                            task_sender,
+                        )
+                        .await;
+                    }
+                },
+            ));
+
+            let task_sender = broker_sender.clone();
+            let devices_settings = settings.devices.clone();
+            runners.push(supervisor::spawn(
+                device::RUNNER_NAME,
+                &settings.supervisor,
+                &shutdown,
+                move || {
+                    let devices = devices.clone();
+                    let devices_settings = devices_settings.clone();
+                    let task_sender = task_sender.clone();
+                    async move {
+                        device::run_with_registry(devices, &devices_settings, task_sender).await;
+                    }
+                },
+            ));
+        }
+
+        // Start unix socket task
+        if let (true, Some(admin_settings)) = (self.admin, settings.admin.clone()) {
+            let task_sender = broker_sender.clone();
+            let listener = self.admin_listener.map(Arc::new);
+            producers.push(supervisor::spawn(
+                "admin",
+                &settings.supervisor,
+                &shutdown,
+                move || {
+                    let admin_settings = admin_settings.clone();
+                    let listener = listener.clone();
+                    let task_sender = task_sender.clone();
+                    async move {
+                        let res = match listener {
+                            Some(l) => admin::serve(&admin_settings, &l, task_sender).await,
+                            None => admin::run(&admin_settings, task_sender).await,
+                        };
+
+                        if let Err(e) = res {
+                            log::error!("Admin runtime error! Err: {}", e);
+                        };
+                    }
+                },
+            ));
+        }
+
+        if self.mqtt {
+            // Set up broker
+            let (mut router, _, rumqtt_server, builder) =
+                construct_broker(settings.mqtt.rumqtt.clone());
+
+            // Running switch
+            producers.push(task::spawn(async {
+                rumqtt_server.await;
+            }));
+
+            // Spawn router task (needs to be done before anything else or else builder.connect blocks)
+            task::spawn_blocking(move || {
+                if let Err(e) = router.start() {
+                    log::error!("mqtt router error. err: {}", e);
+                }
+            });
+
+            // Get the rx/tx channels
+            let (mut tx, rx) = builder.connect("localclient", 200).await?;
+
+            // Subscribe
+            tx.subscribe(settings.mqtt.topics.clone()).await?;
+
+            // Shared between restarts of the MQTT tasks
+            let tx = Arc::new(Mutex::new(tx));
+            let rx = Arc::new(Mutex::new(rx));
+            let mut inbound =
+                Inbound::new(&settings.mqtt).with_telemetry(settings.telemetry.clone());
+            if let Some(registry) = registry {
+                inbound = inbound.with_registry(registry);
+            }
+            let topics = inbound.topics.clone();
+            let encodings = inbound.encodings.clone();
+
+            // Start server task
+            let task_sender = broker_sender.clone();
+            let task_inbound = inbound.clone();
+            producers.push(supervisor::spawn(
+                "mqtt_run",
+                &settings.supervisor,
+                &shutdown,
+                move || {
+                    let rx = rx.clone();
+                    let inbound = task_inbound.clone();
+                    let task_sender = task_sender.clone();
+                    async move {
+                        mqtt::mqtt_run(&mut *rx.lock().await, &inbound, task_sender).await;
+                    }
+                },
+            ));
+
+            // Start mqtt broker task
+            let task_sender = broker_sender.clone();
+            runners.push(supervisor::spawn(
+                "mqtt",
+                &settings.supervisor,
+                &shutdown,
+                move || {
+                    let tx = tx.clone();
+                    let topics = topics.clone();
+                    let encodings = encodings.clone();
+                    let task_sender = task_sender.clone();
+                    async move {
+                        mqtt::run(&mut *tx.lock().await, &topics, &encodings, task_sender).await;
+                    }
+                },
+            ));
+        }
+
+        // Custom runners
+        for (name, runner) in self.runners {
+            let task_sender = broker_sender.clone();
+            runners.push(supervisor::spawn(
+                &name,
+                &settings.supervisor,
+                &shutdown,
+                move || runner(task_sender.clone()),
+            ));
+        }
+
+        // Spawn the broker task that handles it all!
+        let broker_task = task::spawn(broker::run_with_middleware(
+            settings.broker.clone(),
+            self.broker_reciever,
+            self.middleware,
+        ));
+
+        // Metrics and health checks. Kept up until everything else has stopped.
+        let metrics_task = match (
+            self.metrics,
+            self.metrics_listener,
+            settings.metrics.clone(),
+        ) {
+            (true, Some(listener), _) => {
+                let listener = Arc::new(listener);
+                Some(supervisor::spawn(
+                    "metrics",
+                    &settings.supervisor,
+                    &shutdown,
+                    move || {
+                        let listener = listener.clone();
+                        async move {
+                            if let Err(e) = metrics::serve(&listener).await {
+                                log::error!("Metrics runtime error! Err: {}", e);
+                            }
+                        }
+                    },
+                ))
+            }
+            (true, None, Some(metrics_settings)) => Some(supervisor::spawn(
+                "metrics",
+                &settings.supervisor,
+                &shutdown,
+                move || {
+                    let metrics_settings = metrics_settings.clone();
+                    async move {
+                        if let Err(e) = metrics::run(&metrics_settings).await {
+                            log::error!("Metrics runtime error! Err: {}", e);
+                        }
+                    }
+                },
+            )),
+            _ => None,
+        };
+
+        metrics::set(metrics::READY, &[], 1.0);
+
+        shutdown.wait().await;
+
+        log::info!("Shutting down..");
+        metrics::set(metrics::READY, &[], 0.0);
+
+        // Stop taking in new data
+        for producer in producers {
+            producer.abort();
+        }
+
+        // Drain the broker
+        broker_sender.send_async(Event::Shutdown).await?;
+        broker_task.await?;
+
+        // Wait for the runners to work through their queues
+        for runner in runners {
+            runner.await?;
+        }
+
+        if let Some(task) = metrics_task {
+            task.abort();
+        }
+
+        log::info!("Shutdown complete.");
+
+        Ok(())
+    }
+}
+
+/// Returned by `PyrinasServer::start`
+pub struct ServerHandle {
+    broker_sender: Sender<Event>,
+    shutdown: Shutdown,
+    task: JoinHandle<Result<(), Error>>,
+}
+
+impl ServerHandle {
+    /// For sending events to the broker
+    pub fn sender(&self) -> &Sender<Event> {
+        &self.broker_sender
+    }
+
+    /// Trigger this to stop the server without waiting
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+
+    /// Wait for the server to stop
+    pub async fn wait(self) -> Result<(), Error> {
+        self.task.await?
+    }
+
+    /// Stop the server and wait for it to finish
+    pub async fn stop(self) -> Result<(), Error> {
+        self.shutdown.trigger();
+        self.wait().await
+    }
+}
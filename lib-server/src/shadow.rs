@@ -0,0 +1,258 @@
+// System related
+use std::collections::BTreeMap;
+
+// Async Related
+use flume::Sender;
+
+// Cbor
+use serde_cbor::Value;
+
+// Local lib related
+use crate::{acknowledge, broker, Error, Event};
+use pyrinas_shared::{ManagmentDataType, ShadowDocument, ShadowUpdate};
+
+/// Name the broker routes shadow events to
+pub const RUNNER_NAME: &str = "shadow";
+
+/// Merge `patch` into `target`. Keys set to null are removed and nested maps are merged.
+pub fn merge(target: &mut Value, patch: Value) -> Result<(), Error> {
+    let patch = match patch {
+        Value::Map(m) => m,
+        _ => {
+            return Err(Error::CustomError(
+                "Shadow state must be a map!".to_string(),
+            ))
+        }
+    };
+
+    if !matches!(target, Value::Map(_)) {
+        *target = Value::Map(BTreeMap::new());
+    }
+
+    if let Value::Map(target) = target {
+        merge_map(target, patch);
+    }
+
+    Ok(())
+}
+
+fn merge_map(target: &mut BTreeMap<Value, Value>, patch: BTreeMap<Value, Value>) {
+    for (key, value) in patch {
+        match value {
+            Value::Null => {
+                target.remove(&key);
+            }
+            Value::Map(patch) => match target.get_mut(&key) {
+                Some(Value::Map(existing)) => merge_map(existing, patch),
+                _ => {
+                    let mut map = BTreeMap::new();
+                    merge_map(&mut map, patch);
+                    target.insert(key, Value::Map(map));
+                }
+            },
+            value => {
+                target.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Parts of `desired` that `reported` doesn't match. An empty map if the device is in sync.
+pub fn delta(desired: &Value, reported: &Value) -> Value {
+    let empty = BTreeMap::new();
+
+    let desired = match desired {
+        Value::Map(m) => m,
+        _ => &empty,
+    };
+
+    let reported = match reported {
+        Value::Map(m) => m,
+        _ => &empty,
+    };
+
+    Value::Map(delta_map(desired, reported))
+}
+
+fn delta_map(
+    desired: &BTreeMap<Value, Value>,
+    reported: &BTreeMap<Value, Value>,
+) -> BTreeMap<Value, Value> {
+    let mut delta = BTreeMap::new();
+
+    for (key, value) in desired {
+        match (value, reported.get(key)) {
+            (Value::Map(d), Some(Value::Map(r))) => {
+                let nested = delta_map(d, r);
+                if !nested.is_empty() {
+                    delta.insert(key.clone(), Value::Map(nested));
+                }
+            }
+            (d, Some(r)) if d == r => (),
+            (d, _) => {
+                delta.insert(key.clone(), d.clone());
+            }
+        }
+    }
+
+    delta
+}
+
+/// Persistent shadow document for each device
+#[derive(Debug, Clone)]
+pub struct Store {
+    tree: sled::Tree,
+}
+
+impl Store {
+    pub fn open(db: &sled::Db) -> Result<Store, Error> {
+        Ok(Store {
+            tree: db.open_tree("shadows")?,
+        })
+    }
+
+    /// The device's document. Empty if nothing has been set or reported yet.
+    pub fn get(&self, uid: &str) -> Result<ShadowDocument, Error> {
+        match self.tree.get(uid)? {
+            Some(v) => Ok(serde_cbor::from_slice(&v)?),
+            None => Ok(ShadowDocument::new(uid)),
+        }
+    }
+
+    /// Bump the version and save. Returns whether the delta changed.
+    fn save(&self, doc: &mut ShadowDocument) -> Result<bool, Error> {
+        let delta = delta(&doc.desired, &doc.reported);
+        let changed = delta != doc.delta;
+
+        doc.delta = delta;
+        doc.version += 1;
+
+        self.tree
+            .insert(doc.uid.as_bytes(), serde_cbor::to_vec(doc)?)?;
+
+        Ok(changed)
+    }
+
+    /// Merge a change into the desired state. Returns the document and whether the delta changed.
+    ///
+    /// Nothing is saved if the desired state stays the same.
+    pub fn set_desired(
+        &self,
+        uid: &str,
+        patch: Value,
+        now_ms: i64,
+    ) -> Result<(ShadowDocument, bool), Error> {
+        let mut doc = self.get(uid)?;
+        let before = doc.desired.clone();
+
+        merge(&mut doc.desired, patch)?;
+
+        if doc.desired == before {
+            return Ok((doc, false));
+        }
+
+        doc.desired_ms = now_ms;
+        let changed = self.save(&mut doc)?;
+
+        Ok((doc, changed))
+    }
+
+    /// Merge a report from the device into the reported state. Returns the document and whether
+    /// the delta changed.
+    ///
+    /// Nothing is saved if the reported state stays the same.
+    pub fn report(
+        &self,
+        uid: &str,
+        patch: Value,
+        now_ms: i64,
+    ) -> Result<(ShadowDocument, bool), Error> {
+        let mut doc = self.get(uid)?;
+        let before = doc.reported.clone();
+
+        merge(&mut doc.reported, patch)?;
+
+        if doc.reported == before {
+            return Ok((doc, false));
+        }
+
+        doc.reported_ms = now_ms;
+        let changed = self.save(&mut doc)?;
+
+        Ok((doc, changed))
+    }
+}
+
+/// Publish the delta to `<uid>/shadow/s/delta`
+async fn publish_delta(broker_sender: &Sender<Event>, doc: ShadowDocument) {
+    let event = Event::ShadowDelta {
+        uid: doc.uid,
+        delta: Box::new(doc.delta),
+    };
+
+    if let Err(e) = broker_sender.send_async(event).await {
+        log::error!("Unable to send shadow delta. Err: {}", e);
+    }
+}
+
+async fn process_event(broker_sender: &Sender<Event>, store: &Store, event: Event) {
+    let now_ms = chrono::Utc::now().timestamp_millis();
+
+    match event {
+        Event::ShadowReported { uid, state } => match store.report(&uid, *state, now_ms) {
+            Ok((doc, true)) => publish_delta(broker_sender, doc).await,
+            Ok(_) => (),
+            Err(e) => log::warn!("Unable to save report from {}. Err: {}", uid, e),
+        },
+        Event::ShadowDesired(id, update) => {
+            let ShadowUpdate { uid, desired } = *update;
+
+            match store.set_desired(&uid, desired, now_ms) {
+                Ok((doc, changed)) => {
+                    acknowledge(broker_sender, &id, ManagmentDataType::UpdateShadow, Ok(())).await;
+
+                    // Retained, so only when there's something new
+                    if changed {
+                        publish_delta(broker_sender, doc).await;
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Unable to set desired state for {}. Err: {}", uid, e);
+                    acknowledge(broker_sender, &id, ManagmentDataType::UpdateShadow, Err(e)).await;
+                }
+            }
+        }
+        Event::ShadowGetRequest(id, uid) => match store.get(&uid) {
+            Ok(doc) => {
+                if let Err(e) = broker_sender
+                    .send_async(Event::ShadowGetResponse(id, Box::new(doc)))
+                    .await
+                {
+                    log::error!("Unable to send shadow. Err: {}", e);
+                }
+            }
+            Err(e) => {
+                log::error!("Unable to get shadow for {}. Err: {}", uid, e);
+                acknowledge(broker_sender, &id, ManagmentDataType::GetShadow, Err(e)).await;
+            }
+        },
+        _ => (),
+    }
+}
+
+/// Handle shadow events using a database that's already open. Usually the device registry's.
+pub async fn run_with_db(sled_db: sled::Db, broker_sender: Sender<Event>) {
+    // Register this task
+    let reciever = broker::register(RUNNER_NAME, &broker_sender).await.unwrap();
+
+    let store = Store::open(&sled_db).expect("Unable to create shadow db tree.");
+
+    while let Ok(event) = reciever.recv_async().await {
+        process_event(&broker_sender, &store, event).await;
+    }
+
+    // Broker has stopped
+    if let Err(e) = sled_db.flush_async().await {
+        log::error!("Unable to flush shadow db. Err: {}", e);
+    }
+}
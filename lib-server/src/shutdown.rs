@@ -0,0 +1,85 @@
+// System related
+use std::sync::Arc;
+
+// Runtime
+use tokio::sync::watch;
+
+/// Signals the server (and anything else holding a clone) to stop.
+///
+/// Pass one to `pyrinas_server::run` and call `trigger` to shut the server down.
+#[derive(Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+    reciever: watch::Receiver<bool>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (sender, reciever) = watch::channel(false);
+
+        Self {
+            sender: Arc::new(sender),
+            reciever,
+        }
+    }
+
+    /// Start shutting down
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.reciever.borrow()
+    }
+
+    /// Resolves once `trigger` has been called
+    pub async fn wait(&self) {
+        let mut reciever = self.reciever.clone();
+
+        while !*reciever.borrow_and_update() {
+            if reciever.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    /// Trigger on Ctrl-C or SIGTERM
+    pub fn trigger_on_signal(&self) {
+        let shutdown = self.clone();
+
+        tokio::task::spawn(async move {
+            #[cfg(unix)]
+            {
+                use tokio::signal::unix::{signal, SignalKind};
+
+                let mut terminate = match signal(SignalKind::terminate()) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        log::error!("Unable to listen for SIGTERM. Err: {}", e);
+                        return;
+                    }
+                };
+
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => (),
+                    _ = terminate.recv() => (),
+                };
+            }
+
+            #[cfg(not(unix))]
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                log::error!("Unable to listen for Ctrl-C. Err: {}", e);
+                return;
+            }
+
+            log::info!("Shutdown signal recieved.");
+            shutdown.trigger();
+        });
+    }
+}
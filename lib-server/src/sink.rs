@@ -0,0 +1,534 @@
+// System related
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Async Related
+use flume::Sender;
+use futures::future::BoxFuture;
+use tokio::sync::Mutex;
+
+// Postgres
+use tokio_postgres::NoTls;
+
+// Local lib related
+use crate::settings::{self, FileFormat, SinkKind};
+use crate::{broker, influx, Error, Event};
+use pyrinas_shared::SeriesPoint;
+
+/// Name the broker routes data points to when there are sinks
+pub const RUNNER_NAME: &str = "sinks";
+
+/// Time between flushes of every sink
+const FLUSH_INTERVAL_MS: u64 = 1000;
+
+/// Longest wait before trying Postgres again
+const MAX_BACKOFF_MS: u64 = 60 * 1000;
+
+/// Somewhere data points are stored
+pub trait DataSink: Send {
+    /// Store points. They can be buffered until `flush`.
+    fn write<'a>(&'a mut self, points: &'a [SeriesPoint]) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Called every second and before the runner stops
+    fn flush(&mut self) -> BoxFuture<'_, Result<(), Error>>;
+
+    /// Remove points from `measurement` older than `before_ms`. Sinks that can't remove points
+    /// keep them.
+    fn expire<'a>(
+        &'a mut self,
+        _measurement: &'a str,
+        _before_ms: i64,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A sink and the measurements it takes
+pub struct Route {
+    name: String,
+    measurements: Vec<String>,
+    sink: Box<dyn DataSink>,
+}
+
+impl Route {
+    /// Points from every measurement go to `sink` if `measurements` is empty
+    pub fn new<S: DataSink + 'static>(name: &str, measurements: Vec<String>, sink: S) -> Route {
+        Route {
+            name: name.to_string(),
+            measurements,
+            sink: Box::new(sink),
+        }
+    }
+
+    pub fn accepts(&self, measurement: &str) -> bool {
+        self.measurements.is_empty() || self.measurements.iter().any(|m| m == measurement)
+    }
+}
+
+/// Routes for the `[[sinks]]` settings
+pub fn from_settings(settings: &[settings::Sink], broker_sender: &Sender<Event>) -> Vec<Route> {
+    settings
+        .iter()
+        .map(|s| {
+            let measurements = s.measurements.clone();
+
+            match &s.kind {
+                SinkKind::Influx => Route::new("influx", measurements, Influx::new(broker_sender)),
+                SinkKind::Postgres(p) => Route::new("postgres", measurements, Postgres::new(p)),
+                SinkKind::File(f) => Route::new("file", measurements, File::new(f)),
+            }
+        })
+        .collect()
+}
+
+/// Hands points to the Influx runner (or the local time-series store standing in for it)
+pub struct Influx {
+    broker_sender: Sender<Event>,
+}
+
+impl Influx {
+    pub fn new(broker_sender: &Sender<Event>) -> Influx {
+        Influx {
+            broker_sender: broker_sender.clone(),
+        }
+    }
+}
+
+impl DataSink for Influx {
+    fn write<'a>(&'a mut self, points: &'a [SeriesPoint]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            for point in points {
+                self.broker_sender
+                    .send_async(Event::InfluxDataSave(influx::to_query(point)))
+                    .await?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn expire<'a>(
+        &'a mut self,
+        measurement: &'a str,
+        before_ms: i64,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let event = Event::InfluxDataExpire {
+                measurement: measurement.to_string(),
+                before_ms,
+            };
+
+            self.broker_sender.send_async(event).await?;
+
+            Ok(())
+        })
+    }
+}
+
+/// Only letters, numbers and `_` so the name can go straight into SQL
+fn valid_table(table: &str) -> bool {
+    !table.is_empty()
+        && !table.starts_with(|c: char| c.is_ascii_digit())
+        && table.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Give up on a Postgres request after `secs`
+async fn timeout<T>(
+    secs: u64,
+    f: impl std::future::Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match tokio::time::timeout(Duration::from_secs(secs), f).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::CustomError(format!(
+            "Postgres didn't answer within {}s",
+            secs
+        ))),
+    }
+}
+
+/// Inserts points into a Postgres table. With `timescale` set the table is made a hypertable.
+///
+/// Points are written once a second. They're kept while Postgres can't be reached, and writing is
+/// tried again with backoff so other sinks aren't held up.
+pub struct Postgres {
+    settings: settings::PostgresSink,
+    client: Option<tokio_postgres::Client>,
+    pending: VecDeque<SeriesPoint>,
+    /// Failed writes in a row
+    attempt: u32,
+    /// Don't try again before this
+    retry_at: Option<Instant>,
+}
+
+impl Postgres {
+    pub fn new(settings: &settings::PostgresSink) -> Postgres {
+        Postgres {
+            settings: settings.clone(),
+            client: None,
+            pending: VecDeque::new(),
+            attempt: 0,
+            retry_at: None,
+        }
+    }
+
+    /// Connect and create the table if needed
+    async fn connect(&self) -> Result<tokio_postgres::Client, Error> {
+        let table = &self.settings.table;
+
+        if !valid_table(table) {
+            return Err(Error::CustomError(format!("Invalid table name: {}", table)));
+        }
+
+        let (client, connection) = tokio_postgres::connect(&self.settings.url, NoTls).await?;
+
+        // Does the actual communication
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::warn!("Postgres connection closed. Err: {}", e);
+            }
+        });
+
+        client
+            .batch_execute(&format!(
+                "CREATE TABLE IF NOT EXISTS {table} (
+                    time TIMESTAMPTZ NOT NULL,
+                    measurement TEXT NOT NULL,
+                    tags JSONB NOT NULL,
+                    fields JSONB NOT NULL
+                );
+                CREATE INDEX IF NOT EXISTS {table}_measurement_time_idx ON {table} (measurement, time DESC);",
+                table = table
+            ))
+            .await?;
+
+        if self.settings.timescale {
+            client
+                .batch_execute(&format!(
+                    "SELECT create_hypertable('{}', 'time', if_not_exists => TRUE);",
+                    table
+                ))
+                .await?;
+        }
+
+        Ok(client)
+    }
+
+    async fn insert(&mut self) -> Result<(), Error> {
+        if self.client.is_none() {
+            self.client = Some(self.connect().await?);
+        }
+
+        let client = self.client.as_mut().unwrap();
+
+        let transaction = client.transaction().await?;
+        let statement = transaction
+            .prepare(&format!(
+                "INSERT INTO {} (time, measurement, tags, fields) \
+                 VALUES (to_timestamp($1::bigint / 1000.0), $2, $3::text::jsonb, $4::text::jsonb)",
+                self.settings.table
+            ))
+            .await?;
+
+        for point in &self.pending {
+            let tags = serde_json::to_string(&point.tags)?;
+            let fields = serde_json::to_string(&point.fields)?;
+
+            transaction
+                .execute(
+                    &statement,
+                    &[&point.time_ms, &point.measurement, &tags, &fields],
+                )
+                .await?;
+        }
+
+        transaction.commit().await?;
+        self.pending.clear();
+
+        Ok(())
+    }
+
+    /// Remove rows from `measurement` older than `before_ms`. Returns how many.
+    async fn delete(&mut self, measurement: &str, before_ms: i64) -> Result<u64, Error> {
+        if self.client.is_none() {
+            self.client = Some(self.connect().await?);
+        }
+
+        let removed = self
+            .client
+            .as_ref()
+            .unwrap()
+            .execute(
+                &format!(
+                    "DELETE FROM {} WHERE measurement = $1 AND time < to_timestamp($2::bigint / 1000.0)",
+                    self.settings.table
+                ),
+                &[&measurement, &before_ms],
+            )
+            .await?;
+
+        Ok(removed)
+    }
+}
+
+impl DataSink for Postgres {
+    fn write<'a>(&'a mut self, points: &'a [SeriesPoint]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            self.pending.extend(points.iter().cloned());
+
+            if self.pending.len() > self.settings.max_points {
+                let dropped = self.pending.len() - self.settings.max_points;
+                self.pending.drain(..dropped);
+                log::warn!("Postgres sink is full. Dropped {} points.", dropped);
+            }
+
+            Ok(())
+        })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            if self.pending.is_empty() {
+                return Ok(());
+            }
+
+            // Still backing off
+            if matches!(self.retry_at, Some(at) if Instant::now() < at) {
+                return Ok(());
+            }
+
+            let result = timeout(self.settings.timeout_secs, self.insert()).await;
+
+            match result {
+                Ok(()) => {
+                    self.attempt = 0;
+                    self.retry_at = None;
+                }
+                // Reconnect next time
+                Err(_) => {
+                    let delay = std::cmp::min(
+                        FLUSH_INTERVAL_MS.saturating_mul(1 << self.attempt.min(16)),
+                        MAX_BACKOFF_MS,
+                    );
+
+                    self.client = None;
+                    self.attempt += 1;
+                    self.retry_at = Some(Instant::now() + Duration::from_millis(delay));
+                }
+            }
+
+            result
+        })
+    }
+
+    fn expire<'a>(
+        &'a mut self,
+        measurement: &'a str,
+        before_ms: i64,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let secs = self.settings.timeout_secs;
+            let result = timeout(secs, self.delete(measurement, before_ms)).await;
+
+            match result {
+                Ok(removed) => {
+                    log::info!("Removed {} {} points from Postgres.", removed, measurement);
+                    Ok(())
+                }
+                // Reconnect next time
+                Err(e) => {
+                    self.client = None;
+                    Err(e)
+                }
+            }
+        })
+    }
+}
+
+/// Quote a CSV value if it needs it
+fn csv_escape(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+/// Appends points to a file as newline delimited JSON or CSV
+///
+/// Points are buffered and written once a second off the async runtime.
+pub struct File {
+    settings: settings::FileSink,
+    file: Option<std::fs::File>,
+    buffer: Vec<u8>,
+}
+
+impl File {
+    pub fn new(settings: &settings::FileSink) -> File {
+        File {
+            settings: settings.clone(),
+            file: None,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Append `buffer` to the file, opening it and writing the CSV header if it's new. Blocks.
+fn append(
+    file: Option<std::fs::File>,
+    settings: &settings::FileSink,
+    buffer: &[u8],
+) -> Result<std::fs::File, Error> {
+    let mut file = match file {
+        Some(f) => f,
+        None => {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&settings.path)?;
+
+            if file.metadata()?.len() == 0 && settings.format == FileFormat::Csv {
+                writeln!(file, "time_ms,measurement,tags,field,value")?;
+            }
+
+            file
+        }
+    };
+
+    file.write_all(buffer)?;
+
+    Ok(file)
+}
+
+impl DataSink for File {
+    fn write<'a>(&'a mut self, points: &'a [SeriesPoint]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async move {
+            let writer = &mut self.buffer;
+
+            for point in points {
+                match self.settings.format {
+                    FileFormat::Json => {
+                        serde_json::to_writer(&mut *writer, point)?;
+                        writeln!(writer)?;
+                    }
+                    FileFormat::Csv => {
+                        let tags: Vec<String> = point
+                            .tags
+                            .iter()
+                            .map(|(k, v)| format!("{}={}", k, v))
+                            .collect();
+                        let tags = csv_escape(&tags.join(";"));
+
+                        for (name, value) in &point.fields {
+                            writeln!(
+                                writer,
+                                "{},{},{},{},{}",
+                                point.time_ms,
+                                csv_escape(&point.measurement),
+                                tags,
+                                csv_escape(name),
+                                csv_escape(&serde_json::to_string(value)?)
+                            )?;
+                        }
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Points that can't be written are dropped
+    fn flush(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            if self.buffer.is_empty() {
+                return Ok(());
+            }
+
+            let buffer = std::mem::take(&mut self.buffer);
+            let file = self.file.take();
+            let settings = self.settings.clone();
+
+            let file = tokio::task::spawn_blocking(move || append(file, &settings, &buffer))
+                .await
+                .map_err(|e| Error::CustomError(format!("File sink stopped. Err: {}", e)))??;
+
+            self.file = Some(file);
+
+            Ok(())
+        })
+    }
+}
+
+async fn flush_all(routes: &mut [Route]) {
+    for route in routes.iter_mut() {
+        if let Err(e) = route.sink.flush().await {
+            log::error!("Unable to flush {} sink. Err: {}", route.name, e);
+        }
+    }
+}
+
+/// Write every `DataSave` point to the sinks that take its measurement. `DataExpire` goes to the
+/// same sinks.
+///
+/// The routes are shared so they survive the task being restarted.
+pub async fn run(routes: Arc<Mutex<Vec<Route>>>, broker_sender: Sender<Event>) {
+    // Register this task
+    let reciever = broker::register(RUNNER_NAME, &broker_sender).await.unwrap();
+
+    let mut routes = routes.lock().await;
+    let mut interval = tokio::time::interval(Duration::from_millis(FLUSH_INTERVAL_MS));
+
+    loop {
+        tokio::select! {
+            event = reciever.recv_async() => match event {
+                Ok(Event::DataSave(point)) => {
+                    for route in routes.iter_mut().filter(|r| r.accepts(&point.measurement)) {
+                        if let Err(e) = route.sink.write(std::slice::from_ref(&*point)).await {
+                            log::error!("Unable to write to {} sink. Err: {}", route.name, e);
+                        }
+                    }
+                }
+                Ok(Event::DataExpire { measurement, before_ms }) => {
+                    for route in routes.iter_mut().filter(|r| r.accepts(&measurement)) {
+                        if let Err(e) = route.sink.expire(&measurement, before_ms).await {
+                            log::error!("Unable to expire {} in {} sink. Err: {}", measurement, route.name, e);
+                        }
+                    }
+                }
+                Ok(_) => (),
+                Err(_) => break,
+            },
+            _ = interval.tick() => flush_all(&mut routes).await,
+        }
+    }
+
+    // Broker has stopped
+    flush_all(&mut routes).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_table_success() {
+        assert!(valid_table("points"));
+        assert!(valid_table("gps_2"));
+        assert!(!valid_table(""));
+        assert!(!valid_table("2gps"));
+        assert!(!valid_table("points; DROP TABLE x"));
+    }
+
+    #[test]
+    fn csv_escape_success() {
+        assert_eq!(csv_escape("rsrp"), "rsrp");
+        assert_eq!(csv_escape("a,b"), "\"a,b\"");
+        assert_eq!(csv_escape("\"hi\""), "\"\"\"hi\"\"\"");
+    }
+}
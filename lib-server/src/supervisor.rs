@@ -0,0 +1,99 @@
+// System related
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+// Runtime
+use tokio::task::{self, JoinHandle};
+
+// Local lib related
+use crate::{metrics, settings, shutdown::Shutdown};
+
+/// Aborts the task once dropped so aborting the supervisor stops the task too
+struct AbortOnDrop(JoinHandle<()>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Counts a running instance in `metrics::TASK_UP`, including when the supervisor is aborted
+struct Up(String);
+
+impl Up {
+    fn new(name: &str) -> Up {
+        metrics::add(metrics::TASK_UP, &[("task", name)], 1.0);
+        Up(name.to_string())
+    }
+}
+
+impl Drop for Up {
+    fn drop(&mut self) {
+        metrics::add(metrics::TASK_UP, &[("task", self.0.as_str())], -1.0);
+    }
+}
+
+/// Spawn a task that is restarted whenever it returns or panics.
+///
+/// Restarts are delayed with an exponential backoff. `task` is called to create each new instance.
+/// Once `shutdown` is triggered the task is left to finish and isn't restarted. Whether the task is
+/// running is reported in `metrics::TASK_UP`.
+pub fn spawn<F, Fut>(
+    name: &str,
+    settings: &settings::Supervisor,
+    shutdown: &Shutdown,
+    task: F,
+) -> JoinHandle<()>
+where
+    F: Fn() -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let name = name.to_string();
+    let initial = Duration::from_millis(settings.initial_backoff_ms);
+    let max = Duration::from_millis(settings.max_backoff_ms);
+    let shutdown = shutdown.clone();
+
+    task::spawn(async move {
+        let mut backoff = initial;
+
+        loop {
+            let started = Instant::now();
+            let mut handle = AbortOnDrop(task::spawn(task()));
+            let up = Up::new(&name);
+            let result = (&mut handle.0).await;
+            drop(up);
+
+            match result {
+                Ok(()) if shutdown.is_triggered() => {
+                    log::info!("{} task stopped.", name);
+                    return;
+                }
+                Ok(()) => log::warn!("{} task exited.", name),
+                Err(e) if e.is_panic() => log::error!("{} task panicked!", name),
+                Err(_) => {
+                    log::info!("{} task cancelled.", name);
+                    return;
+                }
+            }
+
+            if shutdown.is_triggered() {
+                return;
+            }
+
+            // Ran long enough to be considered healthy
+            if started.elapsed() >= max {
+                backoff = initial;
+            }
+
+            log::info!("Restarting {} task in {}ms.", name, backoff.as_millis());
+            metrics::inc(metrics::TASK_RESTARTS, &[("task", name.as_str())]);
+
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => (),
+                _ = shutdown.wait() => return,
+            };
+
+            backoff = std::cmp::min(backoff * 2, max);
+        }
+    })
+}
@@ -1,47 +1,238 @@
-use chrono::{DateTime, Utc};
-use influxdb::{InfluxDbWriteable, WriteQuery};
+// System related
+use std::collections::{BTreeMap, HashMap};
+
+// Influx
+use chrono::Utc;
+use influxdb::WriteQuery;
+
+// Serde
 use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+
+// Local lib related
+use crate::settings::FieldType;
+use crate::{influx, Error};
+use pyrinas_shared::SeriesPoint;
 
-// TODO: confirm the name works for each
 // Matches `pyrinas_cloud_telemetry_type_t` in `pyrinas_cloud.h`
-// Note: the enum indexes match the entry order below. i.e. the order counts!
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct TelemetryData {
-    version: Option<String>,
-    rsrp: Option<u32>,        // Won't always have rsrp (hub only)
-    rssi_hub: Option<i32>,    // Won't always have this guy either
-    rssi_client: Option<i32>, // Won't always have this guy either
+// Note: older firmware sends these as integer keys so the order counts!
+const LEGACY_FIELDS: [&str; 4] = ["version", "rsrp", "rssi_hub", "rssi_client"];
+
+/// A single telemetry field
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum TelemetryValue {
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    Text(String),
+}
+
+impl TelemetryValue {
+    pub fn field_type(&self) -> FieldType {
+        match self {
+            TelemetryValue::Boolean(_) => FieldType::Boolean,
+            TelemetryValue::Integer(_) => FieldType::Integer,
+            TelemetryValue::Float(_) => FieldType::Float,
+            TelemetryValue::Text(_) => FieldType::String,
+        }
+    }
 }
 
-#[derive(Debug, InfluxDbWriteable, Clone)]
-pub struct InfluxTelemetryData {
-    version: Option<String>,  // Wont always have this guy either.
-    rsrp: Option<u32>,        // Won't always have rsrp (hub only)
-    rssi_hub: Option<i32>,    // Won't always have this guy either
-    rssi_client: Option<i32>, // Won't always have this guy either
-    #[influxdb(tag)]
-    id: String, // Typically not sent as it's included in the MQTT topic
-    time: DateTime<Utc>,      // Only used for inserting data into Influx DB
+/// Telemetry from a device. Every field it sent, by name.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(transparent)]
+pub struct TelemetryData {
+    pub fields: BTreeMap<String, TelemetryValue>,
 }
 
 impl TelemetryData {
-    pub fn to_influx_data(&self, uid: String) -> InfluxTelemetryData {
-        // Return new data structure that's friendly with Influx
-        InfluxTelemetryData {
-            version: self.version.clone(),
-            rsrp: self.rsrp,
-            rssi_hub: self.rssi_hub,
-            rssi_client: self.rssi_client,
-            id: uid,
-            time: Utc::now(),
+    /// Read a decoded payload. Has to be a map.
+    ///
+    /// Nested maps are flattened (i.e. `gps.lat`) and arrays are kept as JSON text. Nulls and
+    /// byte strings are skipped.
+    pub fn from_value(value: Value) -> Result<TelemetryData, Error> {
+        let map = match value {
+            Value::Map(m) => m,
+            _ => return Err(Error::CustomError("Telemetry must be a map!".to_string())),
+        };
+
+        let mut data = TelemetryData::default();
+
+        for (key, value) in map {
+            let name = match key {
+                Value::Integer(i) if (0..LEGACY_FIELDS.len() as i128).contains(&i) => {
+                    LEGACY_FIELDS[i as usize].to_string()
+                }
+                key => key_name(key)?,
+            };
+
+            data.insert(name, value);
+        }
+
+        Ok(data)
+    }
+
+    fn insert(&mut self, name: String, value: Value) {
+        let value = match value {
+            Value::Bool(b) => TelemetryValue::Boolean(b),
+            Value::Integer(i) => match i64::try_from(i) {
+                Ok(i) => TelemetryValue::Integer(i),
+                Err(_) => TelemetryValue::Float(i as f64),
+            },
+            Value::Float(f) => TelemetryValue::Float(f),
+            Value::Text(t) => TelemetryValue::Text(t),
+            Value::Array(_) => match serde_json::to_string(&value) {
+                Ok(t) => TelemetryValue::Text(t),
+                Err(e) => {
+                    log::warn!("Unable to store {}. Err: {}", name, e);
+                    return;
+                }
+            },
+            Value::Map(map) => {
+                for (key, value) in map {
+                    match key_name(key) {
+                        Ok(key) => self.insert(format!("{}.{}", name, key), value),
+                        Err(e) => log::warn!("Unable to store part of {}. Err: {}", name, e),
+                    }
+                }
+
+                return;
+            }
+            _ => {
+                log::debug!("Skipping {}", name);
+                return;
+            }
+        };
+
+        self.fields.insert(name, value);
+    }
+
+    /// Check the fields declared in `schema`. Integers are converted where a float is declared.
+    /// Other mismatches are removed and returned. Fields that aren't declared are kept.
+    pub fn apply_schema(&mut self, schema: &HashMap<String, FieldType>) -> Vec<String> {
+        let mut removed = Vec::new();
+
+        for (name, field_type) in schema {
+            let value = match self.fields.get_mut(name) {
+                Some(v) => v,
+                None => continue,
+            };
+
+            let converted = match (*field_type, &*value) {
+                (t, v) if t == v.field_type() => continue,
+                (FieldType::Float, TelemetryValue::Integer(i)) => TelemetryValue::Float(*i as f64),
+                _ => {
+                    self.fields.remove(name);
+                    removed.push(name.clone());
+                    continue;
+                }
+            };
+
+            *value = converted;
+        }
+
+        removed
+    }
+
+    /// Every field as a point in `measurement`, tagged with the device `id`
+    pub fn to_point(&self, uid: &str, measurement: &str) -> SeriesPoint {
+        let mut point = SeriesPoint::new(measurement, Utc::now().timestamp_millis()).tag("id", uid);
+
+        for (name, value) in &self.fields {
+            point = match value {
+                TelemetryValue::Boolean(b) => point.field(name, *b),
+                TelemetryValue::Integer(i) => point.field(name, *i),
+                TelemetryValue::Float(f) => point.field(name, *f),
+                TelemetryValue::Text(t) => point.field(name, t.clone()),
+            };
         }
+
+        point
+    }
+
+    /// Same as `to_point` as an Influx query
+    pub fn to_influx_query(&self, uid: &str, measurement: &str) -> WriteQuery {
+        influx::to_query(&self.to_point(uid, measurement))
+    }
+}
+
+fn key_name(key: Value) -> Result<String, Error> {
+    match key {
+        Value::Text(t) => Ok(t),
+        Value::Integer(i) => Ok(i.to_string()),
+        _ => Err(Error::CustomError(
+            "Telemetry keys must be text or integers!".to_string(),
+        )),
     }
 }
 
-impl InfluxTelemetryData {
-    pub fn to_influx_query(&self, category: String) -> WriteQuery {
-        // Create and return query
-        let data = self.clone();
-        data.into_query(category)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(s: &str) -> Value {
+        Value::Text(s.to_string())
+    }
+
+    #[test]
+    fn from_value_success() {
+        let value = Value::Map(
+            vec![
+                (Value::Integer(1), Value::Integer(-95)),
+                (text("temp"), Value::Float(21.5)),
+                (text("charging"), Value::Bool(true)),
+                (
+                    text("gps"),
+                    Value::Map(vec![(text("fix"), Value::Integer(3))].into_iter().collect()),
+                ),
+                (
+                    text("cells"),
+                    Value::Array(vec![Value::Integer(1), Value::Integer(2)]),
+                ),
+                (text("note"), Value::Null),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        let data = TelemetryData::from_value(value).unwrap();
+
+        assert_eq!(data.fields.len(), 5);
+        assert_eq!(data.fields["rsrp"], TelemetryValue::Integer(-95));
+        assert_eq!(data.fields["temp"], TelemetryValue::Float(21.5));
+        assert_eq!(data.fields["charging"], TelemetryValue::Boolean(true));
+        assert_eq!(data.fields["gps.fix"], TelemetryValue::Integer(3));
+        assert_eq!(
+            data.fields["cells"],
+            TelemetryValue::Text("[1,2]".to_string())
+        );
+    }
+
+    #[test]
+    fn from_value_failure() {
+        assert!(TelemetryData::from_value(Value::Integer(1)).is_err());
+    }
+
+    #[test]
+    fn apply_schema_success() {
+        let mut data = TelemetryData::default();
+        data.fields
+            .insert("temp".to_string(), TelemetryValue::Integer(21));
+        data.fields
+            .insert("rsrp".to_string(), TelemetryValue::Text("bad".to_string()));
+        data.fields
+            .insert("extra".to_string(), TelemetryValue::Boolean(false));
+
+        let schema = HashMap::from([
+            ("temp".to_string(), FieldType::Float),
+            ("rsrp".to_string(), FieldType::Integer),
+            ("version".to_string(), FieldType::String),
+        ]);
+
+        assert_eq!(data.apply_schema(&schema), vec!["rsrp".to_string()]);
+        assert_eq!(data.fields["temp"], TelemetryValue::Float(21.0));
+        assert_eq!(data.fields["extra"], TelemetryValue::Boolean(false));
+        assert!(!data.fields.contains_key("rsrp"));
     }
 }
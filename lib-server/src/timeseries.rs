@@ -0,0 +1,329 @@
+// System related
+use std::time::Duration;
+
+// Async Related
+use flume::Sender;
+
+// Influx
+use influxdb::{Query, Type, WriteQuery};
+
+// Cbor
+use serde_cbor::Value;
+
+// Local lib related
+use crate::influx::{self, parse_line};
+use crate::{acknowledge, broker, settings, Error, Event};
+use pyrinas_shared::{ManagmentDataType, SeriesPoint, SeriesQuery, SeriesQueryResponse};
+
+/// Takes the place of the Influx runner so `InfluxDataSave`, `DataSave` and expiry events end up
+/// here
+pub const RUNNER_NAME: &str = "influx";
+
+/// Most points returned by a single query
+pub const MAX_QUERY_POINTS: usize = 10000;
+
+/// Time between checks for points past their retention
+const RETENTION_INTERVAL_MS: u64 = 3600 * 1000;
+
+/// Each segment holds a day of points
+const SEGMENT_MS: i64 = 24 * 3600 * 1000;
+
+/// Segment trees are named `series-<days since the epoch>`
+const SEGMENT_PREFIX: &str = "series-";
+
+/// Sorts the same as the timestamp, including ones before the epoch
+fn time_key(time_ms: i64) -> [u8; 8] {
+    ((time_ms as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+/// Convert a point from line protocol. Points without a timestamp are given `now_ms`.
+pub fn from_line(line: &str, precision: &str, now_ms: i64) -> Result<SeriesPoint, Error> {
+    let line = parse_line(line).map_err(Error::CustomError)?;
+
+    let time_ms = match line.timestamp {
+        Some(t) => {
+            let t = t as i64;
+
+            match precision {
+                "ns" => t / 1_000_000,
+                "u" => t / 1000,
+                "ms" => t,
+                "s" => t * 1000,
+                "m" => t * 60 * 1000,
+                "h" => t * 3600 * 1000,
+                p => return Err(Error::CustomError(format!("Unknown precision: {}", p))),
+            }
+        }
+        None => now_ms,
+    };
+
+    let fields = line
+        .fields
+        .into_iter()
+        .map(|(k, v)| {
+            let v = match v {
+                Type::Boolean(b) => Value::Bool(b),
+                Type::Float(f) => Value::Float(f),
+                Type::SignedInteger(i) => Value::Integer(i as i128),
+                Type::UnsignedInteger(u) => Value::Integer(u as i128),
+                Type::Text(t) => Value::Text(t),
+            };
+
+            (k, v)
+        })
+        .collect();
+
+    Ok(SeriesPoint {
+        measurement: line.measurement,
+        time_ms,
+        tags: line.tags.into_iter().collect(),
+        fields,
+    })
+}
+
+/// Convert the query from an `InfluxDataSave` event
+pub fn from_query(query: &WriteQuery, now_ms: i64) -> Result<SeriesPoint, Error> {
+    let line = query
+        .build()
+        .map_err(|e| Error::CustomError(e.to_string()))?
+        .get();
+
+    from_line(&line, &query.get_precision(), now_ms)
+}
+
+/// Points split into a tree per day so old days can be dropped in one go
+#[derive(Debug, Clone)]
+pub struct Store {
+    db: sled::Db,
+}
+
+impl Store {
+    pub fn new(db: sled::Db) -> Store {
+        Store { db }
+    }
+
+    /// Every segment, oldest first
+    fn segments(&self) -> Vec<i64> {
+        let mut days: Vec<i64> = self
+            .db
+            .tree_names()
+            .iter()
+            .filter_map(|n| std::str::from_utf8(n).ok()?.strip_prefix(SEGMENT_PREFIX))
+            .filter_map(|d| d.parse().ok())
+            .collect();
+
+        days.sort_unstable();
+        days
+    }
+
+    fn segment(&self, day: i64) -> Result<sled::Tree, Error> {
+        Ok(self.db.open_tree(format!("{}{}", SEGMENT_PREFIX, day))?)
+    }
+
+    pub fn insert(&self, point: &SeriesPoint) -> Result<(), Error> {
+        let segment = self.segment(point.time_ms.div_euclid(SEGMENT_MS))?;
+
+        // Id keeps points with the same timestamp apart
+        let mut key = time_key(point.time_ms).to_vec();
+        key.extend_from_slice(&self.db.generate_id()?.to_be_bytes());
+
+        segment.insert(key, serde_cbor::to_vec(point)?)?;
+
+        Ok(())
+    }
+
+    /// Matching points, oldest first. Only the most recent `MAX_QUERY_POINTS` if there's no limit.
+    pub fn query(&self, query: &SeriesQuery) -> Result<Vec<SeriesPoint>, Error> {
+        let start_ms = query.start_ms.unwrap_or(i64::MIN);
+        let end_ms = query.end_ms.unwrap_or(i64::MAX);
+        let limit = query
+            .limit
+            .unwrap_or(MAX_QUERY_POINTS)
+            .min(MAX_QUERY_POINTS);
+
+        let first = start_ms.div_euclid(SEGMENT_MS);
+        let last = end_ms.div_euclid(SEGMENT_MS);
+
+        let mut points = Vec::new();
+
+        // Newest first so the limit keeps the most recent points
+        'segments: for day in self.segments().into_iter().rev() {
+            if day < first || day > last {
+                continue;
+            }
+
+            let segment = self.segment(day)?;
+
+            for entry in segment.range(time_key(start_ms)..).rev() {
+                let (_, value) = entry?;
+                let point: SeriesPoint = serde_cbor::from_slice(&value)?;
+
+                if point.time_ms > end_ms
+                    || point.measurement != query.measurement
+                    || query.tags.iter().any(|(k, v)| point.tags.get(k) != Some(v))
+                {
+                    continue;
+                }
+
+                if points.len() == limit {
+                    break 'segments;
+                }
+
+                points.push(point);
+            }
+        }
+
+        points.reverse();
+
+        Ok(points)
+    }
+
+    /// Drop every day that ended before `before_ms`. Returns the number of days dropped.
+    pub fn expire(&self, before_ms: i64) -> Result<usize, Error> {
+        let mut dropped = 0;
+
+        for day in self.segments() {
+            if (day + 1) * SEGMENT_MS > before_ms {
+                break;
+            }
+
+            self.db.drop_tree(format!("{}{}", SEGMENT_PREFIX, day))?;
+            dropped += 1;
+        }
+
+        Ok(dropped)
+    }
+
+    /// Remove points from `measurement` older than `before_ms`. Returns the number removed.
+    pub fn remove(&self, measurement: &str, before_ms: i64) -> Result<usize, Error> {
+        let last = before_ms.div_euclid(SEGMENT_MS);
+        let mut removed = 0;
+
+        for day in self.segments() {
+            if day > last {
+                break;
+            }
+
+            let segment = self.segment(day)?;
+
+            for entry in segment.range(..time_key(before_ms)) {
+                let (key, value) = entry?;
+                let point: SeriesPoint = serde_cbor::from_slice(&value)?;
+
+                if point.measurement == measurement {
+                    segment.remove(key)?;
+                    removed += 1;
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+async fn process_event(broker_sender: &Sender<Event>, store: &Store, event: Event) {
+    match event {
+        Event::InfluxDataSave(query) => {
+            let now_ms = chrono::Utc::now().timestamp_millis();
+
+            if let Err(e) = from_query(&query, now_ms).and_then(|p| store.insert(&p)) {
+                log::error!("Unable to save point. Err: {}", e);
+            }
+        }
+        Event::DataSave(point) => {
+            if let Err(e) = store.insert(&point) {
+                log::error!("Unable to save point. Err: {}", e);
+            }
+        }
+        Event::DataExpire {
+            measurement,
+            before_ms,
+        }
+        | Event::InfluxDataExpire {
+            measurement,
+            before_ms,
+        } => match store.remove(&measurement, before_ms) {
+            Ok(removed) => log::info!("Removed {} {} points.", removed, measurement),
+            Err(e) => log::error!("Unable to remove {} points. Err: {}", measurement, e),
+        },
+        Event::InfluxDataRequest { id, reply_to, .. } => {
+            let result = Err(Error::CustomError(
+                "InfluxQL queries need Influx. Use QuerySeries with the local store.".to_string(),
+            ));
+
+            influx::respond(broker_sender, id, reply_to, result).await;
+        }
+        Event::SeriesQueryRequest(id, query) => match store.query(&query) {
+            Ok(points) => {
+                let response = SeriesQueryResponse { points };
+
+                if let Err(e) = broker_sender
+                    .send_async(Event::SeriesQueryResponse(id, Box::new(response)))
+                    .await
+                {
+                    log::error!("Unable to send query response. Err: {}", e);
+                }
+            }
+            Err(e) => {
+                log::warn!("Unable to query {}. Err: {}", query.measurement, e);
+                acknowledge(broker_sender, &id, ManagmentDataType::QuerySeries, Err(e)).await;
+            }
+        },
+        _ => (),
+    }
+}
+
+fn expire(store: &Store, settings: &settings::Timeseries) -> Result<(), Error> {
+    if settings.retention_days == 0 {
+        return Ok(());
+    }
+
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    let dropped = store.expire(now_ms - settings.retention_days as i64 * SEGMENT_MS)?;
+
+    if dropped > 0 {
+        log::info!("Removed {} days of points.", dropped);
+    }
+
+    Ok(())
+}
+
+pub async fn run(settings: &settings::Timeseries, broker_sender: Sender<Event>) {
+    // Open the DB
+    let db = sled::open(&settings.path).expect("Unable to open time-series database!");
+
+    run_with_db(db, settings, broker_sender).await;
+}
+
+/// Same as `run` but using a database that's already open
+pub async fn run_with_db(
+    sled_db: sled::Db,
+    settings: &settings::Timeseries,
+    broker_sender: Sender<Event>,
+) {
+    // Register this task
+    let reciever = broker::register(RUNNER_NAME, &broker_sender).await.unwrap();
+
+    let store = Store::new(sled_db.clone());
+
+    let mut interval = tokio::time::interval(Duration::from_millis(RETENTION_INTERVAL_MS));
+
+    loop {
+        tokio::select! {
+            event = reciever.recv_async() => match event {
+                Ok(event) => process_event(&broker_sender, &store, event).await,
+                Err(_) => break,
+            },
+            _ = interval.tick() => {
+                if let Err(e) = expire(&store, settings) {
+                    log::error!("Unable to remove old points. Err: {}", e);
+                }
+            }
+        };
+    }
+
+    // Broker has stopped
+    if let Err(e) = sled_db.flush_async().await {
+        log::error!("Unable to flush time-series db. Err: {}", e);
+    }
+}
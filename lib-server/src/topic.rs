@@ -0,0 +1,343 @@
+// System related
+use std::fmt;
+
+// Local lib related
+use crate::encoding::Encoding;
+use crate::Error;
+
+/// What a message is for. Second segment of `<uid>/<kind>/<p|s>/<target>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TopicKind {
+    Ota,
+    Telemetry,
+    Application,
+    /// `online` when the device connects and `offline` as its last will
+    Status,
+    /// Reported state from the device and deltas sent to it
+    Shadow,
+    /// Commands sent to the device and its acknowledgements
+    Command,
+}
+
+impl TopicKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TopicKind::Ota => "ota",
+            TopicKind::Telemetry => "tel",
+            TopicKind::Application => "app",
+            TopicKind::Status => "status",
+            TopicKind::Shadow => "shadow",
+            TopicKind::Command => "cmd",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<TopicKind> {
+        match s {
+            "ota" => Some(TopicKind::Ota),
+            "tel" => Some(TopicKind::Telemetry),
+            "app" => Some(TopicKind::Application),
+            "status" => Some(TopicKind::Status),
+            "shadow" => Some(TopicKind::Shadow),
+            "cmd" => Some(TopicKind::Command),
+            _ => None,
+        }
+    }
+}
+
+/// Which way a message is going
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Device to server (`p`)
+    Publish,
+    /// Server to device (`s`)
+    Subscribe,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Publish => "p",
+            Direction::Subscribe => "s",
+        }
+    }
+}
+
+/// A parsed `<uid>/<kind>/<p|s>/<target>[/json]` topic
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topic {
+    pub uid: String,
+    pub kind: TopicKind,
+    pub direction: Direction,
+    /// Everything after the direction, without the encoding. Can contain `/`.
+    pub target: Option<String>,
+    /// Set by a trailing `json` segment
+    pub encoding: Encoding,
+}
+
+impl Topic {
+    pub fn new(uid: &str, kind: TopicKind, direction: Direction, target: Option<&str>) -> Topic {
+        Topic {
+            uid: uid.to_string(),
+            kind,
+            direction,
+            target: target.map(|t| t.to_string()),
+            encoding: Encoding::Cbor,
+        }
+    }
+
+    /// Same topic with the payload in `encoding`
+    pub fn with_encoding(mut self, encoding: Encoding) -> Topic {
+        self.encoding = encoding;
+        self
+    }
+
+    /// `<uid>/app/s/<target>`
+    pub fn app_response(uid: &str, target: &str) -> Topic {
+        Topic::new(
+            uid,
+            TopicKind::Application,
+            Direction::Subscribe,
+            Some(target),
+        )
+    }
+
+    /// `<uid>/ota/s`
+    pub fn ota_response(uid: &str) -> Topic {
+        Topic::new(uid, TopicKind::Ota, Direction::Subscribe, None)
+    }
+
+    /// `<uid>/ota/s/d`
+    pub fn ota_download(uid: &str) -> Topic {
+        Topic::new(uid, TopicKind::Ota, Direction::Subscribe, Some("d"))
+    }
+
+    /// `<uid>/cmd/s/<name>`
+    pub fn command(uid: &str, name: &str) -> Topic {
+        Topic::new(uid, TopicKind::Command, Direction::Subscribe, Some(name))
+    }
+
+    /// `<uid>/shadow/s/delta`
+    pub fn shadow_delta(uid: &str) -> Topic {
+        Topic::new(uid, TopicKind::Shadow, Direction::Subscribe, Some("delta"))
+    }
+}
+
+impl fmt::Display for Topic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}/{}/{}",
+            self.uid,
+            self.kind.as_str(),
+            self.direction.as_str()
+        )?;
+
+        if let Some(t) = &self.target {
+            write!(f, "/{}", t)?;
+        }
+
+        match self.encoding.suffix() {
+            Some(s) => write!(f, "/{}", s),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parses and formats topics, with an optional prefix (i.e. `org/site`) in front of the uid
+#[derive(Debug, Clone, Default)]
+pub struct TopicScheme {
+    prefix: Option<String>,
+}
+
+impl TopicScheme {
+    pub fn new(prefix: Option<String>) -> TopicScheme {
+        // Slashes on either end would create empty segments
+        let prefix = prefix
+            .map(|p| p.trim_matches('/').to_string())
+            .filter(|p| !p.is_empty());
+
+        TopicScheme { prefix }
+    }
+
+    pub fn parse(&self, topic: &str) -> Result<Topic, Error> {
+        let invalid = |reason: &str| Error::InvalidTopic {
+            topic: topic.to_string(),
+            reason: reason.to_string(),
+        };
+
+        let mut rest = topic;
+
+        if let Some(prefix) = &self.prefix {
+            rest = rest
+                .strip_prefix(prefix.as_str())
+                .and_then(|r| r.strip_prefix('/'))
+                .ok_or_else(|| invalid("missing prefix"))?;
+        }
+
+        let mut segments = rest.splitn(4, '/');
+
+        let uid = match segments.next() {
+            Some(u) if !u.is_empty() => u,
+            _ => return Err(invalid("missing uid")),
+        };
+
+        let kind = segments
+            .next()
+            .and_then(TopicKind::from_str)
+            .ok_or_else(|| invalid("unknown kind"))?;
+
+        let direction = match segments.next() {
+            Some("p") => Direction::Publish,
+            Some("s") => Direction::Subscribe,
+            _ => return Err(invalid("missing p or s")),
+        };
+
+        let target = segments.next().filter(|t| !t.is_empty());
+
+        // A trailing `json` segment sets the encoding rather than being part of the target
+        let (target, encoding) = match target {
+            Some("json") => (None, Encoding::Json),
+            Some(t) => match t.strip_suffix("/json") {
+                Some(t) => (Some(t), Encoding::Json),
+                None => (Some(t), Encoding::Cbor),
+            },
+            None => (None, Encoding::Cbor),
+        };
+
+        Ok(Topic::new(uid, kind, direction, target).with_encoding(encoding))
+    }
+
+    pub fn format(&self, topic: &Topic) -> String {
+        match &self.prefix {
+            Some(prefix) => format!("{}/{}", prefix, topic),
+            None => topic.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_app_success() {
+        let topic = TopicScheme::default().parse("1234/app/p/env").unwrap();
+
+        assert_eq!(
+            topic,
+            Topic::new(
+                "1234",
+                TopicKind::Application,
+                Direction::Publish,
+                Some("env")
+            )
+        );
+    }
+
+    #[test]
+    fn parse_without_target_success() {
+        let topic = TopicScheme::default().parse("1234/ota/p").unwrap();
+
+        assert_eq!(topic.kind, TopicKind::Ota);
+        assert_eq!(topic.direction, Direction::Publish);
+        assert_eq!(topic.target, None);
+    }
+
+    #[test]
+    fn parse_nested_target_success() {
+        // Segments that match the direction don't confuse the parser
+        let topic = TopicScheme::default().parse("p/app/p/s/p").unwrap();
+
+        assert_eq!(topic.uid, "p");
+        assert_eq!(topic.target.as_deref(), Some("s/p"));
+    }
+
+    #[test]
+    fn parse_prefix_success() {
+        let scheme = TopicScheme::new(Some("/org/site/".to_string()));
+        let topic = scheme.parse("org/site/1234/tel/p").unwrap();
+
+        assert_eq!(topic.uid, "1234");
+        assert_eq!(topic.kind, TopicKind::Telemetry);
+    }
+
+    #[test]
+    fn parse_json_success() {
+        let scheme = TopicScheme::default();
+
+        let topic = scheme.parse("1234/tel/p/json").unwrap();
+        assert_eq!(topic.target, None);
+        assert_eq!(topic.encoding, Encoding::Json);
+
+        let topic = scheme.parse("1234/app/p/env/json").unwrap();
+        assert_eq!(topic.target.as_deref(), Some("env"));
+        assert_eq!(topic.encoding, Encoding::Json);
+
+        // Only the last segment counts
+        let topic = scheme.parse("1234/app/p/json/env").unwrap();
+        assert_eq!(topic.target.as_deref(), Some("json/env"));
+        assert_eq!(topic.encoding, Encoding::Cbor);
+    }
+
+    #[test]
+    fn parse_command_success() {
+        let topic = TopicScheme::default().parse("1234/cmd/p").unwrap();
+
+        assert_eq!(topic.kind, TopicKind::Command);
+        assert_eq!(topic.direction, Direction::Publish);
+        assert_eq!(topic.target, None);
+    }
+
+    #[test]
+    fn parse_failure() {
+        let scheme = TopicScheme::default();
+
+        assert!(scheme.parse("").is_err());
+        assert!(scheme.parse("/app/p/env").is_err());
+        assert!(scheme.parse("1234/foo/p/env").is_err());
+        assert!(scheme.parse("1234/app/x/env").is_err());
+        assert!(scheme.parse("1234/app").is_err());
+    }
+
+    #[test]
+    fn parse_prefix_failure() {
+        let scheme = TopicScheme::new(Some("org".to_string()));
+
+        assert!(scheme.parse("1234/app/p/env").is_err());
+        assert!(scheme.parse("organization/1234/app/p/env").is_err());
+    }
+
+    #[test]
+    fn format_success() {
+        let scheme = TopicScheme::default();
+
+        assert_eq!(
+            scheme.format(&Topic::app_response("1234", "env")),
+            "1234/app/s/env"
+        );
+        assert_eq!(scheme.format(&Topic::ota_response("1234")), "1234/ota/s");
+        assert_eq!(scheme.format(&Topic::ota_download("1234")), "1234/ota/s/d");
+        assert_eq!(
+            scheme.format(&Topic::shadow_delta("1234")),
+            "1234/shadow/s/delta"
+        );
+        assert_eq!(
+            scheme.format(&Topic::command("1234", "reboot")),
+            "1234/cmd/s/reboot"
+        );
+        assert_eq!(
+            scheme.format(&Topic::ota_download("1234").with_encoding(Encoding::Json)),
+            "1234/ota/s/d/json"
+        );
+    }
+
+    #[test]
+    fn format_prefix_success() {
+        let scheme = TopicScheme::new(Some("org".to_string()));
+        let topic = Topic::app_response("1234", "env");
+        let formatted = scheme.format(&topic);
+
+        assert_eq!(formatted, "org/1234/app/s/env");
+        assert_eq!(scheme.parse(&formatted).unwrap(), topic);
+    }
+}
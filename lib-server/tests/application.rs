@@ -0,0 +1,226 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use influxdb::{Query, ReadQuery};
+use serde_cbor::Value;
+
+use pyrinas_server::application::Application;
+use pyrinas_server::payload::PyrinasPayload;
+use pyrinas_server::{
+    broker, settings, ApplicationData, Event, InfluxQueryResponse, InfluxSeries, SeriesPoint,
+};
+
+mod common;
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct Counter {
+    count: u32,
+}
+
+fn get_request(target: &str, msg: Vec<u8>) -> Event {
+    Event::ApplicationRequest(ApplicationData {
+        uid: "1234".to_string(),
+        target: target.to_string(),
+        msg,
+    })
+}
+
+#[tokio::test]
+async fn handle_and_reply_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let mqtt = broker::register("mqtt", &broker_sender).await.unwrap();
+
+    // Increments whatever the count was by the amount in settings
+    let app = Application::new().handle("count", |ctx, msg: Counter| async move {
+        let count = msg.count + *ctx.settings();
+        ctx.reply("count", &Counter { count }).await
+    });
+
+    tokio::task::spawn(app.run(Arc::new(2u32), broker_sender.clone()));
+
+    // Wait for the app to register
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+    common::wait_for(&broker_sender, &sock, "app").await;
+
+    // Not valid and not handled
+    broker_sender
+        .send_async(get_request("count", vec![0xff]))
+        .await
+        .unwrap();
+    broker_sender
+        .send_async(get_request("other", vec![]))
+        .await
+        .unwrap();
+
+    // Valid
+    let msg = serde_cbor::to_vec(&Counter { count: 1 }).unwrap();
+    broker_sender
+        .send_async(get_request("count", msg))
+        .await
+        .unwrap();
+
+    match mqtt.recv_async().await.unwrap() {
+        Event::ApplicationResponse(r) => {
+            assert_eq!(r.uid, "1234");
+            assert_eq!(r.target, "count");
+
+            let res: Counter = serde_cbor::from_slice(&r.msg).unwrap();
+            assert_eq!(res, Counter { count: 3 });
+        }
+        _ => panic!("Unexpected event!"),
+    };
+
+    assert!(mqtt.is_empty());
+}
+
+#[derive(Debug, Serialize, Deserialize, PyrinasPayload)]
+#[pyrinas(measurement = "gps")]
+struct GpsReport {
+    lat: f64,
+    #[pyrinas(timestamp)]
+    ts: i64,
+}
+
+#[tokio::test]
+async fn store_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let influx = broker::register("influx", &broker_sender).await.unwrap();
+
+    let app = Application::new().store::<GpsReport>("gps");
+    tokio::task::spawn(app.run(Arc::new(()), broker_sender.clone()));
+
+    // Wait for the app to register
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+    common::wait_for(&broker_sender, &sock, "app").await;
+
+    let msg = serde_cbor::to_vec(&GpsReport {
+        lat: 1.5,
+        ts: 1620000000000,
+    })
+    .unwrap();
+    broker_sender
+        .send_async(get_request("gps", msg))
+        .await
+        .unwrap();
+
+    // Saved without a handler
+    match influx.recv_async().await.unwrap() {
+        Event::DataSave(point) => assert_eq!(
+            *point,
+            SeriesPoint::new("gps", 1620000000000)
+                .tag("id", "1234")
+                .field("lat", 1.5)
+        ),
+        _ => panic!("Unexpected event!"),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+struct Position {
+    lat: f64,
+}
+
+#[tokio::test]
+async fn query_result_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let mqtt = broker::register("mqtt", &broker_sender).await.unwrap();
+    let influx = broker::register("influx", &broker_sender).await.unwrap();
+
+    // Looks up the last position then sends it to the device
+    let app = Application::new()
+        .handle("position", |ctx, _: Counter| async move {
+            ctx.query(ReadQuery::new("SELECT last(lat) AS lat FROM gps"))
+                .await
+                .map(|_| ())
+        })
+        .on_query_result(|ctx, _id, result| async move {
+            let response = result.map_err(pyrinas_server::Error::CustomError)?;
+            let positions: Vec<Position> = response.series[0].rows()?;
+            ctx.publish("1234", "position", &positions[0]).await
+        });
+
+    tokio::task::spawn(app.run(Arc::new(()), broker_sender.clone()));
+
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+    common::wait_for(&broker_sender, &sock, "app").await;
+
+    let msg = serde_cbor::to_vec(&Counter { count: 0 }).unwrap();
+    broker_sender
+        .send_async(get_request("position", msg))
+        .await
+        .unwrap();
+
+    // Stand in for Influx
+    let (id, reply_to) = match influx.recv_async().await.unwrap() {
+        Event::InfluxDataRequest {
+            id,
+            reply_to,
+            query,
+        } => {
+            assert_eq!(
+                query.build().unwrap().get(),
+                "SELECT last(lat) AS lat FROM gps"
+            );
+            (id, reply_to)
+        }
+        _ => panic!("Unexpected event!"),
+    };
+
+    assert_eq!(reply_to, "app");
+
+    let response = InfluxQueryResponse {
+        series: vec![InfluxSeries {
+            name: "gps".to_string(),
+            tags: Default::default(),
+            columns: vec!["time".to_string(), "lat".to_string()],
+            values: vec![vec![Value::Integer(0), Value::Float(1.5)]],
+        }],
+    };
+
+    broker_sender
+        .send_async(Event::InfluxDataResponse {
+            id,
+            reply_to,
+            result: Ok(Box::new(response)),
+        })
+        .await
+        .unwrap();
+
+    match mqtt.recv_async().await.unwrap() {
+        Event::ApplicationResponse(r) => {
+            assert_eq!(r.target, "position");
+
+            let res: Position = serde_cbor::from_slice(&r.msg).unwrap();
+            assert_eq!(res, Position { lat: 1.5 });
+        }
+        _ => panic!("Unexpected event!"),
+    };
+}
@@ -0,0 +1,570 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use flume::Sender;
+
+use pyrinas_server::broker::Registration;
+use pyrinas_server::middleware::{Action, Middleware};
+use pyrinas_server::settings::{self, OverflowPolicy};
+use pyrinas_server::shutdown::Shutdown;
+use pyrinas_server::{
+    broker, supervisor, ApplicationData, BrokerEventCount, BrokerStatsResponse, DeadLetterEntry,
+    Event, ManagmentDataType,
+};
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+/// Broker settings with a tiny queue for the "app" runner
+fn get_settings(overflow: OverflowPolicy, spill_path: Option<String>) -> settings::Broker {
+    let mut runners = HashMap::new();
+    runners.insert(
+        "app".to_string(),
+        settings::Runner {
+            capacity: 1,
+            overflow,
+        },
+    );
+
+    settings::Broker {
+        spill_path,
+        runners,
+        ..Default::default()
+    }
+}
+
+fn get_request(index: u8) -> Event {
+    Event::ApplicationRequest(ApplicationData {
+        uid: "1234".to_string(),
+        target: "data".to_string(),
+        msg: vec![index],
+    })
+}
+
+/// Starts the broker and registers the "app" and "sock" runners
+async fn start(settings: settings::Broker) -> (Sender<Event>, Registration, Registration) {
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let app = broker::register("app", &broker_sender).await.unwrap();
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+
+    (broker_sender, app, sock)
+}
+
+/// Get stats. Also guarantees every event sent before has been handled by the broker.
+async fn get_stats(broker_sender: &Sender<Event>, sock: &Registration) -> BrokerStatsResponse {
+    broker_sender
+        .send_async(Event::BrokerStatsRequest(None))
+        .await
+        .unwrap();
+
+    match sock.recv_async().await.unwrap() {
+        Event::BrokerStatsResponse(_, r) => r,
+        _ => panic!("Unexpected event!"),
+    }
+}
+
+/// Get dead letters from the broker
+async fn get_dead_letters(
+    broker_sender: &Sender<Event>,
+    sock: &Registration,
+) -> Vec<DeadLetterEntry> {
+    broker_sender
+        .send_async(Event::DeadLetterListRequest(None))
+        .await
+        .unwrap();
+
+    match sock.recv_async().await.unwrap() {
+        Event::DeadLetterListResponse(_, r) => r.entries,
+        _ => panic!("Unexpected event!"),
+    }
+}
+
+/// Check an acknowledgement was successful
+async fn assert_ack(sock: &Registration, expected: ManagmentDataType) {
+    match sock.recv_async().await.unwrap() {
+        Event::ManagementAck { cmd, result, .. } => {
+            assert_eq!(cmd, expected);
+            assert!(result.is_ok());
+        }
+        _ => panic!("Unexpected event!"),
+    }
+}
+
+/// Broker settings with dead letters enabled
+fn get_dead_letter_settings(name: &str, capacity: usize) -> (settings::Broker, PathBuf) {
+    let path = std::env::temp_dir().join(format!("pyrinas-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&path);
+
+    let settings = settings::Broker {
+        dead_letter: Some(settings::DeadLetter {
+            path: path.to_string_lossy().to_string(),
+            capacity,
+        }),
+        ..Default::default()
+    };
+
+    (settings, path)
+}
+
+fn get_index(event: Event) -> u8 {
+    match event {
+        Event::ApplicationRequest(r) => r.msg[0],
+        _ => panic!("Unexpected event!"),
+    }
+}
+
+#[tokio::test]
+async fn register_twice_failure() {
+    // Log setup
+    setup();
+
+    let (broker_sender, _app, _sock) = start(Default::default()).await;
+
+    // Second registration with the same name is refused
+    assert!(broker::register("app", &broker_sender).await.is_err());
+}
+
+#[tokio::test]
+async fn deregister_on_drop_success() {
+    // Log setup
+    setup();
+
+    let (broker_sender, app, sock) = start(Default::default()).await;
+
+    drop(app);
+
+    // No longer shows up
+    let stats = get_stats(&broker_sender, &sock).await;
+    assert!(stats.runners.iter().all(|r| r.name != "app"));
+
+    // Can register again
+    let app = broker::register("app", &broker_sender).await.unwrap();
+
+    broker_sender.send_async(get_request(1)).await.unwrap();
+    assert_eq!(get_index(app.recv_async().await.unwrap()), 1);
+}
+
+#[tokio::test]
+async fn supervisor_restart_success() {
+    // Log setup
+    setup();
+
+    let settings = settings::Supervisor {
+        initial_backoff_ms: 10,
+        max_backoff_ms: 100,
+    };
+
+    let (broker_sender, _app, _sock) = start(Default::default()).await;
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    // Crashes while registered the first time around
+    let task_runs = runs.clone();
+    let handle = supervisor::spawn("test", &settings, &Shutdown::new(), move || {
+        let runs = task_runs.clone();
+        let broker_sender = broker_sender.clone();
+        async move {
+            let reciever = broker::register("test", &broker_sender).await.unwrap();
+
+            if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                panic!("First run!");
+            }
+
+            while reciever.recv_async().await.is_ok() {}
+        }
+    });
+
+    // Wait for the restart
+    for _ in 0..100 {
+        if runs.load(Ordering::SeqCst) >= 2 {
+            break;
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    assert_eq!(runs.load(Ordering::SeqCst), 2);
+
+    handle.abort();
+}
+
+#[tokio::test]
+async fn shutdown_drain_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    let broker_task = tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let app = broker::register("app", &broker_sender).await.unwrap();
+
+    // Queued events are still delivered after shutdown
+    broker_sender.send_async(get_request(0)).await.unwrap();
+    broker_sender.send_async(Event::Shutdown).await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(1), broker_task)
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(get_index(app.recv_async().await.unwrap()), 0);
+    assert!(app.recv_async().await.is_err());
+}
+
+#[tokio::test]
+async fn supervisor_shutdown_success() {
+    // Log setup
+    setup();
+
+    let settings = settings::Supervisor {
+        initial_backoff_ms: 10,
+        max_backoff_ms: 100,
+    };
+
+    let shutdown = Shutdown::new();
+    let runs = Arc::new(AtomicUsize::new(0));
+
+    // Exits as soon as shutdown is triggered
+    let task_runs = runs.clone();
+    let task_shutdown = shutdown.clone();
+    let handle = supervisor::spawn("test", &settings, &shutdown, move || {
+        let runs = task_runs.clone();
+        let shutdown = task_shutdown.clone();
+        async move {
+            runs.fetch_add(1, Ordering::SeqCst);
+            shutdown.wait().await;
+        }
+    });
+
+    shutdown.trigger();
+
+    tokio::time::timeout(Duration::from_secs(1), handle)
+        .await
+        .unwrap()
+        .unwrap();
+
+    assert_eq!(runs.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn drop_newest_success() {
+    // Log setup
+    setup();
+
+    let (broker_sender, app, sock) = start(get_settings(OverflowPolicy::DropNewest, None)).await;
+
+    for i in 0..3 {
+        broker_sender.send_async(get_request(i)).await.unwrap();
+    }
+
+    let stats = get_stats(&broker_sender, &sock).await;
+    let app_stats = stats.runners.iter().find(|r| r.name == "app").unwrap();
+
+    assert_eq!(app_stats.dropped, 2);
+    assert_eq!(app_stats.queued, 1);
+
+    // Only the first made it
+    assert_eq!(get_index(app.recv_async().await.unwrap()), 0);
+    assert!(app.is_empty());
+}
+
+#[tokio::test]
+async fn drop_oldest_success() {
+    // Log setup
+    setup();
+
+    let (broker_sender, app, sock) = start(get_settings(OverflowPolicy::DropOldest, None)).await;
+
+    for i in 0..3 {
+        broker_sender.send_async(get_request(i)).await.unwrap();
+    }
+
+    let stats = get_stats(&broker_sender, &sock).await;
+    let app_stats = stats.runners.iter().find(|r| r.name == "app").unwrap();
+
+    assert_eq!(app_stats.dropped, 2);
+
+    // Only the last one is left
+    assert_eq!(get_index(app.recv_async().await.unwrap()), 2);
+    assert!(app.is_empty());
+}
+
+#[tokio::test]
+async fn block_success() {
+    // Log setup
+    setup();
+
+    let (broker_sender, app, sock) = start(get_settings(OverflowPolicy::Block, None)).await;
+
+    for i in 0..2 {
+        broker_sender.send_async(get_request(i)).await.unwrap();
+    }
+
+    // Others are still served while app is full
+    let stats = tokio::time::timeout(Duration::from_secs(1), get_stats(&broker_sender, &sock))
+        .await
+        .unwrap();
+    let app_stats = stats.runners.iter().find(|r| r.name == "app").unwrap();
+
+    assert_eq!(app_stats.dropped, 0);
+
+    // Room now, but it waits its turn behind the one still held
+    assert_eq!(get_index(app.recv_async().await.unwrap()), 0);
+    broker_sender.send_async(get_request(2)).await.unwrap();
+
+    assert_eq!(get_index(app.recv_async().await.unwrap()), 1);
+    assert_eq!(get_index(app.recv_async().await.unwrap()), 2);
+}
+
+#[tokio::test]
+async fn block_timeout_success() {
+    // Log setup
+    setup();
+
+    let mut settings = get_settings(OverflowPolicy::Block, None);
+    settings.block_timeout_ms = 50;
+
+    let (broker_sender, app, sock) = start(settings).await;
+
+    for i in 0..3 {
+        broker_sender.send_async(get_request(i)).await.unwrap();
+    }
+
+    // Both waiting events give up
+    tokio::time::sleep(Duration::from_millis(300)).await;
+
+    let stats = get_stats(&broker_sender, &sock).await;
+    let app_stats = stats.runners.iter().find(|r| r.name == "app").unwrap();
+
+    assert_eq!(app_stats.dropped, 2);
+
+    assert_eq!(get_index(app.recv_async().await.unwrap()), 0);
+    assert!(app.is_empty());
+}
+
+#[test]
+fn capacity_failure() {
+    // Zero capacity queues are refused
+    assert!(toml::from_str::<settings::Broker>("capacity = 0").is_err());
+    assert!(toml::from_str::<settings::Broker>("[default]\ncapacity = 0").is_err());
+    assert!(toml::from_str::<settings::Broker>("[runners.app]\ncapacity = 0").is_err());
+
+    let settings: settings::Broker = toml::from_str("[runners.app]\ncapacity = 1").unwrap();
+    assert_eq!(settings.runner("app").capacity, 1);
+}
+
+#[tokio::test]
+async fn spill_success() {
+    // Log setup
+    setup();
+
+    let path = std::env::temp_dir().join(format!("pyrinas-spill-{}", std::process::id()));
+    let settings = get_settings(
+        OverflowPolicy::Spill,
+        Some(path.to_string_lossy().to_string()),
+    );
+
+    let (broker_sender, app, sock) = start(settings).await;
+
+    for i in 0..5 {
+        broker_sender.send_async(get_request(i)).await.unwrap();
+    }
+
+    let stats = get_stats(&broker_sender, &sock).await;
+    let app_stats = stats.runners.iter().find(|r| r.name == "app").unwrap();
+
+    assert_eq!(app_stats.dropped, 0);
+
+    // Everything arrives, in order
+    for i in 0..5 {
+        assert_eq!(get_index(app.recv_async().await.unwrap()), i);
+    }
+
+    let _ = std::fs::remove_dir_all(path);
+}
+
+#[tokio::test]
+async fn dead_letter_replay_success() {
+    // Log setup
+    setup();
+
+    let (settings, path) = get_dead_letter_settings("dead-letter-replay", 10);
+
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+
+    // Nobody to deliver to
+    broker_sender.send_async(get_request(1)).await.unwrap();
+
+    let entries = get_dead_letters(&broker_sender, &sock).await;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].runner, "app");
+
+    // Deliver once the app is around
+    let app = broker::register("app", &broker_sender).await.unwrap();
+
+    broker_sender
+        .send_async(Event::DeadLetterReplay {
+            id: Some(1),
+            entry: Some(entries[0].id),
+        })
+        .await
+        .unwrap();
+
+    assert_ack(&sock, ManagmentDataType::ReplayDeadLetters).await;
+    assert_eq!(get_index(app.recv_async().await.unwrap()), 1);
+    assert!(get_dead_letters(&broker_sender, &sock).await.is_empty());
+
+    let _ = std::fs::remove_dir_all(path);
+}
+
+#[tokio::test]
+async fn dead_letter_purge_success() {
+    // Log setup
+    setup();
+
+    let (settings, path) = get_dead_letter_settings("dead-letter-purge", 2);
+
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+
+    for i in 0..3 {
+        broker_sender.send_async(get_request(i)).await.unwrap();
+    }
+
+    // Oldest is gone
+    let entries = get_dead_letters(&broker_sender, &sock).await;
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0].event.contains("[1]"));
+
+    broker_sender
+        .send_async(Event::DeadLetterPurge {
+            id: Some(1),
+            entry: None,
+        })
+        .await
+        .unwrap();
+
+    assert_ack(&sock, ManagmentDataType::PurgeDeadLetters).await;
+    assert!(get_dead_letters(&broker_sender, &sock).await.is_empty());
+
+    let _ = std::fs::remove_dir_all(path);
+}
+
+/// Sends every application request twice
+struct Duplicate;
+
+impl Middleware for Duplicate {
+    fn name(&self) -> &str {
+        "duplicate"
+    }
+
+    fn handle(&mut self, event: Event) -> Action {
+        match event {
+            Event::ApplicationRequest(_) => Action::FanOut(vec![event.clone(), event]),
+            _ => Action::Continue(event),
+        }
+    }
+}
+
+/// Starts the broker with middleware and registers the "app" and "sock" runners
+async fn start_with_middleware(
+    settings: settings::Broker,
+    middleware: Vec<Box<dyn Middleware>>,
+) -> (Sender<Event>, Registration, Registration) {
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run_with_middleware(
+        settings,
+        broker_reciever,
+        middleware,
+    ));
+
+    let app = broker::register("app", &broker_sender).await.unwrap();
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+
+    (broker_sender, app, sock)
+}
+
+#[tokio::test]
+async fn middleware_fan_out_success() {
+    // Log setup
+    setup();
+
+    // Metrics goes after the custom middleware so it sees both copies
+    let settings = settings::Broker {
+        middleware: vec!["duplicate".to_string(), "metrics".to_string()],
+        ..Default::default()
+    };
+
+    let (broker_sender, app, sock) =
+        start_with_middleware(settings, vec![Box::new(Duplicate)]).await;
+
+    broker_sender.send_async(get_request(0)).await.unwrap();
+
+    assert_eq!(get_index(app.recv_async().await.unwrap()), 0);
+    assert_eq!(get_index(app.recv_async().await.unwrap()), 0);
+
+    let stats = get_stats(&broker_sender, &sock).await;
+    let count = stats
+        .events
+        .iter()
+        .find(|e| e.name == "ApplicationRequest")
+        .map(|e| e.count);
+
+    assert_eq!(count, Some(2));
+    assert!(stats.rejected.is_empty());
+}
+
+#[tokio::test]
+async fn middleware_size_limit_success() {
+    // Log setup
+    setup();
+
+    let settings = settings::Broker {
+        middleware: vec!["logging".to_string(), "size_limit".to_string()],
+        max_event_bytes: 128,
+        ..Default::default()
+    };
+
+    let (broker_sender, app, sock) = start_with_middleware(settings, Vec::new()).await;
+
+    // Too big
+    broker_sender
+        .send_async(Event::ApplicationRequest(ApplicationData {
+            uid: "1234".to_string(),
+            target: "data".to_string(),
+            msg: vec![0; 256],
+        }))
+        .await
+        .unwrap();
+
+    broker_sender.send_async(get_request(1)).await.unwrap();
+
+    let stats = get_stats(&broker_sender, &sock).await;
+
+    assert_eq!(get_index(app.recv_async().await.unwrap()), 1);
+    assert!(app.is_empty());
+    assert_eq!(
+        stats.rejected,
+        vec![BrokerEventCount {
+            name: "size_limit".to_string(),
+            count: 1
+        }]
+    );
+}
@@ -0,0 +1,281 @@
+use pyrinas_server::command::{self, Store};
+use pyrinas_server::device::{self, Registry};
+use pyrinas_server::{broker, settings, Event};
+use pyrinas_shared::{CommandAck, CommandRecord, CommandRequest, CommandState};
+
+mod common;
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+/// Id of a published command
+fn get_published(event: Event) -> u64 {
+    match event {
+        Event::CommandPublish { uid, name, command } => {
+            assert_eq!(uid, "1234");
+            assert_eq!(name, "reboot");
+            command.id
+        }
+        _ => panic!("Unexpected event!"),
+    }
+}
+
+/// Record from the response to `CommandSend`
+fn get_sent(event: Event) -> Box<CommandRecord> {
+    match event {
+        Event::CommandSendResponse { result, .. } => result.unwrap(),
+        _ => panic!("Unexpected event!"),
+    }
+}
+
+#[test]
+fn store_success() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let store = Store::open(&db).unwrap();
+    let settings: settings::Commands = Default::default();
+
+    let request = CommandRequest::new("1234", "reboot", Vec::new());
+
+    let record = store.add(request.clone(), &settings, 1000).unwrap();
+    assert_eq!(record.state, CommandState::Queued);
+    assert_eq!(record.timeout_secs, settings.timeout_secs);
+    assert_eq!(record.retries, settings.retries);
+    let id = record.id;
+
+    // Every command gets its own id
+    assert_ne!(store.add(request, &settings, 1000).unwrap().id, id);
+
+    assert_eq!(store.pending().unwrap().len(), 2);
+
+    // Only finished commands are pruned
+    assert_eq!(store.prune(2000).unwrap(), 0);
+
+    let mut record = store.get(id).unwrap().unwrap();
+    record.state = CommandState::Succeeded;
+    record.finished_ms = Some(1500);
+    store.save(&record).unwrap();
+
+    assert_eq!(store.pending().unwrap().len(), 1);
+    assert_eq!(store.prune(2000).unwrap(), 1);
+    assert!(store.get(id).unwrap().is_none());
+
+    // Index is rebuilt for commands saved without one
+    db.drop_tree("commands/pending").unwrap();
+    assert_eq!(Store::open(&db).unwrap().pending().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn command_queued_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    // Stand in for mqtt and the application
+    let mqtt = broker::register("mqtt", &broker_sender).await.unwrap();
+    let app = broker::register("app", &broker_sender).await.unwrap();
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let registry = Registry::open(&db).unwrap();
+
+    let commands: settings::Commands = Default::default();
+    let sender = broker_sender.clone();
+    let command_registry = registry.clone();
+    tokio::task::spawn(async move {
+        command::run_with_db(db, command_registry, &commands, sender).await
+    });
+
+    common::wait_for(&broker_sender, &sock, command::RUNNER_NAME).await;
+
+    // Device is offline so it waits
+    broker_sender
+        .send_async(Event::CommandSend {
+            id: Some(1),
+            reply_to: "sock".to_string(),
+            request: Box::new(CommandRequest::new("1234", "reboot", Vec::new())),
+        })
+        .await
+        .unwrap();
+
+    let id = match sock.recv_async().await.unwrap() {
+        Event::CommandSendResponse {
+            id: Some(1),
+            result: Ok(record),
+            ..
+        } => {
+            assert_eq!(record.state, CommandState::Queued);
+            record.id
+        }
+        _ => panic!("Unexpected event!"),
+    };
+
+    assert!(mqtt.is_empty());
+
+    // Sent once it connects
+    registry.seen("1234", 1000).unwrap();
+    broker_sender
+        .send_async(Event::DeviceOnline("1234".to_string()))
+        .await
+        .unwrap();
+
+    assert_eq!(get_published(mqtt.recv_async().await.unwrap()), id);
+
+    // Which the application sees the result of
+    broker_sender
+        .send_async(Event::CommandAck {
+            uid: "1234".to_string(),
+            ack: CommandAck {
+                id,
+                code: 0,
+                msg: Vec::new(),
+            },
+        })
+        .await
+        .unwrap();
+
+    let record = loop {
+        match app.recv_async().await.unwrap() {
+            Event::CommandResult(record) => break record,
+            _ => continue,
+        }
+    };
+
+    assert_eq!(record.id, id);
+    assert_eq!(record.state, CommandState::Succeeded);
+    assert_eq!(record.attempts, 1);
+
+    // And so does the admin
+    broker_sender
+        .send_async(Event::CommandGetRequest(Some(2), id))
+        .await
+        .unwrap();
+
+    match sock.recv_async().await.unwrap() {
+        Event::CommandGetResponse(cid, record) => {
+            assert_eq!(cid, Some(2));
+            assert_eq!(record.state, CommandState::Succeeded);
+            assert_eq!(record.ack.unwrap().code, 0);
+        }
+        _ => panic!("Unexpected event!"),
+    };
+}
+
+#[tokio::test]
+async fn command_timeout_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let mqtt = broker::register("mqtt", &broker_sender).await.unwrap();
+    let app = broker::register("app", &broker_sender).await.unwrap();
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let registry = Registry::open(&db).unwrap();
+    registry.seen("1234", 1000).unwrap();
+
+    let commands: settings::Commands = Default::default();
+    let sender = broker_sender.clone();
+    tokio::task::spawn(async move { command::run_with_db(db, registry, &commands, sender).await });
+
+    common::wait_for(&broker_sender, &sock, command::RUNNER_NAME).await;
+
+    // Never acknowledged
+    let mut request = CommandRequest::new("1234", "reboot", Vec::new());
+    request.timeout_secs = Some(0);
+    request.retries = Some(1);
+
+    broker_sender
+        .send_async(Event::CommandSend {
+            id: None,
+            reply_to: "app".to_string(),
+            request: Box::new(request),
+        })
+        .await
+        .unwrap();
+
+    let id = get_sent(app.recv_async().await.unwrap()).id;
+
+    // Sent, then sent again
+    assert_eq!(get_published(mqtt.recv_async().await.unwrap()), id);
+    assert_eq!(get_published(mqtt.recv_async().await.unwrap()), id);
+
+    let record = loop {
+        match app.recv_async().await.unwrap() {
+            Event::CommandResult(record) => break record,
+            _ => continue,
+        }
+    };
+
+    assert_eq!(record.state, CommandState::TimedOut);
+    assert_eq!(record.attempts, 2);
+    assert!(record.ack.is_none());
+}
+
+#[tokio::test]
+async fn command_restart_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let mqtt = broker::register("mqtt", &broker_sender).await.unwrap();
+    let app = broker::register("app", &broker_sender).await.unwrap();
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+
+    // Online when the server last stopped
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    Registry::open(&db).unwrap().seen("1234", 1000).unwrap();
+
+    // Shared with the command runner like the server does
+    let registry = Registry::open(&db).unwrap();
+    let device_registry = registry.clone();
+    let sender = broker_sender.clone();
+    tokio::task::spawn(async move {
+        device::run_with_registry(device_registry, &Default::default(), sender).await;
+    });
+
+    common::wait_for(&broker_sender, &sock, device::RUNNER_NAME).await;
+
+    let commands: settings::Commands = Default::default();
+    let sender = broker_sender.clone();
+    tokio::task::spawn(async move { command::run_with_db(db, registry, &commands, sender).await });
+
+    common::wait_for(&broker_sender, &sock, command::RUNNER_NAME).await;
+
+    // Waits for the device to come back
+    broker_sender
+        .send_async(Event::CommandSend {
+            id: Some(1),
+            reply_to: "app".to_string(),
+            request: Box::new(CommandRequest::new("1234", "reboot", Vec::new())),
+        })
+        .await
+        .unwrap();
+
+    let record = get_sent(app.recv_async().await.unwrap());
+    assert_eq!(record.state, CommandState::Queued);
+    assert!(mqtt.is_empty());
+
+    // Sent once it does
+    broker_sender
+        .send_async(Event::DeviceSeen("1234".to_string()))
+        .await
+        .unwrap();
+
+    assert_eq!(get_published(mqtt.recv_async().await.unwrap()), record.id);
+}
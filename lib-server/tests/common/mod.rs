@@ -0,0 +1,26 @@
+//! Helpers shared by the integration tests
+
+use std::time::Duration;
+
+use flume::Sender;
+
+use pyrinas_server::broker::Registration;
+use pyrinas_server::Event;
+
+/// Wait for a runner to register. Broker stats are sent to `sock`.
+pub async fn wait_for(broker_sender: &Sender<Event>, sock: &Registration, name: &str) {
+    loop {
+        broker_sender
+            .send_async(Event::BrokerStatsRequest(None))
+            .await
+            .unwrap();
+
+        if let Event::BrokerStatsResponse(_, stats) = sock.recv_async().await.unwrap() {
+            if stats.runners.iter().any(|r| r.name == name) {
+                break;
+            }
+        }
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
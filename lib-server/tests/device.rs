@@ -0,0 +1,267 @@
+use pyrinas_server::device::{self, Registry};
+use pyrinas_server::{broker, settings, Event};
+use pyrinas_shared::ota::v2::OTADeviceType;
+use pyrinas_shared::{DeviceState, DeviceUpdate};
+
+mod common;
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+#[test]
+fn registry_presence_success() {
+    // Log setup
+    setup();
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let registry = Registry::open(&db).unwrap();
+
+    // Comes online the first time it's seen
+    assert!(registry.seen("1234", 1000).unwrap());
+    assert!(!registry.seen("1234", 2000).unwrap());
+    assert_eq!(registry.online(), 1);
+
+    // Last will
+    assert!(registry.set_online("1234", false, 3000).unwrap());
+    assert!(!registry.set_online("1234", false, 4000).unwrap());
+    assert_eq!(registry.online(), 0);
+
+    let info = registry.get("1234").unwrap().unwrap();
+    assert!(!info.online);
+    assert_eq!(info.last_seen_ms, 2000);
+    assert_eq!(info.changed_ms, 3000);
+
+    // Unknown devices aren't added when they go offline
+    assert!(!registry.set_online("5678", false, 5000).unwrap());
+    assert_eq!(registry.list().unwrap().len(), 1);
+}
+
+#[test]
+fn registry_expire_success() {
+    // Log setup
+    setup();
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let registry = Registry::open(&db).unwrap();
+
+    registry.seen("1234", 1000).unwrap();
+    registry.seen("5678", 5000).unwrap();
+
+    assert_eq!(
+        registry.expire(2000, 6000).unwrap(),
+        vec!["1234".to_string()]
+    );
+    assert!(registry.expire(2000, 7000).unwrap().is_empty());
+    assert!(registry.get("5678").unwrap().unwrap().online);
+    assert_eq!(registry.online(), 1);
+
+    // Presence isn't kept when it's opened again
+    let reopened = Registry::open(&db).unwrap();
+    assert_eq!(reopened.online(), 0);
+    assert!(!reopened.get("5678").unwrap().unwrap().online);
+
+    registry.remove("5678").unwrap();
+    assert_eq!(registry.online(), 0);
+}
+
+#[test]
+fn registry_save_seen_success() {
+    // Log setup
+    setup();
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let registry = Registry::open(&db).unwrap();
+    let saved = || Registry::open(&db).unwrap().get("1234").unwrap().unwrap();
+
+    // Saved when it comes online
+    registry.seen("1234", 1000).unwrap();
+    assert_eq!(saved().last_seen_ms, 1000);
+
+    // Only kept in memory after that
+    registry.seen("1234", 5000).unwrap();
+    assert_eq!(saved().last_seen_ms, 1000);
+    assert_eq!(registry.get("1234").unwrap().unwrap().last_seen_ms, 5000);
+
+    assert_eq!(registry.save_seen().unwrap(), 1);
+    assert_eq!(saved().last_seen_ms, 5000);
+    assert_eq!(registry.save_seen().unwrap(), 0);
+
+    // Clones share presence
+    let clone = registry.clone();
+    clone.seen("1234", 6000).unwrap();
+    assert!(registry.is_online("1234"));
+    assert_eq!(registry.get("1234").unwrap().unwrap().last_seen_ms, 6000);
+}
+
+#[test]
+fn registry_lifecycle_success() {
+    // Log setup
+    setup();
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let registry = Registry::open(&db).unwrap();
+
+    let mut update = DeviceUpdate {
+        uid: "1234".to_string(),
+        device_type: Some(OTADeviceType::Cellular),
+        imei: Some("352656100000000".to_string()),
+        tags: vec!["field".to_string()],
+        ..Default::default()
+    };
+
+    let info = registry.add(&update, 1000).unwrap();
+    assert_eq!(info.state, DeviceState::Provisioned);
+    assert_eq!(info.created_ms, 1000);
+    assert_eq!(info.first_seen_ms, None);
+
+    // Can only be added once
+    assert!(registry.add(&update, 2000).is_err());
+
+    // Becomes active when it's first seen
+    registry.seen("1234", 3000).unwrap();
+
+    let info = registry.get("1234").unwrap().unwrap();
+    assert_eq!(info.state, DeviceState::Active);
+    assert_eq!(info.first_seen_ms, Some(3000));
+
+    // Only fields that are set change
+    update = DeviceUpdate {
+        uid: "1234".to_string(),
+        board: Some("circuitdojo_feather_nrf9160".to_string()),
+        state: Some(DeviceState::Suspended),
+        ..Default::default()
+    };
+
+    let info = registry.update(&update).unwrap();
+    assert_eq!(info.state, DeviceState::Suspended);
+    assert_eq!(info.imei.as_deref(), Some("352656100000000"));
+    assert_eq!(info.tags, vec!["field".to_string()]);
+
+    // Seeing it doesn't change the state back
+    registry.seen("1234", 4000).unwrap();
+    assert_eq!(
+        registry.get("1234").unwrap().unwrap().state,
+        DeviceState::Suspended
+    );
+
+    registry.remove("1234").unwrap();
+    assert!(registry.get("1234").unwrap().is_none());
+    assert!(registry.remove("1234").is_err());
+    assert!(registry.update(&update).is_err());
+}
+
+#[tokio::test]
+async fn presence_events_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let app = broker::register("app", &broker_sender).await.unwrap();
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let task_sender = broker_sender.clone();
+    tokio::task::spawn(async move {
+        device::run_with_db(db, &Default::default(), task_sender).await;
+    });
+
+    common::wait_for(&broker_sender, &sock, device::RUNNER_NAME).await;
+
+    broker_sender
+        .send_async(Event::DeviceSeen("1234".to_string()))
+        .await
+        .unwrap();
+
+    broker_sender
+        .send_async(Event::DeviceSeen("1234".to_string()))
+        .await
+        .unwrap();
+
+    broker_sender
+        .send_async(Event::DeviceConnection {
+            uid: "1234".to_string(),
+            online: false,
+        })
+        .await
+        .unwrap();
+
+    // Only changes are sent on
+    match app.recv_async().await.unwrap() {
+        Event::DeviceOnline(uid) => assert_eq!(uid, "1234"),
+        _ => panic!("Unexpected event!"),
+    };
+
+    match app.recv_async().await.unwrap() {
+        Event::DeviceOffline(uid) => assert_eq!(uid, "1234"),
+        _ => panic!("Unexpected event!"),
+    };
+
+    broker_sender
+        .send_async(Event::DeviceListRequest(Some(1)))
+        .await
+        .unwrap();
+
+    match sock.recv_async().await.unwrap() {
+        Event::DeviceListResponse(id, r) => {
+            assert_eq!(id, Some(1));
+            assert_eq!(r.devices.len(), 1);
+            assert_eq!(r.devices[0].uid, "1234");
+            assert!(!r.devices[0].online);
+        }
+        _ => panic!("Unexpected event!"),
+    };
+}
+
+#[tokio::test]
+async fn restart_offline_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let app = broker::register("app", &broker_sender).await.unwrap();
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+
+    // Online when the server last stopped
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    Registry::open(&db).unwrap().seen("1234", 1000).unwrap();
+
+    let task_sender = broker_sender.clone();
+    tokio::task::spawn(async move {
+        device::run_with_db(db, &Default::default(), task_sender).await;
+    });
+
+    common::wait_for(&broker_sender, &sock, device::RUNNER_NAME).await;
+
+    broker_sender
+        .send_async(Event::DeviceListRequest(Some(1)))
+        .await
+        .unwrap();
+
+    match sock.recv_async().await.unwrap() {
+        Event::DeviceListResponse(_, r) => assert!(!r.devices[0].online),
+        _ => panic!("Unexpected event!"),
+    };
+
+    // Reconnecting is noticed
+    broker_sender
+        .send_async(Event::DeviceSeen("1234".to_string()))
+        .await
+        .unwrap();
+
+    match app.recv_async().await.unwrap() {
+        Event::DeviceOnline(uid) => assert_eq!(uid, "1234"),
+        _ => panic!("Unexpected event!"),
+    };
+}
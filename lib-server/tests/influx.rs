@@ -0,0 +1,407 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use pyrinas_server::influx::{self, Spool, SpoolEntry, WriteError, Writer};
+use pyrinas_server::{broker, settings, Event};
+use pyrinas_shared::SeriesPoint;
+
+mod common;
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+fn entry(body: &str, points: usize) -> SpoolEntry {
+    SpoolEntry {
+        precision: "ms".to_string(),
+        body: body.to_string(),
+        points,
+    }
+}
+
+#[test]
+fn spool_order_success() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let spool = Spool::open(&db, 100).unwrap();
+
+    spool.push(&entry("first", 2)).unwrap();
+    spool.push(&entry("second", 3)).unwrap();
+
+    let metrics = spool.metrics();
+    assert_eq!(metrics.points(), 5);
+    assert_eq!(metrics.batches(), 2);
+
+    // Oldest first
+    assert_eq!(spool.first().unwrap(), Some(entry("first", 2)));
+    assert_eq!(spool.pop().unwrap(), Some(entry("first", 2)));
+    assert_eq!(spool.pop().unwrap(), Some(entry("second", 3)));
+    assert_eq!(spool.pop().unwrap(), None);
+
+    assert!(spool.is_empty());
+    assert_eq!(metrics.points(), 0);
+    assert_eq!(metrics.batches(), 0);
+}
+
+#[test]
+fn spool_expire_success() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let spool = Spool::open(&db, 100).unwrap();
+
+    spool
+        .push(&entry("gps lat=1 1000\ngps lat=2 3000", 2))
+        .unwrap();
+    spool.push(&entry("gps lat=3 1500", 1)).unwrap();
+    spool.push(&entry("telemetry rsrp=1i 1000", 1)).unwrap();
+
+    assert_eq!(spool.expire("gps", 2000).unwrap(), 2);
+
+    let metrics = spool.metrics();
+    assert_eq!(metrics.points(), 2);
+    assert_eq!(metrics.batches(), 2);
+
+    // Still in order
+    assert_eq!(spool.pop().unwrap(), Some(entry("gps lat=2 3000", 1)));
+    assert_eq!(
+        spool.pop().unwrap(),
+        Some(entry("telemetry rsrp=1i 1000", 1))
+    );
+}
+
+#[test]
+fn spool_cap_success() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let spool = Spool::open(&db, 5).unwrap();
+
+    spool.push(&entry("first", 2)).unwrap();
+    spool.push(&entry("second", 2)).unwrap();
+    spool.push(&entry("third", 2)).unwrap();
+
+    // Oldest batch makes room
+    let metrics = spool.metrics();
+    assert_eq!(metrics.points(), 4);
+    assert_eq!(metrics.dropped(), 2);
+    assert_eq!(spool.first().unwrap(), Some(entry("second", 2)));
+}
+
+#[test]
+fn spool_reopen_success() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+
+    {
+        let spool = Spool::open(&db, 100).unwrap();
+        spool.push(&entry("first", 2)).unwrap();
+        spool.push(&entry("second", 3)).unwrap();
+    }
+
+    // Picks up where it left off
+    let spool = Spool::open(&db, 100).unwrap();
+    assert_eq!(spool.metrics().points(), 5);
+    assert_eq!(spool.metrics().batches(), 2);
+    assert_eq!(spool.pop().unwrap(), Some(entry("first", 2)));
+}
+
+/// Influx 2.x on `port`. Full batches are written straight away, everything else every 100ms.
+fn get_settings(port: u16) -> settings::Influx {
+    toml::from_str(&format!(
+        r#"
+        host = "127.0.0.1"
+        port = {}
+        org = "pyrinas"
+        bucket = "data"
+        token = "secret"
+
+        [batch]
+        max_points = 3
+        flush_interval_ms = 100
+        initial_backoff_ms = 10
+        "#,
+        port
+    ))
+    .unwrap()
+}
+
+/// What Influx was sent
+#[derive(Debug)]
+struct Request {
+    /// Path and query
+    target: String,
+    /// Lower case names
+    headers: BTreeMap<String, String>,
+    body: String,
+}
+
+async fn read_request(stream: &mut TcpStream) -> Request {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 1024];
+
+    // Headers
+    let end = loop {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0, "Connection closed early!");
+        data.extend_from_slice(&buf[..n]);
+
+        if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i;
+        }
+    };
+
+    let head = String::from_utf8(data[..end].to_vec()).unwrap();
+    let mut lines = head.split("\r\n");
+    let target = lines.next().unwrap().split(' ').nth(1).unwrap().to_string();
+    let headers: BTreeMap<String, String> = lines
+        .filter_map(|l| l.split_once(':'))
+        .map(|(k, v)| (k.trim().to_lowercase(), v.trim().to_string()))
+        .collect();
+
+    // Body
+    let length: usize = headers
+        .get("content-length")
+        .map(|l| l.parse().unwrap())
+        .unwrap_or_default();
+    let mut body = data[end + 4..].to_vec();
+
+    while body.len() < length {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0, "Connection closed early!");
+        body.extend_from_slice(&buf[..n]);
+    }
+
+    Request {
+        target,
+        headers,
+        body: String::from_utf8(body).unwrap(),
+    }
+}
+
+/// Stands in for Influx. Answers requests with `statuses` in order then 204. Returns the port
+/// and every request it gets.
+async fn serve(statuses: Vec<u16>) -> (u16, flume::Receiver<Request>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let (sender, reciever) = flume::unbounded();
+
+    tokio::task::spawn(async move {
+        for i in 0.. {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let request = read_request(&mut stream).await;
+
+            if sender.send_async(request).await.is_err() {
+                break;
+            }
+
+            let response = format!(
+                "HTTP/1.1 {} Influx\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                statuses.get(i).unwrap_or(&204)
+            );
+            stream.write_all(response.as_bytes()).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    });
+
+    (port, reciever)
+}
+
+#[tokio::test]
+async fn writer_success() {
+    // Log setup
+    setup();
+
+    let (port, requests) = serve(vec![503, 204, 429, 400]).await;
+    let writer = Writer::new(&get_settings(port)).unwrap();
+    let body = "gps speed=1 1620000000000000".to_string();
+
+    // Busy or down is worth another try
+    assert!(matches!(
+        writer.write("u", body.clone()).await,
+        Err(WriteError::Retry(_))
+    ));
+    assert!(writer.write("u", body.clone()).await.is_ok());
+    assert!(matches!(
+        writer.write("u", body.clone()).await,
+        Err(WriteError::Retry(_))
+    ));
+
+    // Refused isn't
+    assert!(matches!(
+        writer.write("u", body.clone()).await,
+        Err(WriteError::Rejected(_))
+    ));
+
+    // 2.x doesn't take hours so it isn't sent at all
+    assert!(matches!(
+        writer.write("h", body.clone()).await,
+        Err(WriteError::Rejected(_))
+    ));
+
+    let request = requests.recv_async().await.unwrap();
+    assert_eq!(
+        request.target,
+        "/api/v2/write?org=pyrinas&bucket=data&precision=us"
+    );
+    assert_eq!(request.headers["authorization"], "Token secret");
+    assert!(request.headers["content-type"].starts_with("text/plain"));
+    assert_eq!(request.body, body);
+
+    // One request for each write
+    for _ in 0..3 {
+        assert_eq!(requests.recv_async().await.unwrap().body, body);
+    }
+
+    assert!(requests.is_empty());
+}
+
+#[tokio::test]
+async fn batch_retry_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    // Busy the first time
+    let (port, requests) = serve(vec![503]).await;
+    let settings = get_settings(port);
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let spool = Spool::open(&db, 100).unwrap();
+    let writer = Writer::new(&settings).unwrap();
+
+    let sender = broker_sender.clone();
+    tokio::task::spawn(async move {
+        influx::run_with_writer(writer, spool, &settings.batch, sender).await;
+    });
+
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+    common::wait_for(&broker_sender, &sock, influx::RUNNER_NAME).await;
+
+    // A full batch
+    for i in 0..3 {
+        let point = SeriesPoint::new("gps", 1620000000000 + i).field("speed", i);
+
+        broker_sender
+            .send_async(Event::DataSave(Box::new(point)))
+            .await
+            .unwrap();
+    }
+
+    // Written as one
+    let first = requests.recv_async().await.unwrap();
+    assert_eq!(
+        first.target,
+        "/api/v2/write?org=pyrinas&bucket=data&precision=ms"
+    );
+    assert_eq!(first.body.lines().count(), 3);
+
+    // Then once more after backing off
+    let second = requests.recv_async().await.unwrap();
+    assert_eq!(second.body, first.body);
+
+    // And not again
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(requests.is_empty());
+}
+
+#[tokio::test]
+async fn expire_unwritten_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    // Down for the first write. Not tried again during the test.
+    let (port, requests) = serve(vec![503]).await;
+    let mut settings = get_settings(port);
+    settings.batch.initial_backoff_ms = 60 * 1000;
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let spool = Spool::open(&db, 100).unwrap();
+    let metrics = spool.metrics();
+    let writer = Writer::new(&settings).unwrap();
+
+    let sender = broker_sender.clone();
+    tokio::task::spawn(async move {
+        influx::run_with_writer(writer, spool, &settings.batch, sender).await;
+    });
+
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+    common::wait_for(&broker_sender, &sock, influx::RUNNER_NAME).await;
+
+    for time_ms in [1000, 2000, 3000] {
+        let point = SeriesPoint::new("gps", time_ms).field("speed", 1);
+
+        broker_sender
+            .send_async(Event::DataSave(Box::new(point)))
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(requests.recv_async().await.unwrap().body.lines().count(), 3);
+
+    broker_sender
+        .send_async(Event::DataExpire {
+            measurement: "gps".to_string(),
+            before_ms: 2500,
+        })
+        .await
+        .unwrap();
+
+    // Removed from the spool before the delete is sent
+    let delete = requests.recv_async().await.unwrap();
+    assert!(delete.target.starts_with("/api/v2/delete"));
+    assert_eq!(metrics.points(), 1);
+}
+
+#[tokio::test]
+async fn spool_replay_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    // Down for the first write
+    let (port, requests) = serve(vec![503]).await;
+    let settings = get_settings(port);
+
+    // Left from the last run
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let spool = Spool::open(&db, 100).unwrap();
+    for body in ["first", "second", "third"] {
+        spool.push(&entry(body, 1)).unwrap();
+    }
+
+    let metrics = spool.metrics();
+    let writer = Writer::new(&settings).unwrap();
+
+    let sender = broker_sender.clone();
+    tokio::task::spawn(async move {
+        influx::run_with_writer(writer, spool, &settings.batch, sender).await;
+    });
+
+    // Oldest first. The failed batch is tried again before the others.
+    let mut bodies = Vec::new();
+    for _ in 0..4 {
+        bodies.push(requests.recv_async().await.unwrap().body);
+    }
+
+    assert_eq!(bodies, ["first", "first", "second", "third"]);
+
+    // Removed once they're written
+    while metrics.batches() > 0 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    assert!(requests.is_empty());
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use pyrinas_server::server::ServerHandle;
+use pyrinas_server::{broker, metrics, settings, ApplicationData, Event, PyrinasServer};
+
+mod common;
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+fn get_settings() -> Arc<settings::PyrinasSettings> {
+    let path = format!("{}/../config.minimal.toml", env!("CARGO_MANIFEST_DIR"));
+    Arc::new(settings::PyrinasSettings::new(path).unwrap())
+}
+
+/// Wait for a runner to register
+async fn wait_for(handle: &ServerHandle, name: &str) {
+    let sock = broker::register("sock", handle.sender()).await.unwrap();
+    common::wait_for(handle.sender(), &sock, name).await;
+}
+
+/// Status line and body
+async fn get(port: u16, path: &str) -> (String, String) {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    stream
+        .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+        .await
+        .unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let status = head.lines().next().unwrap().to_string();
+
+    (status, body.to_string())
+}
+
+#[tokio::test]
+async fn metrics_success() {
+    // Log setup
+    setup();
+
+    let ota_db = sled::Config::new().temporary(true).open().unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let handle = PyrinasServer::builder(get_settings())
+        .mqtt(false)
+        .influx(false)
+        .admin(false)
+        .ota_db(ota_db)
+        .devices(false)
+        .metrics_listener(listener)
+        .build()
+        .unwrap()
+        .start();
+
+    tokio::time::timeout(Duration::from_secs(5), wait_for(&handle, "ota"))
+        .await
+        .unwrap();
+
+    let (status, body) = get(port, "/health").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(body, "ok\n");
+
+    let (status, _) = get(port, "/ready").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+
+    let (status, _) = get(port, "/other").await;
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+    handle
+        .sender()
+        .send_async(Event::ApplicationRequest(ApplicationData {
+            uid: "1234".to_string(),
+            target: "data".to_string(),
+            msg: vec![1],
+        }))
+        .await
+        .unwrap();
+
+    // Counted once the broker gets to it
+    let counted = "pyrinas_broker_events_total{kind=\"ApplicationRequest\"} 1";
+    let body = tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            let (status, body) = get(port, "/metrics").await;
+            assert_eq!(status, "HTTP/1.1 200 OK");
+
+            if body.contains(counted) {
+                break body;
+            }
+
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    assert!(body.contains("# TYPE pyrinas_broker_queue_depth gauge"));
+    assert!(body.contains("pyrinas_task_up{task=\"ota\"} 1"));
+    assert!(body.contains("pyrinas_ready 1"));
+
+    tokio::time::timeout(Duration::from_secs(5), handle.stop())
+        .await
+        .unwrap()
+        .unwrap();
+
+    // No longer ready once stopped
+    assert_eq!(metrics::get(metrics::READY, &[]), Some(0.0));
+    assert!(metrics::ready().is_err());
+}
@@ -0,0 +1,233 @@
+use pyrinas_server::device::Registry;
+use pyrinas_server::encoding::Encoding;
+use pyrinas_server::mqtt::{verify_identity, Inbound};
+use pyrinas_server::topic::{Direction, Topic, TopicKind, TopicScheme};
+use pyrinas_server::{broker, settings, Event};
+use pyrinas_shared::{DeviceState, DeviceUpdate, OtaRequestCmd};
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+fn get_msg(event: Event) -> Vec<u8> {
+    match event {
+        Event::ApplicationRequest(r) => {
+            assert_eq!(r.uid, "1234");
+            assert_eq!(r.target, "env");
+            r.msg
+        }
+        _ => panic!("Unexpected event!"),
+    }
+}
+
+/// Published by device 1234
+fn get_topic() -> Topic {
+    Topic::new(
+        "1234",
+        TopicKind::Application,
+        Direction::Publish,
+        Some("env"),
+    )
+}
+
+#[test]
+fn verify_identity_success() {
+    let topic = get_topic();
+
+    assert!(verify_identity(Some("1234"), &topic, true).is_ok());
+    assert!(verify_identity(None, &topic, false).is_ok());
+}
+
+#[test]
+fn verify_identity_failure() {
+    let topic = get_topic();
+
+    assert!(verify_identity(Some("5678"), &topic, false).is_err());
+    assert!(verify_identity(None, &topic, true).is_err());
+}
+
+#[tokio::test]
+async fn inbound_identity_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let app = broker::register("app", &broker_sender).await.unwrap();
+
+    let mut inbound = Inbound {
+        topics: TopicScheme::default(),
+        require_identity: false,
+        devices: None,
+        encodings: Default::default(),
+        telemetry: Default::default(),
+    };
+
+    // Another device pretending to be 1234
+    inbound
+        .handle(Some("5678"), "1234/app/p/env", &[vec![0]], &broker_sender)
+        .await;
+
+    inbound
+        .handle(Some("1234"), "1234/app/p/env", &[vec![1]], &broker_sender)
+        .await;
+
+    inbound
+        .handle(None, "1234/app/p/env", &[vec![2]], &broker_sender)
+        .await;
+
+    // Unknown publishers are rejected too
+    inbound.require_identity = true;
+
+    inbound
+        .handle(None, "1234/app/p/env", &[vec![3]], &broker_sender)
+        .await;
+
+    inbound
+        .handle(Some("1234"), "1234/app/p/env", &[vec![4]], &broker_sender)
+        .await;
+
+    assert_eq!(get_msg(app.recv_async().await.unwrap()), vec![1]);
+    assert_eq!(get_msg(app.recv_async().await.unwrap()), vec![2]);
+    assert_eq!(get_msg(app.recv_async().await.unwrap()), vec![4]);
+}
+
+#[tokio::test]
+async fn inbound_suspended_failure() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let app = broker::register("app", &broker_sender).await.unwrap();
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let registry = Registry::open(&db).unwrap();
+
+    let inbound = Inbound {
+        topics: TopicScheme::default(),
+        require_identity: false,
+        devices: None,
+        encodings: Default::default(),
+        telemetry: Default::default(),
+    }
+    .with_registry(registry.clone());
+
+    let mut update = DeviceUpdate {
+        uid: "1234".to_string(),
+        state: Some(DeviceState::Suspended),
+        ..Default::default()
+    };
+    registry.add(&update, 0).unwrap();
+
+    inbound
+        .handle(None, "1234/app/p/env", &[vec![0]], &broker_sender)
+        .await;
+
+    // Accepted again once it's active
+    update.state = Some(DeviceState::Active);
+    registry.update(&update).unwrap();
+
+    inbound
+        .handle(None, "1234/app/p/env", &[vec![1]], &broker_sender)
+        .await;
+
+    assert_eq!(get_msg(app.recv_async().await.unwrap()), vec![1]);
+}
+
+#[tokio::test]
+async fn inbound_json_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let app = broker::register("app", &broker_sender).await.unwrap();
+    let ota = broker::register("ota", &broker_sender).await.unwrap();
+
+    let inbound = Inbound {
+        topics: TopicScheme::default(),
+        require_identity: false,
+        devices: None,
+        encodings: Default::default(),
+        telemetry: Default::default(),
+    };
+
+    inbound
+        .handle(
+            None,
+            "1234/app/p/env/json",
+            &[br#"{"temp":21}"#],
+            &broker_sender,
+        )
+        .await;
+
+    inbound
+        .handle(
+            None,
+            "1234/ota/p/json",
+            &[br#"{"cmd":2,"id":"1.0.0","start_pos":0,"end_pos":512}"#],
+            &broker_sender,
+        )
+        .await;
+
+    // Applications get CBOR no matter what was sent
+    let msg = get_msg(app.recv_async().await.unwrap());
+    let value: serde_cbor::Value = serde_cbor::from_slice(&msg).unwrap();
+    assert_eq!(
+        value,
+        serde_cbor::Value::Map(
+            vec![(
+                serde_cbor::Value::Text("temp".to_string()),
+                serde_cbor::Value::Integer(21)
+            )]
+            .into_iter()
+            .collect()
+        )
+    );
+
+    match ota.recv_async().await.unwrap() {
+        Event::OtaRequest { device_uid, msg } => {
+            assert_eq!(device_uid, "1234");
+            assert_eq!(msg.cmd, OtaRequestCmd::DownloadBytes);
+            assert_eq!(msg.end_pos, Some(512));
+        }
+        _ => panic!("Unexpected event!"),
+    };
+
+    // Replies use the same encoding
+    assert_eq!(
+        inbound.encodings.get("1234", TopicKind::Application),
+        Encoding::Json
+    );
+    assert_eq!(
+        inbound.encodings.get("1234", TopicKind::Ota),
+        Encoding::Json
+    );
+    assert_eq!(
+        inbound.encodings.get("1234", TopicKind::Telemetry),
+        Encoding::Cbor
+    );
+
+    // Until the device goes back to CBOR
+    inbound
+        .handle(None, "1234/app/p/env", &[vec![0xa0]], &broker_sender)
+        .await;
+
+    assert_eq!(get_msg(app.recv_async().await.unwrap()), vec![0xa0]);
+    assert_eq!(
+        inbound.encodings.get("1234", TopicKind::Application),
+        Encoding::Cbor
+    );
+}
@@ -4,7 +4,7 @@ use flume::unbounded;
 
 use pyrinas_shared::ota::v2::{OTAImageData, OTAImageType, OTAPackage, OTAUpdate};
 use pyrinas_shared::ota::OTAPackageVersion;
-use pyrinas_shared::{OtaRequest, OtaRequestCmd};
+use pyrinas_shared::{ManagmentDataType, OtaRequest, OtaRequestCmd};
 
 use pyrinas_server::ota;
 use pyrinas_server::Event;
@@ -196,7 +196,7 @@ async fn test_ota_new_package_event() {
     let (sender, _) = unbounded::<Event>();
 
     // New OTA package event
-    let event = Event::OtaNewPackage(update);
+    let event = Event::OtaNewPackage(None, update);
 
     // Process
     ota::process_event(&sender, &db, &event).await;
@@ -265,7 +265,7 @@ async fn test_ota_request_check_event_found() {
     let (sender, _) = unbounded::<Event>();
 
     // Save update and then try to get it
-    let event = Event::OtaNewPackage(update.clone());
+    let event = Event::OtaNewPackage(None, update.clone());
 
     // Process
     ota::process_event(&sender, &db, &event).await;
@@ -301,7 +301,7 @@ async fn test_ota_request_check_seconary_found() {
     let (sender, _) = unbounded::<Event>();
 
     // Save update and then try to get it
-    let event = Event::OtaNewPackage(update.clone());
+    let event = Event::OtaNewPackage(None, update.clone());
 
     // Process
     ota::process_event(&sender, &db, &event).await;
@@ -337,13 +337,14 @@ async fn test_ota_request_assign_and_check() {
     let (sender, receiver) = unbounded::<Event>();
 
     // Save update and then try to get it
-    let event = Event::OtaNewPackage(initial_update.clone());
+    let event = Event::OtaNewPackage(None, initial_update.clone());
 
     // Process
     ota::process_event(&sender, &db, &event).await;
 
     // Then assign the new image to a device
     let event = Event::OtaLink {
+        id: None,
         device_id: Some("1234".to_string()),
         group_id: Some("1".to_string()),
         image_id: Some(update_id.clone()),
@@ -388,7 +389,7 @@ async fn test_ota_request_empty_group_and_device_lists() {
     let (sender, receiver) = unbounded::<Event>();
 
     // Save update and then try to get it
-    let event = Event::OtaUpdateGroupListRequest();
+    let event = Event::OtaUpdateGroupListRequest(None);
 
     // Process
     ota::process_event(&sender, &db, &event).await;
@@ -398,7 +399,7 @@ async fn test_ota_request_empty_group_and_device_lists() {
 
     // Len should be 0
     match event {
-        Event::OtaUpdateGroupListRequestResponse(r) => {
+        Event::OtaUpdateGroupListRequestResponse(_, r) => {
             assert_eq!(r.groups.len(), 0);
         }
         _ => {
@@ -407,7 +408,7 @@ async fn test_ota_request_empty_group_and_device_lists() {
     };
 
     // Save update and then try to get it
-    let event = Event::OtaUpdateImageListRequest();
+    let event = Event::OtaUpdateImageListRequest(None);
 
     // Process
     ota::process_event(&sender, &db, &event).await;
@@ -417,7 +418,7 @@ async fn test_ota_request_empty_group_and_device_lists() {
 
     // Len should be 0
     match event {
-        Event::OtaUpdateImageListRequestResponse(r) => {
+        Event::OtaUpdateImageListRequestResponse(_, r) => {
             assert_eq!(r.images.len(), 0);
         }
         _ => {
@@ -446,13 +447,14 @@ async fn test_ota_request_associate_device_image_group_and_get_group_list_and_im
     let (sender, receiver) = unbounded::<Event>();
 
     // Save update and then try to get it
-    let event = Event::OtaNewPackage(initial_update.clone());
+    let event = Event::OtaNewPackage(None, initial_update.clone());
 
     // Process
     ota::process_event(&sender, &db, &event).await;
 
     // Then assign the new image to a device
     let event = Event::OtaLink {
+        id: None,
         device_id: Some("1234".to_string()),
         group_id: Some("1".to_string()),
         image_id: Some(update_id.clone()),
@@ -464,7 +466,7 @@ async fn test_ota_request_associate_device_image_group_and_get_group_list_and_im
     receiver.recv().unwrap();
 
     // Save update and then try to get it
-    let event = Event::OtaUpdateGroupListRequest();
+    let event = Event::OtaUpdateGroupListRequest(None);
 
     // Process
     ota::process_event(&sender, &db, &event).await;
@@ -474,7 +476,7 @@ async fn test_ota_request_associate_device_image_group_and_get_group_list_and_im
 
     // Len should be 0
     match event {
-        Event::OtaUpdateGroupListRequestResponse(r) => {
+        Event::OtaUpdateGroupListRequestResponse(_, r) => {
             assert_eq!(r.groups.len(), 1);
             assert_eq!(r.groups[0], "1".to_string());
         }
@@ -484,7 +486,7 @@ async fn test_ota_request_associate_device_image_group_and_get_group_list_and_im
     };
 
     // Save update and then try to get it
-    let event = Event::OtaUpdateImageListRequest();
+    let event = Event::OtaUpdateImageListRequest(None);
 
     // Process
     ota::process_event(&sender, &db, &event).await;
@@ -494,7 +496,7 @@ async fn test_ota_request_associate_device_image_group_and_get_group_list_and_im
 
     // Len should be 0
     match event {
-        Event::OtaUpdateImageListRequestResponse(r) => {
+        Event::OtaUpdateImageListRequestResponse(_, r) => {
             assert_eq!(r.images.len(), 1);
             assert_eq!(r.images[0].0, update_id);
         }
@@ -524,13 +526,14 @@ async fn test_ota_request_associate_and_dissociate() {
     let (sender, receiver) = unbounded::<Event>();
 
     // Save update and then try to get it
-    let event = Event::OtaNewPackage(initial_update.clone());
+    let event = Event::OtaNewPackage(None, initial_update.clone());
 
     // Process
     ota::process_event(&sender, &db, &event).await;
 
     // Then assign the new image to a device
     let event = Event::OtaLink {
+        id: None,
         device_id: Some("1234".to_string()),
         group_id: Some("1".to_string()),
         image_id: Some(update_id.clone()),
@@ -543,6 +546,7 @@ async fn test_ota_request_associate_and_dissociate() {
 
     // Then assign the new image to a device
     let event = Event::OtaUnlink {
+        id: None,
         device_id: Some("1234".to_string()),
         group_id: None,
     };
@@ -552,3 +556,91 @@ async fn test_ota_request_associate_and_dissociate() {
     assert!(db.devices.get(&"1234".to_string()).unwrap().is_none());
     assert!(db.groups.get(&"1".to_string()).unwrap().is_some());
 }
+
+#[tokio::test]
+/// Requests with an id get an acknowledgement with the same id
+async fn test_ota_request_ack_success() {
+    // Log setup
+    setup();
+
+    // Creates temporary in-memory database
+    let db: sled::Db = sled::Config::new().temporary(true).open().unwrap();
+    let db = ota::init_trees(&db).unwrap();
+
+    // Get the sender/reciever associated with this particular task
+    let (sender, receiver) = unbounded::<Event>();
+
+    // Save update with an id
+    let event = Event::OtaNewPackage(Some(42), get_update(1, 2, 0));
+
+    // Process
+    ota::process_event(&sender, &db, &event).await;
+
+    // Get the event sent.
+    match receiver.recv().unwrap() {
+        Event::ManagementAck { id, cmd, result } => {
+            assert_eq!(id, 42);
+            assert_eq!(cmd, ManagmentDataType::AddOta);
+            assert!(result.is_ok());
+        }
+        _ => {
+            assert!(false, "Unexpected event!")
+        }
+    };
+
+    // List responses carry the id as well
+    let event = Event::OtaUpdateImageListRequest(Some(43));
+
+    // Process
+    ota::process_event(&sender, &db, &event).await;
+
+    // Get the event sent.
+    match receiver.recv().unwrap() {
+        Event::OtaUpdateImageListRequestResponse(id, r) => {
+            assert_eq!(id, Some(43));
+            assert_eq!(r.images.len(), 1);
+        }
+        _ => {
+            assert!(false, "Unexpected event!")
+        }
+    };
+}
+
+#[tokio::test]
+/// Failed requests with an id get an error acknowledgement
+async fn test_ota_request_ack_failure() {
+    // Log setup
+    setup();
+
+    // Creates temporary in-memory database
+    let db: sled::Db = sled::Config::new().temporary(true).open().unwrap();
+    let db = ota::init_trees(&db).unwrap();
+
+    // Get the sender/reciever associated with this particular task
+    let (sender, receiver) = unbounded::<Event>();
+
+    // Device only is not a valid link
+    let event = Event::OtaLink {
+        id: Some(7),
+        device_id: Some("1234".to_string()),
+        group_id: None,
+        image_id: None,
+    };
+
+    ota::process_event(&sender, &db, &event).await;
+
+    // Get the event sent.
+    match receiver.recv().unwrap() {
+        Event::ManagementAck { id, cmd, result } => {
+            assert_eq!(id, 7);
+            assert_eq!(cmd, ManagmentDataType::LinkOta);
+            assert!(result.is_err());
+        }
+        _ => {
+            assert!(false, "Unexpected event!")
+        }
+    };
+
+    // Nothing else was sent
+    assert!(receiver.is_empty());
+}
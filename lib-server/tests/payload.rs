@@ -0,0 +1,91 @@
+use chrono::{TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+
+use pyrinas_server::payload::PyrinasPayload;
+use pyrinas_server::SeriesPoint;
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, PyrinasPayload)]
+struct NetworkReport {
+    rsrp: i32,
+    #[pyrinas(rename = "mode")]
+    m: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, PyrinasPayload)]
+#[pyrinas(measurement = "gps")]
+struct GpsReport {
+    lat: f64,
+    #[pyrinas(tag)]
+    fix: u8,
+    #[pyrinas(flatten)]
+    nw: NetworkReport,
+    acc: Option<f32>,
+    #[pyrinas(skip)]
+    seq: u32,
+    #[pyrinas(timestamp)]
+    ts: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, PyrinasPayload)]
+struct BatteryLevel {
+    val: u32,
+}
+
+fn get_report() -> GpsReport {
+    GpsReport {
+        lat: 1.5,
+        fix: 3,
+        nw: NetworkReport {
+            rsrp: -90,
+            m: "lte".to_string(),
+        },
+        acc: None,
+        seq: 7,
+        ts: 1620000000000,
+    }
+}
+
+#[test]
+fn to_point_success() {
+    let point = get_report().to_point("1234").unwrap();
+
+    let expected = SeriesPoint::new("gps", 1620000000000)
+        .tag("id", "1234")
+        .tag("fix", "3")
+        .field("lat", 1.5)
+        .field("rsrp", Value::Integer(-90))
+        .field("mode", "lte".to_string());
+
+    assert_eq!(point, expected);
+}
+
+#[test]
+fn decode_success() {
+    let msg = serde_cbor::to_vec(&get_report()).unwrap();
+
+    let (report, point) = GpsReport::decode(&msg, "1234").unwrap();
+    assert_eq!(report, get_report());
+    assert_eq!(point, get_report().to_point("1234").unwrap());
+
+    assert!(GpsReport::decode(&[0xff], "1234").is_err());
+}
+
+#[test]
+fn defaults_success() {
+    let before = Utc::now().timestamp_millis();
+    let point = BatteryLevel { val: 3300 }.to_point("1234").unwrap();
+
+    // Named after the struct and timestamped now
+    assert_eq!(point.measurement, "battery_level");
+    assert!(point.time_ms >= before);
+    assert_eq!(point.fields.get("val"), Some(&Value::Integer(3300)));
+}
+
+#[test]
+fn datetime_success() {
+    use pyrinas_server::payload::PayloadTime;
+
+    let ts = Utc.timestamp_millis_opt(1620000000123).unwrap();
+    assert_eq!(ts.time_ms(), 1620000000123);
+}
@@ -0,0 +1,152 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use pyrinas_server::recorder::{Reader, Recorder};
+use pyrinas_server::{broker, replay, settings, ApplicationData, Event};
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+/// Unique path for a recording
+fn get_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("pyrinas-{}-{}.log", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    path
+}
+
+fn get_data(index: u8) -> ApplicationData {
+    ApplicationData {
+        uid: "1234".to_string(),
+        target: "data".to_string(),
+        msg: vec![index],
+    }
+}
+
+#[test]
+fn record_and_read_success() {
+    // Log setup
+    setup();
+
+    let path = get_path("record");
+
+    let mut recorder = Recorder::open(&path).unwrap();
+    recorder
+        .record(&Event::ApplicationRequest(get_data(0)))
+        .unwrap();
+    recorder
+        .record(&Event::ApplicationResponse(get_data(1)))
+        .unwrap();
+    recorder
+        .record(&Event::OtaUpdateImageListRequest(Some(2)))
+        .unwrap();
+    recorder.flush().unwrap();
+
+    let records: Vec<Event> = Reader::open(&path)
+        .unwrap()
+        .map(|r| r.unwrap().event)
+        .collect();
+
+    assert_eq!(records.len(), 3);
+
+    match &records[0] {
+        Event::ApplicationRequest(r) => assert_eq!(r.msg, vec![0]),
+        _ => panic!("Unexpected event!"),
+    };
+
+    match &records[1] {
+        Event::ApplicationResponse(r) => assert_eq!(r.msg, vec![1]),
+        _ => panic!("Unexpected event!"),
+    };
+
+    match &records[2] {
+        Event::OtaUpdateImageListRequest(id) => assert_eq!(*id, Some(2)),
+        _ => panic!("Unexpected event!"),
+    };
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn broker_record_success() {
+    // Log setup
+    setup();
+
+    let path = get_path("broker");
+
+    let settings = settings::Broker {
+        liveness_interval_ms: 10,
+        record_path: Some(path.to_string_lossy().to_string()),
+        ..Default::default()
+    };
+
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let app = broker::register("app", &broker_sender).await.unwrap();
+
+    broker_sender
+        .send_async(Event::ApplicationRequest(get_data(0)))
+        .await
+        .unwrap();
+
+    // Once it's recieved the broker has handled it
+    app.recv_async().await.unwrap();
+
+    // Give it a chance to flush
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    // Registration isn't recorded
+    let records: Vec<Event> = Reader::open(&path)
+        .unwrap()
+        .map(|r| r.unwrap().event)
+        .collect();
+
+    assert_eq!(records.len(), 1);
+    assert!(matches!(records[0], Event::ApplicationRequest(_)));
+
+    let _ = std::fs::remove_file(path);
+}
+
+#[tokio::test]
+async fn feed_success() {
+    // Log setup
+    setup();
+
+    let path = get_path("feed");
+
+    // Device request and the response the app made to it
+    let mut recorder = Recorder::open(&path).unwrap();
+    recorder
+        .record(&Event::ApplicationRequest(get_data(0)))
+        .unwrap();
+    recorder
+        .record(&Event::ApplicationResponse(get_data(1)))
+        .unwrap();
+    recorder.flush().unwrap();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let app = broker::register("app", &broker_sender).await.unwrap();
+    let mqtt = broker::register("mqtt", &broker_sender).await.unwrap();
+
+    // Only the request is replayed
+    assert_eq!(replay::feed(&path, &broker_sender).await.unwrap(), 1);
+
+    match app.recv_async().await.unwrap() {
+        Event::ApplicationRequest(r) => assert_eq!(r.msg, vec![0]),
+        _ => panic!("Unexpected event!"),
+    };
+
+    assert!(mqtt.is_empty());
+
+    let _ = std::fs::remove_file(path);
+}
@@ -0,0 +1,274 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use serde_cbor::Value;
+use tokio::sync::Mutex;
+
+use pyrinas_server::retention::{self, Policies};
+use pyrinas_server::settings::{self, Aggregate};
+use pyrinas_server::sink::{self, DataSink, Route};
+use pyrinas_server::{broker, Error};
+use pyrinas_shared::SeriesPoint;
+
+mod common;
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+const START_MS: i64 = 1620000000000;
+
+/// 1 minute rollups kept for 90 days and raw points kept for 7
+fn get_policies() -> Vec<settings::Retention> {
+    vec![settings::Retention {
+        measurement: "gps".to_string(),
+        keep_days: Some(7),
+        rollups: vec![
+            settings::Rollup {
+                interval_secs: 60,
+                measurement: None,
+                aggregates: vec![Aggregate::Mean, Aggregate::Min, Aggregate::Max],
+                keep_days: Some(90),
+                late_secs: 60,
+            },
+            settings::Rollup {
+                interval_secs: 3600,
+                measurement: Some("gps_hourly".to_string()),
+                aggregates: vec![Aggregate::Count],
+                keep_days: None,
+                late_secs: 60,
+            },
+        ],
+    }]
+}
+
+fn get_db() -> sled::Db {
+    sled::Config::new().temporary(true).open().unwrap()
+}
+
+fn point(uid: &str, time_ms: i64, speed: f64) -> SeriesPoint {
+    SeriesPoint::new("gps", time_ms)
+        .tag("id", uid)
+        .field("speed", speed)
+        .field("sats", 4)
+        .field("fix", "3d".to_string())
+}
+
+/// Keeps everything it's asked to remove
+#[derive(Clone, Default)]
+struct Expired {
+    removed: Arc<std::sync::Mutex<Vec<(String, i64)>>>,
+}
+
+impl DataSink for Expired {
+    fn write<'a>(&'a mut self, _points: &'a [SeriesPoint]) -> BoxFuture<'a, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn expire<'a>(
+        &'a mut self,
+        measurement: &'a str,
+        before_ms: i64,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.removed
+            .lock()
+            .unwrap()
+            .push((measurement.to_string(), before_ms));
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[test]
+fn rollup_success() {
+    let mut policies = Policies::open(&get_db(), &get_policies()).unwrap();
+
+    for (uid, offset_ms, speed) in [
+        ("1234", 0, 1.0),
+        ("1234", 30 * 1000, 3.0),
+        ("5678", 10 * 1000, 7.0),
+        ("1234", 60 * 1000, 5.0),
+    ] {
+        policies.add(&point(uid, START_MS + offset_ms, speed), START_MS);
+    }
+
+    // Other measurements are left alone
+    policies.add(
+        &SeriesPoint::new("telemetry", START_MS).field("rsrp", 1),
+        START_MS,
+    );
+
+    // Not over yet
+    assert!(policies.take_ended(START_MS + 60 * 1000).is_empty());
+
+    // First minute from both devices
+    let points = policies.take_ended(START_MS + 2 * 60 * 1000);
+    assert_eq!(points.len(), 2);
+
+    assert_eq!(points[0].measurement, "gps_1m");
+    assert_eq!(points[0].time_ms, START_MS);
+    assert_eq!(points[0].tags["id"], "1234");
+    assert_eq!(points[0].fields["speed_mean"], Value::Float(2.0));
+    assert_eq!(points[0].fields["speed_min"], Value::Float(1.0));
+    assert_eq!(points[0].fields["speed_max"], Value::Float(3.0));
+    assert_eq!(points[0].fields["sats_mean"], Value::Float(4.0));
+    assert!(!points[0].fields.contains_key("fix_mean"));
+
+    assert_eq!(points[1].tags["id"], "5678");
+    assert_eq!(points[1].fields["speed_mean"], Value::Float(7.0));
+
+    // Taken once
+    assert!(policies.take_ended(START_MS + 2 * 60 * 1000).is_empty());
+
+    // The rest once the hour is over
+    let points = policies.take_ended(START_MS + 2 * 3600 * 1000);
+    assert_eq!(points.len(), 3);
+    assert_eq!(points[0].measurement, "gps_1m");
+    assert_eq!(points[0].time_ms, START_MS + 60 * 1000);
+    assert_eq!(points[1].measurement, "gps_hourly");
+    assert_eq!(points[1].fields["speed_count"], Value::Integer(3));
+    assert_eq!(points[2].fields["speed_count"], Value::Integer(1));
+}
+
+#[test]
+fn rollup_late_success() {
+    let mut policies = Policies::open(&get_db(), &get_policies()).unwrap();
+
+    // Interval was saved a while ago. Only the hourly rollup takes it.
+    policies.add(&point("1234", START_MS, 1.0), START_MS + 10 * 60 * 1000);
+
+    let points = policies.take_ended(START_MS + 2 * 3600 * 1000);
+    assert_eq!(points.len(), 1);
+    assert_eq!(points[0].measurement, "gps_hourly");
+
+    // Unless points that late are still taken
+    let mut settings = get_policies();
+    settings[0].rollups[0].late_secs = 15 * 60;
+    let mut policies = Policies::open(&get_db(), &settings).unwrap();
+
+    policies.add(&point("1234", START_MS, 1.0), START_MS + 10 * 60 * 1000);
+
+    // Held until it's too late for more
+    assert!(policies.take_ended(START_MS + 10 * 60 * 1000).is_empty());
+
+    let points = policies.take_ended(START_MS + 16 * 60 * 1000);
+    assert_eq!(points.len(), 1);
+    assert_eq!(points[0].measurement, "gps_1m");
+}
+
+#[test]
+fn rollup_restart_success() {
+    let db = get_db();
+    let mut policies = Policies::open(&db, &get_policies()).unwrap();
+
+    policies.add(&point("1234", START_MS, 1.0), START_MS);
+    policies.add(&point("1234", START_MS + 30 * 1000, 3.0), START_MS);
+
+    // Only kept in memory until the checkpoint
+    assert!(Policies::open(&db, &get_policies())
+        .unwrap()
+        .take_ended(START_MS + 2 * 3600 * 1000)
+        .is_empty());
+
+    assert_eq!(policies.checkpoint().unwrap(), 2);
+    assert_eq!(policies.checkpoint().unwrap(), 0);
+    drop(policies);
+
+    // Open intervals are picked up again
+    let mut policies = Policies::open(&db, &get_policies()).unwrap();
+    policies.add(&point("1234", START_MS + 40 * 1000, 5.0), START_MS);
+
+    let points = policies.take_ended(START_MS + 2 * 60 * 1000);
+    assert_eq!(points.len(), 1);
+    assert_eq!(points[0].measurement, "gps_1m");
+    assert_eq!(points[0].fields["speed_mean"], Value::Float(3.0));
+    drop(policies);
+
+    // Saved intervals aren't, nor are ones of rollups that have been removed
+    let mut settings = get_policies();
+    settings[0].rollups.truncate(1);
+    let mut policies = Policies::open(&db, &settings).unwrap();
+    assert!(policies.take_ended(START_MS + 2 * 3600 * 1000).is_empty());
+
+    let mut policies = Policies::open(&db, &get_policies()).unwrap();
+    assert!(policies.take_ended(START_MS + 2 * 3600 * 1000).is_empty());
+}
+
+#[test]
+fn policies_failure() {
+    let mut policies = get_policies();
+    policies[0].rollups[0].interval_secs = 0;
+
+    assert!(Policies::open(&get_db(), &policies).is_err());
+}
+
+#[test]
+fn expiries_success() {
+    let policies = Policies::open(&get_db(), &get_policies()).unwrap();
+    let day_ms = 24 * 3600 * 1000;
+
+    assert_eq!(
+        policies.expiries(START_MS),
+        vec![
+            ("gps".to_string(), START_MS - 7 * day_ms),
+            ("gps_1m".to_string(), START_MS - 90 * day_ms),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn expire_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let gps = Expired::default();
+    let telemetry = Expired::default();
+
+    let routes = vec![
+        Route::new("gps", vec!["gps".to_string()], gps.clone()),
+        Route::new(
+            "telemetry",
+            vec!["telemetry".to_string()],
+            telemetry.clone(),
+        ),
+    ];
+
+    tokio::task::spawn(sink::run(
+        Arc::new(Mutex::new(routes)),
+        broker_sender.clone(),
+    ));
+
+    // Wait for the sinks to register
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+    common::wait_for(&broker_sender, &sock, sink::RUNNER_NAME).await;
+
+    // Expires straight away
+    let policies = Policies::open(&get_db(), &get_policies()).unwrap();
+    tokio::task::spawn(retention::run(
+        Arc::new(Mutex::new(policies)),
+        broker_sender.clone(),
+    ));
+
+    // Only the sink that takes gps
+    while gps.removed.lock().unwrap().is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let removed = gps.removed.lock().unwrap().clone();
+    assert_eq!(removed.len(), 1);
+    assert_eq!(removed[0].0, "gps");
+    assert!(telemetry.removed.lock().unwrap().is_empty());
+}
@@ -0,0 +1,206 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+
+use pyrinas_server::server::ServerHandle;
+use pyrinas_server::{broker, settings, ApplicationData, Event, PyrinasServer};
+use pyrinas_shared::{ManagementData, ManagementResponse, ManagmentDataType};
+
+mod common;
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+fn get_settings() -> Arc<settings::PyrinasSettings> {
+    let path = format!("{}/../config.minimal.toml", env!("CARGO_MANIFEST_DIR"));
+    Arc::new(settings::PyrinasSettings::new(path).unwrap())
+}
+
+/// Wait for a runner to register
+async fn wait_for(handle: &ServerHandle, name: &str) {
+    let sock = broker::register("sock", handle.sender()).await.unwrap();
+    common::wait_for(handle.sender(), &sock, name).await;
+}
+
+#[tokio::test]
+async fn builder_runner_success() {
+    // Log setup
+    setup();
+
+    let ota_db = sled::Config::new().temporary(true).open().unwrap();
+
+    // Forwards every app event to the test
+    let (app_sender, app_reciever) = flume::unbounded();
+
+    let handle = PyrinasServer::builder(get_settings())
+        .mqtt(false)
+        .influx(false)
+        .admin(false)
+        .ota_db(ota_db)
+        .devices(false)
+        .runner("app", move |broker_sender| {
+            let app_sender = app_sender.clone();
+            async move {
+                let reciever = broker::register("app", &broker_sender).await.unwrap();
+
+                while let Ok(event) = reciever.recv_async().await {
+                    app_sender.send_async(event).await.unwrap();
+                }
+            }
+        })
+        .build()
+        .unwrap()
+        .start();
+
+    tokio::time::timeout(Duration::from_secs(5), wait_for(&handle, "app"))
+        .await
+        .unwrap();
+
+    handle
+        .sender()
+        .send_async(Event::ApplicationRequest(ApplicationData {
+            uid: "1234".to_string(),
+            target: "data".to_string(),
+            msg: vec![1],
+        }))
+        .await
+        .unwrap();
+
+    // Delivered before the server stops
+    tokio::time::timeout(Duration::from_secs(5), handle.stop())
+        .await
+        .unwrap()
+        .unwrap();
+
+    match app_reciever.recv_async().await.unwrap() {
+        Event::ApplicationRequest(r) => assert_eq!(r.msg, vec![1]),
+        _ => panic!("Unexpected event!"),
+    };
+}
+
+#[tokio::test]
+async fn admin_listener_without_settings_failure() {
+    // Log setup
+    setup();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+
+    let res = PyrinasServer::builder(get_settings())
+        .admin_listener(listener)
+        .build();
+
+    assert!(res.is_err());
+}
+
+#[tokio::test]
+async fn admin_decode_failure() {
+    // Log setup
+    setup();
+
+    let mut settings = (*get_settings()).clone();
+    settings.admin = Some(settings::Admin {
+        port: 0,
+        api_key: "secret".to_string(),
+    });
+
+    let ota_db = sled::Config::new().temporary(true).open().unwrap();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let handle = PyrinasServer::builder(Arc::new(settings))
+        .mqtt(false)
+        .influx(false)
+        .devices(false)
+        .metrics(false)
+        .ota_db(ota_db)
+        .admin_listener(listener)
+        .build()
+        .unwrap()
+        .start();
+
+    let mut request = format!("ws://127.0.0.1:{}", port)
+        .into_client_request()
+        .unwrap();
+    request
+        .headers_mut()
+        .insert("ApiKey", "secret".parse().unwrap());
+
+    let (mut ws, _) = tokio_tungstenite::connect_async(request).await.unwrap();
+
+    let send = |cmd, id, msg: Vec<u8>| {
+        let req = ManagementData {
+            cmd,
+            target: None,
+            msg,
+            id: Some(id),
+        };
+        Message::binary(serde_cbor::to_vec(&req).unwrap())
+    };
+
+    // Not a request at all. Nothing to reply to but the connection stays up.
+    ws.send(Message::binary(vec![0xff, 0x00])).await.unwrap();
+
+    ws.send(send(ManagmentDataType::GetDevice, 1, vec![0xff]))
+        .await
+        .unwrap();
+    ws.send(send(ManagmentDataType::LinkOta, 2, vec![0x01]))
+        .await
+        .unwrap();
+    ws.send(send(ManagmentDataType::GetBrokerStats, 3, Vec::new()))
+        .await
+        .unwrap();
+
+    let mut responses = Vec::new();
+
+    while responses.len() < 3 {
+        let msg = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let res: ManagementResponse = serde_cbor::from_slice(&msg.into_data()).unwrap();
+        responses.push(res);
+    }
+
+    assert_eq!(responses[0].id, Some(1));
+    assert_eq!(responses[0].cmd, ManagmentDataType::GetDevice);
+    assert!(responses[0].result.is_err());
+    assert_eq!(responses[1].id, Some(2));
+    assert!(responses[1].result.is_err());
+    assert_eq!(responses[2].id, Some(3));
+    assert!(responses[2].result.is_ok());
+
+    drop(ws);
+    tokio::time::timeout(Duration::from_secs(5), handle.stop())
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+#[test]
+fn require_identity_failure() {
+    // Off unless it's set
+    assert!(PyrinasServer::builder(get_settings()).build().is_ok());
+
+    let mut settings = (*get_settings()).clone();
+    settings.mqtt.require_identity = true;
+
+    // The bundled broker can't report publishers
+    assert!(PyrinasServer::builder(Arc::new(settings.clone()))
+        .build()
+        .is_err());
+
+    // Fine when messages come from somewhere else
+    assert!(PyrinasServer::builder(Arc::new(settings))
+        .mqtt(false)
+        .build()
+        .is_ok());
+}
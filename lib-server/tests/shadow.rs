@@ -0,0 +1,234 @@
+use std::collections::BTreeMap;
+
+use serde_cbor::Value;
+
+use pyrinas_server::shadow::{self, Store};
+use pyrinas_server::{broker, settings, Event};
+use pyrinas_shared::ShadowUpdate;
+
+mod common;
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+/// Map with text keys
+fn map(entries: Vec<(&str, Value)>) -> Value {
+    Value::Map(
+        entries
+            .into_iter()
+            .map(|(k, v)| (Value::Text(k.to_string()), v))
+            .collect::<BTreeMap<_, _>>(),
+    )
+}
+
+fn get_delta(event: Event) -> Value {
+    match event {
+        Event::ShadowDelta { uid, delta } => {
+            assert_eq!(uid, "1234");
+            *delta
+        }
+        _ => panic!("Unexpected event!"),
+    }
+}
+
+#[test]
+fn merge_success() {
+    let mut target = map(vec![
+        ("interval", Value::Integer(60)),
+        (
+            "gps",
+            map(vec![("mode", Value::Text("single".to_string()))]),
+        ),
+    ]);
+
+    let patch = map(vec![
+        ("interval", Value::Null),
+        ("gps", map(vec![("timeout", Value::Integer(120))])),
+    ]);
+
+    shadow::merge(&mut target, patch).unwrap();
+
+    assert_eq!(
+        target,
+        map(vec![(
+            "gps",
+            map(vec![
+                ("mode", Value::Text("single".to_string())),
+                ("timeout", Value::Integer(120))
+            ])
+        )])
+    );
+}
+
+#[test]
+fn merge_failure() {
+    let mut target = map(vec![]);
+
+    assert!(shadow::merge(&mut target, Value::Integer(1)).is_err());
+}
+
+#[test]
+fn delta_success() {
+    let desired = map(vec![
+        ("interval", Value::Integer(3600)),
+        (
+            "gps",
+            map(vec![
+                ("mode", Value::Text("periodic".to_string())),
+                ("timeout", Value::Integer(120)),
+            ]),
+        ),
+    ]);
+
+    let reported = map(vec![
+        ("interval", Value::Integer(3600)),
+        ("battery", Value::Integer(4100)),
+        (
+            "gps",
+            map(vec![
+                ("mode", Value::Text("single".to_string())),
+                ("timeout", Value::Integer(120)),
+            ]),
+        ),
+    ]);
+
+    // Only desired keys that don't match
+    assert_eq!(
+        shadow::delta(&desired, &reported),
+        map(vec![(
+            "gps",
+            map(vec![("mode", Value::Text("periodic".to_string()))])
+        )])
+    );
+
+    assert_eq!(shadow::delta(&reported, &reported), map(vec![]));
+}
+
+#[test]
+fn store_success() {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let store = Store::open(&db).unwrap();
+
+    // Empty until something is set
+    let doc = store.get("1234").unwrap();
+    assert_eq!(doc.version, 0);
+
+    let (doc, changed) = store
+        .set_desired("1234", map(vec![("interval", Value::Integer(60))]), 1000)
+        .unwrap();
+    assert_eq!(doc.delta, map(vec![("interval", Value::Integer(60))]));
+    assert!(changed);
+
+    let (doc, changed) = store
+        .report("1234", map(vec![("interval", Value::Integer(60))]), 2000)
+        .unwrap();
+    assert_eq!(doc.delta, map(vec![]));
+    assert!(changed);
+    assert_eq!(doc.version, 2);
+    assert_eq!(doc.reported_ms, 2000);
+
+    assert_eq!(store.get("1234").unwrap(), doc);
+
+    // Reporting the same state again isn't a change
+    let (doc, changed) = store
+        .report("1234", map(vec![("interval", Value::Integer(60))]), 3000)
+        .unwrap();
+    assert!(!changed);
+    assert_eq!(doc.version, 2);
+    assert_eq!(doc.reported_ms, 2000);
+
+    // Neither is a new state that leaves the delta empty
+    let (doc, changed) = store
+        .report("1234", map(vec![("rsrp", Value::Integer(-90))]), 4000)
+        .unwrap();
+    assert!(!changed);
+    assert_eq!(doc.version, 3);
+
+    assert_eq!(store.get("1234").unwrap(), doc);
+}
+
+#[tokio::test]
+async fn shadow_events_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    // Stands in for mqtt
+    let mqtt = broker::register("mqtt", &broker_sender).await.unwrap();
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    tokio::task::spawn(shadow::run_with_db(db, broker_sender.clone()));
+
+    common::wait_for(&broker_sender, &sock, shadow::RUNNER_NAME).await;
+
+    // Desired change is published
+    broker_sender
+        .send_async(Event::ShadowDesired(
+            Some(1),
+            Box::new(ShadowUpdate {
+                uid: "1234".to_string(),
+                desired: map(vec![("interval", Value::Integer(3600))]),
+            }),
+        ))
+        .await
+        .unwrap();
+
+    match sock.recv_async().await.unwrap() {
+        Event::ManagementAck { id, result, .. } => {
+            assert_eq!(id, 1);
+            assert!(result.is_ok());
+        }
+        _ => panic!("Unexpected event!"),
+    };
+
+    assert_eq!(
+        get_delta(mqtt.recv_async().await.unwrap()),
+        map(vec![("interval", Value::Integer(3600))])
+    );
+
+    // As is the delta after the device reports
+    broker_sender
+        .send_async(Event::ShadowReported {
+            uid: "1234".to_string(),
+            state: Box::new(map(vec![("interval", Value::Integer(3600))])),
+        })
+        .await
+        .unwrap();
+
+    assert_eq!(get_delta(mqtt.recv_async().await.unwrap()), map(vec![]));
+
+    // Not again when nothing changes
+    broker_sender
+        .send_async(Event::ShadowReported {
+            uid: "1234".to_string(),
+            state: Box::new(map(vec![("interval", Value::Integer(3600))])),
+        })
+        .await
+        .unwrap();
+
+    broker_sender
+        .send_async(Event::ShadowGetRequest(Some(2), "1234".to_string()))
+        .await
+        .unwrap();
+
+    match sock.recv_async().await.unwrap() {
+        Event::ShadowGetResponse(id, doc) => {
+            assert_eq!(id, Some(2));
+            assert_eq!(doc.version, 2);
+            assert_eq!(doc.reported, map(vec![("interval", Value::Integer(3600))]));
+        }
+        _ => panic!("Unexpected event!"),
+    };
+
+    assert!(mqtt.is_empty());
+}
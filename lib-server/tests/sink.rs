@@ -0,0 +1,203 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::BoxFuture;
+use tokio::sync::Mutex;
+
+use pyrinas_server::settings::{self, FileFormat};
+use pyrinas_server::sink::{self, DataSink, Route};
+use pyrinas_server::{broker, Error, Event};
+use pyrinas_shared::SeriesPoint;
+
+mod common;
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+/// Keeps everything it's sent
+#[derive(Clone, Default)]
+struct Collect {
+    points: Arc<std::sync::Mutex<Vec<SeriesPoint>>>,
+}
+
+impl DataSink for Collect {
+    fn write<'a>(&'a mut self, points: &'a [SeriesPoint]) -> BoxFuture<'a, Result<(), Error>> {
+        self.points.lock().unwrap().extend(points.iter().cloned());
+        Box::pin(async { Ok(()) })
+    }
+
+    fn flush(&mut self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+fn get_point(measurement: &str) -> SeriesPoint {
+    SeriesPoint::new(measurement, 1620000000000)
+        .tag("id", "1234")
+        .field("lat", 1.5)
+        .field("note", "a,b".to_string())
+}
+
+fn temp_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("pyrinas-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[tokio::test]
+async fn file_json_success() {
+    let path = temp_path("points.ndjson");
+
+    let mut file = sink::File::new(&settings::FileSink {
+        path: path.to_string_lossy().to_string(),
+        format: FileFormat::Json,
+    });
+
+    file.write(&[get_point("gps"), get_point("telemetry")])
+        .await
+        .unwrap();
+    file.flush().await.unwrap();
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let points: Vec<SeriesPoint> = contents
+        .lines()
+        .map(|l| serde_json::from_str(l).unwrap())
+        .collect();
+
+    assert_eq!(points, vec![get_point("gps"), get_point("telemetry")]);
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn file_csv_success() {
+    let path = temp_path("points.csv");
+
+    let mut file = sink::File::new(&settings::FileSink {
+        path: path.to_string_lossy().to_string(),
+        format: FileFormat::Csv,
+    });
+
+    file.write(&[get_point("gps")]).await.unwrap();
+    file.flush().await.unwrap();
+
+    // Header is only written once
+    let mut file = sink::File::new(&settings::FileSink {
+        path: path.to_string_lossy().to_string(),
+        format: FileFormat::Csv,
+    });
+
+    file.write(&[get_point("gps")]).await.unwrap();
+    file.flush().await.unwrap();
+
+    let contents = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = contents.lines().collect();
+
+    assert_eq!(
+        lines,
+        vec![
+            "time_ms,measurement,tags,field,value",
+            "1620000000000,gps,id=1234,lat,1.5",
+            r#"1620000000000,gps,id=1234,note,"""a,b""""#,
+            "1620000000000,gps,id=1234,lat,1.5",
+            r#"1620000000000,gps,id=1234,note,"""a,b""""#,
+        ]
+    );
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test]
+async fn postgres_backoff_success() {
+    // Log setup
+    setup();
+
+    // Nothing listening
+    let mut postgres = sink::Postgres::new(&settings::PostgresSink {
+        url: "postgres://pyrinas@127.0.0.1:1/pyrinas".to_string(),
+        table: "points".to_string(),
+        timescale: false,
+        max_points: 10,
+        timeout_secs: 1,
+    });
+
+    postgres.write(&[get_point("gps")]).await.unwrap();
+    assert!(postgres.flush().await.is_err());
+
+    // Not tried again straight away
+    assert!(postgres.flush().await.is_ok());
+}
+
+#[tokio::test]
+async fn route_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let gps = Collect::default();
+    let all = Collect::default();
+
+    let routes = vec![
+        Route::new("gps", vec!["gps".to_string()], gps.clone()),
+        Route::new("all", Vec::new(), all.clone()),
+    ];
+
+    tokio::task::spawn(sink::run(
+        Arc::new(Mutex::new(routes)),
+        broker_sender.clone(),
+    ));
+
+    // Wait for the sinks to register
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+    common::wait_for(&broker_sender, &sock, sink::RUNNER_NAME).await;
+
+    for measurement in ["telemetry", "gps"] {
+        broker_sender
+            .send_async(Event::DataSave(Box::new(get_point(measurement))))
+            .await
+            .unwrap();
+    }
+
+    // Handled in order
+    while all.points.lock().unwrap().len() < 2 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    assert_eq!(*gps.points.lock().unwrap(), vec![get_point("gps")]);
+    assert_eq!(
+        *all.points.lock().unwrap(),
+        vec![get_point("telemetry"), get_point("gps")]
+    );
+}
+
+#[tokio::test]
+async fn data_save_without_sinks_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let influx = broker::register("influx", &broker_sender).await.unwrap();
+
+    broker_sender
+        .send_async(Event::DataSave(Box::new(get_point("gps"))))
+        .await
+        .unwrap();
+
+    // Goes straight to Influx
+    match influx.recv_async().await.unwrap() {
+        Event::DataSave(point) => assert_eq!(*point, get_point("gps")),
+        _ => panic!("Unexpected event!"),
+    }
+}
@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+
+use serde_cbor::Value;
+
+use pyrinas_server::timeseries::{self, Store};
+use pyrinas_server::{broker, settings, Event};
+use pyrinas_shared::{SeriesPoint, SeriesQuery};
+
+mod common;
+
+use std::sync::Once;
+
+static INIT: Once = Once::new();
+
+/// Setup function that is only run once, even if called multiple times.
+fn setup() {
+    INIT.call_once(env_logger::init);
+}
+
+const DAY_MS: i64 = 24 * 3600 * 1000;
+
+fn point(measurement: &str, uid: &str, time_ms: i64, value: i128) -> SeriesPoint {
+    SeriesPoint {
+        measurement: measurement.to_string(),
+        time_ms,
+        tags: BTreeMap::from([("id".to_string(), uid.to_string())]),
+        fields: BTreeMap::from([("rsrp".to_string(), Value::Integer(value))]),
+    }
+}
+
+/// A few points from two devices over three days
+fn get_store() -> Store {
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    let store = Store::new(db);
+
+    for day in 0..3 {
+        for hour in 0..4 {
+            let time_ms = day * DAY_MS + hour * 3600 * 1000;
+            store
+                .insert(&point("telemetry", "1234", time_ms, hour as i128))
+                .unwrap();
+            store
+                .insert(&point("telemetry", "5678", time_ms, 0))
+                .unwrap();
+        }
+    }
+
+    store.insert(&point("gps", "1234", DAY_MS, 0)).unwrap();
+
+    store
+}
+
+fn query(uid: &str) -> SeriesQuery {
+    SeriesQuery {
+        measurement: "telemetry".to_string(),
+        tags: BTreeMap::from([("id".to_string(), uid.to_string())]),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn from_line_success() {
+    let point = timeseries::from_line(
+        r#"telemetry,id=1234 rsrp=-95i,temp=21.5,note="ok" 1620000000"#,
+        "s",
+        0,
+    )
+    .unwrap();
+
+    assert_eq!(point.measurement, "telemetry");
+    assert_eq!(point.time_ms, 1620000000000);
+    assert_eq!(point.tags["id"], "1234");
+    assert_eq!(point.fields["rsrp"], Value::Integer(-95));
+    assert_eq!(point.fields["temp"], Value::Float(21.5));
+    assert_eq!(point.fields["note"], Value::Text("ok".to_string()));
+
+    // Missing timestamps are filled in
+    let point = timeseries::from_line("telemetry rsrp=1i", "ms", 42).unwrap();
+    assert_eq!(point.time_ms, 42);
+}
+
+#[test]
+fn from_line_failure() {
+    assert!(timeseries::from_line("telemetry rsrp=1i 1", "d", 0).is_err());
+    assert!(timeseries::from_line("telemetry", "ms", 0).is_err());
+}
+
+#[test]
+fn query_success() {
+    let store = get_store();
+
+    // Everything from one device, oldest first
+    let points = store.query(&query("1234")).unwrap();
+    assert_eq!(points.len(), 12);
+    assert_eq!(points[0].time_ms, 0);
+    assert_eq!(points[11].time_ms, 2 * DAY_MS + 3 * 3600 * 1000);
+
+    // A range spanning two days
+    let mut q = query("1234");
+    q.start_ms = Some(3 * 3600 * 1000);
+    q.end_ms = Some(DAY_MS + 3600 * 1000);
+    let points = store.query(&q).unwrap();
+    assert_eq!(
+        points.iter().map(|p| p.time_ms).collect::<Vec<_>>(),
+        vec![3 * 3600 * 1000, DAY_MS, DAY_MS + 3600 * 1000]
+    );
+
+    // Only the latest
+    let mut q = query("1234");
+    q.limit = Some(2);
+    let points = store.query(&q).unwrap();
+    assert_eq!(points.len(), 2);
+    assert_eq!(points[1].fields["rsrp"], Value::Integer(3));
+    assert_eq!(points[0].fields["rsrp"], Value::Integer(2));
+
+    // Other measurements are left out
+    let mut q = query("1234");
+    q.measurement = "gps".to_string();
+    assert_eq!(store.query(&q).unwrap().len(), 1);
+}
+
+#[test]
+fn expire_success() {
+    let store = get_store();
+
+    // Only whole days are removed
+    assert_eq!(store.expire(DAY_MS + 1).unwrap(), 1);
+    assert_eq!(store.query(&query("1234")).unwrap().len(), 8);
+
+    assert_eq!(store.expire(10 * DAY_MS).unwrap(), 2);
+    assert!(store.query(&query("1234")).unwrap().is_empty());
+}
+
+#[test]
+fn remove_success() {
+    let store = get_store();
+
+    // Only telemetry from before the second hour of the second day
+    assert_eq!(store.remove("telemetry", DAY_MS + 3600 * 1000).unwrap(), 10);
+    assert_eq!(store.query(&query("1234")).unwrap().len(), 7);
+    assert_eq!(
+        store.query(&query("1234")).unwrap()[0].time_ms,
+        DAY_MS + 3600 * 1000
+    );
+
+    let mut q = query("1234");
+    q.measurement = "gps".to_string();
+    assert_eq!(store.query(&q).unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn query_event_success() {
+    // Log setup
+    setup();
+
+    let settings: settings::Broker = Default::default();
+    let (broker_sender, broker_reciever) = broker::channel(&settings);
+    tokio::task::spawn(broker::run(settings, broker_reciever));
+
+    let sock = broker::register("sock", &broker_sender).await.unwrap();
+
+    let db = sled::Config::new().temporary(true).open().unwrap();
+    // Recent enough to be kept
+    let now_ms = chrono::Utc::now().timestamp_millis();
+    Store::new(db.clone())
+        .insert(&point("telemetry", "1234", now_ms, 1))
+        .unwrap();
+
+    let timeseries_settings: settings::Timeseries = Default::default();
+    let sender = broker_sender.clone();
+    tokio::task::spawn(
+        async move { timeseries::run_with_db(db, &timeseries_settings, sender).await },
+    );
+
+    common::wait_for(&broker_sender, &sock, timeseries::RUNNER_NAME).await;
+
+    broker_sender
+        .send_async(Event::SeriesQueryRequest(Some(1), Box::new(query("1234"))))
+        .await
+        .unwrap();
+
+    match sock.recv_async().await.unwrap() {
+        Event::SeriesQueryResponse(id, response) => {
+            assert_eq!(id, Some(1));
+            assert_eq!(response.points, vec![point("telemetry", "1234", now_ms, 1)]);
+        }
+        _ => panic!("Unexpected event!"),
+    };
+}
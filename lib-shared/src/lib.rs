@@ -1,7 +1,9 @@
-use std::str;
+use std::collections::BTreeMap;
+use std::{fmt, str};
 
-use ota::v2::OTAPackage;
-use serde::{Deserialize, Serialize};
+use ota::v2::{OTADeviceType, OTAPackage};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_cbor::Value;
 use serde_repr::*;
 
 use clap::Parser;
@@ -19,6 +21,414 @@ pub struct OtaGroupListResponse {
     pub groups: Vec<String>,
 }
 
+/// Queue statistics for a single broker runner
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BrokerRunnerStats {
+    /// Name the runner registered with
+    pub name: String,
+    /// Events currently waiting in the queue
+    pub queued: usize,
+    /// Maximum number of queued events
+    pub capacity: usize,
+    /// Events currently spilled to disk
+    pub spilled: usize,
+    /// Events dropped since the server started
+    pub dropped: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BrokerStatsResponse {
+    pub runners: Vec<BrokerRunnerStats>,
+    /// Events seen by the `metrics` middleware, by kind
+    #[serde(default)]
+    pub events: Vec<BrokerEventCount>,
+    /// Events rejected by each middleware
+    #[serde(default)]
+    pub rejected: Vec<BrokerEventCount>,
+}
+
+/// Number of events counted under a name
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BrokerEventCount {
+    pub name: String,
+    pub count: u64,
+}
+
+/// An event the broker was unable to deliver
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetterEntry {
+    /// Used to replay or purge this entry
+    pub id: u64,
+    /// Unix timestamp (ms) of when delivery failed
+    pub timestamp_ms: i64,
+    /// Runner the event was meant for
+    pub runner: String,
+    /// Why delivery failed
+    pub reason: String,
+    /// Short description of the event
+    pub event: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeadLetterListResponse {
+    pub entries: Vec<DeadLetterEntry>,
+}
+
+/// Selects dead letters to replay or purge
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeadLetterRequest {
+    /// Entry to act on. All of them if `None`.
+    pub id: Option<u64>,
+}
+
+/// Where a device is in its lifecycle
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceState {
+    /// Added by an admin but hasn't connected yet
+    #[default]
+    Provisioned,
+    Active,
+    /// Messages from the device are refused
+    Suspended,
+    /// Retired. Messages from the device are refused.
+    Decommissioned,
+}
+
+impl DeviceState {
+    /// Whether messages from a device in this state are accepted
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, DeviceState::Provisioned | DeviceState::Active)
+    }
+}
+
+impl fmt::Display for DeviceState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            DeviceState::Provisioned => "provisioned",
+            DeviceState::Active => "active",
+            DeviceState::Suspended => "suspended",
+            DeviceState::Decommissioned => "decommissioned",
+        };
+
+        write!(f, "{}", text)
+    }
+}
+
+impl str::FromStr for DeviceState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "provisioned" => Ok(DeviceState::Provisioned),
+            "active" => Ok(DeviceState::Active),
+            "suspended" => Ok(DeviceState::Suspended),
+            "decommissioned" => Ok(DeviceState::Decommissioned),
+            _ => Err(format!("unknown device state {}", s)),
+        }
+    }
+}
+
+/// Everything the server knows about a single device
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
+pub struct DeviceInfo {
+    pub uid: String,
+    pub online: bool,
+    /// Unix timestamp (ms) of the last message from the device
+    pub last_seen_ms: i64,
+    /// Unix timestamp (ms) of when it last came online or went offline
+    pub changed_ms: i64,
+    #[serde(default)]
+    pub state: DeviceState,
+    #[serde(default)]
+    pub device_type: Option<OTADeviceType>,
+    #[serde(default)]
+    pub board: Option<String>,
+    #[serde(default)]
+    pub imei: Option<String>,
+    #[serde(default)]
+    pub iccid: Option<String>,
+    #[serde(default)]
+    pub firmware_version: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Unix timestamp (ms) of when the device was added
+    #[serde(default)]
+    pub created_ms: i64,
+    /// Unix timestamp (ms) of the first message from the device
+    #[serde(default)]
+    pub first_seen_ms: Option<i64>,
+}
+
+/// Adds a device or changes the fields that are set
+#[derive(Parser, Debug, Serialize, Deserialize, Clone, Default)]
+#[clap(version)]
+pub struct DeviceUpdate {
+    /// Device Id
+    pub uid: String,
+    /// cellular or bluetooth
+    #[clap(long)]
+    pub device_type: Option<OTADeviceType>,
+    #[clap(long)]
+    pub board: Option<String>,
+    #[clap(long)]
+    pub imei: Option<String>,
+    #[clap(long)]
+    pub iccid: Option<String>,
+    #[clap(long)]
+    pub firmware_version: Option<String>,
+    /// Replaces the existing tags. Can be repeated.
+    #[clap(long = "tag")]
+    pub tags: Vec<String>,
+    /// provisioned, active, suspended or decommissioned
+    #[clap(long)]
+    pub state: Option<DeviceState>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DeviceListResponse {
+    pub devices: Vec<DeviceInfo>,
+}
+
+/// Desired and reported state of a device. Both are maps.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShadowDocument {
+    pub uid: String,
+    /// Set by admins and the application
+    pub desired: Value,
+    /// Set by the device
+    pub reported: Value,
+    /// Parts of `desired` that `reported` doesn't match yet
+    pub delta: Value,
+    /// Incremented on every change
+    pub version: u64,
+    /// Unix timestamp (ms) of the last change to `desired`
+    pub desired_ms: i64,
+    /// Unix timestamp (ms) of the last change the device reported
+    pub reported_ms: i64,
+}
+
+impl ShadowDocument {
+    /// Document for a device that doesn't have one yet
+    pub fn new(uid: &str) -> ShadowDocument {
+        ShadowDocument {
+            uid: uid.to_string(),
+            desired: Value::Map(Default::default()),
+            reported: Value::Map(Default::default()),
+            delta: Value::Map(Default::default()),
+            version: 0,
+            desired_ms: 0,
+            reported_ms: 0,
+        }
+    }
+}
+
+/// Merged into a device's desired state. Keys set to `null` are removed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ShadowUpdate {
+    pub uid: String,
+    pub desired: Value,
+}
+
+/// Backend neutral data point. Saved to every data sink and kept by the local time-series store.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SeriesPoint {
+    pub measurement: String,
+    /// Unix timestamp (ms)
+    pub time_ms: i64,
+    pub tags: BTreeMap<String, String>,
+    pub fields: BTreeMap<String, Value>,
+}
+
+impl SeriesPoint {
+    pub fn new(measurement: &str, time_ms: i64) -> SeriesPoint {
+        SeriesPoint {
+            measurement: measurement.to_string(),
+            time_ms,
+            tags: BTreeMap::new(),
+            fields: BTreeMap::new(),
+        }
+    }
+
+    pub fn tag(mut self, name: &str, value: &str) -> SeriesPoint {
+        self.tags.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    pub fn field<T: Into<Value>>(mut self, name: &str, value: T) -> SeriesPoint {
+        self.fields.insert(name.to_string(), value.into());
+        self
+    }
+}
+
+/// Points to get from the local time-series store
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SeriesQuery {
+    /// i.e. `telemetry`
+    pub measurement: String,
+    /// Only points with every one of these tags
+    pub tags: BTreeMap<String, String>,
+    /// Unix timestamp (ms) of the earliest point
+    pub start_ms: Option<i64>,
+    /// Unix timestamp (ms) of the latest point
+    pub end_ms: Option<i64>,
+    /// Only the most recent points, up to this many
+    pub limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SeriesQueryResponse {
+    /// Oldest first
+    pub points: Vec<SeriesPoint>,
+}
+
+/// One series returned by an Influx query
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InfluxSeries {
+    pub name: String,
+    /// Set when the query groups by tag
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    pub columns: Vec<String>,
+    /// One row per point, in the same order as `columns`
+    #[serde(default)]
+    pub values: Vec<Vec<Value>>,
+}
+
+impl InfluxSeries {
+    /// Decode every row into `T` using the column (and tag) names as field names
+    pub fn rows<T: DeserializeOwned>(&self) -> Result<Vec<T>, serde_cbor::Error> {
+        self.values
+            .iter()
+            .map(|row| {
+                let mut map: BTreeMap<Value, Value> = self
+                    .tags
+                    .iter()
+                    .map(|(k, v)| (Value::Text(k.clone()), Value::Text(v.clone())))
+                    .collect();
+
+                for (column, value) in self.columns.iter().zip(row) {
+                    map.insert(Value::Text(column.clone()), value.clone());
+                }
+
+                serde_cbor::value::from_value(Value::Map(map))
+            })
+            .collect()
+    }
+}
+
+/// Result of an Influx query
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct InfluxQueryResponse {
+    pub series: Vec<InfluxSeries>,
+}
+
+/// Identifies a command sent to a device
+pub type CommandId = u64;
+
+/// Run a command on a device
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CommandRequest {
+    pub uid: String,
+    /// Published to `<uid>/cmd/s/<name>`
+    pub name: String,
+    pub msg: Vec<u8>,
+    /// How long to wait for each acknowledgement. Server default if `None`.
+    pub timeout_secs: Option<u64>,
+    /// How many more times to send it if it isn't acknowledged. Server default if `None`.
+    pub retries: Option<u32>,
+}
+
+impl CommandRequest {
+    /// Request with the server's timeout and retries. The server assigns the id.
+    pub fn new(uid: &str, name: &str, msg: Vec<u8>) -> CommandRequest {
+        CommandRequest {
+            uid: uid.to_string(),
+            name: name.to_string(),
+            msg,
+            timeout_secs: None,
+            retries: None,
+        }
+    }
+}
+
+/// Published to `<uid>/cmd/s/<name>`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DeviceCommand {
+    pub id: CommandId,
+    pub msg: Vec<u8>,
+}
+
+/// Published by the device to `<uid>/cmd/p` once it has run a command
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CommandAck {
+    pub id: CommandId,
+    /// 0 on success. Anything else is a failure.
+    pub code: i32,
+    /// Result data or error details
+    #[serde(default)]
+    pub msg: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandState {
+    /// Waiting for the device to come online
+    Queued,
+    /// Waiting for the device to acknowledge it
+    Sent,
+    Succeeded,
+    Failed,
+    /// Wasn't acknowledged after every retry
+    TimedOut,
+}
+
+impl CommandState {
+    /// Whether the command is done
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            CommandState::Succeeded | CommandState::Failed | CommandState::TimedOut
+        )
+    }
+}
+
+impl fmt::Display for CommandState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match self {
+            CommandState::Queued => "queued",
+            CommandState::Sent => "sent",
+            CommandState::Succeeded => "succeeded",
+            CommandState::Failed => "failed",
+            CommandState::TimedOut => "timed out",
+        };
+
+        write!(f, "{}", text)
+    }
+}
+
+/// A command and how it's going
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CommandRecord {
+    pub id: CommandId,
+    pub uid: String,
+    pub name: String,
+    pub msg: Vec<u8>,
+    pub state: CommandState,
+    pub timeout_secs: u64,
+    pub retries: u32,
+    /// Times it has been sent
+    pub attempts: u32,
+    /// Unix timestamp (ms) of when it was requested
+    pub created_ms: i64,
+    /// Unix timestamp (ms) of the last time it was sent
+    pub sent_ms: Option<i64>,
+    /// Unix timestamp (ms) of when it finished
+    pub finished_ms: Option<i64>,
+    /// Acknowledgement from the device
+    pub ack: Option<CommandAck>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct OtaRequest {
     /// Command type
@@ -52,13 +462,46 @@ pub enum ManagmentDataType {
     UnlinkOta,
     GetGroupList,
     GetImageList,
+    GetBrokerStats,
+    GetDeadLetters,
+    ReplayDeadLetters,
+    PurgeDeadLetters,
+    GetDeviceList,
+    GetDevice,
+    AddDevice,
+    UpdateDevice,
+    RemoveDevice,
+    GetShadow,
+    UpdateShadow,
+    SendCommand,
+    GetCommand,
+    QuerySeries,
+    QueryInflux,
 }
 
+/// Identifies an admin request so the response can be matched to it
+pub type CorrelationId = u32;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ManagementData {
     pub cmd: ManagmentDataType,
     pub target: Option<String>,
     pub msg: Vec<u8>,
+    /// Set by the client. Echoed back in the response to this request.
+    #[serde(default)]
+    pub id: Option<CorrelationId>,
+}
+
+/// Response to a `ManagementData` request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ManagementResponse {
+    /// Command this is a response to
+    pub cmd: ManagmentDataType,
+    /// Id of the request this is a response to
+    pub id: Option<CorrelationId>,
+    pub target: Option<String>,
+    /// Response data (depends on `cmd`) or the reason the request failed
+    pub result: Result<Vec<u8>, String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
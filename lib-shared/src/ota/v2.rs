@@ -9,7 +9,7 @@ use chrono::{DateTime, Utc};
 
 use super::OTAPackageVersion;
 
-#[derive(Debug, Serialize_repr, Deserialize_repr, Clone)]
+#[derive(Debug, Serialize_repr, Deserialize_repr, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum OTADeviceType {
     /// Hub/cellular device
@@ -29,6 +29,18 @@ impl fmt::Display for OTADeviceType {
     }
 }
 
+impl str::FromStr for OTADeviceType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cellular" => Ok(OTADeviceType::Cellular),
+            "bluetooth" => Ok(OTADeviceType::Bluetooth),
+            _ => Err(format!("unknown device type {}", s)),
+        }
+    }
+}
+
 // Struct that gets serialized for OTA support
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct OTAPackage {